@@ -2,11 +2,13 @@ extern crate bench;
 extern crate rustfs;
 extern crate rand;
 
-use rustfs::{Proc, O_CREAT, O_RDWR, FileDescriptor};
+use rustfs::{Proc, FileSystem, Geometry, O_CREAT, O_RDWR, FileDescriptor};
 use std::string::String;
+use std::rc::Rc;
 use bench::{benchmark, Benchmarker};
 use rand::random;
-use std::iter::repeat;
+use std::iter::repeat_n;
+use std::env;
 
 static NUM: usize = 100;
 
@@ -14,7 +16,7 @@ macro_rules! bench {
   ($wrap:ident, $name:ident, $time:expr, |$p:ident, $filenames:ident| $task:stmt) => ({
     let $filenames = generate_names(NUM);
     let $wrap = |b: &mut Benchmarker| {
-      let mut $p = Proc::new();
+      let mut $p = Proc::with_geometry(geometry());
       b.run(|| {
         $task
       });
@@ -24,10 +26,14 @@ macro_rules! bench {
 }
 
 macro_rules! bench_many {
-  ($wrap:ident, $name:ident, $time:expr, |$p:ident, $fd:ident, $filename:ident| $op:stmt) => ({
+  ($wrap:ident, $name:ident, $time:expr, |$p:ident, $fd:ident, $filename:ident| $op:stmt) => (
+    bench_many!($wrap, $name, $time, pooled, |$p, $fd, $filename| $op)
+  );
+  ($wrap:ident, $name:ident, $time:expr, $make:ident,
+   |$p:ident, $fd:ident, $filename:ident| $op:stmt) => ({
     let filenames = generate_names(NUM);
     let $wrap = |b: &mut Benchmarker| {
-      let mut $p = Proc::new();
+      let mut $p = $make();
       b.run(|| {
         for i_j in 0..NUM {
           let $filename = &filenames[i_j];
@@ -40,8 +46,33 @@ macro_rules! bench_many {
   })
 }
 
+// The geometry to benchmark, from the optional `page_size list_size` arguments.
+fn geometry() -> Geometry {
+  let args: Vec<usize> = env::args().skip(1).map(|arg| {
+    arg.parse().expect("Arguments must be numbers.")
+  }).collect();
+
+  match args.len() {
+    0 => Geometry::default(),
+    2 => Geometry::new(args[0], args[1]).expect("Invalid geometry."),
+    _ => panic!("Usage: rustfs-bench [page_size list_size]")
+  }
+}
+
+// A `Proc` on a file system that pools freed pages for reuse, the default.
+fn pooled<'a>() -> Proc<'a> {
+  Proc::with_geometry(geometry())
+}
+
+// A `Proc` on a file system that gives freed pages straight back.
+fn unpooled<'a>() -> Proc<'a> {
+  let fs = FileSystem::with_geometry(geometry());
+  fs.set_pool_limit(0);
+  Proc::with_fs(Rc::new(fs))
+}
+
 fn ceil_div(x: usize, y: usize) -> usize {
-  return (x + y - 1) / y;
+  x.div_ceil(y)
 }
 
 fn rand_array(size: usize) -> Vec<u8> {
@@ -50,7 +81,7 @@ fn rand_array(size: usize) -> Vec<u8> {
 
 fn generate_names(n: usize) -> Vec<String> {
   let name_length = ceil_div(n, 26);
-  let mut name: Vec<_> = repeat('@' as u8).take(name_length).collect();
+  let mut name: Vec<_> = repeat_n(b'@', name_length).collect();
 
   (0..n).map(|i| {
     let next = name[i / 26] + 1;
@@ -64,27 +95,28 @@ fn generate_names(n: usize) -> Vec<String> {
   }).collect()
 }
 
-fn open_many<'a>(p: &mut Proc<'a>, names: &'a Vec<String>) -> Vec<FileDescriptor> {
-  (0..names.len()).map(|i| {
-    let fd = p.open(&names[i], O_CREAT | O_RDWR);
-    fd
-  }).collect()
+fn open_many<'a>(p: &mut Proc<'a>, names: &'a [String]) -> Vec<FileDescriptor> {
+  names.iter().map(|name| p.open(name, O_CREAT | O_RDWR)).collect()
 }
 
-fn close_all(p: &mut Proc, fds: &Vec<FileDescriptor>) {
+fn close_all(p: &mut Proc, fds: &[FileDescriptor]) {
   for fd in fds.iter() {
     p.close(*fd);
   }
 }
 
-fn unlink_all<'a>(p: &mut Proc<'a>, names: &'a Vec<String>) {
+fn unlink_all<'a>(p: &mut Proc<'a>, names: &'a [String]) {
   for filename in names.iter() {
-    p.unlink(&filename);
+    p.unlink(filename).unwrap();
   }
 }
 
 #[allow(non_snake_case)]
 fn main() {
+  let geometry = geometry();
+  println!("page size: {}, list size: {}", geometry.page_size(),
+           geometry.list_size());
+
   bench!(bench_OC1, OC1, 1, |p, _n| {
     let fd = p.open("test", O_CREAT);
     p.close(fd);
@@ -107,44 +139,57 @@ fn main() {
 
   bench_many!(bench_OCU, OCU, 500, |p, fd, filename| {
     p.close(fd);
-    p.unlink(filename);
+    p.unlink(filename).unwrap();
+  });
+
+  bench_many!(bench_OCUnp, OCUnp, 500, unpooled, |p, fd, filename| {
+    p.close(fd);
+    p.unlink(filename).unwrap();
   });
 
   let size = 1024;
   let content = rand_array(size);
   bench_many!(bench_OWsC, OWsC, 100, |p, fd, filename| {
-    p.write(fd, &content);
+    p.write(fd, &content).unwrap();
     p.close(fd);
   });
 
   let size = 1024;
   let content = rand_array(size);
   bench_many!(bench_OWsCU, OWsCU, 100, |p, fd, filename| {
-    p.write(fd, &content);
+    p.write(fd, &content).unwrap();
     p.close(fd);
-    p.unlink(filename);
+    p.unlink(filename).unwrap();
   });
 
   let size = 40960;
   let content = rand_array(size);
   bench_many!(bench_OWbC, OWbC, 100, |p, fd, filename| {
-    p.write(fd, &content);
+    p.write(fd, &content).unwrap();
     p.close(fd);
   });
 
   let size = 40960;
   let content = rand_array(size);
   bench_many!(bench_OWbCU, OWbCU, 100, |p, fd, filename| {
-    p.write(fd, &content);
+    p.write(fd, &content).unwrap();
+    p.close(fd);
+    p.unlink(filename).unwrap();
+  });
+
+  let size = 40960;
+  let content = rand_array(size);
+  bench_many!(bench_OWbCUnp, OWbCUnp, 100, unpooled, |p, fd, filename| {
+    p.write(fd, &content).unwrap();
     p.close(fd);
-    p.unlink(filename);
+    p.unlink(filename).unwrap();
   });
 
   let (size, many) = (1024, 4096);
   let content = rand_array(size);
   bench_many!(bench_OWMsC, OWMsC, 3000, |p, fd, filename| {
     for _ in 0..many {
-      p.write(fd, &content);
+      p.write(fd, &content).unwrap();
     }
     p.close(fd);
   });
@@ -153,17 +198,17 @@ fn main() {
   let content = rand_array(size);
   bench_many!(bench_OWMsCU, OWMsCU, 5000, |p, fd, filename| {
     for _ in 0..many {
-      p.write(fd, &content);
+      p.write(fd, &content).unwrap();
     }
     p.close(fd);
-    p.unlink(filename);
+    p.unlink(filename).unwrap();
   });
 
   let (size, many) = (1048576, 32);
   let content = rand_array(size);
   bench_many!(bench_OWMbC, OWMbC, 5000, |p, fd, filename| {
     for _ in 0..many {
-      p.write(fd, &content);
+      p.write(fd, &content).unwrap();
     }
     p.close(fd);
   });
@@ -172,17 +217,17 @@ fn main() {
   let content = rand_array(size);
   bench_many!(bench_OWMbCU, OWMbCU, 7000, |p, fd, filename| {
     for _ in 0..many {
-      p.write(fd, &content);
+      p.write(fd, &content).unwrap();
     }
     p.close(fd);
-    p.unlink(filename);
+    p.unlink(filename).unwrap();
   });
 
   let (start_size, many) = (2, 4096);
   let content = rand_array(start_size * many);
   bench_many!(bench_OWbbC, OWbbC, 5000, |p, fd, filename| {
     for i in 1..(many + 1) {
-      p.write(fd, &content[..(i * start_size)]);
+      p.write(fd, &content[..(i * start_size)]).unwrap();
     }
     p.close(fd);
   });
@@ -191,9 +236,9 @@ fn main() {
   let content = rand_array(start_size * many);
   bench_many!(bench_OWbbCU, OWbbCU, 7000, |p, fd, filename| {
     for i in 1..(many + 1) {
-      p.write(fd, &content[0..(i * start_size)]);
+      p.write(fd, &content[0..(i * start_size)]).unwrap();
     }
     p.close(fd);
-    p.unlink(filename);
+    p.unlink(filename).unwrap();
   });
 }
@@ -1,8 +1,7 @@
-#![feature(asm)]
-
 extern crate time;
 
 use std::cmp;
+use std::hint;
 use time::precise_time_ns;
 
 pub struct Benchmarker {
@@ -17,7 +16,13 @@ pub type BenchResults = (IterationCount, BenchTimeNS);
 
 fn black_box<T>(dummy: T) {
   // we need to "use" the argument in some way LLVM can't introspect.
-  unsafe {asm!("" : : "r"(&dummy))}
+  hint::black_box(dummy);
+}
+
+impl Default for Benchmarker {
+  fn default() -> Benchmarker {
+    Benchmarker::new()
+  }
 }
 
 impl Benchmarker {
@@ -29,7 +34,7 @@ impl Benchmarker {
     }
   }
 
-  pub fn run<F: FnMut()>(&mut self, mut f: F) -> BenchTimeNS {
+  pub fn run<R, F: FnMut() -> R>(&mut self, mut f: F) -> BenchTimeNS {
     let k = self.iterations;
     self.ns_start = precise_time_ns();
     for _ in 0..k {
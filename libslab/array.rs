@@ -0,0 +1,203 @@
+/*!
+ * Arrays of a length fixed when the allocator is made, from a slab of their
+ * own.
+ *
+ * Where `SlabAllocator` keeps one value per slot, this keeps `len` of them, so
+ * structures whose size is only known at run time, like a file system's page
+ * lists, can come from a slab too. An array owns its values outright, like a
+ * `Box<[T]>`, and drops them before its slot goes back to the slab.
+ */
+
+use std::alloc::Layout;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+use std::rc::Rc;
+use std::slice;
+use raw::{Header, RawSlab, SlabStats};
+use Release;
+
+pub struct ArrayAllocator<T> {
+  len: usize,
+  offset: usize, // of the values in a slot, after its header
+  raw: Rc<RawSlab>,
+  marker: PhantomData<T>,
+}
+
+impl<T> ArrayAllocator<T> {
+  /// An allocator of arrays of `len` values, with room for `initial_size`
+  /// arrays to begin with.
+  pub fn new(len: usize, initial_size: usize) -> ArrayAllocator<T> {
+    let values = Layout::array::<T>(len).expect("arrays too large");
+    let (slot, offset) = Layout::new::<Header>().extend(values).expect("arrays too large");
+    let raw = Rc::new(RawSlab::new(slot, initial_size));
+    ArrayAllocator { len, offset, raw, marker: PhantomData }
+  }
+
+  /// The number of values in each array.
+  pub fn array_len(&self) -> usize {
+    self.len
+  }
+
+  /// An array of the values `f` returns, called once for each in order.
+  pub fn alloc_with<F: FnMut() -> T>(&self, f: F) -> SlabArray<T> {
+    fill(&self.raw, self.offset, self.len, f)
+  }
+
+  /// Gives back every chunk with nothing in use, returning the number of
+  /// arrays given back.
+  pub fn shrink(&self) -> usize {
+    self.raw.shrink()
+  }
+
+  /// Sets the high-water mark of free arrays. See
+  /// `SlabAllocator::set_high_water`.
+  pub fn set_high_water(&self, mark: Option<usize>) {
+    self.raw.set_high_water(mark);
+  }
+
+  pub fn high_water(&self) -> Option<usize> {
+    self.raw.high_water()
+  }
+
+  /// The number of outstanding arrays and slots, and how full each chunk is.
+  pub fn stats(&self) -> SlabStats {
+    self.raw.stats()
+  }
+}
+
+impl<T> fmt::Debug for ArrayAllocator<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let stats = self.stats();
+    f.debug_struct("ArrayAllocator")
+      .field("len", &self.len)
+      .field("alloc", &stats.alloc)
+      .field("capacity", &stats.capacity)
+      .finish()
+  }
+}
+
+// Takes a slot of `raw` and fills the `len` values at `offset` in it with `f`.
+fn fill<T, F: FnMut() -> T>(raw: &Rc<RawSlab>, offset: usize, len: usize, mut f: F)
+    -> SlabArray<T> {
+  let slot = raw.take();
+  let data = unsafe { NonNull::new_unchecked(slot.as_ptr().add(offset) as *mut T) };
+
+  // Should `f` panic, the values written so far are dropped and the slot freed.
+  let mut written = Written { raw, slot, data, len: 0 };
+  while written.len < len {
+    unsafe { data.as_ptr().add(written.len).write(f()); }
+    written.len += 1;
+  }
+
+  mem::forget(written);
+  SlabArray { raw: raw.clone(), slot, data, len, marker: PhantomData }
+}
+
+// The first `len` values at `data` in `slot`: dropping this drops them and
+// frees the slot, even if dropping a value panics.
+struct Written<'a, T> {
+  raw: &'a RawSlab,
+  slot: NonNull<u8>,
+  data: NonNull<T>,
+  len: usize,
+}
+
+impl<'a, T> Drop for Written<'a, T> {
+  fn drop(&mut self) {
+    let _release = Release { raw: self.raw, slot: self.slot };
+    unsafe { ptr::drop_in_place(ptr::slice_from_raw_parts_mut(self.data.as_ptr(), self.len)); }
+  }
+}
+
+/// An array from an `ArrayAllocator`, given back when dropped. It may outlive
+/// the allocator.
+pub struct SlabArray<T> {
+  raw: Rc<RawSlab>,
+  slot: NonNull<u8>,
+  data: NonNull<T>,
+  len: usize,
+  marker: PhantomData<T>,
+}
+
+impl<T> Deref for SlabArray<T> {
+  type Target = [T];
+
+  fn deref(&self) -> &[T] {
+    unsafe { slice::from_raw_parts(self.data.as_ptr(), self.len) }
+  }
+}
+
+impl<T> DerefMut for SlabArray<T> {
+  fn deref_mut(&mut self) -> &mut [T] {
+    unsafe { slice::from_raw_parts_mut(self.data.as_ptr(), self.len) }
+  }
+}
+
+impl<T> Drop for SlabArray<T> {
+  fn drop(&mut self) {
+    drop(Written { raw: &self.raw, slot: self.slot, data: self.data, len: self.len });
+  }
+}
+
+/// A copy of the array, from the same slab.
+impl<T: Clone> Clone for SlabArray<T> {
+  fn clone(&self) -> SlabArray<T> {
+    let offset = self.data.as_ptr() as usize - self.slot.as_ptr() as usize;
+    let mut values = self.iter();
+    fill(&self.raw, offset, self.len, || values.next().unwrap().clone())
+  }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabArray<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_tuple("SlabArray").field(&&self[..]).finish()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::ArrayAllocator;
+  use std::panic::{self, AssertUnwindSafe};
+  use std::rc::Rc;
+
+  #[test]
+  fn test_arrays() {
+    let value = Rc::new(7);
+    let allocator = ArrayAllocator::new(3, 2);
+    let mut first = allocator.alloc_with(|| Some(value.clone()));
+    first[1] = None;
+    let copy = first.clone();
+    assert_eq!(copy.iter().map(|v| v.is_some()).collect::<Vec<_>>(), [true, false, true]);
+    assert_eq!(Rc::strong_count(&value), 5);
+    assert_eq!((allocator.stats().alloc, allocator.stats().capacity), (2, 2));
+
+    // Dropping an array drops its values, and its slot is reused.
+    drop(first);
+    assert_eq!(Rc::strong_count(&value), 3);
+    let again = allocator.alloc_with(|| None);
+    assert!(again.iter().all(Option::is_none));
+    assert_eq!(allocator.stats().capacity, 2);
+
+    // A panic while filling an array drops what was filled in.
+    let mut count = 0;
+    let filled = panic::catch_unwind(AssertUnwindSafe(|| {
+      allocator.alloc_with(|| {
+        count += 1;
+        if count == 3 { panic!("third value"); }
+        Some(value.clone())
+      })
+    }));
+    assert!(filled.is_err());
+    assert_eq!(Rc::strong_count(&value), 3);
+    assert_eq!(allocator.stats().alloc, 2);
+
+    // Arrays outlive the allocator.
+    drop(allocator);
+    assert_eq!(copy.len(), 3);
+    drop((copy, again));
+    assert_eq!(Rc::strong_count(&value), 1);
+  }
+}
@@ -0,0 +1,399 @@
+/*!
+ * A slab allocator that threads can share, after Bonwick and Adams' magazines.
+ *
+ * Each thread caches free slots in two magazines of its own, so most
+ * allocations and frees touch no lock at all. Only when both are empty, on
+ * allocating, or both full, on freeing, does a thread go to the depot: a
+ * mutex-guarded stock of full and empty magazines over the slab itself. It
+ * swaps a magazine there, and failing that takes a slot straight from the
+ * slab.
+ *
+ * Boxes own their value outright, like `Box`, and are `Send`, so they can be
+ * freed on any thread. A slot freed on another thread joins that thread's
+ * cache.
+ */
+
+use std::alloc::Layout;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use raw::{Header, RawSlab, SlabStats};
+
+/// The number of slots a magazine holds, unless set otherwise.
+pub const DEFAULT_MAGAZINE_SIZE: usize = 32;
+
+// Tells allocators apart in the thread caches.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+  // This thread's cache for each allocator it has used, by id.
+  static CACHES: RefCell<HashMap<usize, Cache>> = RefCell::new(HashMap::new());
+}
+
+// A stack of free slots.
+struct Magazine(Vec<NonNull<u8>>);
+
+// The slots are only reached through the depot's lock or by the one thread
+// whose cache holds the magazine.
+unsafe impl Send for Magazine {}
+
+struct Depot {
+  raw: RawSlab,
+  full: Vec<Magazine>,
+  empty: Vec<Magazine>,
+}
+
+// What the allocator and its boxes share.
+struct Shared {
+  id: usize,
+  magazine_size: usize,
+  depot: Mutex<Depot>,
+  alloc: AtomicUsize, // number of outstanding boxes
+}
+
+impl Shared {
+  fn depot(&self) -> MutexGuard<'_, Depot> {
+    self.depot.lock().unwrap()
+  }
+
+  fn new_magazine(&self) -> Magazine {
+    Magazine(Vec::with_capacity(self.magazine_size))
+  }
+
+  // Runs `f` on this thread's cache, or returns `None` if the thread's caches
+  // are gone, as they are while it exits.
+  fn with_cache<R, F: FnOnce(&mut Cache) -> R>(self: &Arc<Self>, f: F) -> Option<R> {
+    CACHES.try_with(|caches| {
+      let mut caches = caches.borrow_mut();
+      if !caches.contains_key(&self.id) {
+        // Caches of allocators that are gone are of no use to anyone.
+        caches.retain(|_, cache| cache.shared.strong_count() > 0);
+        let cache = Cache {
+          shared: Arc::downgrade(self),
+          loaded: self.new_magazine(),
+          previous: self.new_magazine(),
+        };
+        caches.insert(self.id, cache);
+      }
+
+      f(caches.get_mut(&self.id).unwrap())
+    }).ok()
+  }
+
+  fn take(self: &Arc<Self>) -> NonNull<u8> {
+    let slot = self.with_cache(|cache| cache.take(self))
+      .unwrap_or_else(|| self.depot().raw.take());
+    self.alloc.fetch_add(1, Ordering::Relaxed);
+    slot
+  }
+
+  // Returns `slot`, which came from `take` and holds nothing, to the cache.
+  fn recycle(self: &Arc<Self>, slot: NonNull<u8>) {
+    self.alloc.fetch_sub(1, Ordering::Relaxed);
+    if self.with_cache(|cache| cache.give(self, slot)).is_none() {
+      unsafe { self.depot().raw.free(slot); }
+    }
+  }
+}
+
+// A thread's two magazines for one allocator: the one slots come from and go
+// to, and the one before it.
+struct Cache {
+  shared: Weak<Shared>,
+  loaded: Magazine,
+  previous: Magazine,
+}
+
+impl Cache {
+  fn take(&mut self, shared: &Shared) -> NonNull<u8> {
+    if let Some(slot) = self.loaded.0.pop() { return slot; }
+    if !self.previous.0.is_empty() {
+      mem::swap(&mut self.loaded, &mut self.previous);
+      return self.loaded.0.pop().unwrap();
+    }
+
+    // Both are empty: trade one for a full magazine, or go to the slab.
+    let mut depot = shared.depot();
+    match depot.full.pop() {
+      Some(full) => {
+        let empty = mem::replace(&mut self.previous, mem::replace(&mut self.loaded, full));
+        depot.empty.push(empty);
+        self.loaded.0.pop().unwrap()
+      }
+      None => depot.raw.take()
+    }
+  }
+
+  fn give(&mut self, shared: &Shared, slot: NonNull<u8>) {
+    if self.loaded.0.len() >= shared.magazine_size {
+      if self.previous.0.is_empty() {
+        mem::swap(&mut self.loaded, &mut self.previous);
+      } else {
+        // Both are full: trade one for an empty magazine.
+        let mut depot = shared.depot();
+        let empty = depot.empty.pop().unwrap_or_else(|| shared.new_magazine());
+        let full = mem::replace(&mut self.previous, mem::replace(&mut self.loaded, empty));
+        depot.full.push(full);
+      }
+    }
+
+    self.loaded.0.push(slot);
+  }
+}
+
+impl Drop for Cache {
+  fn drop(&mut self) {
+    // If the allocator is gone, so is the memory the slots were in.
+    let shared = match self.shared.upgrade() {
+      Some(shared) => shared,
+      None => return
+    };
+
+    let depot = shared.depot();
+    for slot in self.loaded.0.drain(..).chain(self.previous.0.drain(..)) {
+      unsafe { depot.raw.free(slot); }
+    }
+  }
+}
+
+/// How a `SyncSlabAllocator` is used.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncSlabStats {
+  /// The number of outstanding boxes.
+  pub alloc: usize,
+  /// The number of free slots in full magazines in the depot.
+  pub depot: usize,
+  /// The slab beneath, where slots in magazines count as in use. Those not in
+  /// the depot or in a box are cached by threads.
+  pub slab: SlabStats,
+}
+
+#[repr(C)]
+struct Slot<T> {
+  header: Header,
+  value: MaybeUninit<T>,
+}
+
+// Recycles a slot when dropped, even if dropping its value panics.
+struct Recycle<'a> {
+  shared: &'a Arc<Shared>,
+  slot: NonNull<u8>,
+}
+
+impl<'a> Drop for Recycle<'a> {
+  fn drop(&mut self) {
+    self.shared.recycle(self.slot);
+  }
+}
+
+/// A value in a `SyncSlabAllocator`'s slab, which it owns. Like the
+/// allocator's memory, it may outlive the allocator.
+pub struct SyncSlabBox<T> {
+  shared: Arc<Shared>,
+  slot: NonNull<Slot<T>>,
+  marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for SyncSlabBox<T> {}
+unsafe impl<T: Sync> Sync for SyncSlabBox<T> {}
+
+impl<T> Deref for SyncSlabBox<T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { self.slot.as_ref().value.assume_init_ref() }
+  }
+}
+
+impl<T> DerefMut for SyncSlabBox<T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { self.slot.as_mut().value.assume_init_mut() }
+  }
+}
+
+impl<T> Drop for SyncSlabBox<T> {
+  fn drop(&mut self) {
+    let _recycle = Recycle { shared: &self.shared, slot: self.slot.cast() };
+    unsafe { ptr::drop_in_place(self.slot.as_mut().value.as_mut_ptr()); }
+  }
+}
+
+impl<T: PartialEq> PartialEq for SyncSlabBox<T> {
+  fn eq(&self, other: &SyncSlabBox<T>) -> bool {
+    **self == **other
+  }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SyncSlabBox<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_tuple("SyncSlabBox").field(&**self).finish()
+  }
+}
+
+/// A slab allocator for any number of threads. Clones share the slab.
+pub struct SyncSlabAllocator<T> {
+  shared: Arc<Shared>,
+  marker: PhantomData<fn() -> T>,
+}
+
+impl<T> SyncSlabAllocator<T> {
+  pub fn new(initial_size: usize) -> SyncSlabAllocator<T> {
+    SyncSlabAllocator::with_magazine_size(initial_size, DEFAULT_MAGAZINE_SIZE)
+  }
+
+  /// An allocator whose magazines hold `magazine_size` slots, at least one.
+  /// Each thread caches up to twice that.
+  pub fn with_magazine_size(initial_size: usize, magazine_size: usize)
+      -> SyncSlabAllocator<T> {
+    let depot = Depot {
+      raw: RawSlab::new(Layout::new::<Slot<T>>(), initial_size),
+      full: vec![],
+      empty: vec![],
+    };
+
+    let shared = Shared {
+      id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+      magazine_size: magazine_size.max(1),
+      depot: Mutex::new(depot),
+      alloc: AtomicUsize::new(0),
+    };
+
+    SyncSlabAllocator { shared: Arc::new(shared), marker: PhantomData }
+  }
+
+  pub fn alloc(&self, value: T) -> SyncSlabBox<T> {
+    let mut slot = self.shared.take().cast::<Slot<T>>();
+    unsafe { slot.as_mut().value.write(value); }
+    SyncSlabBox { shared: self.shared.clone(), slot, marker: PhantomData }
+  }
+
+  /// Returns the slots this thread has cached to the slab.
+  pub fn flush(&self) {
+    let cache = CACHES.try_with(|caches| caches.borrow_mut().remove(&self.shared.id));
+    drop(cache);
+  }
+
+  /**
+   * Returns the slots in the depot's magazines to the slab, then gives back
+   * every chunk with nothing in use, returning the number of slots given
+   * back. Slots cached by threads stay put; see `flush`.
+   */
+  pub fn shrink(&self) -> usize {
+    let mut depot = self.shared.depot();
+    let full = mem::take(&mut depot.full);
+    for slot in full.into_iter().flat_map(|magazine| magazine.0) {
+      unsafe { depot.raw.free(slot); }
+    }
+
+    depot.empty.clear();
+    depot.raw.shrink()
+  }
+
+  pub fn stats(&self) -> SyncSlabStats {
+    let depot = self.shared.depot();
+    SyncSlabStats {
+      alloc: self.shared.alloc.load(Ordering::Relaxed),
+      depot: depot.full.iter().map(|magazine| magazine.0.len()).sum(),
+      slab: depot.raw.stats(),
+    }
+  }
+}
+
+impl<T> Clone for SyncSlabAllocator<T> {
+  fn clone(&self) -> SyncSlabAllocator<T> {
+    SyncSlabAllocator { shared: self.shared.clone(), marker: PhantomData }
+  }
+}
+
+impl<T> fmt::Debug for SyncSlabAllocator<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let stats = self.stats();
+    f.debug_struct("SyncSlabAllocator")
+      .field("alloc", &stats.alloc)
+      .field("capacity", &stats.slab.capacity)
+      .finish()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::SyncSlabAllocator;
+  use std::sync::mpsc;
+  use std::thread;
+
+  #[test]
+  fn test_magazines() {
+    let allocator = SyncSlabAllocator::with_magazine_size(8, 2);
+    let objects: Vec<_> = (0..5).map(|i| allocator.alloc(Box::new(i))).collect();
+    assert_eq!(*objects[4], Box::new(4));
+    drop(objects);
+
+    // Two slots in each of this thread's magazines, one in the loaded one
+    // after the trip to the depot, and two in the depot.
+    let stats = allocator.stats();
+    assert_eq!((stats.alloc, stats.depot, stats.slab.alloc), (0, 2, 5));
+
+    // Allocating takes from the cache first, then the depot.
+    let objects: Vec<_> = (0..4).map(|i| allocator.alloc(Box::new(i))).collect();
+    assert_eq!(allocator.stats().depot, 0);
+    assert_eq!(allocator.stats().slab.alloc, 5);
+    drop(objects);
+
+    allocator.flush();
+    assert_eq!(allocator.stats().slab.alloc, 2);
+    assert_eq!(allocator.shrink(), 8);
+    assert_eq!(allocator.stats().slab.capacity, 0);
+  }
+
+  #[test]
+  fn test_threads() {
+    let allocator = SyncSlabAllocator::with_magazine_size(4, 4);
+    let (sender, receiver) = mpsc::channel();
+
+    let threads: Vec<_> = (0..4).map(|t| {
+      let (allocator, sender) = (allocator.clone(), sender.clone());
+      thread::spawn(move || {
+        let mut kept = vec![];
+        for i in 0..50 {
+          let mut object = allocator.alloc(vec![t; i]);
+          object.push(i);
+          if i % 5 == 0 { sender.send(object).unwrap(); } else { kept.push(object); }
+          if i % 7 == 0 { kept.clear(); }
+        }
+      })
+    }).collect();
+
+    drop(sender);
+    let received: Vec<_> = receiver.iter().collect();
+    for thread in threads { thread.join().unwrap(); }
+    assert_eq!(received.len(), 4 * 10);
+    assert!(received.iter().all(|object| object.last() == Some(&(object.len() - 1))));
+    assert_eq!(allocator.stats().alloc, 40);
+
+    // The threads' caches went back to the slab as they exited.
+    drop(received);
+    allocator.flush();
+    let stats = allocator.stats();
+    assert_eq!(stats.alloc, 0);
+    assert_eq!(stats.slab.alloc, stats.depot);
+    allocator.shrink();
+    assert_eq!(allocator.stats().slab.capacity, 0);
+  }
+
+  #[test]
+  fn test_outlives_allocator() {
+    let allocator = SyncSlabAllocator::new(1);
+    let object = allocator.alloc(String::from("slab"));
+    drop(allocator);
+
+    let object = thread::spawn(move || { assert_eq!(*object, "slab"); object })
+      .join().unwrap();
+    assert_eq!(format!("{:?}", object), "SyncSlabBox(\"slab\")");
+  }
+}
@@ -1,211 +1,296 @@
-#![allow(raw_pointer_deriving)]
-#![crate_type = "lib"]
-#![crate_id = "slab"]
-
-/**
- * A growing (not yet shrinking), typed slab allocator.
+/*!
+ * A growing and shrinking, typed slab allocator.
+ *
+ * Values live in chunks of slots allocated up front; when every slot is taken
+ * the allocator doubles its capacity with a new chunk. Chunks with nothing in
+ * use can be given back with `shrink`, or as they empty out past a high-water
+ * mark of free slots; see `set_high_water`. Allocating hands out a
+ * `SlabBox`, a reference-counted handle to a slot. Clones of a box refer to
+ * the same value, and the slot goes back to the allocator, with its value
+ * dropped, once the last of them is.
+ *
+ * As with `RefCell`, the value is reached through `borrow` and `borrow_mut`,
+ * which panic if they'd conflict with a borrow that's still alive. Boxes keep
+ * the allocator's memory alive, so they may outlive the `SlabAllocator` they
+ * came from.
+ *
+ * `SizeClassAllocator` hands out byte buffers of varying sizes from a slab per
+ * size class, `ArrayAllocator` arrays of a length chosen at run time, and
+ * `SyncSlabAllocator` is shared by threads, handing out boxes that can be
+ * sent between them.
  *
  * To use:
  *
+ * ```
+ * use slab::SlabAllocator;
+ *
  * let s = SlabAllocator::new(10);
  * {
- *   let mut first = s.alloc(0); // Type is SlabBox<'a, int>
- *   *first = 10;
- *   assert_eq!(*first, 10);
- *
- *   let mut second = s.alloc(0);
- *   *second = 20;
- *   assert_eq!(*first, 20);
- *   assert_eq!(*second, *first);
+ *   let first = s.alloc(10); // Type is SlabBox<i32>
+ *   assert_eq!(*first.borrow(), 10);
  *
- *   let third = first.clone(); // Referencing same as first.
- *   assert!(*first == *third);
+ *   let second = first.clone(); // Referencing same as first.
+ *   *second.borrow_mut() = 20;
+ *   assert_eq!(*first.borrow(), 20);
  *
- *   *second = 30;
- *   assert_eq!(*first == 30);
- *   assert_eq!(*first == *third);
- *   assert_eq!(*second == *third);
+ *   let third = s.alloc(30);
+ *   assert_eq!((s.stats().alloc, s.stats().capacity), (2, 10));
+ *   assert!(first != third);
  * } // first, second, third returned to allocator.
+ * assert_eq!(s.stats().alloc, 0);
+ * ```
  */
 
-extern crate libc;
-
-use std::mem;
-use std::mem::transmute;
+mod raw;
+mod sized;
+mod array;
+mod concurrent;
+
+use std::alloc::Layout;
+use std::cell::{Cell, UnsafeCell};
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
 use std::rc::Rc;
-use libc::{size_t, malloc, free};
-use libc::types::common::c95::c_void;
-use std::cell::{Cell, RefCell};
-use std::intrinsics;
-
-#[deriving(Show)]
-struct Slab<'a, T> {
-  parent: &'a SlabAllocator<T>, // Would RC be better to get rid of 'a? How to?
-  ptr: *mut T
+use raw::{Header, RawSlab};
+
+pub use raw::{SlabStats, ChunkStats};
+pub use sized::{SizeClassAllocator, SlabBytes, DEFAULT_SIZE_CLASSES};
+pub use array::{ArrayAllocator, SlabArray};
+pub use concurrent::{SyncSlabAllocator, SyncSlabBox, SyncSlabStats, DEFAULT_MAGAZINE_SIZE};
+
+// A slot for one value: the slab's header, the number of boxes referring to
+// it, the state of the borrows of its value (negative while mutably
+// borrowed), and the value.
+#[repr(C)]
+struct Slot<T> {
+  header: Header,
+  refs: Cell<usize>,
+  borrows: Cell<isize>,
+  value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// Frees a slot when dropped, even if dropping its value panics.
+struct Release<'a> {
+  raw: &'a RawSlab,
+  slot: NonNull<u8>,
+}
+
+impl<'a> Drop for Release<'a> {
+  fn drop(&mut self) {
+    unsafe { self.raw.free(self.slot); }
+  }
+}
+
+/// A handle to a value in a slab. See the crate documentation.
+pub struct SlabBox<T> {
+  raw: Rc<RawSlab>,
+  slot: NonNull<Slot<T>>,
 }
 
-impl<'a, T> Slab<'a, T> {
-  fn borrow<'r>(&'r self) -> &'r T {
-    unsafe { &*self.ptr }
+impl<T> SlabBox<T> {
+  fn slot(&self) -> &Slot<T> {
+    unsafe { self.slot.as_ref() }
+  }
+
+  /// Borrows the value, or returns `None` if it's mutably borrowed.
+  pub fn try_borrow(&self) -> Option<SlabRef<'_, T>> {
+    let slot = self.slot();
+    let borrows = slot.borrows.get();
+    if borrows < 0 { return None; }
+    slot.borrows.set(borrows + 1);
+    Some(SlabRef { slot })
   }
 
-  fn borrow_mut<'r>(&'r mut self) -> &'r mut T {
-    unsafe { &mut*self.ptr }
+  /// Mutably borrows the value, or returns `None` if it's borrowed.
+  pub fn try_borrow_mut(&self) -> Option<SlabRefMut<'_, T>> {
+    let slot = self.slot();
+    if slot.borrows.get() != 0 { return None; }
+    slot.borrows.set(-1);
+    Some(SlabRefMut { slot })
+  }
+
+  /// Borrows the value. Panics if it's mutably borrowed.
+  pub fn borrow(&self) -> SlabRef<'_, T> {
+    self.try_borrow().expect("already mutably borrowed")
+  }
+
+  /// Mutably borrows the value. Panics if it's borrowed.
+  pub fn borrow_mut(&self) -> SlabRefMut<'_, T> {
+    self.try_borrow_mut().expect("already borrowed")
+  }
+
+  /// Whether `a` and `b` refer to the same value.
+  pub fn ptr_eq(a: &SlabBox<T>, b: &SlabBox<T>) -> bool {
+    a.slot == b.slot
   }
 }
 
-impl<'a, T: Eq> Eq for Slab<'a, T> {
-  fn eq(&self, other: &Slab<T>) -> bool {
-    self.borrow() == other.borrow()
+impl<T> Clone for SlabBox<T> {
+  fn clone(&self) -> SlabBox<T> {
+    let slot = self.slot();
+    slot.refs.set(slot.refs.get() + 1);
+    SlabBox { raw: self.raw.clone(), slot: self.slot }
   }
 }
 
-#[unsafe_destructor]
-impl<'a, T> Drop for Slab<'a, T> {
+impl<T> Drop for SlabBox<T> {
   fn drop(&mut self) {
-    self.parent.free(self.ptr);
+    let slot = self.slot();
+    let refs = slot.refs.get() - 1;
+    slot.refs.set(refs);
+    if refs > 0 { return; }
+
+    let _release = Release { raw: &self.raw, slot: self.slot.cast() };
+    unsafe { ptr::drop_in_place((*slot.value.get()).as_mut_ptr()); }
   }
 }
 
-#[deriving(Clone)]
-pub struct SlabBox<'a, T>(Rc<RefCell<Slab<'a, T>>>);
+impl<T: PartialEq> PartialEq for SlabBox<T> {
+  fn eq(&self, other: &SlabBox<T>) -> bool {
+    *self.borrow() == *other.borrow()
+  }
+}
 
-impl<'a, T> SlabBox<'a, T> {
-  #[inline(always)]
-  pub fn borrow<'r>(&'r self) -> &'r T {
-    let SlabBox(ref rc) = *self;
-    unsafe { transmute(rc.borrow().borrow()) }
+impl<T: fmt::Debug> fmt::Debug for SlabBox<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.try_borrow() {
+      Some(value) => f.debug_tuple("SlabBox").field(&*value).finish(),
+      None => f.write_str("SlabBox(<borrowed>)")
+    }
   }
+}
 
-  #[inline(always)]
-  pub fn borrow_mut<'r>(&'r mut self) -> &'r mut T {
-    let SlabBox(ref rc) = *self;
-    unsafe { transmute(rc.borrow_mut().borrow_mut()) }
+/// A borrow of the value in a `SlabBox`.
+pub struct SlabRef<'b, T: 'b> {
+  slot: &'b Slot<T>,
+}
+
+impl<'b, T> Deref for SlabRef<'b, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { (*self.slot.value.get()).assume_init_ref() }
   }
 }
 
-impl<'a, T> Deref<T> for SlabBox<'a, T> {
-  #[inline(always)]
-  fn deref<'r>(&'r self) -> &'r T {
-    self.borrow()
+impl<'b, T> Drop for SlabRef<'b, T> {
+  fn drop(&mut self) {
+    self.slot.borrows.set(self.slot.borrows.get() - 1);
   }
 }
 
-impl<'a, T> DerefMut<T> for SlabBox<'a, T> {
-  #[inline(always)]
-  fn deref_mut<'r>(&'r mut self) -> &'r mut T {
-    self.borrow_mut()
+/// A mutable borrow of the value in a `SlabBox`.
+pub struct SlabRefMut<'b, T: 'b> {
+  slot: &'b Slot<T>,
+}
+
+impl<'b, T> Deref for SlabRefMut<'b, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { (*self.slot.value.get()).assume_init_ref() }
+  }
+}
+
+impl<'b, T> DerefMut for SlabRefMut<'b, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { (*self.slot.value.get()).assume_init_mut() }
+  }
+}
+
+impl<'b, T> Drop for SlabRefMut<'b, T> {
+  fn drop(&mut self) {
+    self.slot.borrows.set(0);
   }
 }
 
-#[deriving(Show)]
 pub struct SlabAllocator<T> {
-  items: Vec<*mut T>,       // holds pointers to allocations
-  alloc: Cell<usize>,        // number of outstanding items
-  capacity: Cell<usize>,     // number of items pre-allocated (valid in items)
-  chunks: Vec<*mut T>,      // holds pointers to each chunk for freeing
+  raw: Rc<RawSlab>,
+  marker: PhantomData<T>,
 }
 
 impl<T> SlabAllocator<T> {
   pub fn new(initial_size: usize) -> SlabAllocator<T> {
-    let mut allocator = SlabAllocator {
-      items: Vec::with_capacity(initial_size),
-      alloc: Cell::new(0),
-      capacity: Cell::new(0),
-      chunks: Vec::with_capacity(20)
-    };
-
-    allocator.expand(initial_size);
-    allocator
-  }
-  
-  // pre-allocates and additional new_items and adds them to the end of
-  // self.items, increasing self.capacity with the new size
-  fn expand(&mut self, new_items: usize) {
-    unsafe {
-      let memory = malloc((mem::size_of::<T>() * new_items) as size_t) as *mut T;
-      assert!(!memory.is_null());
+    let raw = RawSlab::new(Layout::new::<Slot<T>>(), initial_size);
+    SlabAllocator { raw: Rc::new(raw), marker: PhantomData }
+  }
 
-      self.chunks.push(memory);
-      for i in range(0, new_items as isize) {
-        self.items.push(memory.offset(i));
-      }
+  // Takes a slot and gives it its first box.
+  fn take(&self) -> SlabBox<T> {
+    let slot = self.raw.take().cast::<Slot<T>>();
+    unsafe {
+      slot.as_ref().refs.set(1);
+      slot.as_ref().borrows.set(0);
     }
 
-    self.capacity.set(self.capacity.get() + new_items);
+    SlabBox { raw: self.raw.clone(), slot }
   }
 
-  pub unsafe fn dirty_alloc<'r>(&'r self) -> SlabBox<'r, T> {
-    self.all_alloc(None)
+  /**
+   * Allocates a slot without initializing it: its value is whatever was last
+   * freed there, already dropped, or all zero bytes if nothing was.
+   *
+   * # Safety
+   *
+   * Both of those must be valid values of `T` that are fine to use again and
+   * to drop again, as they are for plain data like integers.
+   */
+  pub unsafe fn dirty_alloc(&self) -> SlabBox<T> {
+    self.take()
   }
 
-  pub fn alloc<'r>(&'r self, value: T) -> SlabBox<'r, T> {
-    self.all_alloc(Some(value))
+  pub fn alloc(&self, value: T) -> SlabBox<T> {
+    let object = self.take();
+    unsafe { (*object.slot().value.get()).write(value); }
+    object
   }
 
-  fn all_alloc<'r>(&'r self, value: Option<T>) -> SlabBox<'r, T> {
-    let (alloc, capacity) = (self.alloc.get(), self.capacity.get());
-    if alloc >= capacity {
-      unsafe {
-        // is there a safe/better way to do something like this?
-        let mut_self: &mut SlabAllocator<T> = transmute(self);
-        mut_self.expand(capacity * 2 - capacity);
-      }
-    }
-
-    let ptr: *mut T = *self.items.get(alloc);
-    match value {
-      Some(val) => unsafe { mem::overwrite(&mut *ptr, val); },
-      None => { /* Giving back dirty value. */ }
-    }
-
-    self.alloc.set(alloc + 1);
-    let slab = Slab { parent: self, ptr: ptr }; 
-    SlabBox(Rc::new(RefCell::new(slab)))
+  /// Gives back every chunk with nothing in use, returning the number of
+  /// slots given back.
+  pub fn shrink(&self) -> usize {
+    self.raw.shrink()
   }
 
-  fn free(&self, ptr: *mut T) {
-    let alloc = self.alloc.get();
-    if alloc <= 0 { panic!("Over-freeing....somehow"); }
-
-    self.alloc.set(alloc - 1);
-    unsafe {
-      // Dropping if needed
-      if intrinsics::needs_drop::<T>() {
-        let ty = intrinsics::get_tydesc::<T>();
-        ((*ty).drop_glue)(ptr as *i8);
-      }
+  /**
+   * Has the allocator give back chunks with nothing in use, newest first,
+   * whenever more than `mark` slots are free, until no more than `mark` are.
+   * With `None`, the default, chunks are only given back by `shrink`.
+   */
+  pub fn set_high_water(&self, mark: Option<usize>) {
+    self.raw.set_high_water(mark);
+  }
 
-      // Letting an immutable slice be mutable, unsafely
-      let items: &mut [*mut T] = transmute(self.items.as_slice());
-      items[alloc - 1] = ptr;
-    }
+  pub fn high_water(&self) -> Option<usize> {
+    self.raw.high_water()
   }
 
-  pub fn stats(&self) -> (usize, usize) {
-    (self.alloc.get(), self.capacity.get())
+  /// The number of outstanding items and slots, and how full each chunk is.
+  pub fn stats(&self) -> SlabStats {
+    self.raw.stats()
   }
 }
 
-#[unsafe_destructor]
-impl<T> Drop for SlabAllocator<T> {
-  fn drop(&mut self) {
-    for chunk in self.chunks.iter() {
-      unsafe { free(*chunk as *mut c_void); }
-    }
+impl<T> fmt::Debug for SlabAllocator<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let stats = self.stats();
+    f.debug_struct("SlabAllocator")
+      .field("alloc", &stats.alloc)
+      .field("capacity", &stats.capacity)
+      .finish()
   }
 }
 
 #[cfg(test)]
 mod tests {
-  extern crate test;
   use super::{SlabAllocator, SlabBox};
-  use std::mem;
 
   // Used to test that deallocation works.
-  #[deriving(Eq, Show, Clone)]
+  #[derive(PartialEq, Debug, Clone)]
   struct MyThing {
-    field1: Option<Box<int>>,
+    field1: Option<Box<isize>>,
     done: bool
   }
 
@@ -220,26 +305,26 @@ mod tests {
   fn test_one_mut_alloc() {
     let slab_allocator = SlabAllocator::new(20);
     let object = slab_allocator.alloc(239);
-    assert_eq!(*object, 239);
-    assert_eq!(*object, *slab_allocator.alloc(239));
+    assert_eq!(*object.borrow(), 239);
+    assert_eq!(*object.borrow(), *slab_allocator.alloc(239).borrow());
   }
 
   #[test]
   fn test_one_struct_alloc() {
     let slab_allocator = SlabAllocator::new(20);
-    let struct_obj = MyThing { field1: Some(box 20), done: false };
-    let mut object = slab_allocator.alloc(struct_obj);
+    let struct_obj = MyThing { field1: Some(Box::new(20)), done: false };
+    let object = slab_allocator.alloc(struct_obj);
 
-    assert_eq!(object.field1, Some(box 20));
-    object.done = true;
+    assert_eq!(object.borrow().field1, Some(Box::new(20)));
+    object.borrow_mut().done = true;
 
     // Now with a boxed type
     let slab_allocator = SlabAllocator::new(20);
-    let struct_obj = box MyThing { field1: Some(box 40), done: false };
-    let mut object = slab_allocator.alloc(struct_obj);
+    let struct_obj = Box::new(MyThing { field1: Some(Box::new(40)), done: false });
+    let object = slab_allocator.alloc(struct_obj);
 
-    assert_eq!(object.field1, Some(box 40)); // same as (*object).field1
-    object.done = true;
+    assert_eq!(object.borrow().field1, Some(Box::new(40))); // same as (**object).field1
+    object.borrow_mut().done = true;
   }
 
   #[test]
@@ -247,9 +332,9 @@ mod tests {
   fn test_struct_dealloc() {
     let slab_allocator = SlabAllocator::new(20);
     {
-      let struct_obj = MyThing { field1: Some(box 20), done: false };
+      let struct_obj = MyThing { field1: Some(Box::new(20)), done: false };
       let object = slab_allocator.alloc(struct_obj);
-      assert_eq!(object.field1, Some(box 20));
+      assert_eq!(object.borrow().field1, Some(Box::new(20)));
     }
     // Will cause double-fail if deallocation didn't occur.
     panic!("Did not dallocate struct in time.");
@@ -260,9 +345,9 @@ mod tests {
   fn test_box_struct_dealloc() {
     let slab_allocator = SlabAllocator::new(20);
     {
-      let struct_obj = box MyThing { field1: Some(box 2445), done: false };
+      let struct_obj = Box::new(MyThing { field1: Some(Box::new(2445)), done: false });
       let object = slab_allocator.alloc(struct_obj);
-      assert_eq!(object.field1, Some(box 2445));
+      assert_eq!(object.borrow().field1, Some(Box::new(2445)));
     }
     // Will cause double-fail if deallocation didn't occur.
     panic!("Did not dallocate struct in time.");
@@ -271,95 +356,94 @@ mod tests {
   #[test]
   fn test_two_allocs_with_boxes() {
     let slab_allocator = SlabAllocator::new(20);
-    let object = slab_allocator.alloc(box 239);
-    let object2 = slab_allocator.alloc(box 23089);
+    let object = slab_allocator.alloc(Box::new(239));
+    let object2 = slab_allocator.alloc(Box::new(23089));
     let object3 = object.clone();
 
-    assert!(**object == 239);
-    assert_eq!(*object2, box 23089);
-    assert!(*object2 != *object);
-    assert!(*object2 != *object);
-    assert_eq!(*object3, *object);
+    assert!(**object.borrow() == 239);
+    assert_eq!(*object2.borrow(), Box::new(23089));
+    assert!(*object2.borrow() != *object.borrow());
+    assert!(object2 != object);
+    assert_eq!(*object3.borrow(), *object.borrow());
   }
 
   #[test]
   fn test_mut_alloc() {
     let slab_allocator = SlabAllocator::new(20);
-    let mut object = slab_allocator.alloc(239);
-    assert_eq!(*object, 239);
+    let object = slab_allocator.alloc(239);
+    assert_eq!(*object.borrow(), 239);
 
-    *object = 500;
-    assert_eq!(*object, 500);
+    *object.borrow_mut() = 500;
+    assert_eq!(*object.borrow(), 500);
 
-    *object = 50;
-    assert_eq!(*object, 50);
+    *object.borrow_mut() = 50;
+    assert_eq!(*object.borrow(), 50);
   }
 
   #[test]
   fn test_mut_alloc_clone() {
     let slab_allocator = SlabAllocator::new(20);
-    let mut object = slab_allocator.alloc(239);
+    let object = slab_allocator.alloc(239);
     let object2 = object.clone();
     let object3 = slab_allocator.alloc(77);
 
-    assert_eq!(*object, 239);
-    assert_eq!(*object, *object2);
-    assert_eq!(*object3, 77);
+    assert_eq!(*object.borrow(), 239);
+    assert_eq!(object, object2);
+    assert_eq!(*object3.borrow(), 77);
+
+    *object.borrow_mut() = 349;
+    assert_eq!(*object.borrow(), 349);
+    assert_eq!(object, object2);
+    assert_eq!(*object3.borrow(), 77);
+  }
 
-    *object = 349;
-    assert_eq!(*object, 349);
-    assert_eq!(*object, *object2);
-    assert_eq!(*object3, 77);
+  // Where the boxed value in `object` lives.
+  fn address<T>(object: &SlabBox<Box<T>>) -> *const T {
+    &**object.borrow()
   }
 
   #[test]
   fn test_mut_box_alloc() {
     let slab_allocator = SlabAllocator::new(20);
-    let mut object = slab_allocator.alloc(box 1111);
-    assert_eq!(*object, box 1111);
-    assert_eq!(*object, *slab_allocator.alloc(box 1111));
+    let object = slab_allocator.alloc(Box::new(1111));
+    assert_eq!(*object.borrow(), Box::new(1111));
+    assert_eq!(object, slab_allocator.alloc(Box::new(1111)));
 
     let object2 = object.clone();
-    assert_eq!(*object, *object2);
-    let init_ptr: *int;
-    unsafe {
-      let ptr1: *int = mem::transmute(object.deref());
-      let ptr2: *int = mem::transmute(object2.deref());
-      init_ptr = ptr1;
-      assert_eq!(ptr1, ptr2); // make sure we're not allocating new internal box
-    }
-
-    *object = box 2222;
-    assert_eq!(*object, *object2);
-    assert_eq!(*object2, box 2222);
-    assert_eq!(**object2, 2222);
-
-    let mut object3 = object2.clone();
-    *object3 = box 3333;
-    assert_eq!(*object, box 3333);
-    assert_eq!(*object2, *object);
-    assert_eq!(*object, *object3);
-
-    unsafe {
-      let ptr1: *int = mem::transmute(object.deref());
-      let ptr2: *int = mem::transmute(object2.deref());
-      let ptr3: *int = mem::transmute(object3.deref());
-      assert_eq!(ptr1, ptr2); 
-      assert_eq!(ptr2, ptr3);
-
-      // this one is a bit interesting. after doing some tests, it looks like if
-      // you have a let mut a: Box<thing> and then you do a = box newthing, the
-      // box will continue being the same one and only the content inside will
-      // change, which is pretty cool.
-      assert_eq!(init_ptr, ptr1); // because they're boxes, ptr shouldn't change
-    }
+    assert_eq!(object, object2);
+    let ptr1 = address(&object);
+    let ptr2 = address(&object2);
+    let init_ptr = ptr1;
+    assert_eq!(ptr1, ptr2); // make sure we're not allocating new internal box
+    assert!(SlabBox::ptr_eq(&object, &object2));
+
+    **object.borrow_mut() = 2222;
+    assert_eq!(object, object2);
+    assert_eq!(*object2.borrow(), Box::new(2222));
+    assert_eq!(**object2.borrow(), 2222);
+
+    let object3 = object2.clone();
+    **object3.borrow_mut() = 3333;
+    assert_eq!(*object.borrow(), Box::new(3333));
+    assert_eq!(object2, object);
+    assert_eq!(object, object3);
+
+    let ptr1 = address(&object);
+    let ptr2 = address(&object2);
+    let ptr3 = address(&object3);
+    assert_eq!(ptr1, ptr2);
+    assert_eq!(ptr2, ptr3);
+
+    // Writing through the box changes the value in place rather than
+    // allocating a new one, so the pointer stays the same.
+    assert_eq!(init_ptr, ptr1);
   }
 
   #[test]
   fn test_one_alloc_boxed() {
-    let slab_allocator = box SlabAllocator::new(20);
+    let slab_allocator = Box::new(SlabAllocator::new(20));
     let object = slab_allocator.alloc(239);
-    assert_eq!(*object, 239);
+    assert_eq!(*object.borrow(), 239);
   }
 
   #[test]
@@ -368,42 +452,40 @@ mod tests {
 
     // Alloacting and verifying 20 items and putting into vector.
     let mut vec = Vec::new();
-    for i in range(0, 20) {
+    for i in 0..20 {
       let obj = slab_allocator.alloc(i);
-      assert_eq!(*obj, i);
+      assert_eq!(*obj.borrow(), i);
       vec.push(obj);
     }
 
     // Making sure they're all still there and different.
-    let mut i = 0;
-    for obj in vec.iter() {
-      assert_eq!(**obj, i);
-      i += 1;
+    for (i, obj) in vec.iter().enumerate() {
+      assert_eq!(*obj.borrow(), i);
     }
   }
 
   #[test]
   fn test_alloc_return() {
-    let slab_allocator = box SlabAllocator::new(20);
+    let slab_allocator = Box::new(SlabAllocator::new(20));
 
     // Drop should be called for each object after each loop
-    for i in range(0, 50) {
+    for i in 0..50 {
       // Making sure nothing's in there
-      let (alloc, _) = slab_allocator.stats();
+      let alloc = slab_allocator.stats().alloc;
       assert_eq!(alloc, 0);
 
       let object = slab_allocator.alloc(i);
-      assert_eq!(*object, i);
+      assert_eq!(*object.borrow(), i);
     }
 
     // Just in case some weird business is happenning
-    for i in range(-239, -180) {
+    for i in -239..-180 {
       let object = slab_allocator.alloc(i);
-      assert_eq!(*object, i);
+      assert_eq!(*object.borrow(), i);
     }
 
     // Should still be empty
-    let (alloc, _) = slab_allocator.stats();
+    let alloc = slab_allocator.stats().alloc;
     assert_eq!(alloc, 0);
   }
 
@@ -415,14 +497,15 @@ mod tests {
     // Alloacting more then the capacity
     // Testing reference counting (by using clone), shouldn't drop
     let mut vec = Vec::new();
-    for i in range(0, 100) {
+    for i in 0..100 {
       let obj = slab_allocator.alloc(i);
-      assert_eq!(*obj, i);
+      assert_eq!(*obj.borrow(), i);
       vec.push(obj.clone());
     }
 
     // Make sure the allocator performed as expected
-    let (allocated, capacity) = slab_allocator.stats();
+    let stats = slab_allocator.stats();
+    let (allocated, capacity) = (stats.alloc, stats.capacity);
     assert_eq!(allocated, 100);
     assert_eq!(capacity, 160); // 20 -> 40 -> 80 -> 160
 
@@ -430,7 +513,8 @@ mod tests {
     vec.truncate(0);
 
     // Make sure they were deallocated
-    let (allocated, capacity) = slab_allocator.stats();
+    let stats = slab_allocator.stats();
+    let (allocated, capacity) = (stats.alloc, stats.capacity);
     assert_eq!(allocated, 0);
     assert_eq!(capacity, 160); // 20 -> 40 -> 80 -> 160
   }
@@ -442,31 +526,31 @@ mod tests {
     // Drop should be called for each object after each loop since we're only
     // storing the value of the object and not the object itself
     let mut vec = Vec::new();
-    for i in range(0, 25) {
-      let (alloc, _) = slab_allocator.stats();
+    for i in 0..25 {
+      let alloc = slab_allocator.stats().alloc;
       assert_eq!(alloc, 0);
 
       let obj = slab_allocator.alloc(i);
-      assert_eq!(*obj, i);
-      vec.push(*obj);
+      assert_eq!(*obj.borrow(), i);
+      vec.push(*obj.borrow());
     }
 
-    let (alloc, _) = slab_allocator.stats();
+    let alloc = slab_allocator.stats().alloc;
     assert_eq!(alloc, 0);
   }
 
   #[test]
   fn test_usage_external_allocator() {
     struct MyThing<'r> {
-      item: SlabBox<'r, int>,
-      allocator: &'r SlabAllocator<int>,
+      item: SlabBox<isize>,
+      allocator: &'r SlabAllocator<isize>,
     }
 
     impl<'r> MyThing<'r> {
-      fn new(allocator: &'r SlabAllocator<int>, num: isize) -> MyThing<'r> {
+      fn new(allocator: &'r SlabAllocator<isize>, num: isize) -> MyThing<'r> {
         MyThing {
           item: allocator.alloc(num),
-          allocator: allocator,
+          allocator,
         }
       }
 
@@ -479,71 +563,73 @@ mod tests {
     let mut thing = MyThing::new(&allocator, 120);
     let thing2 = MyThing::new(&allocator, 130);
 
-    assert_eq!(*thing.item, 120);
-    assert_eq!(*thing2.item, 130);
+    assert_eq!(*thing.item.borrow(), 120);
+    assert_eq!(*thing2.item.borrow(), 130);
 
-    let (alloc, _) = allocator.stats();
+    let alloc = allocator.stats().alloc;
     assert_eq!(alloc, 2);
 
     thing.set_num(434);
-    assert_eq!(*thing.item, 434);
-    assert_eq!(*thing2.item, 130);
+    assert_eq!(*thing.item.borrow(), 434);
+    assert_eq!(*thing2.item.borrow(), 130);
 
-    let (alloc, _) = allocator.stats();
+    let alloc = allocator.stats().alloc;
     assert_eq!(alloc, 2);
   }
 
-  // Not sure if this is possible.
   #[test]
   fn test_usage_internal_allocator() {
     use std::cell::RefCell;
 
-    struct MyThing<'r> {
-      item: RefCell<Option<SlabBox<'r, int>>>,
-      allocator: SlabAllocator<int>
+    struct MyThing {
+      item: RefCell<Option<SlabBox<isize>>>,
+      allocator: SlabAllocator<isize>
     }
 
-    impl<'r> MyThing<'r> {
-      fn new() -> MyThing {
+    impl MyThing {
+      fn new(num: isize) -> MyThing {
         let thing = MyThing {
           item: RefCell::new(None),
           allocator: SlabAllocator::new(10)
         };
 
+        thing.set(num);
         thing
       }
 
-      fn set(&'r self, num: isize) {
+      fn set(&self, num: isize) {
         *self.item.borrow_mut() = Some(self.allocator.alloc(num));
       }
 
-      fn item(&'r self) -> SlabBox<'r, int> {
-        self.item.borrow().get_ref().clone()
+      fn item(&self) -> SlabBox<isize> {
+        self.item.borrow().as_ref().unwrap().clone()
       }
     }
 
-    // Ideally, this would work.
-    // let thing = MyThing::new(120);
-    // assert!(**thing.item() == 120);
-    
-    let thing = MyThing::new();
+    // Boxes don't borrow their allocator, so this works.
+    let thing = MyThing::new(120);
+    assert!(*thing.item().borrow() == 120);
 
     thing.set(127);
-    assert!(*thing.item() == 127);
+    assert!(*thing.item().borrow() == 127);
 
     thing.set(50);
-    assert!(*thing.item() == 50);
+    assert!(*thing.item().borrow() == 50);
 
-    let (alloc, _) = thing.allocator.stats();
+    let alloc = thing.allocator.stats().alloc;
     assert_eq!(alloc, 1);
 
     let oldthing = thing.item();
     thing.set(120);
-    assert_eq!(*oldthing, 50);
-    assert_eq!(*thing.item(), 120);
+    assert_eq!(*oldthing.borrow(), 50);
+    assert_eq!(*thing.item().borrow(), 120);
 
-    let (alloc, _) = thing.allocator.stats();
+    let alloc = thing.allocator.stats().alloc;
     assert_eq!(alloc, 2);
+
+    // The allocator's memory lives on while its boxes do.
+    drop(thing);
+    assert_eq!(*oldthing.borrow(), 50);
   }
 
   #[test]
@@ -559,23 +645,23 @@ mod tests {
     {
       let struct_obj = ValHolder { value: 3490, value2: 871 };
       let object = slab_allocator.alloc(struct_obj);
-      assert_eq!(object.value, 3490);
-      assert_eq!(object.value2, 871);
+      assert_eq!(object.borrow().value, 3490);
+      assert_eq!(object.borrow().value2, 871);
     }
 
     // Making sure object is returned back.
-    let (alloc, _) = slab_allocator.stats();
+    let alloc = slab_allocator.stats().alloc;
     assert_eq!(0, alloc);
 
     // Making sure dirty_alloc returns the same structure.
     {
       let object = unsafe { slab_allocator.dirty_alloc() };
-      assert_eq!(object.value, 3490);
-      assert_eq!(object.value2, 871);
+      assert_eq!(object.borrow().value, 3490);
+      assert_eq!(object.borrow().value2, 871);
     }
 
     // Again, should be deallocated.
-    let (alloc, _) = slab_allocator.stats();
+    let alloc = slab_allocator.stats().alloc;
     assert_eq!(0, alloc);
 
     // Allocating two objects. First should be dirty with same values, second
@@ -583,9 +669,63 @@ mod tests {
     let object1 = unsafe { slab_allocator.dirty_alloc() };
     let object2 = unsafe { slab_allocator.dirty_alloc() };
 
-    assert_eq!(object1.value, 3490);
-    assert_eq!(object1.value2, 871);
-    assert!(object2.value != 3490);
-    assert!(object2.value2 != 871);
+    assert_eq!(object1.borrow().value, 3490);
+    assert_eq!(object1.borrow().value2, 871);
+    assert!(object2.borrow().value != 3490);
+    assert!(object2.borrow().value2 != 871);
+  }
+
+  #[test]
+  fn test_borrows() {
+    let slab_allocator = SlabAllocator::new(1);
+    let object = slab_allocator.alloc(String::from("slab"));
+    let object2 = object.clone();
+    {
+      let value = object.borrow();
+      assert!(object2.try_borrow_mut().is_none());
+      assert_eq!(*object2.borrow(), *value);
+    }
+
+    object2.borrow_mut().push('s');
+    let value = object.borrow_mut();
+    assert!(object2.try_borrow().is_none());
+    assert_eq!(format!("{:?}", object2), "SlabBox(<borrowed>)");
+    assert_eq!(*value, "slabs");
+  }
+
+  #[test]
+  fn test_shrink() {
+    let slab_allocator = SlabAllocator::new(8);
+    let mut objects: Vec<_> = (0..40).map(|i| slab_allocator.alloc(Box::new(i))).collect();
+    assert_eq!(slab_allocator.stats().capacity, 64); // 8 -> 16 -> 32 -> 64
+
+    // Dropping the last 24 empties the two newest chunks, and they're given
+    // back as they do, since more than 10 slots are free.
+    slab_allocator.set_high_water(Some(10));
+    drop(objects.split_off(16));
+    let stats = slab_allocator.stats();
+    assert_eq!((stats.alloc, stats.capacity), (16, 16));
+    assert_eq!(stats.chunks.iter().map(|chunk| chunk.used).collect::<Vec<_>>(), [8, 8]);
+    assert_eq!(stats.fragmentation(), 0.0);
+
+    // Values in kept chunks are untouched.
+    let sum: i32 = objects.iter().map(|object| **object.borrow()).sum();
+    assert_eq!(sum, (0..16).sum());
+
+    // Dropping the rest gives back the first chunk, which empties first. The
+    // second stays, as its 8 free slots are within the mark.
+    drop(objects);
+    assert_eq!(slab_allocator.stats().capacity, 8);
+    slab_allocator.set_high_water(None);
+    assert_eq!(slab_allocator.shrink(), 8);
+  }
+
+  #[test]
+  fn test_zero_sized() {
+    let slab_allocator = SlabAllocator::new(0);
+    let objects: Vec<_> = (0..5).map(|_| slab_allocator.alloc(())).collect();
+    assert_eq!(slab_allocator.stats().capacity, 8); // 1 -> 2 -> 4 -> 8
+    drop(objects);
+    assert_eq!(slab_allocator.stats().alloc, 0);
   }
 }
@@ -0,0 +1,306 @@
+/*!
+ * The untyped core of the allocators: chunks of equally sized slots, a free
+ * list, and the bookkeeping to hand chunks back once they're empty.
+ *
+ * Every slot starts with a `Header` naming the chunk it belongs to, so freeing
+ * a slot finds its chunk without a search. What follows the header is up to
+ * the allocator using the slots.
+ */
+
+use std::alloc::{self, Layout};
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::ptr::NonNull;
+
+// The start of every slot.
+#[repr(C)]
+pub struct Header {
+  chunk: Cell<*const Chunk>,
+}
+
+// A chunk of `len` slots starting at `base`, `used` of which are handed out.
+struct Chunk {
+  base: NonNull<u8>,
+  len: usize,
+  used: Cell<usize>,
+}
+
+/// How full one chunk of a slab is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkStats {
+  /// The number of slots in the chunk.
+  pub capacity: usize,
+  /// The number of them handed out.
+  pub used: usize,
+}
+
+/// How a slab is used: overall, and chunk by chunk in the order they were
+/// allocated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlabStats {
+  /// The number of outstanding items.
+  pub alloc: usize,
+  /// The number of slots in all chunks.
+  pub capacity: usize,
+  pub chunks: Vec<ChunkStats>,
+}
+
+impl SlabStats {
+  /// The number of free slots.
+  pub fn free(&self) -> usize {
+    self.capacity - self.alloc
+  }
+
+  /// The number of free slots in chunks with nothing in use, which shrinking
+  /// would give back.
+  pub fn releasable(&self) -> usize {
+    self.chunks.iter().filter(|chunk| chunk.used == 0).map(|chunk| chunk.capacity).sum()
+  }
+
+  /**
+   * The share of free slots stuck in chunks that are partly in use, and so
+   * can't be given back: 0 when every free slot could be, 1 when none could.
+   * 0 when there are no free slots.
+   */
+  pub fn fragmentation(&self) -> f64 {
+    match self.free() {
+      0 => 0.0,
+      free => (free - self.releasable()) as f64 / free as f64
+    }
+  }
+}
+
+pub struct RawSlab {
+  slot: Layout,                         // one slot, padded to its alignment
+  min_chunk: usize,                     // the fewest slots to grow by
+  free: RefCell<Vec<NonNull<Header>>>,  // free slots, last freed last
+  chunks: RefCell<Vec<NonNull<Chunk>>>, // boxed, in the order they were allocated
+  alloc: Cell<usize>,                   // number of outstanding items
+  capacity: Cell<usize>,                // number of slots in all chunks
+  high_water: Cell<Option<usize>>,      // the most free slots to keep
+}
+
+// A slab owns its chunks, and nothing else refers to them but its slots.
+unsafe impl Send for RawSlab {}
+
+impl RawSlab {
+  /// A slab of slots laid out as `slot`, which must start with a `Header`,
+  /// holding `initial_size` slots to begin with.
+  pub fn new(slot: Layout, initial_size: usize) -> RawSlab {
+    let raw = RawSlab {
+      slot: slot.pad_to_align(),
+      min_chunk: cmp::max(initial_size, 1),
+      free: RefCell::new(Vec::with_capacity(initial_size)),
+      chunks: RefCell::new(Vec::with_capacity(20)),
+      alloc: Cell::new(0),
+      capacity: Cell::new(0),
+      high_water: Cell::new(None),
+    };
+
+    if initial_size > 0 { raw.expand(initial_size); }
+    raw
+  }
+
+  fn chunk_layout(&self, len: usize) -> Layout {
+    let size = self.slot.size().checked_mul(len).expect("slab too large");
+    Layout::from_size_align(size, self.slot.align()).expect("slab too large")
+  }
+
+  // Allocates a chunk of `new_items` more slots, zeroed, and adds them to the
+  // free list.
+  fn expand(&self, new_items: usize) {
+    let layout = self.chunk_layout(new_items);
+    let memory = match NonNull::new(unsafe { alloc::alloc_zeroed(layout) }) {
+      Some(memory) => memory,
+      None => alloc::handle_alloc_error(layout)
+    };
+
+    // Slots point at their chunk, so it stays put, as a raw pointer, until it's
+    // released.
+    let chunk = Box::new(Chunk { base: memory, len: new_items, used: Cell::new(0) });
+    let chunk = unsafe { NonNull::new_unchecked(Box::into_raw(chunk)) };
+    let mut free = self.free.borrow_mut();
+    for i in (0..new_items).rev() {
+      unsafe {
+        let slot = memory.as_ptr().add(i * self.slot.size()) as *mut Header;
+        (*slot).chunk.set(chunk.as_ptr());
+        free.push(NonNull::new_unchecked(slot));
+      }
+    }
+
+    self.chunks.borrow_mut().push(chunk);
+    self.capacity.set(self.capacity.get() + new_items);
+  }
+
+  /// Takes a free slot, growing by a chunk as large as the slab if there's
+  /// none.
+  pub fn take(&self) -> NonNull<u8> {
+    if self.free.borrow().is_empty() {
+      self.expand(cmp::max(self.capacity.get(), self.min_chunk));
+    }
+
+    let slot = self.free.borrow_mut().pop().unwrap();
+    unsafe {
+      let chunk = &*slot.as_ref().chunk.get();
+      chunk.used.set(chunk.used.get() + 1);
+    }
+
+    self.alloc.set(self.alloc.get() + 1);
+    slot.cast()
+  }
+
+  /**
+   * Returns `slot` to the free list, and gives back empty chunks if that
+   * leaves more free slots than the high-water mark.
+   *
+   * # Safety
+   *
+   * `slot` must have come from `take` on this slab and not been freed since.
+   * Nothing in it may be used afterwards.
+   */
+  pub unsafe fn free(&self, slot: NonNull<u8>) {
+    let alloc = self.alloc.get();
+    if alloc == 0 { panic!("Over-freeing....somehow"); }
+
+    let slot = slot.cast::<Header>();
+    let chunk = &*slot.as_ref().chunk.get();
+    chunk.used.set(chunk.used.get() - 1);
+    self.alloc.set(alloc - 1);
+    self.free.borrow_mut().push(slot);
+
+    if let Some(mark) = self.high_water.get() {
+      if self.capacity.get() - self.alloc.get() > mark { self.release(Some(mark)); }
+    }
+  }
+
+  // Gives back empty chunks, newest first, until no more than `mark` slots
+  // are free, or every empty chunk if there's no mark.
+  fn release(&self, mark: Option<usize>) -> usize {
+    let mut released = 0;
+    let mut chunks = self.chunks.borrow_mut();
+    let mut i = chunks.len();
+    while i > 0 {
+      i -= 1;
+      let free = self.capacity.get() - self.alloc.get();
+      if mark.is_some_and(|mark| free <= mark) { break; }
+      if unsafe { chunks[i].as_ref() }.used.get() > 0 { continue; }
+
+      let start = chunks.remove(i).as_ptr();
+      self.free.borrow_mut().retain(|slot| unsafe { slot.as_ref().chunk.get() != start });
+      let chunk = unsafe { Box::from_raw(start) };
+      unsafe { alloc::dealloc(chunk.base.as_ptr(), self.chunk_layout(chunk.len)); }
+      self.capacity.set(self.capacity.get() - chunk.len);
+      released += chunk.len;
+    }
+
+    released
+  }
+
+  /// Gives back every chunk with nothing in use, returning the number of
+  /// slots given back.
+  pub fn shrink(&self) -> usize {
+    self.release(None)
+  }
+
+  /// Has the slab give back empty chunks whenever more than `mark` slots are
+  /// free, or never if `None`, the default.
+  pub fn set_high_water(&self, mark: Option<usize>) {
+    self.high_water.set(mark);
+    if let Some(mark) = mark { self.release(Some(mark)); }
+  }
+
+  pub fn high_water(&self) -> Option<usize> {
+    self.high_water.get()
+  }
+
+  pub fn stats(&self) -> SlabStats {
+    SlabStats {
+      alloc: self.alloc.get(),
+      capacity: self.capacity.get(),
+      chunks: self.chunks.borrow().iter()
+        .map(|chunk| unsafe { chunk.as_ref() })
+        .map(|chunk| ChunkStats { capacity: chunk.len, used: chunk.used.get() })
+        .collect(),
+    }
+  }
+}
+
+impl Drop for RawSlab {
+  fn drop(&mut self) {
+    for &chunk in self.chunks.borrow().iter() {
+      let chunk = unsafe { Box::from_raw(chunk.as_ptr()) };
+      unsafe { alloc::dealloc(chunk.base.as_ptr(), self.chunk_layout(chunk.len)); }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{RawSlab, Header, ChunkStats};
+  use std::alloc::Layout;
+
+  #[repr(C)]
+  struct Slot {
+    header: Header,
+    value: u64,
+  }
+
+  fn slab(initial_size: usize) -> RawSlab {
+    RawSlab::new(Layout::new::<Slot>(), initial_size)
+  }
+
+  #[test]
+  fn test_chunk_stats() {
+    let raw = slab(2);
+    let slots: Vec<_> = (0..5).map(|_| raw.take()).collect();
+    let stats = raw.stats();
+    assert_eq!(stats.chunks, [ChunkStats { capacity: 2, used: 2 },
+                              ChunkStats { capacity: 2, used: 2 },
+                              ChunkStats { capacity: 4, used: 1 }]);
+    assert_eq!((stats.free(), stats.releasable(), stats.fragmentation()), (3, 0, 1.0));
+
+    // Freeing the first chunk's slots leaves it empty, and releasable.
+    unsafe {
+      raw.free(slots[0]);
+      raw.free(slots[1]);
+    }
+    let stats = raw.stats();
+    assert_eq!(stats.chunks[0].used, 0);
+    assert_eq!((stats.free(), stats.releasable()), (5, 2));
+    assert_eq!(stats.fragmentation(), 0.6);
+
+    assert_eq!(raw.shrink(), 2);
+    assert_eq!(raw.stats().capacity, 6);
+    for &slot in &slots[2..] { unsafe { raw.free(slot); } }
+    assert_eq!(raw.shrink(), 6);
+    assert_eq!(raw.stats().fragmentation(), 0.0);
+
+    // An empty slab grows by at least its initial size again.
+    raw.take();
+    assert_eq!(raw.stats().capacity, 2);
+  }
+
+  #[test]
+  fn test_high_water() {
+    let raw = slab(4);
+    let slots: Vec<_> = (0..16).map(|_| raw.take()).collect();
+    assert_eq!(raw.stats().capacity, 16); // 4 -> 8 -> 16
+    raw.set_high_water(Some(5));
+
+    // The newest chunk goes once it's empty, leaving 8 slots, none free.
+    for &slot in &slots[8..] { unsafe { raw.free(slot); } }
+    assert_eq!(raw.stats().capacity, 8);
+    assert_eq!(raw.stats().free(), 0);
+
+    // Chunks that would leave the slab under the mark stay.
+    for &slot in &slots[..4] { unsafe { raw.free(slot); } }
+    assert_eq!(raw.stats().capacity, 8);
+    assert_eq!(raw.stats().releasable(), 4);
+    for &slot in &slots[4..5] { unsafe { raw.free(slot); } }
+    assert_eq!(raw.stats().capacity, 8);
+    unsafe { raw.free(slots[5]); }
+    assert_eq!(raw.stats().capacity, 4);
+    assert_eq!(raw.stats().free(), 2);
+    assert_eq!(raw.high_water(), Some(5));
+  }
+}
@@ -0,0 +1,223 @@
+/*!
+ * Byte buffers of varying sizes, from a slab per size class.
+ *
+ * A request is served by the smallest class that fits it, so objects whose
+ * size varies, like inodes holding their data inline, share a handful of
+ * slabs rather than each size getting its own. Requests larger than every
+ * class go to the system allocator.
+ */
+
+use std::alloc::Layout;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+use std::rc::Rc;
+use std::slice;
+use raw::{Header, RawSlab, SlabStats};
+
+/// The size classes `SizeClassAllocator::new` uses: powers of two from 16
+/// bytes to 4 KiB.
+pub const DEFAULT_SIZE_CLASSES: [usize; 9] = [16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
+
+// A slab of buffers of `size` bytes, stored after each slot's header at
+// `offset`.
+struct Class {
+  size: usize,
+  offset: usize,
+  raw: Rc<RawSlab>,
+}
+
+pub struct SizeClassAllocator {
+  classes: Vec<Class>,
+}
+
+impl SizeClassAllocator {
+  /// An allocator with the `DEFAULT_SIZE_CLASSES`, each slab holding
+  /// `initial_size` buffers to begin with.
+  pub fn new(initial_size: usize) -> SizeClassAllocator {
+    SizeClassAllocator::with_classes(&DEFAULT_SIZE_CLASSES, initial_size)
+  }
+
+  /// An allocator with the given size classes, in bytes. Sizes of 0 are
+  /// ignored.
+  pub fn with_classes(sizes: &[usize], initial_size: usize) -> SizeClassAllocator {
+    let mut sizes: Vec<usize> = sizes.iter().cloned().filter(|&size| size > 0).collect();
+    sizes.sort_unstable();
+    sizes.dedup();
+
+    let classes = sizes.into_iter().map(|size| {
+      let data = Layout::array::<u8>(size).expect("size class too large");
+      let (slot, offset) = Layout::new::<Header>().extend(data).expect("size class too large");
+      Class { size, offset, raw: Rc::new(RawSlab::new(slot, initial_size)) }
+    }).collect();
+
+    SizeClassAllocator { classes }
+  }
+
+  /// The size of the class serving requests for `len` bytes, or `None` if
+  /// they're too large for every class.
+  pub fn class_size(&self, len: usize) -> Option<usize> {
+    self.class(len).map(|class| class.size)
+  }
+
+  fn class(&self, len: usize) -> Option<&Class> {
+    self.classes.iter().find(|class| class.size >= len)
+  }
+
+  /// A zeroed buffer of `len` bytes.
+  pub fn alloc(&self, len: usize) -> SlabBytes {
+    let class = match self.class(len) {
+      Some(class) => class,
+      None => return SlabBytes { len, storage: Storage::Heap(vec![0; len].into_boxed_slice()) }
+    };
+
+    let slot = class.raw.take();
+    unsafe {
+      let data = NonNull::new_unchecked(slot.as_ptr().add(class.offset));
+      ptr::write_bytes(data.as_ptr(), 0, len);
+      SlabBytes { len, storage: Storage::Slab { raw: class.raw.clone(), slot, data } }
+    }
+  }
+
+  /// A buffer holding a copy of `bytes`.
+  pub fn alloc_from(&self, bytes: &[u8]) -> SlabBytes {
+    let mut buf = self.alloc(bytes.len());
+    buf.copy_from_slice(bytes);
+    buf
+  }
+
+  /// Gives back every chunk with nothing in use, in every class, returning
+  /// the number of buffers given back.
+  pub fn shrink(&self) -> usize {
+    self.classes.iter().map(|class| class.raw.shrink()).sum()
+  }
+
+  /// Sets the high-water mark of every class. See
+  /// `SlabAllocator::set_high_water`.
+  pub fn set_high_water(&self, mark: Option<usize>) {
+    for class in &self.classes { class.raw.set_high_water(mark); }
+  }
+
+  /// The size of each class and how its slab is used.
+  pub fn stats(&self) -> Vec<(usize, SlabStats)> {
+    self.classes.iter().map(|class| (class.size, class.raw.stats())).collect()
+  }
+}
+
+impl fmt::Debug for SizeClassAllocator {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("SizeClassAllocator")
+      .field("classes", &self.classes.iter().map(|class| class.size).collect::<Vec<_>>())
+      .finish()
+  }
+}
+
+enum Storage {
+  // A slot of `raw`, with the buffer at `data`.
+  Slab { raw: Rc<RawSlab>, slot: NonNull<u8>, data: NonNull<u8> },
+  Heap(Box<[u8]>),
+}
+
+/// A byte buffer from a `SizeClassAllocator`, given back when dropped. It may
+/// outlive the allocator.
+pub struct SlabBytes {
+  len: usize,
+  storage: Storage,
+}
+
+impl SlabBytes {
+  /// Whether the buffer came from a slab rather than the system allocator.
+  pub fn is_pooled(&self) -> bool {
+    match self.storage {
+      Storage::Slab { .. } => true,
+      Storage::Heap(_) => false
+    }
+  }
+}
+
+impl Deref for SlabBytes {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    match self.storage {
+      Storage::Slab { data, .. } => unsafe { slice::from_raw_parts(data.as_ptr(), self.len) },
+      Storage::Heap(ref data) => data
+    }
+  }
+}
+
+impl DerefMut for SlabBytes {
+  fn deref_mut(&mut self) -> &mut [u8] {
+    match self.storage {
+      Storage::Slab { data, .. } => unsafe {
+        slice::from_raw_parts_mut(data.as_ptr(), self.len)
+      },
+      Storage::Heap(ref mut data) => data
+    }
+  }
+}
+
+impl Drop for SlabBytes {
+  fn drop(&mut self) {
+    if let Storage::Slab { ref raw, slot, .. } = self.storage {
+      unsafe { raw.free(slot); }
+    }
+  }
+}
+
+impl fmt::Debug for SlabBytes {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_tuple("SlabBytes").field(&&self[..]).finish()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::SizeClassAllocator;
+
+  #[test]
+  fn test_size_classes() {
+    let allocator = SizeClassAllocator::with_classes(&[64, 0, 16, 64, 256], 2);
+    assert_eq!(allocator.class_size(0), Some(16));
+    assert_eq!(allocator.class_size(17), Some(64));
+    assert_eq!(allocator.class_size(257), None);
+
+    let mut small = allocator.alloc(10);
+    small.copy_from_slice(b"inode data");
+    let copy = allocator.alloc_from(&small);
+    assert_eq!(&copy[..], b"inode data");
+    let large = allocator.alloc(200);
+    let huge = allocator.alloc_from(&[7; 300]);
+    assert!(large.is_pooled() && !huge.is_pooled());
+    assert_eq!(huge.len(), 300);
+
+    let used: Vec<_> = allocator.stats().iter().map(|(size, stats)| (*size, stats.alloc))
+      .collect();
+    assert_eq!(used, [(16, 2), (64, 0), (256, 1)]);
+    drop((small, copy));
+
+    // Reused buffers come back zeroed.
+    let again = allocator.alloc(16);
+    assert!(again.iter().all(|&byte| byte == 0));
+    drop(again);
+
+    // Buffers outlive the allocator.
+    drop(allocator);
+    assert_eq!(large.len(), 200);
+  }
+
+  #[test]
+  fn test_shrink_classes() {
+    let allocator = SizeClassAllocator::with_classes(&[32, 128], 4);
+    let buffers: Vec<_> = (0..10).map(|i| allocator.alloc(i * 12)).collect();
+    drop(buffers);
+    assert_eq!(allocator.shrink(), 4 + 8);
+    assert!(allocator.stats().iter().all(|(_, stats)| stats.capacity == 0));
+
+    allocator.set_high_water(Some(0));
+    let buffer = allocator.alloc(100);
+    assert_eq!(allocator.stats()[1].1.capacity, 4);
+    drop(buffer);
+    assert_eq!(allocator.stats()[1].1.capacity, 0);
+  }
+}
@@ -0,0 +1,51 @@
+extern crate rustfs;
+
+use std::env;
+use std::process;
+
+// Exit codes, as with e2fsck.
+const EXIT_CLEAN: i32 = 0;
+const EXIT_REPAIRED: i32 = 1;
+const EXIT_UNREPAIRED: i32 = 4;
+const EXIT_ERROR: i32 = 8;
+const EXIT_USAGE: i32 = 16;
+
+fn usage() -> ! {
+  eprintln!("usage: rustfs-fsck [-n | -y] IMAGE");
+  eprintln!("  -n  only check the image (the default)");
+  eprintln!("  -y  repair any problems found");
+  process::exit(EXIT_USAGE);
+}
+
+// Checks an image, printing one line per problem found to stdout.
+fn main() {
+  let mut repair = false;
+  let mut path = None;
+  for arg in env::args().skip(1) {
+    match arg.as_str() {
+      "-n" => repair = false,
+      "-y" => repair = true,
+      _ if arg.starts_with('-') || path.is_some() => usage(),
+      _ => path = Some(arg)
+    }
+  }
+
+  let path = path.unwrap_or_else(|| usage());
+  let report = match rustfs::fsck_image(&path, repair) {
+    Ok(report) => report,
+    Err(e) => {
+      eprintln!("rustfs-fsck: {}: {:?}", path, e);
+      process::exit(EXIT_ERROR);
+    }
+  };
+
+  for problem in report.problems.iter() {
+    println!("{}", problem);
+  }
+
+  process::exit(match (report.is_clean(), report.repaired) {
+    (true, _) => EXIT_CLEAN,
+    (false, true) => EXIT_REPAIRED,
+    (false, false) => EXIT_UNREPAIRED
+  });
+}
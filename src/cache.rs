@@ -0,0 +1,240 @@
+/*!
+ * The page cache of a mounted file system: which pages of its files are
+ * resident, from least to most recently used, and how often they were found
+ * resident when needed.
+ *
+ * Pages of a mounted file system are read from the device as they're first
+ * used, and by default stay resident for good. With a limit on resident pages,
+ * the file system evicts the least recently used ones past it after each read
+ * or write, writing dirty ones back to the device first; see
+ * `FileSystem::set_cache_limit`. Reads that pick up where the last one on a
+ * descriptor left off also read ahead of themselves, more the longer the run
+ * of sequential reads.
+ *
+ * Only files in the tree of a mounted file system are cached; files of file
+ * systems that live in memory, snapshots and orphans have nowhere to be
+ * evicted to.
+ */
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::rc::{Rc, Weak};
+use inode::Inode;
+use file::RcInode;
+
+/// The most pages read ahead of a run of sequential reads, by default.
+pub const DEFAULT_READ_AHEAD: usize = 32;
+
+/// How a file system's page cache has been used, as reported by
+/// `FileSystem::cache_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+  /// Pages of cached files that are resident.
+  pub resident: usize,
+  /// The most resident pages, if limited.
+  pub limit: Option<usize>,
+  /// Page accesses that found the page resident.
+  pub hits: u64,
+  /// Page accesses that had to read the page from the device.
+  pub misses: u64,
+  /// Pages read from the device ahead of sequential reads.
+  pub read_ahead: u64,
+  /// Pages evicted.
+  pub evictions: u64,
+  /// Dirty pages written back to the device to evict them.
+  pub writebacks: u64,
+}
+
+// A cached file: its inode, and when each of its resident pages was last used.
+#[derive(Debug)]
+struct Cached {
+  inode: Weak<RefCell<Box<Inode>>>,
+  pages: HashMap<usize, u64>,
+}
+
+#[derive(Debug)]
+pub(crate) struct PageCache {
+  files: RefCell<HashMap<*const Inode, Cached>>,
+  lru: RefCell<BTreeMap<u64, (*const Inode, usize)>>, // by time of last use
+  clock: Cell<u64>,
+  max_read_ahead: Cell<usize>,
+  stats: Cell<CacheStats>,
+}
+
+impl PageCache {
+  pub fn new() -> PageCache {
+    PageCache {
+      files: RefCell::new(HashMap::new()),
+      lru: RefCell::new(BTreeMap::new()),
+      clock: Cell::new(0),
+      max_read_ahead: Cell::new(DEFAULT_READ_AHEAD),
+      stats: Cell::new(CacheStats::default()),
+    }
+  }
+
+  pub fn stats(&self) -> CacheStats {
+    CacheStats { resident: self.lru.borrow().len(), ..self.stats.get() }
+  }
+
+  fn update<F: FnOnce(&mut CacheStats)>(&self, f: F) {
+    let mut stats = self.stats.get();
+    f(&mut stats);
+    self.stats.set(stats);
+  }
+
+  pub fn limit(&self) -> Option<usize> {
+    self.stats.get().limit
+  }
+
+  pub fn set_limit(&self, limit: Option<usize>) {
+    self.update(|stats| stats.limit = limit);
+  }
+
+  pub fn max_read_ahead(&self) -> usize {
+    self.max_read_ahead.get()
+  }
+
+  pub fn set_max_read_ahead(&self, pages: usize) {
+    self.max_read_ahead.set(pages);
+  }
+
+  /// Starts caching the pages of `inode`, which must not be borrowed, along
+  /// with those already resident. Does nothing if it's cached already.
+  pub fn track(&self, rc: &RcInode) {
+    let mut inode = rc.borrow_mut();
+    let key: *const Inode = &**inode;
+    if self.files.borrow().contains_key(&key) { return; }
+
+    let cached = Cached { inode: Rc::downgrade(rc), pages: HashMap::new() };
+    self.files.borrow_mut().insert(key, cached);
+    for (num, page) in inode.pages_mut() {
+      if page.is_resident() { self.insert(key, num); }
+    }
+  }
+
+  /// Stops caching the pages of the inode at `key`, as when it's dropped.
+  pub fn forget(&self, key: *const Inode) {
+    if let Some(cached) = self.files.borrow_mut().remove(&key) {
+      let mut lru = self.lru.borrow_mut();
+      for time in cached.pages.values() { lru.remove(time); }
+    }
+  }
+
+  // Marks page `num` of the inode at `key` as just used, returning whether
+  // the inode is cached.
+  fn touch(&self, key: *const Inode, num: usize) -> bool {
+    let mut files = self.files.borrow_mut();
+    let cached = match files.get_mut(&key) {
+      Some(cached) => cached,
+      None => return false
+    };
+
+    let now = self.clock.get() + 1;
+    self.clock.set(now);
+    let mut lru = self.lru.borrow_mut();
+    if let Some(then) = cached.pages.insert(num, now) { lru.remove(&then); }
+    lru.insert(now, (key, num));
+    true
+  }
+
+  /// Records that page `num` of the inode at `key` became resident without
+  /// being read from the device, as a new page does.
+  pub fn insert(&self, key: *const Inode, num: usize) {
+    self.touch(key, num);
+  }
+
+  /// Records a use of page `num` of the inode at `key`, which was resident.
+  pub fn hit(&self, key: *const Inode, num: usize) {
+    if self.touch(key, num) { self.update(|stats| stats.hits += 1); }
+  }
+
+  /// Records that page `num` of the inode at `key` was read from the device,
+  /// to be used or, with `ahead`, in case it's used next.
+  pub fn miss(&self, key: *const Inode, num: usize, ahead: bool) {
+    if self.touch(key, num) {
+      match ahead {
+        true => self.update(|stats| stats.read_ahead += 1),
+        false => self.update(|stats| stats.misses += 1)
+      }
+    }
+  }
+
+  /// Records that page `num` of the inode at `key` is no longer resident, as
+  /// when it's released.
+  pub fn remove(&self, key: *const Inode, num: usize) {
+    let mut files = self.files.borrow_mut();
+    let time = files.get_mut(&key).and_then(|cached| cached.pages.remove(&num));
+    if let Some(time) = time { self.lru.borrow_mut().remove(&time); }
+  }
+
+  /// Records that page `num` of the inode at `key` was evicted, and written
+  /// back first if `written_back`.
+  pub fn evicted(&self, key: *const Inode, num: usize, written_back: bool) {
+    self.remove(key, num);
+    self.update(|stats| {
+      stats.evictions += 1;
+      if written_back { stats.writebacks += 1; }
+    });
+  }
+
+  /**
+   * The pages past the limit, least recently used first, after skipping the
+   * `skip` least recently used pages, along with their inodes. None if
+   * there's no limit.
+   */
+  pub fn victims(&self, skip: usize) -> Vec<(RcInode, usize)> {
+    let lru = self.lru.borrow();
+    let excess = match self.limit() {
+      Some(limit) => lru.len().saturating_sub(limit),
+      None => 0
+    };
+
+    let files = self.files.borrow();
+    lru.values().skip(skip).take(excess)
+      .filter_map(|&(key, num)| files[&key].inode.upgrade().map(|inode| (inode, num)))
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::PageCache;
+  use std::cell::RefCell;
+  use std::rc::Rc;
+  use file::RcInode;
+  use inode::Inode;
+
+  fn key(inode: &RcInode) -> *const Inode {
+    &**inode.borrow()
+  }
+
+  #[test]
+  fn test_lru_order() {
+    let cache = PageCache::new();
+    let (a, b) = (Rc::new(RefCell::new(Box::new(Inode::new()))),
+                  Rc::new(RefCell::new(Box::new(Inode::new()))));
+    let (key_a, key_b) = (key(&a), key(&b));
+    cache.track(&a);
+    cache.track(&b);
+
+    cache.insert(key_a, 0);
+    cache.miss(key_b, 0, false);
+    cache.miss(key_a, 1, true);
+    cache.hit(key_a, 0);
+    cache.insert(key_a, 5);
+    assert!(cache.victims(0).is_empty());
+
+    // Least recently used first: b's 0, then a's 1.
+    cache.set_limit(Some(1));
+    let victims: Vec<_> = cache.victims(0).into_iter()
+      .map(|(inode, num)| (Rc::ptr_eq(&inode, &a), num)).collect();
+    assert_eq!(victims, [(false, 0), (true, 1), (true, 0)]);
+    assert_eq!(cache.victims(2).len(), 2);
+
+    cache.evicted(key_b, 0, true);
+    cache.forget(key_a);
+    let stats = cache.stats();
+    assert_eq!((stats.resident, stats.hits, stats.misses, stats.read_ahead), (0, 1, 1, 1));
+    assert_eq!((stats.evictions, stats.writebacks), (1, 1));
+  }
+}
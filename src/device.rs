@@ -0,0 +1,457 @@
+extern crate memmap2;
+
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::rc::Rc;
+use self::memmap2::MmapMut;
+use error::{Error, Result};
+
+/**
+ * Fixed-size block storage underneath a file system. Blocks are numbered from
+ * 0 to `block_count() - 1` and are `block_size()` bytes long; every buffer
+ * passed to `read_block` and `write_block` must be exactly that long. Writes
+ * are only guaranteed to be durable once `flush` returns.
+ */
+pub trait BlockDevice {
+  fn block_size(&self) -> usize;
+  fn block_count(&self) -> u64;
+  fn read_block(&mut self, block: u64, buf: &mut [u8]) -> Result<()>;
+  fn write_block(&mut self, block: u64, buf: &[u8]) -> Result<()>;
+  fn flush(&mut self) -> Result<()>;
+}
+
+/// A block device shared between a file system and the inodes loaded from it.
+pub type SharedDevice = Rc<RefCell<dyn BlockDevice>>;
+
+#[inline(always)]
+fn check_access(device: &dyn BlockDevice, block: u64, len: usize) -> Result<()> {
+  if block >= device.block_count() || len != device.block_size() {
+    return Err(Error::InvalidArgument);
+  }
+
+  Ok(())
+}
+
+/// Reads the `buf.len() / block_size` consecutive blocks starting at `start`.
+pub fn read_blocks(device: &mut dyn BlockDevice, start: u64, buf: &mut [u8])
+    -> Result<()> {
+  let block_size = device.block_size();
+  for (i, chunk) in buf.chunks_mut(block_size).enumerate() {
+    device.read_block(start + i as u64, chunk)?;
+  }
+
+  Ok(())
+}
+
+/// Writes `buf` to the `buf.len() / block_size` consecutive blocks starting at
+/// `start`.
+pub fn write_blocks(device: &mut dyn BlockDevice, start: u64, buf: &[u8])
+    -> Result<()> {
+  let block_size = device.block_size();
+  for (i, chunk) in buf.chunks(block_size).enumerate() {
+    device.write_block(start + i as u64, chunk)?;
+  }
+
+  Ok(())
+}
+
+/// A device held in memory. Blocks are allocated on first write; blocks never
+/// written read as zeros.
+#[derive(Clone)]
+pub struct MemDevice {
+  block_size: usize,
+  blocks: Vec<Option<Box<[u8]>>>,
+}
+
+impl MemDevice {
+  pub fn new(block_size: usize, block_count: u64) -> MemDevice {
+    MemDevice {
+      block_size,
+      blocks: (0..block_count).map(|_| None).collect(),
+    }
+  }
+}
+
+impl BlockDevice for MemDevice {
+  fn block_size(&self) -> usize {
+    self.block_size
+  }
+
+  fn block_count(&self) -> u64 {
+    self.blocks.len() as u64
+  }
+
+  fn read_block(&mut self, block: u64, buf: &mut [u8]) -> Result<()> {
+    check_access(self, block, buf.len())?;
+    match self.blocks[block as usize] {
+      Some(ref data) => buf.copy_from_slice(data),
+      None => buf.fill(0)
+    }
+
+    Ok(())
+  }
+
+  fn write_block(&mut self, block: u64, buf: &[u8]) -> Result<()> {
+    check_access(self, block, buf.len())?;
+    self.blocks[block as usize] = Some(buf.to_vec().into_boxed_slice());
+    Ok(())
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    Ok(())
+  }
+}
+
+/// A device backed by a regular host file, accessed with reads and writes.
+pub struct FileDevice {
+  file: File,
+  block_size: usize,
+  block_count: u64,
+}
+
+impl FileDevice {
+  /// Creates a zero-filled file of `block_count` blocks at `path`, replacing
+  /// any file there.
+  pub fn create<P: AsRef<Path>>(path: P, block_size: usize, block_count: u64)
+      -> Result<FileDevice> {
+    let file = OpenOptions::new().read(true).write(true).create(true)
+      .truncate(true).open(path)?;
+    file.set_len(block_count * block_size as u64)?;
+    Ok(FileDevice { file, block_size, block_count })
+  }
+
+  /// Opens the file at `path` as a device of `block_size` blocks. Any partial
+  /// block at the end of the file is ignored.
+  pub fn open<P: AsRef<Path>>(path: P, block_size: usize) -> Result<FileDevice> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let block_count = file.metadata()?.len() / block_size as u64;
+    Ok(FileDevice { file, block_size, block_count })
+  }
+}
+
+impl BlockDevice for FileDevice {
+  fn block_size(&self) -> usize {
+    self.block_size
+  }
+
+  fn block_count(&self) -> u64 {
+    self.block_count
+  }
+
+  fn read_block(&mut self, block: u64, buf: &mut [u8]) -> Result<()> {
+    check_access(self, block, buf.len())?;
+    self.file.seek(SeekFrom::Start(block * self.block_size as u64))?;
+    self.file.read_exact(buf)?;
+    Ok(())
+  }
+
+  fn write_block(&mut self, block: u64, buf: &[u8]) -> Result<()> {
+    check_access(self, block, buf.len())?;
+    self.file.seek(SeekFrom::Start(block * self.block_size as u64))?;
+    self.file.write_all(buf)?;
+    Ok(())
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    self.file.sync_all()?;
+    Ok(())
+  }
+}
+
+/// A device backed by a memory-mapped host file.
+pub struct MmapDevice {
+  map: MmapMut,
+  block_size: usize,
+  block_count: u64,
+}
+
+impl MmapDevice {
+  /// Creates a zero-filled file of `block_count` blocks at `path`, replacing
+  /// any file there, and maps it.
+  pub fn create<P: AsRef<Path>>(path: P, block_size: usize, block_count: u64)
+      -> Result<MmapDevice> {
+    if block_count == 0 { return Err(Error::InvalidArgument); }
+    let file = OpenOptions::new().read(true).write(true).create(true)
+      .truncate(true).open(path)?;
+    file.set_len(block_count * block_size as u64)?;
+    MmapDevice::map(&file, block_size, block_count)
+  }
+
+  /// Maps the file at `path` as a device of `block_size` blocks. Any partial
+  /// block at the end of the file is ignored.
+  pub fn open<P: AsRef<Path>>(path: P, block_size: usize) -> Result<MmapDevice> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let block_count = file.metadata()?.len() / block_size as u64;
+    if block_count == 0 { return Err(Error::InvalidArgument); }
+    MmapDevice::map(&file, block_size, block_count)
+  }
+
+  fn map(file: &File, block_size: usize, block_count: u64) -> Result<MmapDevice> {
+    // The mapping is private to this device; the file must not be changed
+    // from elsewhere while it's mapped.
+    let map = unsafe { MmapMut::map_mut(file)? };
+    Ok(MmapDevice { map, block_size, block_count })
+  }
+
+  fn range(&self, block: u64) -> (usize, usize) {
+    let start = block as usize * self.block_size;
+    (start, start + self.block_size)
+  }
+}
+
+impl BlockDevice for MmapDevice {
+  fn block_size(&self) -> usize {
+    self.block_size
+  }
+
+  fn block_count(&self) -> u64 {
+    self.block_count
+  }
+
+  fn read_block(&mut self, block: u64, buf: &mut [u8]) -> Result<()> {
+    check_access(self, block, buf.len())?;
+    let (start, end) = self.range(block);
+    buf.copy_from_slice(&self.map[start..end]);
+    Ok(())
+  }
+
+  fn write_block(&mut self, block: u64, buf: &[u8]) -> Result<()> {
+    check_access(self, block, buf.len())?;
+    let (start, end) = self.range(block);
+    self.map[start..end].copy_from_slice(buf);
+    Ok(())
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    self.map.flush()?;
+    Ok(())
+  }
+}
+
+// The most writes between two flushes for which `CrashDevice` tries every
+// subset of them.
+const MAX_EXHAUSTIVE: usize = 8;
+
+enum Op {
+  Write(u64, Box<[u8]>),
+  Flush,
+}
+
+/**
+ * An in-memory device that records every write and flush made to it, so that
+ * the states the device could be left in by a crash can be replayed. Reads
+ * see every write made so far.
+ *
+ * A write is durable once a later flush returns. The writes since the last
+ * flush may reach the device in any order, or not at all.
+ */
+pub struct CrashDevice {
+  base: MemDevice,
+  current: MemDevice,
+  log: Vec<Op>,
+}
+
+impl CrashDevice {
+  /// Starts recording on top of `base`, which is the state before any crash.
+  pub fn new(base: MemDevice) -> CrashDevice {
+    CrashDevice { current: base.clone(), base, log: Vec::new() }
+  }
+
+  /// The number of writes recorded.
+  pub fn writes(&self) -> usize {
+    self.log.iter().filter(|op| matches!(**op, Op::Write(..))).count()
+  }
+
+  /// The device as it is now, with every write applied.
+  pub fn current(&self) -> &MemDevice {
+    &self.current
+  }
+
+  /**
+   * Calls `f` with every state a crash could leave the device in, starting
+   * with the base state and ending with the current one. Without `reorder`,
+   * writes reach the device in the order they were made, so the states are
+   * those after each write. With `reorder`, the writes made since the last
+   * flush may land in any order: every subset of them is tried if there are
+   * at most 8, otherwise every in-order prefix with any one write missing.
+   */
+  pub fn crash_states<F: FnMut(MemDevice)>(&self, reorder: bool, mut f: F) {
+    let mut durable = self.base.clone();
+    let mut pending = Vec::new();
+    f(durable.clone());
+
+    for op in self.log.iter().map(Some).chain(Some(None)) {
+      match op {
+        Some(&Op::Write(block, ref data)) => pending.push((block, &data[..])),
+        _ => {
+          CrashDevice::epoch_states(&durable, &pending, reorder, &mut f);
+          for &(block, data) in pending.iter() {
+            durable.write_block(block, data).unwrap();
+          }
+
+          pending.clear();
+        }
+      }
+    }
+  }
+
+  // Calls `f` with the states reachable from `durable` by applying some of
+  // the `pending` writes, at least one of them.
+  fn epoch_states<F: FnMut(MemDevice)>(durable: &MemDevice, pending: &[(u64, &[u8])],
+                                       reorder: bool, f: &mut F) {
+    let apply = |applied: &mut dyn Iterator<Item=usize>| {
+      let mut state = durable.clone();
+      for i in applied {
+        let (block, data) = pending[i];
+        state.write_block(block, data).unwrap();
+      }
+
+      state
+    };
+
+    let n = pending.len();
+    if reorder && n <= MAX_EXHAUSTIVE {
+      for mask in 1..(1u32 << n) {
+        f(apply(&mut (0..n).filter(|&i| (mask & (1 << i)) != 0)));
+      }
+    } else {
+      for len in 1..(n + 1) {
+        if reorder {
+          for missing in 0..(len - 1) {
+            f(apply(&mut (0..len).filter(|&i| i != missing)));
+          }
+        }
+
+        f(apply(&mut (0..len)));
+      }
+    }
+  }
+}
+
+impl BlockDevice for CrashDevice {
+  fn block_size(&self) -> usize {
+    self.current.block_size()
+  }
+
+  fn block_count(&self) -> u64 {
+    self.current.block_count()
+  }
+
+  fn read_block(&mut self, block: u64, buf: &mut [u8]) -> Result<()> {
+    self.current.read_block(block, buf)
+  }
+
+  fn write_block(&mut self, block: u64, buf: &[u8]) -> Result<()> {
+    self.current.write_block(block, buf)?;
+    self.log.push(Op::Write(block, buf.into()));
+    Ok(())
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    self.log.push(Op::Flush);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs;
+  use std::process;
+  use super::{BlockDevice, MemDevice, FileDevice, MmapDevice, CrashDevice};
+  use super::{read_blocks, write_blocks};
+  use error::Error;
+
+  fn exercise(device: &mut dyn BlockDevice) {
+    assert_eq!(device.block_size(), 512);
+    assert_eq!(device.block_count(), 8);
+
+    let mut buf = [1u8; 512];
+    device.read_block(7, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0));
+
+    device.write_block(3, &[3u8; 512]).unwrap();
+    device.write_block(4, &[4u8; 512]).unwrap();
+    device.flush().unwrap();
+    device.read_block(3, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 3));
+
+    let mut both = [0u8; 1024];
+    read_blocks(device, 3, &mut both).unwrap();
+    assert!(both[..512].iter().all(|&b| b == 3));
+    assert!(both[512..].iter().all(|&b| b == 4));
+
+    write_blocks(device, 5, &[9u8; 1024]).unwrap();
+    device.read_block(6, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 9));
+
+    assert_eq!(device.read_block(8, &mut buf), Err(Error::InvalidArgument));
+    assert_eq!(device.write_block(0, &[0u8; 100]), Err(Error::InvalidArgument));
+  }
+
+  #[test]
+  fn test_mem_device() {
+    exercise(&mut MemDevice::new(512, 8));
+  }
+
+  #[test]
+  fn test_file_device() {
+    let path = env::temp_dir().join(format!("rustfs-dev-{}.img", process::id()));
+    exercise(&mut FileDevice::create(&path, 512, 8).unwrap());
+
+    // Reopening sees the same contents.
+    let mut device = FileDevice::open(&path, 512).unwrap();
+    let mut buf = [0u8; 512];
+    device.read_block(4, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 4));
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_mmap_device() {
+    let path = env::temp_dir().join(format!("rustfs-mmap-{}.img", process::id()));
+    exercise(&mut MmapDevice::create(&path, 512, 8).unwrap());
+
+    let mut device = MmapDevice::open(&path, 512).unwrap();
+    let mut buf = [0u8; 512];
+    device.read_block(4, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 4));
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_crash_states() {
+    let mut device = CrashDevice::new(MemDevice::new(512, 8));
+    device.write_block(1, &[1u8; 512]).unwrap();
+    device.write_block(2, &[2u8; 512]).unwrap();
+    device.flush().unwrap();
+    device.write_block(3, &[3u8; 512]).unwrap();
+    device.write_block(1, &[4u8; 512]).unwrap();
+    assert_eq!(device.writes(), 4);
+
+    // Each state as the first byte of blocks 1 to 3.
+    let states = |reorder| {
+      let mut states = Vec::new();
+      device.crash_states(reorder, |mut state| {
+        let mut buf = [0u8; 512];
+        states.push((1..4).map(|block| {
+          state.read_block(block, &mut buf).unwrap();
+          buf[0]
+        }).collect::<Vec<u8>>());
+      });
+
+      states
+    };
+
+    assert_eq!(states(false), vec![vec![0, 0, 0], vec![1, 0, 0], vec![1, 2, 0],
+                                   vec![1, 2, 3], vec![4, 2, 3]]);
+
+    // Reordering never crosses the flush.
+    let reordered = states(true);
+    assert_eq!(reordered.len(), 7);
+    assert!(reordered.contains(&vec![0, 2, 0]) && reordered.contains(&vec![4, 2, 0]));
+    assert!(!reordered.contains(&vec![0, 0, 3]));
+  }
+}
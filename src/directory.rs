@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use file::File;
 use file::File::Directory;
 
@@ -10,30 +11,24 @@ pub trait DirectoryHandle<'r>: Sized {
 
 impl<'r> DirectoryHandle<'r> for File<'r> {
   fn is_dir(&self) -> bool {
-    match self {
-      &Directory(_) => true,
-      _ => false
-    }
+    matches!(*self, Directory(_))
   }
 
   fn insert(&mut self, name: &'r str, file: File<'r>) {
     let rc = self.get_dir_rc();
     let mut content = rc.borrow_mut();
-    content.entries.insert(name, file);
+    content.entries.insert(Cow::Borrowed(name), file);
   }
 
   fn remove(&mut self, name: &'r str) {
     let rc = self.get_dir_rc();
     let mut content = rc.borrow_mut();
-    content.entries.remove(&name);
+    content.entries.remove(name);
   }
 
   fn get(&self, name: &'r str) -> Option<File<'r>> {
     let rc = self.get_dir_rc();
     let content = rc.borrow();
-    match content.entries.get(&name) {
-      None => None,
-      Some(ref file) => Some((*file).clone()) // It's RC
-    }
+    content.entries.get(name).cloned() // It's RC
   }
 }
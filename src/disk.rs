@@ -0,0 +1,514 @@
+/*!
+ * The on-disk image format.
+ *
+ * An image is a sequence of blocks, each the size of a page in the file
+ * system's geometry. All integers are little-endian. Block pointers are 32-bit,
+ * and block 0, which always holds the superblock, doubles as the null pointer.
+ *
+ *   block 0               superblock
+ *   bitmap_start          free-space bitmap, one bit per block (1 = in use)
+ *   inode_table_start     inode table, `INODE_SIZE` bytes per inode
+ *   journal_start         write-ahead journal; see the `journal` module
+ *   data_start            data region: directory, list and file data blocks
+ *
+ * Superblock:
+ *
+ *   0   [u8; 8]  magic, `MAGIC`
+ *   8   u32      format version, `VERSION`
+ *   12  u32      page size
+ *   16  u32      list size
+ *   20  u32      number of inodes in the inode table
+ *   24  u64      number of blocks in the image
+ *   32  u64      bitmap_start
+ *   40  u64      bitmap_blocks
+ *   48  u64      inode_table_start
+ *   56  u64      inode_table_blocks
+ *   64  u64      data_start
+ *   72  u64      number of free blocks
+ *   80  u32      number of free inodes
+ *   88  u64      journal_start
+ *   96  u64      journal_blocks
+ *
+ * Inodes are numbered from 1; inode `n` lives at byte `(n - 1) * INODE_SIZE`
+ * of the inode table. The root directory is always `ROOT_INO`. An inode is:
+ *
+ *   0   u16      kind: `KIND_FREE`, `KIND_FILE` or `KIND_DIRECTORY`
+ *   2   u16      number of directory entries naming the inode
+ *   8   u64      size in bytes
+ *   16  u64      number of allocated pages
+ *   24  i64      creation time, seconds
+ *   32  i64      access time, seconds
+ *   40  i64      modification time, seconds
+ *   48  u32      creation time, nanoseconds
+ *   52  u32      access time, nanoseconds
+ *   56  u32      modification time, nanoseconds
+ *   60  u32      block of the singly-indirect list, or 0
+ *   64  u32      block of the doubly-indirect list, or 0
+ *   68  u32      block of the extended attributes, or 0
+ *   72  u32      owning user id
+ *   76  u32      owning group id
+ *
+ * Page lists mirror the in-memory `Inode` layout. The singly-indirect list
+ * block holds `list_size` pointers to the first `list_size` pages of the file.
+ * The doubly-indirect list block holds `list_size` pointers to further list
+ * blocks, each mapping the next `list_size` pages. A null pointer is a hole.
+ *
+ * A directory's contents are stored as its data, addressed through its page
+ * lists exactly like a file's. The data is a sequence of entries, each a u32
+ * inode number, a u16 name length and that many bytes of UTF-8 name.
+ *
+ * An inode's extended attributes, if it has any, take a single block: a u16
+ * number of attributes, then for each a u8 name length, a u32 value length,
+ * and that many bytes of UTF-8 name and of value.
+ */
+
+use std::str;
+use time::Timespec;
+use error::{Error, Result};
+use inode::Geometry;
+use journal;
+
+pub const MAGIC: [u8; 8] = *b"RustFS\0\0";
+pub const VERSION: u32 = 2;
+pub const INODE_SIZE: usize = 128;
+pub const ROOT_INO: u32 = 1;
+
+pub const XATTR_HEADER: usize = 2;
+pub const XATTR_ENTRY_HEADER: usize = 5;
+
+pub const KIND_FREE: u16 = 0;
+pub const KIND_FILE: u16 = 1;
+pub const KIND_DIRECTORY: u16 = 2;
+
+#[inline(always)]
+fn ceil_div(x: u64, y: u64) -> u64 {
+  x.div_ceil(y)
+}
+
+pub fn get_u16(buf: &[u8], at: usize) -> u16 {
+  let mut bytes = [0u8; 2];
+  bytes.copy_from_slice(&buf[at..(at + 2)]);
+  u16::from_le_bytes(bytes)
+}
+
+pub fn get_u32(buf: &[u8], at: usize) -> u32 {
+  let mut bytes = [0u8; 4];
+  bytes.copy_from_slice(&buf[at..(at + 4)]);
+  u32::from_le_bytes(bytes)
+}
+
+pub fn get_u64(buf: &[u8], at: usize) -> u64 {
+  let mut bytes = [0u8; 8];
+  bytes.copy_from_slice(&buf[at..(at + 8)]);
+  u64::from_le_bytes(bytes)
+}
+
+pub fn put_u16(buf: &mut [u8], at: usize, value: u16) {
+  buf[at..(at + 2)].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_u32(buf: &mut [u8], at: usize, value: u32) {
+  buf[at..(at + 4)].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn put_u64(buf: &mut [u8], at: usize, value: u64) {
+  buf[at..(at + 8)].copy_from_slice(&value.to_le_bytes());
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Superblock {
+  pub geometry: Geometry,
+  pub inode_count: u32,
+  pub block_count: u64,
+  pub bitmap_start: u64,
+  pub bitmap_blocks: u64,
+  pub inode_table_start: u64,
+  pub inode_table_blocks: u64,
+  pub journal_start: u64,
+  pub journal_blocks: u64,
+  pub data_start: u64,
+  pub free_blocks: u64,
+  pub free_inodes: u32,
+}
+
+impl Superblock {
+  /// Lays out an image of `block_count` blocks with room for `inode_count`
+  /// inodes.
+  pub fn new(geometry: Geometry, block_count: u64, inode_count: u32)
+      -> Result<Superblock> {
+    let page_size = geometry.page_size() as u64;
+    if inode_count == 0 || block_count > u64::from(u32::MAX) {
+      return Err(Error::InvalidArgument);
+    }
+
+    let bitmap_blocks = ceil_div(block_count, page_size * 8);
+    let inode_table_blocks = ceil_div(u64::from(inode_count) * INODE_SIZE as u64,
+                                      page_size);
+    let journal_start = 1 + bitmap_blocks + inode_table_blocks;
+    let journal_blocks = journal::default_blocks(journal_start);
+    let data_start = journal_start + journal_blocks;
+    if data_start >= block_count {
+      return Err(Error::InvalidArgument);
+    }
+
+    Ok(Superblock {
+      geometry,
+      inode_count,
+      block_count,
+      bitmap_start: 1,
+      bitmap_blocks,
+      inode_table_start: 1 + bitmap_blocks,
+      inode_table_blocks,
+      journal_start,
+      journal_blocks,
+      data_start,
+      free_blocks: block_count - data_start,
+      free_inodes: inode_count,
+    })
+  }
+
+  pub fn encode(&self, buf: &mut [u8]) {
+    for byte in buf.iter_mut() { *byte = 0; }
+    buf[0..8].copy_from_slice(&MAGIC);
+    put_u32(buf, 8, VERSION);
+    put_u32(buf, 12, self.geometry.page_size() as u32);
+    put_u32(buf, 16, self.geometry.list_size() as u32);
+    put_u32(buf, 20, self.inode_count);
+    put_u64(buf, 24, self.block_count);
+    put_u64(buf, 32, self.bitmap_start);
+    put_u64(buf, 40, self.bitmap_blocks);
+    put_u64(buf, 48, self.inode_table_start);
+    put_u64(buf, 56, self.inode_table_blocks);
+    put_u64(buf, 64, self.data_start);
+    put_u64(buf, 72, self.free_blocks);
+    put_u32(buf, 80, self.free_inodes);
+    put_u64(buf, 88, self.journal_start);
+    put_u64(buf, 96, self.journal_blocks);
+  }
+
+  /// Decodes and sanity checks a superblock. `buf` must be at least
+  /// `MIN_PAGE_SIZE` bytes long.
+  pub fn decode(buf: &[u8]) -> Result<Superblock> {
+    if buf[0..8] != MAGIC || get_u32(buf, 8) != VERSION {
+      return Err(Error::Corrupted);
+    }
+
+    let page_size = get_u32(buf, 12) as usize;
+    let list_size = get_u32(buf, 16) as usize;
+    let geometry = Geometry::new(page_size, list_size)
+      .map_err(|_| Error::Corrupted)?;
+
+    let block_count = get_u64(buf, 24);
+    let inode_count = get_u32(buf, 20);
+    let expected = Superblock::new(geometry, block_count, inode_count)
+      .map_err(|_| Error::Corrupted)?;
+
+    let superblock = Superblock {
+      bitmap_start: get_u64(buf, 32),
+      bitmap_blocks: get_u64(buf, 40),
+      inode_table_start: get_u64(buf, 48),
+      inode_table_blocks: get_u64(buf, 56),
+      data_start: get_u64(buf, 64),
+      free_blocks: get_u64(buf, 72),
+      free_inodes: get_u32(buf, 80),
+      journal_start: get_u64(buf, 88),
+      journal_blocks: get_u64(buf, 96),
+      ..expected
+    };
+
+    let free = (superblock.free_blocks, superblock.free_inodes);
+    let layout = Superblock { free_blocks: expected.free_blocks,
+                              free_inodes: expected.free_inodes, ..superblock };
+    if layout != expected || free.0 > expected.free_blocks || free.1 > inode_count {
+      return Err(Error::Corrupted);
+    }
+
+    Ok(superblock)
+  }
+
+  /// Whether `block` lies in the data region.
+  pub fn is_data_block(&self, block: u32) -> bool {
+    let block = u64::from(block);
+    block >= self.data_start && block < self.block_count
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiskInode {
+  pub kind: u16,
+  pub nlink: u16,
+  pub size: u64,
+  pub pages: u64,
+  pub create_time: Timespec,
+  pub access_time: Timespec,
+  pub mod_time: Timespec,
+  pub single: u32,
+  pub double: u32,
+  pub xattrs: u32,
+  pub uid: u32,
+  pub gid: u32,
+}
+
+impl DiskInode {
+  pub fn free() -> DiskInode {
+    let zero = Timespec::new(0, 0);
+    DiskInode {
+      kind: KIND_FREE,
+      nlink: 0,
+      size: 0,
+      pages: 0,
+      create_time: zero,
+      access_time: zero,
+      mod_time: zero,
+      single: 0,
+      double: 0,
+      xattrs: 0,
+      uid: 0,
+      gid: 0,
+    }
+  }
+
+  pub fn encode(&self, buf: &mut [u8]) {
+    for byte in buf[..INODE_SIZE].iter_mut() { *byte = 0; }
+    put_u16(buf, 0, self.kind);
+    put_u16(buf, 2, self.nlink);
+    put_u64(buf, 8, self.size);
+    put_u64(buf, 16, self.pages);
+    put_u64(buf, 24, self.create_time.sec as u64);
+    put_u64(buf, 32, self.access_time.sec as u64);
+    put_u64(buf, 40, self.mod_time.sec as u64);
+    put_u32(buf, 48, self.create_time.nsec as u32);
+    put_u32(buf, 52, self.access_time.nsec as u32);
+    put_u32(buf, 56, self.mod_time.nsec as u32);
+    put_u32(buf, 60, self.single);
+    put_u32(buf, 64, self.double);
+    put_u32(buf, 68, self.xattrs);
+    put_u32(buf, 72, self.uid);
+    put_u32(buf, 76, self.gid);
+  }
+
+  pub fn decode(buf: &[u8]) -> DiskInode {
+    let time = |sec_at, nsec_at| {
+      Timespec::new(get_u64(buf, sec_at) as i64, get_u32(buf, nsec_at) as i32)
+    };
+
+    DiskInode {
+      kind: get_u16(buf, 0),
+      nlink: get_u16(buf, 2),
+      size: get_u64(buf, 8),
+      pages: get_u64(buf, 16),
+      create_time: time(24, 48),
+      access_time: time(32, 52),
+      mod_time: time(40, 56),
+      single: get_u32(buf, 60),
+      double: get_u32(buf, 64),
+      xattrs: get_u32(buf, 68),
+      uid: get_u32(buf, 72),
+      gid: get_u32(buf, 76),
+    }
+  }
+}
+
+/// One bit per block; a set bit marks the block as in use.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bitmap {
+  bits: Vec<u8>,
+  len: u64,
+}
+
+impl Bitmap {
+  pub fn new(len: u64) -> Bitmap {
+    Bitmap { bits: vec![0; ceil_div(len, 8) as usize], len }
+  }
+
+  /// Decodes a bitmap of `len` bits from the start of `buf`.
+  pub fn decode(buf: &[u8], len: u64) -> Bitmap {
+    let bits = buf[..ceil_div(len, 8) as usize].to_vec();
+    Bitmap { bits, len }
+  }
+
+  /// Encodes the bitmap into `buf`, which is zero-padded.
+  pub fn encode(&self, buf: &mut [u8]) {
+    for byte in buf.iter_mut() { *byte = 0; }
+    buf[..self.bits.len()].copy_from_slice(&self.bits);
+  }
+
+  pub fn len(&self) -> u64 {
+    self.len
+  }
+
+  pub fn get(&self, bit: u64) -> bool {
+    (self.bits[(bit / 8) as usize] & (1 << (bit % 8))) != 0
+  }
+
+  pub fn set(&mut self, bit: u64) {
+    self.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+  }
+
+  pub fn count_set(&self) -> u64 {
+    self.bits.iter().map(|byte| u64::from(byte.count_ones())).sum()
+  }
+}
+
+/// Encodes a page list of `list.len()` block pointers into `buf`.
+pub fn encode_list(list: &[u32], buf: &mut [u8]) {
+  for byte in buf.iter_mut() { *byte = 0; }
+  for (i, &block) in list.iter().enumerate() {
+    put_u32(buf, i * 4, block);
+  }
+}
+
+/// Decodes a page list of `list_size` block pointers from `buf`.
+pub fn decode_list(buf: &[u8], list_size: usize) -> Vec<u32> {
+  (0..list_size).map(|i| get_u32(buf, i * 4)).collect()
+}
+
+/// Encodes directory entries as the contents of a directory.
+pub fn encode_entries(entries: &[(u32, &str)]) -> Vec<u8> {
+  let mut data = Vec::new();
+  for &(ino, name) in entries {
+    data.extend_from_slice(&ino.to_le_bytes());
+    data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    data.extend_from_slice(name.as_bytes());
+  }
+
+  data
+}
+
+/// Decodes the contents of a directory into its entries.
+pub fn decode_entries(data: &[u8]) -> Result<Vec<(u32, String)>> {
+  let mut entries = Vec::new();
+  let mut at = 0;
+  while at < data.len() {
+    if at + 6 > data.len() { return Err(Error::Corrupted); }
+    let ino = get_u32(data, at);
+    let len = get_u16(data, at + 4) as usize;
+    let start = at + 6;
+    if start + len > data.len() { return Err(Error::Corrupted); }
+
+    let name = str::from_utf8(&data[start..(start + len)])
+      .map_err(|_| Error::Corrupted)?;
+    entries.push((ino, name.to_string()));
+    at = start + len;
+  }
+
+  Ok(entries)
+}
+
+/// Encodes extended attributes as the contents of their block.
+pub fn encode_xattrs(xattrs: &[(&str, &[u8])]) -> Vec<u8> {
+  let mut data = (xattrs.len() as u16).to_le_bytes().to_vec();
+  for &(name, value) in xattrs {
+    data.push(name.len() as u8);
+    data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    data.extend_from_slice(name.as_bytes());
+    data.extend_from_slice(value);
+  }
+
+  data
+}
+
+/// Decodes the contents of an extended attribute block.
+pub fn decode_xattrs(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+  if data.len() < XATTR_HEADER { return Err(Error::Corrupted); }
+  let mut xattrs = Vec::new();
+  let mut at = XATTR_HEADER;
+  for _ in 0..get_u16(data, 0) {
+    if at + XATTR_ENTRY_HEADER > data.len() { return Err(Error::Corrupted); }
+    let name_len = data[at] as usize;
+    let value_len = get_u32(data, at + 1) as usize;
+    let start = at + XATTR_ENTRY_HEADER;
+    if start + name_len + value_len > data.len() { return Err(Error::Corrupted); }
+
+    let name = str::from_utf8(&data[start..(start + name_len)])
+      .map_err(|_| Error::Corrupted)?;
+    let value = &data[(start + name_len)..(start + name_len + value_len)];
+    xattrs.push((name.to_string(), value.to_vec()));
+    at = start + name_len + value_len;
+  }
+
+  Ok(xattrs)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Superblock, DiskInode, Bitmap, KIND_FILE};
+  use super::{encode_entries, decode_entries, encode_list, decode_list};
+  use error::Error;
+  use inode::Geometry;
+  use time::Timespec;
+
+  #[test]
+  fn test_superblock_roundtrip() {
+    let geometry = Geometry::new(1024, 32).unwrap();
+    let superblock = Superblock::new(geometry, 10000, 100).unwrap();
+    assert_eq!(superblock.bitmap_blocks, 2);
+    assert_eq!(superblock.inode_table_blocks, 13);
+    assert_eq!(superblock.journal_start, 16);
+    assert_eq!(superblock.journal_blocks, 40);
+    assert_eq!(superblock.data_start, 56);
+
+    let mut buf = vec![0u8; 1024];
+    superblock.encode(&mut buf);
+    assert_eq!(Superblock::decode(&buf), Ok(superblock));
+
+    buf[0] = b'X';
+    assert_eq!(Superblock::decode(&buf), Err(Error::Corrupted));
+  }
+
+  #[test]
+  fn test_superblock_too_small() {
+    let geometry = Geometry::default();
+    assert_eq!(Superblock::new(geometry, 4, 1000), Err(Error::InvalidArgument));
+    assert_eq!(Superblock::new(geometry, 100, 0), Err(Error::InvalidArgument));
+  }
+
+  #[test]
+  fn test_inode_roundtrip() {
+    let inode = DiskInode {
+      kind: KIND_FILE,
+      nlink: 2,
+      size: 123456789,
+      pages: 31,
+      create_time: Timespec::new(1, 2),
+      access_time: Timespec::new(3, 4),
+      mod_time: Timespec::new(5, 999_999_999),
+      single: 77,
+      double: 78,
+      xattrs: 79,
+      uid: 1000,
+      gid: 100,
+    };
+
+    let mut buf = [0u8; 128];
+    inode.encode(&mut buf);
+    assert_eq!(DiskInode::decode(&buf), inode);
+  }
+
+  #[test]
+  fn test_bitmap_and_lists() {
+    let mut bitmap = Bitmap::new(20);
+    bitmap.set(0);
+    bitmap.set(19);
+    assert!(bitmap.get(19) && !bitmap.get(7));
+    assert_eq!(bitmap.count_set(), 2);
+
+    let mut buf = [0xffu8; 16];
+    bitmap.encode(&mut buf);
+    assert_eq!(Bitmap::decode(&buf, 20), bitmap);
+
+    let list = [0, 5, 0, 9];
+    encode_list(&list, &mut buf);
+    assert_eq!(decode_list(&buf, 4), list.to_vec());
+  }
+
+  #[test]
+  fn test_entries_roundtrip() {
+    let data = encode_entries(&[(3, "file"), (4, "ünïcode"), (5, "")]);
+    let entries = decode_entries(&data).unwrap();
+    assert_eq!(entries, vec![(3, "file".to_string()), (4, "ünïcode".to_string()),
+                             (5, "".to_string())]);
+
+    assert_eq!(decode_entries(&data[..5]), Err(Error::Corrupted));
+  }
+}
@@ -0,0 +1,121 @@
+use std::fmt;
+use std::io;
+use std::result;
+
+/// Errors returned by RustFS operations. Each variant mirrors the POSIX errno
+/// noted next to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+  /// EINVAL: an argument was out of range or otherwise malformed.
+  InvalidArgument,
+  /// ENOENT: no file exists at the given path.
+  NotFound,
+  /// ENOTDIR: the operation requires a directory but found a file.
+  NotDirectory,
+  /// EISDIR: the operation requires a data file but found a directory.
+  IsDirectory,
+  /// EBADF: the file descriptor is not open.
+  BadDescriptor,
+  /// EFBIG: the operation would grow a file past its maximum size.
+  FileTooLarge,
+  /// EOPNOTSUPP: the combination of flags is not supported.
+  NotSupported,
+  /// ENXIO: there is no data or hole at or after the requested offset.
+  NoSuchAddress,
+  /// EOVERFLOW: the result cannot be represented.
+  Overflow,
+  /// ENOSPC: the file system image has no room left.
+  NoSpace,
+  /// EEXIST: something by that name already exists.
+  Exists,
+  /// EROFS: the file system is read-only.
+  ReadOnly,
+  /// ENODATA: the file has no extended attribute by that name.
+  NoAttribute,
+  /// ERANGE: a name is too long, or a buffer too small for the result.
+  OutOfRange,
+  /// E2BIG: an extended attribute value is too large.
+  TooBig,
+  /// EAGAIN: a conflicting lock is held, and waiting for it would never end.
+  WouldBlock,
+  /// EIO: reading or writing the host file backing the file system failed.
+  Io(io::ErrorKind),
+  /// EUCLEAN: the file system image is malformed.
+  Corrupted,
+  /// EDQUOT: the operation would put a user or group past a quota.
+  QuotaExceeded,
+  /// EACCES: the access asked for wasn't granted, as writing a read-only
+  /// mapping.
+  PermissionDenied,
+  /// EMLINK: the file has as many names as its link count can hold.
+  TooManyLinks,
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+  /// The errno value corresponding to this error.
+  pub fn errno(&self) -> i32 {
+    match *self {
+      Error::NotFound => 2,
+      Error::Io(_) => 5,
+      Error::NoSuchAddress => 6,
+      Error::TooBig => 7,
+      Error::BadDescriptor => 9,
+      Error::WouldBlock => 11,
+      Error::PermissionDenied => 13,
+      Error::Exists => 17,
+      Error::NotDirectory => 20,
+      Error::IsDirectory => 21,
+      Error::InvalidArgument => 22,
+      Error::FileTooLarge => 27,
+      Error::NoSpace => 28,
+      Error::ReadOnly => 30,
+      Error::TooManyLinks => 31,
+      Error::OutOfRange => 34,
+      Error::NoAttribute => 61,
+      Error::Overflow => 75,
+      Error::NotSupported => 95,
+      Error::Corrupted => 117,
+      Error::QuotaExceeded => 122,
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let msg = match *self {
+      Error::InvalidArgument => "invalid argument",
+      Error::NotFound => "no such file or directory",
+      Error::NotDirectory => "not a directory",
+      Error::IsDirectory => "is a directory",
+      Error::BadDescriptor => "bad file descriptor",
+      Error::FileTooLarge => "file too large",
+      Error::NotSupported => "operation not supported",
+      Error::NoSuchAddress => "no such device or address",
+      Error::Overflow => "value too large for defined data type",
+      Error::NoSpace => "no space left on device",
+      Error::Exists => "file exists",
+      Error::ReadOnly => "read-only file system",
+      Error::NoAttribute => "no data available",
+      Error::OutOfRange => "numerical result out of range",
+      Error::TooBig => "argument list too long",
+      Error::WouldBlock => "resource temporarily unavailable",
+      Error::Io(_) => "input/output error",
+      Error::Corrupted => "structure needs cleaning",
+      Error::QuotaExceeded => "disk quota exceeded",
+      Error::PermissionDenied => "permission denied",
+      Error::TooManyLinks => "too many links",
+    };
+
+    write!(f, "{}", msg)
+  }
+}
+
+impl ::std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+  fn from(err: io::Error) -> Error {
+    Error::Io(err.kind())
+  }
+}
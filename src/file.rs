@@ -1,9 +1,13 @@
 extern crate time;
 
+use std::borrow::Cow;
+use std::cmp;
 use std::collections::HashMap;
 use std::rc::Rc;
 use std::cell::{Cell, RefCell};
-use inode::{Inode};
+use inode::{Inode, PageSlice};
+use error::{Error, Result};
+use xattr::Xattrs;
 use self::File::{DataFile, Directory};
 
 pub type RcDirContent<'r> = Rc<RefCell<Box<DirectoryContent<'r>>>>;
@@ -13,6 +17,7 @@ pub type RcInode = Rc<RefCell<Box<Inode>>>;
 // provide a layer of indirection. FileHandle's and Directory entries, then,
 // point to these guys instead of directly to Inodes/Directories
 #[derive(Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum File<'r> {
   DataFile(RcInode),
   Directory(RcDirContent<'r>),
@@ -22,23 +27,34 @@ pub enum File<'r> {
 #[derive(Clone)]
 pub struct FileHandle<'r> {
   file: File<'r>,
-  seek: Cell<usize>
+  seek: Cell<usize>,
+  // Where the last read ended, and how many pages were read ahead of it.
+  read_end: Cell<usize>,
+  read_ahead: Cell<usize>
 }
 
+// Names passed in by callers are borrowed; names read back from an image are
+// owned by the directory.
 #[derive(Clone)]
 pub struct DirectoryContent<'r> {
-  pub entries: HashMap<&'r str, File<'r>>
+  pub entries: HashMap<Cow<'r, str>, File<'r>>,
+  pub xattrs: Xattrs
 }
 
 pub enum Whence {
   SeekSet,
   SeekCur,
-  SeekEnd
+  SeekEnd,
+  /// Seek to the first allocated page at or after the offset.
+  SeekData,
+  /// Seek to the first hole (or the end of file) at or after the offset.
+  SeekHole
 }
 
 impl<'r> File<'r> {
   pub fn new_dir(_parent: Option<File<'r>>) -> File<'r> {
-    let content = Box::new(DirectoryContent { entries: HashMap::new() });
+    let content = Box::new(DirectoryContent { entries: HashMap::new(),
+                                              xattrs: Xattrs::default() });
     let rc = Rc::new(RefCell::new(content));
     let dir = Directory(rc);
 
@@ -61,57 +77,140 @@ impl<'r> File<'r> {
     DataFile(inode)
   }
 
-  pub fn get_dir_rc<'a>(&'a self) -> &'a RcDirContent<'r> {
-    match self {
-      &Directory(ref rc) => rc,
+  pub fn get_dir_rc(&self) -> &RcDirContent<'r> {
+    match *self {
+      Directory(ref rc) => rc,
       _ => panic!("not a directory")
     }
   }
 
-  pub fn get_inode_rc<'a>(&'a self) -> &'a RcInode {
-    match self {
-      &DataFile(ref rc) => rc,
+  pub fn get_inode_rc(&self) -> &RcInode {
+    match *self {
+      DataFile(ref rc) => rc,
       _ => panic!("not a directory")
     }
   }
+
+  /// Runs `f` on the extended attributes of the file or directory.
+  pub fn with_xattrs<T, F: FnOnce(&mut Xattrs) -> T>(&self, f: F) -> T {
+    match *self {
+      DataFile(ref rc) => f(rc.borrow_mut().xattrs_mut()),
+      Directory(ref rc) => f(&mut rc.borrow_mut().xattrs),
+      File::EmptyFile => panic!("empty files have no attributes")
+    }
+  }
 }
 
 impl<'r> FileHandle<'r> {
   // Probably not the right type.
   pub fn new(file: File<'r>) -> FileHandle<'r> {
     FileHandle {
-      file: file,
-      seek: Cell::new(0)
+      file,
+      seek: Cell::new(0),
+      read_end: Cell::new(0),
+      read_ahead: Cell::new(0)
     }
   }
 
-  pub fn read(&self, dst: &mut [u8]) -> usize {
+  pub fn inode_rc(&self) -> &RcInode {
+    self.file.get_inode_rc()
+  }
+
+  pub fn file(&self) -> &File<'r> {
+    &self.file
+  }
+
+  /**
+   * Reads from the handle's offset and moves it past what was read. A read
+   * that starts where the last one ended continues a sequential run, and has
+   * pages read ahead of it for the next: at least as many as it read, and
+   * twice as many as the last read of the run had, up to the page cache's
+   * limit. See the `cache` module.
+   */
+  pub fn read(&self, dst: &mut [u8]) -> Result<usize> {
     let offset = self.seek.get();
-    let inode_rc = self.file.get_inode_rc();
-    let changed = inode_rc.borrow().read(offset, dst);
+    let mut inode = self.file.get_inode_rc().borrow_mut();
+    let changed = inode.read(offset, dst)?;
+    self.advance(&mut inode, offset, changed);
+    Ok(changed)
+  }
+
+  /// Like `read`, but returns slices of the pages read rather than copying
+  /// them out; see `Inode::read_slices`.
+  pub fn read_slices(&self, len: usize) -> Result<Vec<PageSlice>> {
+    let offset = self.seek.get();
+    let mut inode = self.file.get_inode_rc().borrow_mut();
+    let slices = inode.read_slices(offset, len)?;
+    let changed = slices.iter().map(|slice| slice.len()).sum();
+    self.advance(&mut inode, offset, changed);
+    Ok(slices)
+  }
+
+  // Moves the offset past the `changed` bytes read from `offset`, and reads
+  // ahead of a sequential run.
+  fn advance(&self, inode: &mut Inode, offset: usize, changed: usize) {
     self.seek.set(offset + changed);
-    changed
+
+    let end = offset + changed;
+    let ahead = match offset == self.read_end.get() && changed > 0 {
+      true => {
+        let page_size = inode.geometry().page_size();
+        let read = (end - 1) / page_size - offset / page_size + 1;
+        cmp::max(self.read_ahead.get() * 2, read)
+      }
+      false => 0
+    };
+
+    self.read_ahead.set(inode.read_ahead(end, ahead));
+    self.read_end.set(end);
   }
 
-  pub fn write(&mut self, src: &[u8]) -> usize {
+  pub fn write(&mut self, src: &[u8]) -> Result<usize> {
     let offset = self.seek.get();
     let inode_rc = self.file.get_inode_rc();
-    let changed = inode_rc.borrow_mut().write(offset, src);
+    let changed = inode_rc.borrow_mut().write(offset, src)?;
     self.seek.set(offset + changed);
-    changed
+    Ok(changed)
   }
 
-  pub fn seek(&mut self, offset: isize, whence: Whence) -> usize {
-    let inode_rc = self.file.get_inode_rc();
+  /**
+   * Moves the handle's offset. The resulting offset may not be negative or
+   * lie past the inode's maximum file size; such seeks fail with
+   * `InvalidArgument` and leave the offset unchanged, as do seeks whose result
+   * isn't representable (`Overflow`). `SeekData` and `SeekHole` fail with
+   * `NoSuchAddress` when there's no data or hole at or after the offset.
+   */
+  pub fn seek(&mut self, offset: isize, whence: Whence) -> Result<usize> {
+    let inode = self.file.get_inode_rc().borrow();
+
+    let base = match whence {
+      Whence::SeekSet => 0,
+      Whence::SeekCur => self.seek.get(),
+      Whence::SeekEnd => inode.size(),
+      Whence::SeekData | Whence::SeekHole => {
+        if offset < 0 { return Err(Error::InvalidArgument); }
+        let found = match whence {
+          Whence::SeekData => inode.next_data(offset as usize),
+          _ => inode.next_hole(offset as usize)
+        };
+
+        let new_seek = found.ok_or(Error::NoSuchAddress)?;
+        self.seek.set(new_seek);
+        return Ok(new_seek);
+      }
+    };
 
-    let seek = self.seek.get();
-    let new_seek = match whence {
-      Whence::SeekSet => offset as usize,
-      Whence::SeekCur => (seek as isize + offset) as usize,
-      Whence::SeekEnd => (inode_rc.borrow().size() as isize + offset) as usize
+    let new_seek = match base.checked_add_signed(offset) {
+      Some(new_seek) => new_seek,
+      None if offset < 0 => return Err(Error::InvalidArgument),
+      None => return Err(Error::Overflow)
     };
 
+    if new_seek > inode.geometry().max_file_size() {
+      return Err(Error::InvalidArgument);
+    }
+
     self.seek.set(new_seek);
-    new_seek
+    Ok(new_seek)
   }
 }
@@ -0,0 +1,1908 @@
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File as HostFile;
+use std::io::Read;
+use std::path::Path;
+use std::rc::Rc;
+use time::{self, Duration, Timespec};
+use device::{SharedDevice, FileDevice, read_blocks, write_blocks};
+use disk::{Superblock, DiskInode, Bitmap, INODE_SIZE, ROOT_INO};
+use disk::{KIND_FILE, KIND_DIRECTORY, KIND_FREE};
+use disk::{encode_list, decode_list, encode_entries, decode_entries};
+use disk::{encode_xattrs, decode_xattrs};
+use error::{Error, Result};
+use cache::CacheStats;
+use file::{File, RcInode};
+use file::File::{DataFile, Directory, EmptyFile};
+use inode::{Inode, Geometry, Page, ListBlocks, Stat};
+use journal::{Journal, JournalMode, Logged};
+use pool::PoolStats;
+use quota::{Owner, Quota, QuotaKind, QuotaUsage};
+use space::{Limits, Space, SharedSpace};
+use xattr::Xattrs;
+
+pub const DEFAULT_BLOCKS: usize = 32768;
+pub const DEFAULT_INODES: usize = 4096;
+/// The most names a file may have, as images keep link counts in 16 bits.
+pub const LINK_MAX: usize = u16::MAX as usize;
+
+/**
+ * File system-wide information as reported by `Proc::statfs`. Blocks are
+ * `bsize` bytes. As with tmpfs, the block counts are all 0 when there's no
+ * limit on file data, and the file counts all 0 when there's no limit on
+ * files; see `FileSystem::set_limits`. The `avail` counts are what's left for
+ * unprivileged users, which here is everything that's free.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StatFs {
+  pub bsize: usize,
+  pub list_size: usize,
+  pub max_file_size: usize,
+  pub blocks: usize,
+  pub bfree: usize,
+  pub bavail: usize,
+  pub files: usize,
+  pub ffree: usize,
+  pub favail: usize,
+}
+
+/// A file that's still open but no longer has a name in the tree, as
+/// reported by `FileSystem::orphans`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Orphan {
+  /// The number of descriptors it's open through, across all `Proc`s.
+  pub opens: usize,
+  /// Whether it was created with `O_TMPFILE` and never linked, so that
+  /// `Proc::linkat` can still give it a name.
+  pub tmpfile: bool,
+  pub stat: Stat,
+}
+
+// A data file open through `opens` descriptors.
+struct OpenFile<'r> {
+  file: File<'r>,
+  opens: usize,
+  tmpfile: bool,
+}
+
+// A device holding a file system image, along with the superblock, bitmap
+// and raw metadata blocks it held as of the last flush, and the blocks pages
+// were written back to since.
+struct Image {
+  device: SharedDevice,
+  journal: Journal,
+  superblock: Superblock,
+  bitmap: Bitmap,
+  metadata: Option<Box<[u8]>>,
+  reserved: Bitmap,
+}
+
+impl Image {
+  // Reads the `buf.len() / page_size` blocks starting at `block`.
+  fn read_block(&mut self, block: u64, buf: &mut [u8]) -> Result<()> {
+    read_blocks(&mut *self.device.borrow_mut(), block, buf)
+  }
+
+  fn write_block(&mut self, block: u64, buf: &[u8]) -> Result<()> {
+    write_blocks(&mut *self.device.borrow_mut(), block, buf)
+  }
+
+  /**
+   * Writes `page` back ahead of the next flush, as the page cache does to
+   * evict it. A page with no block yet gets one that the image's current
+   * tree doesn't use, so a crash leaves the tree intact. Overwrites of blocks
+   * the tree does use are treated as a flush would treat them under `mode`.
+   */
+  fn write_back(&mut self, mode: JournalMode, page: &mut Page) -> Result<()> {
+    if page.block() == 0 {
+      let start = self.superblock.data_start;
+      let free = (start..self.bitmap.len())
+        .find(|&block| !self.bitmap.get(block) && !self.reserved.get(block))
+        .ok_or(Error::NoSpace)?;
+
+      self.reserved.set(free);
+      page.set_block(free as u32);
+    }
+
+    let block = u64::from(page.block());
+    match mode {
+      JournalMode::Data if self.bitmap.get(block) => {
+        let writes = [(block, page.to_vec().into_boxed_slice())];
+        self.journal.commit(&mut *self.device.borrow_mut(), &writes)
+      }
+      _ => self.write_block(block, page)
+    }
+  }
+
+  // Reads the page list rooted at `single` and `double` into a map of page
+  // numbers to blocks, along with the list blocks it's stored in.
+  fn read_map(&mut self, single: u32, double: u32)
+      -> Result<(Vec<(usize, u32)>, ListBlocks)> {
+    let superblock = self.superblock;
+    let list_size = superblock.geometry.list_size();
+    let mut buf = vec![0u8; superblock.geometry.page_size()];
+    let mut read_list = |image: &mut Image, block: u32| -> Result<Vec<u32>> {
+      if !superblock.is_data_block(block) { return Err(Error::Corrupted); }
+      image.read_block(u64::from(block), &mut buf)?;
+      let list = decode_list(&buf, list_size);
+      if list.iter().any(|&b| b != 0 && !superblock.is_data_block(b)) {
+        return Err(Error::Corrupted);
+      }
+
+      Ok(list)
+    };
+
+    let mut map = Vec::new();
+    let mut lists = ListBlocks { single, double, blocks: Vec::new() };
+    if single != 0 {
+      let list = read_list(self, single)?;
+      lists.blocks.push(single);
+      map.extend(list.into_iter().enumerate().filter(|&(_, b)| b != 0));
+    }
+
+    if double != 0 {
+      let list_blocks = read_list(self, double)?;
+      lists.blocks.push(double);
+      for (slot, &list_block) in list_blocks.iter().enumerate() {
+        if list_block == 0 { continue; }
+        let base = list_size + slot * list_size;
+        let list = read_list(self, list_block)?;
+        lists.blocks.push(list_block);
+        map.extend(list.into_iter().enumerate().filter(|&(_, b)| b != 0)
+                   .map(|(i, b)| (base + i, b)));
+      }
+    }
+
+    Ok((map, lists))
+  }
+}
+
+// Hands out data blocks during a flush. Blocks in use as of the last flush
+// are avoided where possible so that the previous state stays intact until
+// the new one is complete.
+struct Allocator {
+  used: Bitmap,
+  previous: Bitmap,
+  next: u64,
+}
+
+impl Allocator {
+  fn alloc(&mut self) -> Result<u32> {
+    let len = self.used.len();
+    let free = (self.next..len).chain(0..self.next)
+      .find(|&b| !self.used.get(b) && !self.previous.get(b))
+      .or_else(|| (0..len).find(|&b| !self.used.get(b)));
+
+    match free {
+      Some(block) => {
+        self.used.set(block);
+        self.next = block + 1;
+        Ok(block as u32)
+      }
+      None => Err(Error::NoSpace)
+    }
+  }
+}
+
+// The writes of one flush. Writes to blocks that the image's current tree
+// doesn't use go straight to the device; the rest are journaled.
+struct Transaction {
+  mode: JournalMode,
+  previous: Bitmap,
+  capacity: usize,
+  data: Vec<Logged>,
+  metadata: Vec<Logged>,
+}
+
+impl Transaction {
+  fn new(image: &Image, mode: JournalMode) -> Transaction {
+    let block_size = image.superblock.geometry.page_size();
+    Transaction {
+      mode,
+      previous: image.bitmap.clone(),
+      capacity: image.journal.capacity(block_size),
+      data: Vec::new(),
+      metadata: Vec::new(),
+    }
+  }
+
+  // Writes a file's page. `in_place` is set when the page already had its
+  // block as of the last flush.
+  fn write_data(&mut self, image: &mut Image, block: u32, buf: &[u8], in_place: bool)
+      -> Result<()> {
+    if !in_place || !self.previous.get(u64::from(block)) {
+      return self.write(image, block, buf);
+    }
+
+    match self.mode {
+      JournalMode::Ordered => image.write_block(u64::from(block), buf),
+      JournalMode::Data => {
+        self.data.push((u64::from(block), buf.to_vec().into_boxed_slice()));
+
+        // Journaled data needn't commit atomically with the metadata, so it
+        // goes in batches when it doesn't all fit.
+        if self.data.len() + self.metadata.len() >= self.capacity {
+          image.journal.commit(&mut *image.device.borrow_mut(), &self.data)?;
+          self.data.clear();
+        }
+
+        Ok(())
+      }
+    }
+  }
+
+  // Writes a block of the data region other than an existing file page.
+  fn write(&mut self, image: &mut Image, block: u32, buf: &[u8]) -> Result<()> {
+    if self.previous.get(u64::from(block)) {
+      self.metadata.push((u64::from(block), buf.to_vec().into_boxed_slice()));
+      Ok(())
+    } else {
+      image.write_block(u64::from(block), buf)
+    }
+  }
+
+  // Journals the blocks of `metadata` that changed since the last flush, then
+  // commits everything.
+  fn commit(mut self, image: &mut Image, metadata: &[u8]) -> Result<()> {
+    let block_size = image.superblock.geometry.page_size();
+    for (block, chunk) in metadata.chunks(block_size).enumerate() {
+      let changed = match image.metadata {
+        Some(ref previous) => *chunk != previous[(block * block_size)..][..block_size],
+        None => true
+      };
+
+      if changed { self.metadata.push((block as u64, chunk.into())); }
+    }
+
+    let mut device = image.device.borrow_mut();
+    if self.metadata.len() > self.capacity { return Err(Error::NoSpace); }
+    if self.data.len() + self.metadata.len() > self.capacity {
+      image.journal.commit(&mut *device, &self.data)?;
+      self.data.clear();
+    }
+
+    self.data.append(&mut self.metadata);
+    image.journal.commit(&mut *device, &self.data)?;
+    device.flush()
+  }
+}
+
+/**
+ * A file system: its geometry, its directory tree, and, when mounted from an
+ * image, the block device backing it.
+ *
+ * File systems created with `new` or `with_geometry` live entirely in memory.
+ * `format` writes an empty file system image to a host file, `mount` loads an
+ * image back into memory, and `unmount` writes the file system's current
+ * state back to the image it was mounted from. `format_device` and
+ * `mount_device` do the same over any `BlockDevice`. File data is read from
+ * the device as it's first used. See the `disk` module for the image format.
+ *
+ * Writes that would change what the image's current tree looks like go
+ * through a journal, so a crash at any point leaves the image either as it
+ * was or as the interrupted flush would have left it. See `JournalMode` for
+ * how file data is treated.
+ *
+ * `snapshot` captures the tree in memory, cheaply: files share their pages
+ * with the snapshot until they're changed. `open_snapshot` gives a read-only
+ * view of a snapshot, and `rollback` resets the tree to one.
+ *
+ * The file system also keeps track of which files are open, through a
+ * descriptor or a shared mapping. A file that's unlinked while open lives on,
+ * in memory only, as an orphan until it's no longer open; `orphans` lists
+ * them.
+ */
+pub struct FileSystem<'r> {
+  geometry: Geometry,
+  root: File<'r>,
+  image: RefCell<Option<Image>>,
+  journal_mode: Cell<JournalMode>,
+  read_only: bool,
+  snapshots: RefCell<HashMap<String, File<'r>>>,
+  open_files: RefCell<Vec<OpenFile<'r>>>,
+  space: SharedSpace,
+}
+
+impl<'r> FileSystem<'r> {
+  pub fn new() -> FileSystem<'r> {
+    FileSystem::with_geometry(Geometry::default())
+  }
+
+  pub fn with_geometry(geometry: Geometry) -> FileSystem<'r> {
+    FileSystem::from_root(geometry, File::new_dir(None), None)
+  }
+
+  fn from_root(geometry: Geometry, root: File<'r>, image: Option<Image>) -> FileSystem<'r> {
+    let fs = FileSystem {
+      geometry,
+      root,
+      image: RefCell::new(image),
+      journal_mode: Cell::new(JournalMode::default()),
+      read_only: false,
+      snapshots: RefCell::new(HashMap::new()),
+      open_files: RefCell::new(Vec::new()),
+      space: Rc::new(Space::new(geometry)),
+    };
+
+    fs.charge_tree();
+    fs
+  }
+
+  /// Writes an empty file system image with the default geometry, block count
+  /// and inode count to `path`, replacing any file there.
+  pub fn format<P: AsRef<Path>>(path: P) -> Result<()> {
+    FileSystem::format_with(path, Geometry::default(), DEFAULT_BLOCKS,
+                            DEFAULT_INODES)
+  }
+
+  /// Writes an empty file system image of `blocks` pages of `geometry`'s page
+  /// size, with room for `inodes` files and directories, to `path`.
+  pub fn format_with<P: AsRef<Path>>(path: P, geometry: Geometry, blocks: usize,
+                                     inodes: usize) -> Result<()> {
+    // Checked here so that a bad geometry doesn't leave a file behind.
+    if inodes > u32::MAX as usize { return Err(Error::InvalidArgument); }
+    Superblock::new(geometry, blocks as u64, inodes as u32)?;
+
+    let device = FileDevice::create(path, geometry.page_size(), blocks as u64)?;
+    FileSystem::format_device(Rc::new(RefCell::new(device)), geometry, inodes)
+  }
+
+  /// Writes an empty file system spanning all of `device`, with room for
+  /// `inodes` files and directories. The device's block size must be
+  /// `geometry`'s page size.
+  pub fn format_device(device: SharedDevice, geometry: Geometry, inodes: usize)
+      -> Result<()> {
+    let (block_size, blocks) = {
+      let device = device.borrow();
+      (device.block_size(), device.block_count())
+    };
+
+    if inodes > u32::MAX as usize || block_size != geometry.page_size() {
+      return Err(Error::InvalidArgument);
+    }
+
+    let superblock = Superblock::new(geometry, blocks, inodes as u32)?;
+    let journal = Journal::format(&mut *device.borrow_mut(), superblock.journal_start,
+                                  superblock.journal_blocks)?;
+
+    let bitmap = Bitmap::new(superblock.block_count);
+    let reserved = Bitmap::new(superblock.block_count);
+    let mut image = Image { device, journal, superblock, bitmap, metadata: None, reserved };
+    FileSystem::with_geometry(geometry).flush(&mut image)
+  }
+
+  /// Loads the file system image at `path` into memory. Changes are written
+  /// back to the image on `unmount`.
+  pub fn mount<P: AsRef<Path>>(path: P) -> Result<FileSystem<'r>> {
+    FileSystem::mount_device(open_image(path)?)
+  }
+
+  /// Loads the file system on `device`, first finishing any flush that was
+  /// interrupted. Directories are read in right away, file data as it's first
+  /// used. Changes are written back on `unmount`.
+  pub fn mount_device(device: SharedDevice) -> Result<FileSystem<'r>> {
+    let (superblock, journal, metadata) = read_image(&device)?;
+    let geometry = superblock.geometry;
+    let block_size = geometry.page_size();
+    let block = |start: u64| &metadata[(start as usize * block_size)..];
+    let bitmap = Bitmap::decode(block(superblock.bitmap_start), superblock.block_count);
+    let inodes: Vec<DiskInode> = (0..superblock.inode_count as usize)
+      .map(|i| DiskInode::decode(&block(superblock.inode_table_start)[(i * INODE_SIZE)..]))
+      .collect();
+
+    let reserved = Bitmap::new(superblock.block_count);
+    let mut image = Image { device, journal, superblock, bitmap, metadata: Some(metadata),
+                            reserved };
+    let root = Loader { image: &mut image, inodes: &inodes, files: HashMap::new(),
+                        dirs: HashSet::new() }.load_dir(ROOT_INO)?;
+
+    Ok(FileSystem::from_root(geometry, root, Some(image)))
+  }
+
+  /// Writes the file system back to the image it was mounted from and detaches
+  /// it. The file system stays usable in memory, but later changes are not
+  /// persisted. File data not yet read is still read from the device, which
+  /// must not be changed while the file system is in use.
+  pub fn unmount(&self) -> Result<()> {
+    let mut image = self.image.borrow_mut();
+    match image.as_mut() {
+      Some(image) => self.flush(image)?,
+      None => return Err(Error::InvalidArgument)
+    }
+
+    *image = None;
+    Ok(())
+  }
+
+  /**
+   * Writes every change made since the last flush to the image, in the same
+   * order as `unmount`, and waits for it to be durable. Only dirty pages, and
+   * the page lists of files whose pages came or went, are written. Does
+   * nothing for a file system that isn't mounted.
+   */
+  pub fn sync(&self) -> Result<()> {
+    match self.image.borrow_mut().as_mut() {
+      Some(image) => self.flush(image),
+      None => Ok(())
+    }
+  }
+
+  /**
+   * Replaces the tree on `device`, an image laid out as `superblock` whose
+   * journal holds no committed transaction, with `root`. Every metadata block
+   * is written afresh. Blocks set in `keep` are only overwritten through the
+   * journal, so they survive a crash midway.
+   */
+  pub(crate) fn rewrite(device: SharedDevice, superblock: Superblock, root: File<'r>,
+                        keep: Bitmap) -> Result<()> {
+    let journal = Journal::recover(&mut *device.borrow_mut(), superblock.journal_start,
+                                   superblock.journal_blocks, superblock.block_count)?;
+    let reserved = Bitmap::new(superblock.block_count);
+    let mut image = Image { device, journal, superblock, bitmap: keep, metadata: None,
+                            reserved };
+    FileSystem::from_root(superblock.geometry, root, None).flush(&mut image)
+  }
+
+  pub fn journal_mode(&self) -> JournalMode {
+    self.journal_mode.get()
+  }
+
+  /// Sets how later flushes journal file data. Has no effect on a file system
+  /// that isn't mounted.
+  pub fn set_journal_mode(&self, mode: JournalMode) {
+    self.journal_mode.set(mode);
+  }
+
+  pub fn geometry(&self) -> Geometry {
+    self.geometry
+  }
+
+  pub(crate) fn root(&self) -> File<'r> {
+    self.root.clone()
+  }
+
+  pub fn statfs(&self) -> StatFs {
+    let limits = self.space.limits();
+    let blocks = limits.max_bytes.map_or(0, |_| self.space.max_pages());
+    let files = limits.max_inodes.unwrap_or(0);
+    let bfree = blocks.saturating_sub(self.space.pages());
+    let ffree = files.saturating_sub(self.space.inodes());
+    StatFs {
+      bsize: self.geometry.page_size(),
+      list_size: self.geometry.list_size(),
+      max_file_size: self.geometry.max_file_size(),
+      blocks,
+      bfree,
+      bavail: bfree,
+      files,
+      ffree,
+      favail: ffree,
+    }
+  }
+
+  /**
+   * Captures the tree as it is now as snapshot `name`. The snapshot's files
+   * share their pages with the live tree; a page is copied only once either
+   * side changes it. File data not yet read from the image is read in first.
+   *
+   * Snapshots live in memory only and aren't written to the image. Fails with
+   * `Exists` if there's already a snapshot named `name`.
+   */
+  pub fn snapshot(&self, name: &str) -> Result<()> {
+    if self.snapshots.borrow().contains_key(name) { return Err(Error::Exists); }
+    let tree = copy_tree(&self.root)?;
+    self.snapshots.borrow_mut().insert(name.to_string(), tree);
+    Ok(())
+  }
+
+  /// The names of the snapshots taken, sorted.
+  pub fn snapshots(&self) -> Vec<String> {
+    let mut names: Vec<String> = self.snapshots.borrow().keys().cloned().collect();
+    names.sort();
+    names
+  }
+
+  /// A read-only file system showing snapshot `name`, which stays readable
+  /// even if the snapshot is later deleted.
+  pub fn open_snapshot(&self, name: &str) -> Result<FileSystem<'r>> {
+    let root = self.snapshots.borrow().get(name).cloned().ok_or(Error::NotFound)?;
+    Ok(FileSystem { read_only: true, ..FileSystem::from_root(self.geometry, root, None) })
+  }
+
+  /**
+   * Resets the tree to snapshot `name`, which is kept for later rollbacks.
+   * Files open at the time are left detached from the tree, as if unlinked.
+   * On a mounted file system, the restored files are written out anew on the
+   * next flush.
+   */
+  pub fn rollback(&self, name: &str) -> Result<()> {
+    if self.read_only { return Err(Error::ReadOnly); }
+    let tree = match self.snapshots.borrow().get(name) {
+      Some(tree) => copy_tree(tree)?,
+      None => return Err(Error::NotFound)
+    };
+
+    let content = tree.get_dir_rc().borrow().clone();
+    *self.root.get_dir_rc().borrow_mut() = content;
+    self.charge_tree();
+    Ok(())
+  }
+
+  pub fn delete_snapshot(&self, name: &str) -> Result<()> {
+    match self.snapshots.borrow_mut().remove(name) {
+      Some(_) => Ok(()),
+      None => Err(Error::NotFound)
+    }
+  }
+
+  /// Whether the file system is a snapshot view, whose files can't be
+  /// changed.
+  pub fn is_read_only(&self) -> bool {
+    self.read_only
+  }
+
+  /// Records that `file` was opened through another descriptor or shared
+  /// mapping. `tmpfile` is set for files created with `O_TMPFILE`.
+  pub(crate) fn opened(&self, file: &File<'r>, tmpfile: bool) {
+    let mut open_files = self.open_files.borrow_mut();
+    let ptr = file_ptr(file);
+    match open_files.iter_mut().find(|open| file_ptr(&open.file) == ptr) {
+      Some(open) => open.opens += 1,
+      None => open_files.push(OpenFile { file: file.clone(), opens: 1, tmpfile })
+    }
+  }
+
+  /// Records that a descriptor or mapping for `file` was closed. Once the last
+  /// one is, the file system lets go of the file, so that an orphan is freed
+  /// as soon as the caller drops its handle.
+  pub(crate) fn closed(&self, file: &File<'r>) {
+    let mut open_files = self.open_files.borrow_mut();
+    let ptr = file_ptr(file);
+    if let Some(i) = open_files.iter().position(|open| file_ptr(&open.file) == ptr) {
+      open_files[i].opens -= 1;
+      if open_files[i].opens == 0 { open_files.remove(i); }
+    }
+  }
+
+  /// Checks that the open `file` may be given a new name: it must either be
+  /// linked into the tree already or be an `O_TMPFILE` file never linked.
+  /// Fails with `NotFound` for other orphans, and with `TooManyLinks` for a
+  /// file with `LINK_MAX` names. The file loses its `O_TMPFILE` status, as the
+  /// caller is about to link it.
+  pub(crate) fn link(&self, file: &File<'r>) -> Result<()> {
+    let ptr = file_ptr(file);
+    let mut open_files = self.open_files.borrow_mut();
+    let open = open_files.iter_mut().find(|open| file_ptr(&open.file) == ptr);
+    if let Some(open) = open {
+      if open.tmpfile {
+        open.tmpfile = false;
+        return Ok(());
+      }
+    }
+
+    match self.links(ptr) {
+      0 => Err(Error::NotFound),
+      links if links >= LINK_MAX => Err(Error::TooManyLinks),
+      _ => Ok(())
+    }
+  }
+
+  // The number of names the file at `ptr` has in the tree.
+  fn links(&self, ptr: *const ()) -> usize {
+    walk(&self.root).iter().filter(|file| matches!(**file, Directory(_)))
+      .map(|dir| {
+        let content = dir.get_dir_rc().borrow();
+        content.entries.values().filter(|file| file_ptr(file) == ptr).count()
+      })
+      .sum()
+  }
+
+  /// The open files that have no name in the tree, in the order they were
+  /// first opened.
+  pub fn orphans(&self) -> Vec<Orphan> {
+    let linked = self.linked();
+    self.open_files.borrow().iter()
+      .filter(|open| !linked.contains(&file_ptr(&open.file)))
+      .map(|open| Orphan {
+        opens: open.opens,
+        tmpfile: open.tmpfile,
+        stat: open.file.get_inode_rc().borrow().stat(),
+      })
+      .collect()
+  }
+
+  // Every file and directory reachable from the root.
+  fn linked(&self) -> HashSet<*const ()> {
+    walk(&self.root).iter().map(file_ptr).collect()
+  }
+
+  // Charges every file in the tree to the file system's space, whatever the
+  // limits, and caches its pages if the file system is mounted.
+  fn charge_tree(&self) {
+    for file in walk(&self.root) {
+      if let DataFile(ref rc) = file {
+        rc.borrow_mut().set_space(&self.space, true).expect("forced charges can't fail");
+        self.cache_file(rc);
+      }
+    }
+  }
+
+  // Has the pages of `inode` cached, and read from the device once evicted,
+  // if the file system is mounted.
+  fn cache_file(&self, inode: &RcInode) {
+    if let Some(ref image) = *self.image.borrow() {
+      inode.borrow_mut().set_device(image.device.clone());
+      self.space.cache().track(inode);
+    }
+  }
+
+  /// A new, empty file of `owner`'s, charged to the file system. Fails with
+  /// `NoSpace` if the file system already holds as many files as its limits
+  /// allow, or with `QuotaExceeded` if `owner` is at an inode quota.
+  pub(crate) fn new_file(&self, owner: Owner) -> Result<File<'r>> {
+    // FIXME: Fetch from allocator. Only the inode's pages and page lists come
+    // from the pool; the inode itself is still boxed.
+    let inode = Rc::new(RefCell::new(Box::new(Inode::in_space(&self.space, self.geometry,
+                                                              owner)?)));
+    self.cache_file(&inode);
+    Ok(File::new_data_file(inode))
+  }
+
+  /// How the file system's page cache has been used. See the `cache` module.
+  pub fn cache_stats(&self) -> CacheStats {
+    self.space.cache().stats()
+  }
+
+  /**
+   * Keeps at most `limit` pages of the mounted file system's files resident,
+   * evicting the least recently used past it, or with `None`, the default,
+   * keeps every page resident once used. Dirty pages are written back to the
+   * device before they're evicted.
+   */
+  pub fn set_cache_limit(&self, limit: Option<usize>) {
+    self.space.cache().set_limit(limit);
+    self.trim_cache();
+  }
+
+  /// Reads at most `pages` pages ahead of sequential reads;
+  /// `DEFAULT_READ_AHEAD` to start with. 0 turns read-ahead off.
+  pub fn set_read_ahead(&self, pages: usize) {
+    self.space.cache().set_max_read_ahead(pages);
+  }
+
+  /**
+   * Evicts the least recently used pages past the page cache's limit, as
+   * after each read or write. Pages of files in use stay, as do dirty pages
+   * that can't be written back, as when the file system is no longer mounted
+   * or the device is full; a failed write-back is retried by the next flush.
+   */
+  pub(crate) fn trim_cache(&self) {
+    let cache = self.space.cache();
+    let mode = self.journal_mode.get();
+    let mut image = self.image.borrow_mut();
+    let mut kept = 0;
+    loop {
+      let victims = cache.victims(kept);
+      if victims.is_empty() { break; }
+      for (rc, num) in victims {
+        let evicted = match rc.try_borrow_mut() {
+          Ok(mut inode) => inode.evict_page(num, |page| match image.as_mut() {
+            Some(image) => image.write_back(mode, page),
+            None => Err(Error::InvalidArgument)
+          }),
+          Err(_) => false
+        };
+
+        if !evicted { kept += 1; }
+      }
+    }
+  }
+
+  /// How the file system's page pool is used. See the `pool` module.
+  pub fn pool_stats(&self) -> PoolStats {
+    self.space.pool().stats()
+  }
+
+  /// Gives chunks of the page pool back to the system as they empty out
+  /// while more than `limit` page buffers, or page lists, are free;
+  /// `DEFAULT_POOL_LIMIT` to start with. A limit of 0 turns pooling off.
+  pub fn set_pool_limit(&self, limit: usize) {
+    self.space.pool().set_limit(limit);
+  }
+
+  pub fn limits(&self) -> Limits {
+    self.space.limits()
+  }
+
+  /**
+   * Limits the file data and the number of files the file system holds, like
+   * tmpfs's `size=` and `nr_inodes=` options. Writes and creates that would
+   * exceed a limit fail with `NoSpace`. Fails with `InvalidArgument` if the
+   * file system already holds more than `limits` allow.
+   */
+  pub fn set_limits(&self, limits: Limits) -> Result<()> {
+    self.space.set_limits(limits)
+  }
+
+  /// Sets the quota of user or group `id`, per `kind`. See the `quota` module.
+  pub fn set_quota(&self, kind: QuotaKind, id: u32, quota: Quota) {
+    self.space.quotas.borrow_mut().set(kind, id, quota, self.space.now());
+  }
+
+  /// The usage and quota of user or group `id`.
+  pub fn quota(&self, kind: QuotaKind, id: u32) -> QuotaUsage {
+    self.space.quotas.borrow().get(kind, id)
+  }
+
+  /// The usage and quota of every user or group that has either, by id.
+  pub fn quota_report(&self, kind: QuotaKind) -> Vec<(u32, QuotaUsage)> {
+    self.space.quotas.borrow().report(kind)
+  }
+
+  /// Sets the block and inode grace periods of quotas of `kind`. Grace periods
+  /// already running keep the end they had.
+  pub fn set_grace(&self, kind: QuotaKind, blocks: Duration, inodes: Duration) {
+    self.space.quotas.borrow_mut().set_grace(kind, blocks, inodes);
+  }
+
+  /// The time on the file system clock, which quota grace periods run on.
+  pub fn now(&self) -> Timespec {
+    self.space.now()
+  }
+
+  /// Stops the file system clock at `time`, or with `None` has it follow the
+  /// system time again.
+  pub fn set_clock(&self, time: Option<Timespec>) {
+    self.space.set_clock(time);
+  }
+
+  // Writes the whole tree to `image`: file data first, then page lists and
+  // directory contents into newly allocated blocks, and finally the inode
+  // table, bitmap and superblock that make them reachable.
+  fn flush(&self, image: &mut Image) -> Result<()> {
+    let superblock = image.superblock;
+    let page_size = self.geometry.page_size();
+
+    // Number every file and directory reachable from the root, breadth first.
+    let mut nodes: Vec<(File<'r>, u16)> = vec![(self.root.clone(), 1)];
+    let mut numbers: HashMap<*const (), u32> = HashMap::new();
+    let mut entries: HashMap<u32, Vec<(u32, String)>> = HashMap::new();
+    numbers.insert(file_ptr(&self.root), ROOT_INO);
+
+    let mut queue = VecDeque::new();
+    queue.push_back(ROOT_INO);
+    while let Some(dir_ino) = queue.pop_front() {
+      let content = nodes[dir_ino as usize - 1].0.get_dir_rc().clone();
+      let content = content.borrow();
+      let mut names: Vec<&Cow<str>> = content.entries.keys().collect();
+      names.sort();
+
+      let mut dir_entries = Vec::new();
+      for name in names {
+        let file = &content.entries[name];
+        let ino = match numbers.get(&file_ptr(file)) {
+          Some(&ino) => ino,
+          None => {
+            nodes.push((file.clone(), 0));
+            let ino = nodes.len() as u32;
+            numbers.insert(file_ptr(file), ino);
+            if let Directory(_) = *file { queue.push_back(ino); }
+            ino
+          }
+        };
+
+        let nlink = &mut nodes[ino as usize - 1].1;
+        *nlink = nlink.checked_add(1).ok_or(Error::TooManyLinks)?;
+        dir_entries.push((ino, name.to_string()));
+      }
+
+      entries.insert(dir_ino, dir_entries);
+    }
+
+    if nodes.len() > superblock.inode_count as usize {
+      return Err(Error::NoSpace);
+    }
+
+    // Orphans aren't written out, and the blocks they were read from are about
+    // to be freed, so they move into memory for good.
+    for open in self.open_files.borrow().iter() {
+      if !numbers.contains_key(&file_ptr(&open.file)) {
+        open.file.get_inode_rc().borrow_mut().detach()?;
+      }
+    }
+
+    // Metadata, blocks already holding file data and the lists of files whose
+    // pages are all where they were stay where they are.
+    let mut used = Bitmap::new(superblock.block_count);
+    for block in 0..superblock.data_start { used.set(block); }
+    for node in nodes.iter() {
+      if let DataFile(ref rc) = node.0 {
+        let mut inode = rc.borrow_mut();
+        for (_, page) in inode.pages_mut() {
+          if page.block() != 0 { used.set(u64::from(page.block())); }
+        }
+
+        if let Some(lists) = inode.lists() {
+          for &block in lists.blocks.iter() { used.set(u64::from(block)); }
+        }
+      }
+    }
+
+    let previous = image.bitmap.clone();
+    let mut alloc = Allocator { used, previous, next: superblock.data_start };
+    let mut tx = Transaction::new(image, self.journal_mode.get());
+    let mut table = vec![0u8; page_size * superblock.inode_table_blocks as usize];
+    let time_now = time::get_time();
+    let mut synced = Vec::new();
+
+    for (i, &(ref file, nlink)) in nodes.iter().enumerate() {
+      let ino = i as u32 + 1;
+      let mut disk_inode = DiskInode { nlink, ..DiskInode::free() };
+
+      // A file's lists only need writing if its pages came or went.
+      let (map, lists) = match *file {
+        DataFile(ref rc) => {
+          let mut inode = rc.borrow_mut();
+          let mut map = Vec::new();
+          for (num, page) in inode.pages_mut() {
+            // Pages never read are unchanged on the device.
+            let in_place = page.block() != 0;
+            if !in_place { page.set_block(alloc.alloc()?); }
+            if page.is_dirty() {
+              tx.write_data(image, page.block(), page, in_place)?;
+            }
+
+            map.push((num, page.block()));
+          }
+
+          let stat = inode.stat();
+          disk_inode.kind = KIND_FILE;
+          disk_inode.uid = inode.owner().uid;
+          disk_inode.gid = inode.owner().gid;
+          disk_inode.size = stat.size as u64;
+          disk_inode.pages = stat.blocks as u64;
+          disk_inode.create_time = stat.create_time;
+          disk_inode.access_time = stat.access_time;
+          disk_inode.mod_time = stat.mod_time;
+          (map, inode.lists().cloned())
+        }
+        Directory(_) => {
+          let dir_entries: Vec<(u32, &str)> = entries[&ino].iter()
+            .map(|&(ino, ref name)| (ino, name.as_str())).collect();
+          let data = encode_entries(&dir_entries);
+
+          let mut map = Vec::new();
+          let mut buf = vec![0u8; page_size];
+          for (num, chunk) in data.chunks(page_size).enumerate() {
+            let block = alloc.alloc()?;
+            buf[..chunk.len()].copy_from_slice(chunk);
+            for byte in buf[chunk.len()..].iter_mut() { *byte = 0; }
+            tx.write(image, block, &buf)?;
+            map.push((num, block));
+          }
+
+          disk_inode.kind = KIND_DIRECTORY;
+          disk_inode.size = data.len() as u64;
+          disk_inode.pages = map.len() as u64;
+          disk_inode.create_time = time_now;
+          disk_inode.access_time = time_now;
+          disk_inode.mod_time = time_now;
+          (map, None)
+        }
+        EmptyFile => unreachable!("empty files are never linked")
+      };
+
+      if map.last().is_some_and(|&(num, _)| num >= self.geometry.max_pages()) {
+        return Err(Error::FileTooLarge);
+      }
+
+      // Extended attributes, like directory contents, move every flush.
+      let xattrs = file.with_xattrs(|xattrs| match xattrs.is_empty() {
+        true => None,
+        false => Some(encode_xattrs(&xattrs.iter().collect::<Vec<_>>()))
+      });
+
+      if let Some(mut data) = xattrs {
+        if data.len() > page_size { return Err(Error::NoSpace); }
+        data.resize(page_size, 0);
+        disk_inode.xattrs = alloc.alloc()?;
+        tx.write(image, disk_inode.xattrs, &data)?;
+      }
+
+      let lists = match lists {
+        Some(lists) => lists,
+        None => self.write_map(image, &mut alloc, &mut tx, &map)?
+      };
+
+      disk_inode.single = lists.single;
+      disk_inode.double = lists.double;
+      if let DataFile(ref rc) = *file { synced.push((rc.clone(), lists)); }
+
+      disk_inode.encode(&mut table[(i * INODE_SIZE)..]);
+    }
+
+    // Everything the new tree references is written or journaled: commit the
+    // metadata that makes it reachable.
+    let superblock = Superblock {
+      free_blocks: superblock.block_count - alloc.used.count_set(),
+      free_inodes: superblock.inode_count - nodes.len() as u32,
+      ..superblock
+    };
+
+    let mut metadata = vec![0u8; page_size * superblock.journal_start as usize];
+    let block = |start: u64| start as usize * page_size;
+    superblock.encode(&mut metadata[..page_size]);
+    alloc.used.encode(&mut metadata[block(superblock.bitmap_start)..
+                                    block(superblock.inode_table_start)]);
+    metadata[block(superblock.inode_table_start)..].copy_from_slice(&table);
+    tx.commit(image, &metadata)?;
+
+    for (rc, lists) in synced { rc.borrow_mut().mark_synced(lists); }
+    image.reserved = Bitmap::new(superblock.block_count);
+    image.bitmap = alloc.used;
+    image.superblock = superblock;
+    image.metadata = Some(metadata.into_boxed_slice());
+    Ok(())
+  }
+
+  // Writes the page lists for a map of page numbers to blocks, returning the
+  // blocks they were written to.
+  fn write_map(&self, image: &mut Image, alloc: &mut Allocator,
+               tx: &mut Transaction, map: &[(usize, u32)]) -> Result<ListBlocks> {
+    let list_size = self.geometry.list_size();
+    let mut buf = vec![0u8; self.geometry.page_size()];
+    let mut blocks = Vec::new();
+    let mut write_list = |image: &mut Image, alloc: &mut Allocator, list: &[u32]|
+        -> Result<u32> {
+      let block = alloc.alloc()?;
+      encode_list(list, &mut buf);
+      tx.write(image, block, &buf)?;
+      blocks.push(block);
+      Ok(block)
+    };
+
+    let mut single = vec![0u32; list_size];
+    let mut double = vec![vec![0u32; list_size]; list_size];
+    for &(num, block) in map {
+      if num < list_size {
+        single[num] = block;
+      } else {
+        let entry = num - list_size;
+        double[entry / list_size][entry % list_size] = block;
+      }
+    }
+
+    let is_empty = |list: &[u32]| list.iter().all(|&b| b == 0);
+    let single_block = if is_empty(&single) {
+      0
+    } else {
+      write_list(image, alloc, &single)?
+    };
+
+    let mut lists = vec![0u32; list_size];
+    for (slot, list) in double.iter().enumerate() {
+      if !is_empty(list) { lists[slot] = write_list(image, alloc, list)?; }
+    }
+
+    let double_block = if is_empty(&lists) {
+      0
+    } else {
+      write_list(image, alloc, &lists)?
+    };
+
+    Ok(ListBlocks { single: single_block, double: double_block, blocks })
+  }
+}
+
+impl<'r> Default for FileSystem<'r> {
+  fn default() -> FileSystem<'r> {
+    FileSystem::new()
+  }
+}
+
+/// Reads the superblock of the image on `device`, recovers its journal and
+/// reads the metadata blocks that precede the journal.
+pub(crate) fn read_image(device: &SharedDevice) -> Result<(Superblock, Journal, Box<[u8]>)> {
+  let (block_size, blocks) = {
+    let device = device.borrow();
+    (device.block_size(), device.block_count())
+  };
+
+  if block_size < ::inode::MIN_PAGE_SIZE { return Err(Error::InvalidArgument); }
+  let mut buf = vec![0u8; block_size];
+  device.borrow_mut().read_block(0, &mut buf)?;
+
+  // The superblock may be in the journal, but its layout never changes.
+  let superblock = Superblock::decode(&buf)?;
+  if superblock.geometry.page_size() != block_size || superblock.block_count > blocks {
+    return Err(Error::Corrupted);
+  }
+
+  let journal = Journal::recover(&mut *device.borrow_mut(), superblock.journal_start,
+                                 superblock.journal_blocks, superblock.block_count)?;
+
+  let mut metadata = vec![0u8; block_size * superblock.journal_start as usize];
+  read_blocks(&mut *device.borrow_mut(), 0, &mut metadata)?;
+  let superblock = Superblock::decode(&metadata)?;
+  Ok((superblock, journal, metadata.into_boxed_slice()))
+}
+
+/// Opens the image file at `path` as a device whose block size is the image's
+/// page size.
+pub(crate) fn open_image<P: AsRef<Path>>(path: P) -> Result<SharedDevice> {
+  // The superblock fits in the smallest page, and says how big pages are.
+  let mut buf = vec![0u8; ::inode::MIN_PAGE_SIZE];
+  HostFile::open(path.as_ref())?.read_exact(&mut buf)?;
+  let page_size = Superblock::decode(&buf)?.geometry.page_size();
+
+  let device = FileDevice::open(path, page_size)?;
+  Ok(Rc::new(RefCell::new(device)))
+}
+
+// Every file and directory reachable from `root`, `root` included, once each.
+fn walk<'r>(root: &File<'r>) -> Vec<File<'r>> {
+  let mut seen = HashSet::new();
+  let mut files = vec![root.clone()];
+  let mut queue = VecDeque::new();
+  seen.insert(file_ptr(root));
+  queue.push_back(root.clone());
+  while let Some(dir) = queue.pop_front() {
+    for file in dir.get_dir_rc().borrow().entries.values() {
+      if !seen.insert(file_ptr(file)) { continue; }
+      if let Directory(_) = *file { queue.push_back(file.clone()); }
+      files.push(file.clone());
+    }
+  }
+
+  files
+}
+
+// Identifies the inode or directory behind a file, for finding hard links.
+fn file_ptr(file: &File) -> *const () {
+  match *file {
+    DataFile(ref rc) => Rc::as_ptr(rc) as *const (),
+    Directory(ref rc) => Rc::as_ptr(rc) as *const (),
+    EmptyFile => unreachable!("empty files are never linked")
+  }
+}
+
+// Copies the tree below `dir`, sharing file pages through `Inode::snapshot`
+// and keeping hard links linked.
+fn copy_tree<'r>(dir: &File<'r>) -> Result<File<'r>> {
+  fn copy<'r>(file: &File<'r>, copies: &mut HashMap<*const (), File<'r>>)
+      -> Result<File<'r>> {
+    if let Some(copy) = copies.get(&file_ptr(file)) { return Ok(copy.clone()); }
+    let copy = match *file {
+      DataFile(ref rc) => {
+        let inode = rc.borrow_mut().snapshot()?;
+        File::new_data_file(Rc::new(RefCell::new(Box::new(inode))))
+      }
+      Directory(ref rc) => {
+        let dir = File::new_dir(None);
+        dir.get_dir_rc().borrow_mut().xattrs = rc.borrow().xattrs.clone();
+        for (name, file) in rc.borrow().entries.iter() {
+          let entry = copy(file, copies)?;
+          dir.get_dir_rc().borrow_mut().entries.insert(name.clone(), entry);
+        }
+
+        dir
+      }
+      EmptyFile => unreachable!("empty files are never linked")
+    };
+
+    copies.insert(file_ptr(file), copy.clone());
+    Ok(copy)
+  }
+
+  copy(dir, &mut HashMap::new())
+}
+
+// Rebuilds the directory tree from an image's inode table.
+struct Loader<'a, 'r> {
+  image: &'a mut Image,
+  inodes: &'a [DiskInode],
+  files: HashMap<u32, File<'r>>,
+  dirs: HashSet<u32>,
+}
+
+impl<'a, 'r> Loader<'a, 'r> {
+  fn kind(&self, ino: u32) -> Result<u16> {
+    if ino == 0 || ino as usize > self.inodes.len() { return Err(Error::Corrupted); }
+    match self.inodes[ino as usize - 1].kind {
+      KIND_FREE => Err(Error::Corrupted),
+      kind => Ok(kind)
+    }
+  }
+
+  fn disk_inode(&self, ino: u32, kind: u16) -> Result<DiskInode> {
+    if self.kind(ino)? != kind { return Err(Error::Corrupted); }
+    Ok(self.inodes[ino as usize - 1])
+  }
+
+  fn load_dir(&mut self, ino: u32) -> Result<File<'r>> {
+    // A directory reachable twice means the tree has a cycle or a hard link to
+    // a directory; neither can be represented.
+    if !self.dirs.insert(ino) { return Err(Error::Corrupted); }
+
+    let disk_inode = self.disk_inode(ino, KIND_DIRECTORY)?;
+    let (map, _) = self.image.read_map(disk_inode.single, disk_inode.double)?;
+    let page_size = self.image.superblock.geometry.page_size();
+    let mut data = vec![0u8; map.last().map_or(0, |&(num, _)| (num + 1) * page_size)];
+    for &(num, block) in map.iter() {
+      let start = num * page_size;
+      self.image.read_block(u64::from(block), &mut data[start..(start + page_size)])?;
+    }
+
+    if disk_inode.size as usize > data.len() { return Err(Error::Corrupted); }
+    data.truncate(disk_inode.size as usize);
+
+    let dir = File::new_dir(None);
+    dir.get_dir_rc().borrow_mut().xattrs = self.load_xattrs(&disk_inode)?;
+    for (entry_ino, name) in decode_entries(&data)? {
+      let file = if self.kind(entry_ino)? == KIND_DIRECTORY {
+        self.load_dir(entry_ino)?
+      } else {
+        match self.files.get(&entry_ino) {
+          Some(file) => file.clone(),
+          None => self.load_file(entry_ino)?
+        }
+      };
+
+      dir.get_dir_rc().borrow_mut().entries.insert(Cow::Owned(name), file);
+    }
+
+    Ok(dir)
+  }
+
+  fn load_file(&mut self, ino: u32) -> Result<File<'r>> {
+    let disk_inode = self.disk_inode(ino, KIND_FILE)?;
+    let geometry = self.image.superblock.geometry;
+    let mut inode = Inode::with_geometry(geometry);
+
+    let (map, lists) = self.image.read_map(disk_inode.single, disk_inode.double)?;
+    if disk_inode.size > geometry.max_file_size() as u64 {
+      return Err(Error::Corrupted);
+    }
+
+    for (num, block) in map {
+      inode.insert_page(num, Page::on_device(block))?;
+    }
+
+    *inode.xattrs_mut() = self.load_xattrs(&disk_inode)?;
+    inode.set_owner(Owner { uid: disk_inode.uid, gid: disk_inode.gid })?;
+    inode.set_device(self.image.device.clone());
+    inode.restore(disk_inode.size as usize, disk_inode.create_time,
+                  disk_inode.access_time, disk_inode.mod_time);
+    inode.mark_synced(lists);
+
+    let file = File::new_data_file(Rc::new(RefCell::new(Box::new(inode))));
+    self.files.insert(ino, file.clone());
+    Ok(file)
+  }
+
+  fn load_xattrs(&mut self, disk_inode: &DiskInode) -> Result<Xattrs> {
+    if disk_inode.xattrs == 0 { return Ok(Xattrs::default()); }
+    if !self.image.superblock.is_data_block(disk_inode.xattrs) {
+      return Err(Error::Corrupted);
+    }
+
+    let mut buf = vec![0u8; self.image.superblock.geometry.page_size()];
+    self.image.read_block(u64::from(disk_inode.xattrs), &mut buf)?;
+    decode_xattrs(&buf).map(Xattrs::from)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate rand;
+
+  use std::env;
+  use std::fs;
+  use std::path::PathBuf;
+  use std::process;
+  use std::cell::RefCell;
+  use std::rc::Rc;
+  use self::rand::random;
+  use super::{FileSystem, LINK_MAX};
+  use device::{SharedDevice, MemDevice, MmapDevice, CrashDevice};
+  use directory::DirectoryHandle;
+  use error::Error;
+  use fsck::{fsck_device, FsckReport};
+  use inode::Geometry;
+  use journal::JournalMode;
+  use quota::Owner;
+  use space::Limits;
+  use {Proc, Whence, O_CREAT, O_RDWR, O_TMPFILE, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE};
+  use {PROT_READ, PROT_WRITE, MAP_SHARED, MAP_PRIVATE, MS_ASYNC, MS_SYNC};
+
+  fn rand_array(size: usize) -> Vec<u8> {
+    (0..size).map(|_| random::<u8>()).collect()
+  }
+
+  fn image_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("rustfs-{}-{}.img", name, process::id()))
+  }
+
+  fn free_blocks(fs: &FileSystem) -> u64 {
+    fs.image.borrow().as_ref().unwrap().superblock.free_blocks
+  }
+
+  fn read_file(p: &mut Proc<'static>, name: &'static str, size: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; size + 1];
+    let fd = p.open(name, O_RDWR);
+    assert_eq!(p.read(fd, &mut buf), Ok(size));
+    p.close(fd);
+    buf.truncate(size);
+    buf
+  }
+
+  #[test]
+  fn test_mount_roundtrip() {
+    let path = image_path("roundtrip");
+    let geometry = Geometry::new(1024, 32).unwrap();
+    FileSystem::format_with(&path, geometry, 4096, 64).unwrap();
+
+    let small = rand_array(100);
+    let large = rand_array(1024 * 40 + 7);
+    let sparse = rand_array(1024 * 8);
+
+    let fs = Rc::new(FileSystem::mount(&path).unwrap());
+    let stats = {
+      let mut p = Proc::with_fs(fs.clone());
+      for &(name, data) in [("small", &small), ("large", &large)].iter() {
+        let fd = p.open(name, O_CREAT | O_RDWR);
+        p.write(fd, data).unwrap();
+        p.close(fd);
+      }
+
+      let fd = p.open("sparse", O_CREAT | O_RDWR);
+      p.write(fd, &sparse).unwrap();
+      p.fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, 1024, 1024 * 6)
+        .unwrap();
+      p.close(fd);
+
+      (p.stat("small").unwrap(), p.stat("large").unwrap(), p.stat("sparse").unwrap())
+    };
+
+    fs.unmount().unwrap();
+    assert_eq!(fs.unmount(), Err(Error::InvalidArgument));
+
+    let fs = Rc::new(FileSystem::mount(&path).unwrap());
+    assert_eq!(fs.geometry(), geometry);
+
+    let mut p = Proc::with_fs(fs.clone());
+    assert_eq!(p.stat("small").unwrap(), stats.0);
+    assert_eq!(p.stat("large").unwrap(), stats.1);
+    assert_eq!(p.stat("sparse").unwrap(), stats.2);
+    assert_eq!(read_file(&mut p, "small", small.len()), small);
+    assert_eq!(read_file(&mut p, "large", large.len()), large);
+
+    let contents = read_file(&mut p, "sparse", sparse.len());
+    assert_eq!(&contents[..1024], &sparse[..1024]);
+    assert!(contents[1024..(1024 * 7)].iter().all(|&b| b == 0));
+    assert_eq!(&contents[(1024 * 7)..], &sparse[(1024 * 7)..]);
+
+    let fd = p.open("sparse", O_RDWR);
+    assert_eq!(p.seek(fd, 0, Whence::SeekHole), Ok(1024));
+    p.close(fd);
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  // Writes `data` to "file" on a fresh file system on `device`, then remounts
+  // it twice: once to overwrite part of a page that hasn't been read yet, and
+  // once to check the result.
+  fn exercise_device(device: SharedDevice, data: &[u8]) {
+    let geometry = Geometry::new(512, 16).unwrap();
+    FileSystem::format_device(device.clone(), geometry, 16).unwrap();
+
+    let fs = Rc::new(FileSystem::mount_device(device.clone()).unwrap());
+    {
+      let mut p = Proc::with_fs(fs.clone());
+      let fd = p.open("file", O_CREAT | O_RDWR);
+      p.write(fd, data).unwrap();
+      p.close(fd);
+    }
+
+    fs.unmount().unwrap();
+
+    let fs = Rc::new(FileSystem::mount_device(device.clone()).unwrap());
+    {
+      let mut p = Proc::with_fs(fs.clone());
+      let fd = p.open("file", O_RDWR);
+      p.seek(fd, 700, Whence::SeekSet).unwrap();
+      p.write(fd, &[7u8; 100]).unwrap();
+      p.close(fd);
+    }
+
+    fs.unmount().unwrap();
+
+    let mut expected = data.to_vec();
+    expected[700..800].copy_from_slice(&[7u8; 100]);
+    let fs = Rc::new(FileSystem::mount_device(device).unwrap());
+    let mut p = Proc::with_fs(fs.clone());
+    assert_eq!(read_file(&mut p, "file", expected.len()), expected);
+  }
+
+  #[test]
+  fn test_mem_device() {
+    let device = Rc::new(RefCell::new(MemDevice::new(512, 256)));
+    exercise_device(device, &rand_array(512 * 40 + 9));
+
+    // The device's block size must be the geometry's page size.
+    let device = Rc::new(RefCell::new(MemDevice::new(512, 256)));
+    let geometry = Geometry::new(1024, 32).unwrap();
+    assert_eq!(FileSystem::format_device(device.clone(), geometry, 16),
+               Err(Error::InvalidArgument));
+    assert_eq!(FileSystem::mount_device(device).err(), Some(Error::Corrupted));
+  }
+
+  #[test]
+  fn test_mmap_device() {
+    let path = image_path("mmap");
+    let device = MmapDevice::create(&path, 512, 256).unwrap();
+    exercise_device(Rc::new(RefCell::new(device)), &rand_array(512 * 40 + 9));
+
+    // The image is an ordinary one, mountable through a file device.
+    let fs = Rc::new(FileSystem::mount(&path).unwrap());
+    assert_eq!(Proc::with_fs(fs).stat("file").unwrap().size, 512 * 40 + 9);
+    fs::remove_file(&path).unwrap();
+  }
+
+  // The files in the tree on `device`, as (name, contents) pairs, checking
+  // that the image is consistent. Mounting finishes any interrupted flush;
+  // with `replay`, every state a crash during that could leave must show the
+  // same files.
+  fn snapshot(device: MemDevice, replay: bool) -> Vec<(&'static str, Vec<u8>)> {
+    let checked = Rc::new(RefCell::new(device.clone()));
+    assert_eq!(fsck_device(checked, false), Ok(FsckReport::default()));
+
+    let crash = Rc::new(RefCell::new(CrashDevice::new(device)));
+    let fs = Rc::new(FileSystem::mount_device(crash.clone()).unwrap());
+
+    let mut p = Proc::with_fs(fs);
+    let files: Vec<_> = ["a", "b", "c"].iter().filter_map(|&name| {
+      let size = p.stat(name).ok()?.size;
+      Some((name, read_file(&mut p, name, size)))
+    }).collect();
+
+    if replay && crash.borrow().writes() > 0 {
+      crash.borrow().crash_states(false, |state| {
+        assert_eq!(snapshot(state, false), files);
+      });
+    }
+
+    files
+  }
+
+  // Unlinks "a", creates "c", and overwrites part of a page of "b", then
+  // checks every state a crash during the flush that follows could leave the
+  // image in: it must show either the old files or the new ones.
+  fn crash_flush(mode: JournalMode) {
+    let (a, b, c) = (rand_array(512 * 20), rand_array(512 * 3), rand_array(512 * 30));
+    let mut new_b = b.clone();
+    new_b[600..700].copy_from_slice(&[7u8; 100]);
+
+    let device = Rc::new(RefCell::new(MemDevice::new(512, 512)));
+    FileSystem::format_device(device.clone(), Geometry::new(512, 16).unwrap(), 32)
+      .unwrap();
+    let fs = Rc::new(FileSystem::mount_device(device.clone()).unwrap());
+    {
+      let mut p = Proc::with_fs(fs.clone());
+      for &(name, data) in [("a", &a), ("b", &b)].iter() {
+        let fd = p.open(name, O_CREAT | O_RDWR);
+        p.write(fd, data).unwrap();
+        p.close(fd);
+      }
+    }
+
+    fs.unmount().unwrap();
+
+    let device = Rc::new(RefCell::new(CrashDevice::new(device.borrow().clone())));
+    let fs = Rc::new(FileSystem::mount_device(device.clone()).unwrap());
+    fs.set_journal_mode(mode);
+    {
+      let mut p = Proc::with_fs(fs.clone());
+      p.unlink("a").unwrap();
+      let fd = p.open("c", O_CREAT | O_RDWR);
+      p.write(fd, &c).unwrap();
+      p.close(fd);
+      let fd = p.open("b", O_RDWR);
+      p.seek(fd, 600, Whence::SeekSet).unwrap();
+      p.write(fd, &new_b[600..700]).unwrap();
+      p.close(fd);
+    }
+
+    fs.unmount().unwrap();
+
+    // Only ordered mode may show data written ahead of the metadata.
+    let before = vec![("a", a.clone()), ("b", b.clone())];
+    let after = vec![("b", new_b.clone()), ("c", c)];
+    let early = vec![("a", a), ("b", new_b)];
+    let allowed = |state: &Vec<(&str, Vec<u8>)>| {
+      *state == before || *state == after ||
+        (mode == JournalMode::Ordered && *state == early)
+    };
+
+    // In order, the image switches to the new files once, and stays there.
+    let (mut committed, mut states) = (false, 0);
+    device.borrow().crash_states(false, |state| {
+      let state = snapshot(state, true);
+      assert!(allowed(&state), "crash after {} writes left a mixed image", states);
+      assert!(!committed || state == after, "crash after {} writes undid a commit",
+              states);
+      committed = state == after;
+      states += 1;
+    });
+
+    assert!(committed);
+    device.borrow().crash_states(true, |state| {
+      assert!(allowed(&snapshot(state, true)), "a reordered crash left a mixed image");
+    });
+  }
+
+  #[test]
+  fn test_crash_ordered() {
+    crash_flush(JournalMode::Ordered);
+  }
+
+  #[test]
+  fn test_crash_data_journal() {
+    crash_flush(JournalMode::Data);
+  }
+
+  #[test]
+  fn test_sync_writes_dirty_pages() {
+    let base = Rc::new(RefCell::new(MemDevice::new(512, 1024)));
+    FileSystem::format_device(base.clone(), Geometry::new(512, 16).unwrap(), 16)
+      .unwrap();
+    let device = Rc::new(RefCell::new(CrashDevice::new(base.borrow().clone())));
+    let fs = Rc::new(FileSystem::mount_device(device.clone()).unwrap());
+    let mut p = Proc::with_fs(fs.clone());
+
+    let data = rand_array(512 * 100);
+    let fd = p.open("file", O_CREAT | O_RDWR);
+    p.write(fd, &data).unwrap();
+    p.fsync(fd).unwrap();
+    let after_fsync = device.borrow().writes();
+    assert!(after_fsync > 100);
+
+    // Reading changes nothing worth a flush, and one changed page is all of
+    // the file that's written again.
+    let mut buf = [0u8; 10];
+    p.seek(fd, 512 * 50, Whence::SeekSet).unwrap();
+    p.read(fd, &mut buf).unwrap();
+    p.fdatasync(fd).unwrap();
+    assert_eq!(device.borrow().writes(), after_fsync);
+
+    p.seek(fd, 512 * 50, Whence::SeekSet).unwrap();
+    p.write(fd, &[7u8; 10]).unwrap();
+    p.fdatasync(fd).unwrap();
+    let synced_at = device.borrow().writes();
+    let rewritten = synced_at - after_fsync;
+    assert!(rewritten < 20, "{} writes to sync one page", rewritten);
+
+    // Whatever state a crash leaves the device in from then on, the synced
+    // data is there.
+    let mut synced = data.clone();
+    synced[(512 * 50)..(512 * 50 + 10)].copy_from_slice(&[7u8; 10]);
+    let mut expected = synced.clone();
+    expected[(512 * 60)..(512 * 60 + 10)].copy_from_slice(&[7u8; 10]);
+    p.seek(fd, 512 * 60, Whence::SeekSet).unwrap();
+    p.write(fd, &[7u8; 10]).unwrap();
+    p.close(fd);
+    p.sync().unwrap();
+
+    let mut writes = 0;
+    device.borrow().crash_states(false, |state| {
+      if writes >= synced_at {
+        let fs = Rc::new(FileSystem::mount_device(Rc::new(RefCell::new(state))).unwrap());
+        let contents = read_file(&mut Proc::with_fs(fs), "file", data.len());
+        assert!(contents == synced || contents == expected);
+      }
+
+      writes += 1;
+    });
+
+    // Without an image there's nothing to do.
+    fs.unmount().unwrap();
+    let unmounted = device.borrow().writes();
+    assert_eq!(fs.sync(), Ok(()));
+    assert_eq!(device.borrow().writes(), unmounted);
+  }
+
+  #[test]
+  fn test_unlink_frees_blocks() {
+    let path = image_path("unlink");
+    let geometry = Geometry::new(1024, 32).unwrap();
+    FileSystem::format_with(&path, geometry, 1024, 16).unwrap();
+
+    let fs = Rc::new(FileSystem::mount(&path).unwrap());
+    let empty = free_blocks(&fs);
+    {
+      let mut p = Proc::with_fs(fs.clone());
+      let fd = p.open("file", O_CREAT | O_RDWR);
+      p.write(fd, &rand_array(1024 * 50)).unwrap();
+      p.close(fd);
+    }
+
+    fs.unmount().unwrap();
+
+    // The file takes 50 data pages, a singly-indirect list, and the
+    // doubly-indirect list with one list below it. The root directory's entry
+    // takes a page and its list.
+    let fs = Rc::new(FileSystem::mount(&path).unwrap());
+    assert_eq!(free_blocks(&fs), empty - 50 - 3 - 2);
+
+    let mut p = Proc::with_fs(fs.clone());
+    p.unlink("file").unwrap();
+    fs.unmount().unwrap();
+
+    let fs = Rc::new(FileSystem::mount(&path).unwrap());
+    assert_eq!(free_blocks(&fs), empty);
+    let mut p = Proc::with_fs(fs.clone());
+    assert_eq!(p.open("file", O_RDWR), -2);
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_mount_errors() {
+    let path = image_path("garbage");
+    fs::write(&path, rand_array(8192)).unwrap();
+    assert_eq!(FileSystem::mount(&path).err(), Some(Error::Corrupted));
+
+    let geometry = Geometry::default();
+    assert_eq!(FileSystem::format_with(&path, geometry, 4, 64),
+               Err(Error::InvalidArgument));
+    fs::remove_file(&path).unwrap();
+
+    assert!(FileSystem::mount(&path).is_err());
+    assert_eq!(FileSystem::new().unmount(), Err(Error::InvalidArgument));
+  }
+
+  #[test]
+  fn test_image_full() {
+    let path = image_path("full");
+    let geometry = Geometry::new(512, 16).unwrap();
+    FileSystem::format_with(&path, geometry, 64, 8).unwrap();
+
+    let fs = Rc::new(FileSystem::mount(&path).unwrap());
+    let mut p = Proc::with_fs(fs.clone());
+    let fd = p.open("file", O_CREAT | O_RDWR);
+    p.write(fd, &rand_array(512 * 64)).unwrap();
+    p.close(fd);
+
+    assert_eq!(fs.unmount(), Err(Error::NoSpace));
+    fs::remove_file(&path).unwrap();
+  }
+
+  fn overwrite(p: &mut Proc<'static>, name: &'static str, data: &[u8]) {
+    let fd = p.open(name, O_CREAT | O_RDWR);
+    p.write(fd, data).unwrap();
+    p.close(fd);
+  }
+
+  #[test]
+  fn test_snapshot_rollback() {
+    let (a, b, c) = (rand_array(512 * 3), rand_array(100), rand_array(512 * 2));
+    let fs = Rc::new(FileSystem::with_geometry(Geometry::new(512, 16).unwrap()));
+    let mut p = Proc::with_fs(fs.clone());
+    overwrite(&mut p, "a", &a);
+    overwrite(&mut p, "b", &b);
+
+    fs.snapshot("base").unwrap();
+    assert_eq!(fs.snapshot("base"), Err(Error::Exists));
+    assert_eq!(fs.snapshots(), ["base"]);
+
+    for _ in 0..100 {
+      overwrite(&mut p, "a", &[7u8; 10]);
+      overwrite(&mut p, "c", &c);
+      p.unlink("b").unwrap();
+      assert_eq!(&read_file(&mut p, "a", a.len())[..10], &[7u8; 10]);
+
+      // Only the page "a" changed was copied.
+      let snapshot = fs.snapshots.borrow()["base"].get("a").unwrap();
+      let pages = |file: &::file::File| -> Vec<*const u8> {
+        let mut inode = file.get_inode_rc().borrow_mut();
+        inode.pages_mut().map(|(_, page)| page.as_ptr()).collect()
+      };
+      let (live, saved) = (pages(&fs.root.get("a").unwrap()), pages(&snapshot));
+      assert!(live[0] != saved[0] && live[1..] == saved[1..]);
+
+      fs.rollback("base").unwrap();
+      assert_eq!(read_file(&mut p, "a", a.len()), a);
+      assert_eq!(read_file(&mut p, "b", b.len()), b);
+      assert!(p.stat("c").is_err());
+    }
+
+    // Views don't see later changes, and can't be changed themselves.
+    overwrite(&mut p, "c", &c);
+    let view = Rc::new(fs.open_snapshot("base").unwrap());
+    fs.delete_snapshot("base").unwrap();
+    assert_eq!(fs.rollback("base"), Err(Error::NotFound));
+    assert!(fs.snapshots().is_empty());
+
+    let mut q = Proc::with_fs(view.clone());
+    assert_eq!(read_file(&mut q, "a", a.len()), a);
+    assert!(q.stat("c").is_err());
+    assert_eq!(q.open("c", O_CREAT | O_RDWR), -30);
+
+    let fd = q.open("a", O_RDWR);
+    assert_eq!(q.write(fd, &[1]), Err(Error::ReadOnly));
+    assert_eq!(q.unlink("b"), Err(Error::ReadOnly));
+    assert_eq!(read_file(&mut q, "b", b.len()), b);
+    assert_eq!(view.rollback("base"), Err(Error::ReadOnly));
+  }
+
+  // A file system formatted on a fresh in-memory device of `blocks` blocks,
+  // with 512-byte pages and room for 16 files, and mounted.
+  fn mounted<'r>(blocks: u64) -> (Rc<RefCell<MemDevice>>, Rc<FileSystem<'r>>) {
+    let device = Rc::new(RefCell::new(MemDevice::new(512, blocks)));
+    FileSystem::format_device(device.clone(), Geometry::new(512, 16).unwrap(), 16)
+      .unwrap();
+    let fs = remount(&device);
+    (device, fs)
+  }
+
+  // Mounts the file system on `device` again.
+  fn remount<'r>(device: &Rc<RefCell<MemDevice>>) -> Rc<FileSystem<'r>> {
+    Rc::new(FileSystem::mount_device(device.clone()).unwrap())
+  }
+
+  #[test]
+  fn test_snapshot_mounted() {
+    let (a, b) = (rand_array(512 * 20), rand_array(512 * 3));
+    let (device, fs) = mounted(256);
+    overwrite(&mut Proc::with_fs(fs.clone()), "a", &a);
+    fs.unmount().unwrap();
+
+    // The snapshot reads in what it shares, so overwriting "a" in place and
+    // freeing its blocks doesn't change it.
+    let fs = remount(&device);
+    fs.snapshot("base").unwrap();
+    let mut p = Proc::with_fs(fs.clone());
+    overwrite(&mut p, "a", &b);
+    p.sync().unwrap();
+    p.unlink("a").unwrap();
+    overwrite(&mut p, "b", &a);
+    p.sync().unwrap();
+
+    let mut q = Proc::with_fs(Rc::new(fs.open_snapshot("base").unwrap()));
+    assert_eq!(read_file(&mut q, "a", a.len()), a);
+
+    fs.rollback("base").unwrap();
+    fs.unmount().unwrap();
+    assert_eq!(snapshot(device.borrow().clone(), false), [("a", a)]);
+  }
+
+  #[test]
+  fn test_reflink_mounted() {
+    let data = rand_array(512 * 20 + 3);
+    let (device, fs) = mounted(256);
+    overwrite(&mut Proc::with_fs(fs.clone()), "a", &data);
+    fs.unmount().unwrap();
+
+    // Shared pages get blocks of their own when flushed.
+    let fs = remount(&device);
+    {
+      let mut p = Proc::with_fs(fs.clone());
+      let (a, b) = (p.open("a", O_RDWR), p.open("b", O_CREAT | O_RDWR));
+      p.reflink(a, b).unwrap();
+      let c = p.open("c", O_CREAT | O_RDWR);
+      assert_eq!(p.copy_file_range(a, 512, c, 0, 512 * 4), Ok(512 * 4));
+    }
+
+    fs.unmount().unwrap();
+    let c = data[512..(512 * 5)].to_vec();
+    assert_eq!(snapshot(device.borrow().clone(), false),
+               [("a", data.clone()), ("b", data), ("c", c)]);
+  }
+
+  #[test]
+  fn test_xattrs_persist() {
+    let (device, fs) = mounted(256);
+    {
+      let mut p = Proc::with_fs(fs.clone());
+      overwrite(&mut p, "a", b"data");
+      p.setxattr("a", "user.origin", b"ci", 0).unwrap();
+      p.setxattr(".", "user.root", &[7u8; 300], 0).unwrap();
+      fs.snapshot("base").unwrap();
+      p.removexattr("a", "user.origin").unwrap();
+      fs.rollback("base").unwrap();
+    }
+
+    fs.unmount().unwrap();
+    assert_eq!(snapshot(device.borrow().clone(), false), [("a", b"data".to_vec())]);
+
+    let fs = remount(&device);
+    let mut p = Proc::with_fs(fs.clone());
+    let mut buf = [0u8; 512];
+    assert_eq!(p.getxattr("a", "user.origin", &mut buf), Ok(2));
+    assert_eq!(&buf[..2], b"ci");
+    assert_eq!(p.getxattr(".", "user.root", &mut buf), Ok(300));
+    assert_eq!(&buf[..300], &[7u8; 300][..]);
+
+    // Attributes left empty take no block.
+    let free = free_blocks(&fs);
+    p.removexattr(".", "user.root").unwrap();
+    p.sync().unwrap();
+    assert_eq!(free_blocks(&fs), free + 1);
+  }
+
+  #[test]
+  fn test_orphans_mounted() {
+    let (data, other, tmp) = (rand_array(512 * 20), rand_array(512 * 20), rand_array(700));
+    let (device, fs) = mounted(128);
+    overwrite(&mut Proc::with_fs(fs.clone()), "a", &data);
+    fs.unmount().unwrap();
+
+    // The orphan's pages were never read, and its blocks are free once it's
+    // unlinked, so it has to read them in before they're reused.
+    let fs = remount(&device);
+    let mut p = Proc::with_fs(fs.clone());
+    let free = free_blocks(&fs);
+    let fd = p.open("a", O_RDWR);
+    p.unlink("a").unwrap();
+    p.sync().unwrap();
+
+    // The file's pages and three lists go, and so does the root's only page
+    // along with its list.
+    assert_eq!(free_blocks(&fs), free + 20 + 3 + 2);
+    overwrite(&mut p, "b", &other);
+    p.sync().unwrap();
+    assert_eq!(read_file(&mut p, "b", other.len()), other);
+
+    let mut buf = vec![0u8; data.len()];
+    assert_eq!(p.read(fd, &mut buf), Ok(data.len()));
+    assert_eq!(buf, data);
+    assert_eq!(fs.orphans().len(), 1);
+    p.close(fd);
+
+    let fd = p.open(".", O_RDWR | O_TMPFILE);
+    p.write(fd, &tmp).unwrap();
+    p.sync().unwrap();
+    p.linkat(fd, "c").unwrap();
+    p.close(fd);
+    fs.unmount().unwrap();
+    assert_eq!(snapshot(device.borrow().clone(), false), [("b", other), ("c", tmp)]);
+  }
+
+  #[test]
+  fn test_page_cache() {
+    let (data, other) = (rand_array(512 * 20), rand_array(512 * 20));
+    let (device, fs) = mounted(128);
+    let mut p = Proc::with_fs(fs.clone());
+
+    // With room for 8 pages, writing 20 writes the first 12 back, to blocks
+    // the image's tree doesn't use: it's untouched until the flush.
+    fs.set_cache_limit(Some(8));
+    overwrite(&mut p, "a", &data);
+    let stats = fs.cache_stats();
+    assert_eq!((stats.resident, stats.evictions, stats.writebacks), (8, 12, 12));
+    assert_eq!(snapshot(device.borrow().clone(), false), []);
+
+    // Reading it back misses on those 12, which go again afterwards.
+    assert_eq!(read_file(&mut p, "a", data.len()), data);
+    let stats = fs.cache_stats();
+    assert_eq!((stats.misses, stats.hits, stats.read_ahead), (12, 8, 0));
+    assert_eq!((stats.resident, stats.evictions, stats.writebacks), (8, 24, 12));
+    p.sync().unwrap();
+    assert_eq!(snapshot(device.borrow().clone(), false), [("a", data.clone())]);
+
+    // Pages written back in place are journaled in data mode, as on a flush.
+    fs.set_journal_mode(JournalMode::Data);
+    overwrite(&mut p, "a", &other);
+    assert_eq!(fs.cache_stats().writebacks, 24);
+    fs.unmount().unwrap();
+    assert_eq!(snapshot(device.borrow().clone(), false), [("a", other.clone())]);
+
+    // A read away from the last one isn't read ahead of, but once reads run
+    // on sequentially, more and more is.
+    let fs = remount(&device);
+    let mut p = Proc::with_fs(fs.clone());
+    fs.set_read_ahead(4);
+    let fd = p.open("a", O_RDWR);
+    let mut buf = vec![0u8; 512];
+    p.seek(fd, 512 * 10, Whence::SeekSet).unwrap();
+    assert_eq!(p.read(fd, &mut buf), Ok(512));
+    p.seek(fd, 0, Whence::SeekSet).unwrap();
+    for chunk in other.chunks(512) {
+      assert_eq!(p.read(fd, &mut buf), Ok(512));
+      assert_eq!(buf, chunk);
+    }
+
+    let stats = fs.cache_stats();
+    assert_eq!((stats.misses, stats.read_ahead, stats.hits), (3, 17, 18));
+    assert_eq!((stats.resident, stats.limit), (20, None));
+  }
+
+  #[test]
+  fn test_mmap_msync() {
+    let data = rand_array(512 * 6);
+    let (device, fs) = mounted(128);
+    let mut p = Proc::with_fs(fs.clone());
+    overwrite(&mut p, "a", &data);
+    p.sync().unwrap();
+
+    // Writes through a shared mapping reach the file at once, and the device
+    // with an `MS_SYNC`.
+    let fd = p.open("a", O_RDWR);
+    let map = p.mmap(fd, 512, 512 * 4, PROT_READ | PROT_WRITE, MAP_SHARED).unwrap();
+    map.view_mut().unwrap()[100..1000].copy_from_slice(&[3u8; 900]);
+    p.msync(&map, MS_ASYNC).unwrap();
+    assert_eq!(snapshot(device.borrow().clone(), false), [("a", data.clone())]);
+    p.msync(&map, MS_SYNC).unwrap();
+    let mut expected = data.clone();
+    expected[612..1512].copy_from_slice(&[3u8; 900]);
+    assert_eq!(snapshot(device.borrow().clone(), false), [("a", expected.clone())]);
+
+    // A private mapping reads the file's pages in, but never writes them.
+    let private = p.mmap(fd, 0, 512 * 6, PROT_READ | PROT_WRITE, MAP_PRIVATE).unwrap();
+    assert_eq!(&*private.view().unwrap(), &expected[..]);
+    private.view_mut().unwrap().copy_from_slice(&data);
+    p.msync(&private, MS_SYNC).unwrap();
+    p.sync().unwrap();
+    assert_eq!(read_file(&mut p, "a", expected.len()), expected);
+
+    // A page written to a hole there's no room for stays pending, failing
+    // every `msync` until there's room to write it back.
+    p.fallocate(fd, FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE, 512, 512).unwrap();
+    fs.set_limits(Limits { max_bytes: Some(512 * 5), max_inodes: None }).unwrap();
+    map.view_mut().unwrap()[0] = 1;
+    assert_eq!(p.msync(&map, MS_SYNC), Err(Error::NoSpace));
+    assert_eq!(p.msync(&map, MS_SYNC), Err(Error::NoSpace));
+    assert_eq!(map.view().unwrap()[0], 1);
+
+    fs.set_limits(Limits { max_bytes: None, max_inodes: None }).unwrap();
+    assert_eq!(p.msync(&map, MS_SYNC), Ok(()));
+    expected[512..1024].fill(0);
+    expected[512] = 1;
+    assert_eq!(snapshot(device.borrow().clone(), false), [("a", expected)]);
+    p.munmap(map);
+  }
+
+  #[test]
+  fn test_mmap_unlinked() {
+    let (data, other) = (vec![0xAA; 512 * 20], rand_array(512 * 20));
+    let (device, fs) = mounted(96);
+    overwrite(&mut Proc::with_fs(fs.clone()), "a", &data);
+    fs.unmount().unwrap();
+
+    // A shared mapping keeps its file open, so once unlinked the file is an
+    // orphan, whose pages are read in before other files reuse its blocks.
+    let fs = remount(&device);
+    let mut p = Proc::with_fs(fs.clone());
+    let fd = p.open("a", O_RDWR);
+    let map = p.mmap(fd, 0, data.len(), PROT_READ | PROT_WRITE, MAP_SHARED).unwrap();
+    p.close(fd);
+    p.unlink("a").unwrap();
+    assert_eq!(fs.orphans().len(), 1);
+    for name in ["b", "c", "d", "e"].iter() {
+      overwrite(&mut p, name, &other);
+      p.sync().unwrap();
+      if *name != "e" { p.unlink(name).unwrap(); }
+    }
+
+    assert_eq!(&*map.view().unwrap(), &data[..]);
+    map.view_mut().unwrap().fill(0x55);
+    p.sync().unwrap();
+    assert_eq!(read_file(&mut p, "e", other.len()), other);
+
+    p.munmap(map);
+    assert!(fs.orphans().is_empty());
+  }
+
+  #[test]
+  fn test_link_count_overflow() {
+    let names: Vec<String> = (0..=LINK_MAX).map(|i| format!("link{}", i)).collect();
+    let (_, fs) = mounted(256);
+    let mut root = fs.root();
+    let file = fs.new_file(Owner::default()).unwrap();
+    for name in names.iter() { root.insert(name, file.clone()); }
+
+    // An image's link counts can't go past `LINK_MAX`.
+    assert_eq!(fs.sync(), Err(Error::TooManyLinks));
+  }
+}
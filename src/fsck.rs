@@ -0,0 +1,812 @@
+/*!
+ * Checking and repairing file system images.
+ *
+ * `fsck_device` reads an image's inode table, then walks its directory tree
+ * from the root and reports each inconsistency it finds as an `FsckProblem`.
+ * Like mounting, checking first replays a committed transaction left in the
+ * journal.
+ *
+ * With `repair` set, the image is then rebuilt from what could be salvaged:
+ *
+ *   * entries naming free inodes, and all but the first entry naming a
+ *     directory, are dropped;
+ *   * page pointers outside the data region are dropped, and a block claimed
+ *     by more than one page gets copied for all but the first;
+ *   * inodes in use but unreachable from the root are linked into
+ *     `/lost+found` as `#<ino>`.
+ *
+ * Link counts, page counts, sizes past the maximum file size, the bitmap and
+ * the free counts are recomputed as the tree is written back through the
+ * journal, so a crash midway leaves the image as it was.
+ */
+
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+use std::cell::RefCell;
+use device::{SharedDevice, read_blocks};
+use disk::{Superblock, DiskInode, Bitmap, INODE_SIZE, ROOT_INO};
+use disk::{KIND_FILE, KIND_DIRECTORY, KIND_FREE, decode_list, decode_entries};
+use disk::decode_xattrs;
+use error::Result;
+use file::File;
+use file::File::Directory;
+use fs::{FileSystem, read_image, open_image};
+use inode::{Inode, Page};
+use quota::Owner;
+use xattr::Xattrs;
+
+/// The directory orphaned inodes are linked into by a repair.
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/**
+ * An inconsistency found by `fsck_device`. Inodes are identified by their
+ * number in the inode table, which starts at 1 for the root directory.
+ *
+ * Problems display as one line each: a kebab-case kind followed by
+ * `key=value` pairs, with names quoted, as in
+ * `dangling-entry dir=1 name="a" ino=7`.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FsckProblem {
+  /// The root inode isn't a directory. A repair creates an empty root.
+  BadRoot,
+  /// An inode's kind is unknown. It's treated as free.
+  BadKind { ino: u32, kind: u16 },
+  /// Entry `name` in directory `dir` names an inode that isn't in use.
+  DanglingEntry { dir: u32, name: String, ino: u32 },
+  /// Entry `name` in directory `dir` names a directory already reached from
+  /// elsewhere: either a cycle or a hard link to a directory.
+  DirectoryCycle { dir: u32, name: String, ino: u32 },
+  /// A directory's entries couldn't be decoded. It's treated as empty.
+  BadDirectory { ino: u32 },
+  /// An inode's extended attributes couldn't be decoded. A repair drops them.
+  BadXattrs { ino: u32 },
+  /// An inode's recorded link count doesn't match the entries naming it.
+  LinkCount { ino: u32, recorded: u16, actual: u32 },
+  /// An inode is in use but unreachable from the root.
+  Orphan { ino: u32 },
+  /// An inode's recorded page count doesn't match its page lists.
+  PageCount { ino: u32, recorded: u64, actual: u64 },
+  /// A file is larger than the maximum file size, or a directory than its
+  /// pages.
+  BadSize { ino: u32, size: u64 },
+  /// An inode points to a block outside the data region.
+  BadPointer { ino: u32, block: u32 },
+  /// An inode points to a block that an inode before it already claimed.
+  DuplicateBlock { ino: u32, block: u32 },
+  /// A block in use isn't marked in the bitmap.
+  UnmarkedBlock { block: u64 },
+  /// A block marked in the bitmap isn't in use.
+  LeakedBlock { block: u64 },
+  /// The superblock's free block count is wrong.
+  FreeBlocks { recorded: u64, actual: u64 },
+  /// The superblock's free inode count is wrong.
+  FreeInodes { recorded: u32, actual: u32 },
+}
+
+impl fmt::Display for FsckProblem {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    use self::FsckProblem::*;
+
+    match *self {
+      BadRoot => write!(f, "bad-root"),
+      BadKind { ino, kind } => write!(f, "bad-kind ino={} kind={}", ino, kind),
+      DanglingEntry { dir, ref name, ino } =>
+        write!(f, "dangling-entry dir={} name={:?} ino={}", dir, name, ino),
+      DirectoryCycle { dir, ref name, ino } =>
+        write!(f, "directory-cycle dir={} name={:?} ino={}", dir, name, ino),
+      BadDirectory { ino } => write!(f, "bad-directory ino={}", ino),
+      BadXattrs { ino } => write!(f, "bad-xattrs ino={}", ino),
+      LinkCount { ino, recorded, actual } =>
+        write!(f, "link-count ino={} recorded={} actual={}", ino, recorded, actual),
+      Orphan { ino } => write!(f, "orphan ino={}", ino),
+      PageCount { ino, recorded, actual } =>
+        write!(f, "page-count ino={} recorded={} actual={}", ino, recorded, actual),
+      BadSize { ino, size } => write!(f, "bad-size ino={} size={}", ino, size),
+      BadPointer { ino, block } => write!(f, "bad-pointer ino={} block={}", ino, block),
+      DuplicateBlock { ino, block } =>
+        write!(f, "duplicate-block ino={} block={}", ino, block),
+      UnmarkedBlock { block } => write!(f, "unmarked-block block={}", block),
+      LeakedBlock { block } => write!(f, "leaked-block block={}", block),
+      FreeBlocks { recorded, actual } =>
+        write!(f, "free-blocks recorded={} actual={}", recorded, actual),
+      FreeInodes { recorded, actual } =>
+        write!(f, "free-inodes recorded={} actual={}", recorded, actual),
+    }
+  }
+}
+
+/// What `fsck_device` found, and whether it rewrote the image to fix it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FsckReport {
+  pub problems: Vec<FsckProblem>,
+  pub repaired: bool,
+}
+
+impl FsckReport {
+  pub fn is_clean(&self) -> bool {
+    self.problems.is_empty()
+  }
+}
+
+/// Checks the image file at `path`. See `fsck_device`.
+pub fn fsck_image<P: AsRef<Path>>(path: P, repair: bool) -> Result<FsckReport> {
+  fsck_device(open_image(path)?, repair)
+}
+
+/**
+ * Checks the file system image on `device`, repairing it if `repair` is set
+ * and there's anything to repair. Images whose superblock or journal can't be
+ * read fail with `Corrupted`; everything past them is reported as problems.
+ */
+pub fn fsck_device(device: SharedDevice, repair: bool) -> Result<FsckReport> {
+  let mut checker = Checker::load(device)?;
+  checker.check()?;
+
+  let repaired = repair && !checker.problems.is_empty();
+  if repaired { checker.repair()?; }
+  Ok(FsckReport { problems: checker.problems, repaired })
+}
+
+// The pages of an inode: page number, block, and whether the block was claimed
+// by an earlier page, so that the page must be copied elsewhere.
+type PageMap = Vec<(usize, u32, bool)>;
+
+struct Checker {
+  device: SharedDevice,
+  superblock: Superblock,
+  bitmap: Bitmap,
+  inodes: Vec<DiskInode>,
+  root_ok: bool,
+
+  // Filled in by `check`: the blocks claimed by inodes, each inode's pages,
+  // entries and attributes, the entries that survive a repair, and the
+  // orphans.
+  claimed: Bitmap,
+  maps: Vec<PageMap>,
+  entries: Vec<Vec<(u32, String)>>,
+  xattrs: Vec<Xattrs>,
+  edges: Vec<(u32, String, u32)>,
+  orphans: Vec<u32>,
+  problems: Vec<FsckProblem>,
+}
+
+impl Checker {
+  fn load(device: SharedDevice) -> Result<Checker> {
+    let (superblock, _, metadata) = read_image(&device)?;
+    let block_size = superblock.geometry.page_size();
+    let block = |start: u64| &metadata[(start as usize * block_size)..];
+    let bitmap = Bitmap::decode(block(superblock.bitmap_start), superblock.block_count);
+    let inodes: Vec<DiskInode> = (0..superblock.inode_count as usize)
+      .map(|i| DiskInode::decode(&block(superblock.inode_table_start)[(i * INODE_SIZE)..]))
+      .collect();
+
+    let count = inodes.len();
+    Ok(Checker {
+      device,
+      superblock,
+      bitmap,
+      inodes,
+      root_ok: false,
+      claimed: Bitmap::new(superblock.block_count),
+      maps: vec![Vec::new(); count],
+      entries: vec![Vec::new(); count],
+      xattrs: vec![Xattrs::default(); count],
+      edges: Vec::new(),
+      orphans: Vec::new(),
+      problems: Vec::new(),
+    })
+  }
+
+  // The kind of inode `ino`, or `KIND_FREE` if there's no such inode.
+  fn kind(&self, ino: u32) -> u16 {
+    if ino == 0 || ino as usize > self.inodes.len() { return KIND_FREE; }
+    self.inodes[ino as usize - 1].kind
+  }
+
+  fn read_block(&self, block: u32, buf: &mut [u8]) -> Result<()> {
+    read_blocks(&mut *self.device.borrow_mut(), u64::from(block), buf)
+  }
+
+  fn check(&mut self) -> Result<()> {
+    for ino in 1..=(self.inodes.len() as u32) {
+      match self.kind(ino) {
+        KIND_FREE => {},
+        KIND_FILE | KIND_DIRECTORY => self.check_inode(ino)?,
+        kind => {
+          self.problems.push(FsckProblem::BadKind { ino, kind });
+          self.inodes[ino as usize - 1].kind = KIND_FREE;
+        }
+      }
+    }
+
+    self.check_tree();
+    self.check_blocks();
+    Ok(())
+  }
+
+  // Claims the blocks of inode `ino` and reads its attributes and, for a
+  // directory, its entries.
+  fn check_inode(&mut self, ino: u32) -> Result<()> {
+    let disk_inode = self.inodes[ino as usize - 1];
+    let geometry = self.superblock.geometry;
+    let page_size = geometry.page_size();
+    let map = self.read_map(ino, disk_inode.single, disk_inode.double)?;
+    if map.len() as u64 != disk_inode.pages {
+      self.problems.push(FsckProblem::PageCount { ino, recorded: disk_inode.pages,
+                                                  actual: map.len() as u64 });
+    }
+
+    if disk_inode.kind == KIND_FILE {
+      if disk_inode.size > geometry.max_file_size() as u64 {
+        self.problems.push(FsckProblem::BadSize { ino, size: disk_inode.size });
+      }
+    } else {
+      let mut data = vec![0u8; map.last().map_or(0, |&(num, _, _)| (num + 1) * page_size)];
+      for &(num, block, _) in map.iter() {
+        let start = num * page_size;
+        self.read_block(block, &mut data[start..(start + page_size)])?;
+      }
+
+      if disk_inode.size > data.len() as u64 {
+        self.problems.push(FsckProblem::BadSize { ino, size: disk_inode.size });
+      }
+
+      data.truncate(disk_inode.size as usize);
+      match decode_entries(&data) {
+        Ok(entries) => self.entries[ino as usize - 1] = entries,
+        Err(_) => self.problems.push(FsckProblem::BadDirectory { ino })
+      }
+    }
+
+    if disk_inode.xattrs != 0 && self.claim(ino, disk_inode.xattrs).is_some() {
+      let mut buf = vec![0u8; page_size];
+      self.read_block(disk_inode.xattrs, &mut buf)?;
+      match decode_xattrs(&buf) {
+        Ok(xattrs) => self.xattrs[ino as usize - 1] = Xattrs::from(xattrs),
+        Err(_) => self.problems.push(FsckProblem::BadXattrs { ino })
+      }
+    }
+
+    self.maps[ino as usize - 1] = map;
+    Ok(())
+  }
+
+  // Marks `block` as claimed by inode `ino`. Returns whether it was already
+  // claimed, or `None` if it's not a data block.
+  fn claim(&mut self, ino: u32, block: u32) -> Option<bool> {
+    if !self.superblock.is_data_block(block) {
+      self.problems.push(FsckProblem::BadPointer { ino, block });
+      return None;
+    }
+
+    let claimed = self.claimed.get(u64::from(block));
+    if claimed {
+      self.problems.push(FsckProblem::DuplicateBlock { ino, block });
+    }
+
+    self.claimed.set(u64::from(block));
+    Some(claimed)
+  }
+
+  // Reads the list block `block` of inode `ino`, unless it's a bad pointer.
+  fn read_list(&mut self, ino: u32, block: u32) -> Result<Option<Vec<u32>>> {
+    if self.claim(ino, block).is_none() { return Ok(None); }
+    let mut buf = vec![0u8; self.superblock.geometry.page_size()];
+    self.read_block(block, &mut buf)?;
+    Ok(Some(decode_list(&buf, self.superblock.geometry.list_size())))
+  }
+
+  // Like `Image::read_map`, but skipping bad pointers rather than failing.
+  fn read_map(&mut self, ino: u32, single: u32, double: u32) -> Result<PageMap> {
+    let list_size = self.superblock.geometry.list_size();
+    let mut lists = Vec::new();
+    if single != 0 {
+      if let Some(list) = self.read_list(ino, single)? { lists.push((0, list)); }
+    }
+
+    if double != 0 {
+      if let Some(list_blocks) = self.read_list(ino, double)? {
+        for (slot, &list_block) in list_blocks.iter().enumerate() {
+          if list_block == 0 { continue; }
+          if let Some(list) = self.read_list(ino, list_block)? {
+            lists.push((list_size + slot * list_size, list));
+          }
+        }
+      }
+    }
+
+    let mut map = Vec::new();
+    for (base, list) in lists {
+      for (i, block) in list.into_iter().enumerate() {
+        if block == 0 { continue; }
+        if let Some(claimed) = self.claim(ino, block) {
+          map.push((base + i, block, claimed));
+        }
+      }
+    }
+
+    Ok(map)
+  }
+
+  // Walks the tree from the root, then from each orphan, checking link counts
+  // of everything reachable from the root.
+  fn check_tree(&mut self) {
+    let count = self.inodes.len();
+    let mut reached = vec![false; count];
+    let mut refs = vec![0u32; count];
+
+    self.root_ok = self.kind(ROOT_INO) == KIND_DIRECTORY;
+    if self.root_ok {
+      reached[ROOT_INO as usize - 1] = true;
+      refs[ROOT_INO as usize - 1] = 1;
+      self.walk(ROOT_INO, &mut reached, &mut refs);
+    } else {
+      self.problems.push(FsckProblem::BadRoot);
+    }
+
+    for ino in 1..=(count as u32) {
+      let (nlink, actual) = (self.inodes[ino as usize - 1].nlink, refs[ino as usize - 1]);
+      if reached[ino as usize - 1] && u32::from(nlink) != actual {
+        self.problems.push(FsckProblem::LinkCount { ino, recorded: nlink, actual });
+      }
+    }
+
+    // Orphans named by other orphaned directories are found by walking those,
+    // so walk from the ones nothing names first. Anything left is in a cycle
+    // of unreachable directories.
+    let unreached = |checker: &Checker, reached: &[bool], ino: u32| {
+      checker.kind(ino) != KIND_FREE && !reached[ino as usize - 1]
+    };
+
+    let named: HashSet<u32> = (1..=(count as u32))
+      .filter(|&ino| unreached(self, &reached, ino) && self.kind(ino) == KIND_DIRECTORY)
+      .flat_map(|ino| self.entries[ino as usize - 1].iter().map(|&(entry, _)| entry))
+      .collect();
+
+    for &top_level in [true, false].iter() {
+      for ino in 1..=(count as u32) {
+        if !unreached(self, &reached, ino) || (top_level && named.contains(&ino)) {
+          continue;
+        }
+
+        self.problems.push(FsckProblem::Orphan { ino });
+        self.orphans.push(ino);
+        reached[ino as usize - 1] = true;
+        if self.kind(ino) == KIND_DIRECTORY { self.walk(ino, &mut reached, &mut refs); }
+      }
+    }
+  }
+
+  // Walks the directories below `dir` breadth first, recording the entries
+  // worth keeping as edges.
+  fn walk(&mut self, dir: u32, reached: &mut [bool], refs: &mut [u32]) {
+    let mut queue = VecDeque::new();
+    queue.push_back(dir);
+    while let Some(dir) = queue.pop_front() {
+      for (ino, name) in self.entries[dir as usize - 1].clone() {
+        let kind = self.kind(ino);
+        if kind == KIND_FREE {
+          self.problems.push(FsckProblem::DanglingEntry { dir, name, ino });
+          continue;
+        }
+
+        let index = ino as usize - 1;
+        if kind == KIND_DIRECTORY && reached[index] {
+          self.problems.push(FsckProblem::DirectoryCycle { dir, name, ino });
+          continue;
+        }
+
+        if !reached[index] {
+          reached[index] = true;
+          if kind == KIND_DIRECTORY { queue.push_back(ino); }
+        }
+
+        refs[index] += 1;
+        self.edges.push((dir, name, ino));
+      }
+    }
+  }
+
+  // Compares the bitmap and free counts with what's actually in use.
+  fn check_blocks(&mut self) {
+    let superblock = self.superblock;
+    let mut used = self.claimed.clone();
+    for block in 0..superblock.data_start { used.set(block); }
+
+    for block in 0..superblock.block_count {
+      match (used.get(block), self.bitmap.get(block)) {
+        (true, false) => self.problems.push(FsckProblem::UnmarkedBlock { block }),
+        (false, true) => self.problems.push(FsckProblem::LeakedBlock { block }),
+        _ => {}
+      }
+    }
+
+    let free_blocks = superblock.block_count - used.count_set();
+    if free_blocks != superblock.free_blocks {
+      self.problems.push(FsckProblem::FreeBlocks { recorded: superblock.free_blocks,
+                                                   actual: free_blocks });
+    }
+
+    let in_use = self.inodes.iter().filter(|inode| inode.kind != KIND_FREE).count();
+    let free_inodes = superblock.inode_count - in_use as u32;
+    if free_inodes != superblock.free_inodes {
+      self.problems.push(FsckProblem::FreeInodes { recorded: superblock.free_inodes,
+                                                   actual: free_inodes });
+    }
+  }
+
+  // Rebuilds the tree from the edges and orphans found by `check`, and writes
+  // it back over the image.
+  fn repair(&self) -> Result<()> {
+    let mut files: Vec<Option<File>> = vec![None; self.inodes.len()];
+    for ino in 1..=(self.inodes.len() as u32) {
+      files[ino as usize - 1] = match self.kind(ino) {
+        KIND_FILE => Some(self.load_file(ino)?),
+        KIND_DIRECTORY => Some(File::new_dir(None)),
+        _ => None
+      };
+    }
+
+    let root = match self.root_ok {
+      true => files[ROOT_INO as usize - 1].clone().unwrap(),
+      false => File::new_dir(None)
+    };
+
+    let file = |ino: u32| files[ino as usize - 1].clone().unwrap();
+    for (i, xattrs) in self.xattrs.iter().enumerate() {
+      if let Some(ref file) = files[i] {
+        file.with_xattrs(|attrs| *attrs = xattrs.clone());
+      }
+    }
+
+    for &(dir, ref name, ino) in self.edges.iter() {
+      let dir = file(dir);
+      dir.get_dir_rc().borrow_mut().entries.insert(Cow::Owned(name.clone()), file(ino));
+    }
+
+    if !self.orphans.is_empty() {
+      let lost_and_found = lost_and_found(&root);
+      let mut content = lost_and_found.get_dir_rc().borrow_mut();
+      for &ino in self.orphans.iter() {
+        let name = unused_name(&content.entries, format!("#{}", ino));
+        content.entries.insert(Cow::Owned(name), file(ino));
+      }
+    }
+
+    // Nothing the image currently uses is overwritten outside the journal.
+    let mut keep = self.bitmap.clone();
+    for block in 0..self.superblock.block_count {
+      if self.claimed.get(block) { keep.set(block); }
+    }
+
+    FileSystem::rewrite(self.device.clone(), self.superblock, root, keep)
+  }
+
+  // Loads file inode `ino` with its pages left on the device, other than
+  // those whose blocks another page claimed first, which are read in.
+  fn load_file<'r>(&self, ino: u32) -> Result<File<'r>> {
+    let disk_inode = self.inodes[ino as usize - 1];
+    let geometry = self.superblock.geometry;
+    let mut inode = Inode::with_geometry(geometry);
+
+    for &(num, block, claimed) in self.maps[ino as usize - 1].iter() {
+      let page = if claimed {
+        let mut data = vec![0u8; geometry.page_size()];
+        self.read_block(block, &mut data)?;
+        Page::from_data(data.into_boxed_slice())
+      } else {
+        Page::on_device(block)
+      };
+
+      inode.insert_page(num, page)?;
+    }
+
+    let size = cmp::min(disk_inode.size, geometry.max_file_size() as u64);
+    inode.set_owner(Owner { uid: disk_inode.uid, gid: disk_inode.gid })?;
+    inode.set_device(self.device.clone());
+    inode.restore(size as usize, disk_inode.create_time, disk_inode.access_time,
+                  disk_inode.mod_time);
+    Ok(File::new_data_file(Rc::new(RefCell::new(Box::new(inode)))))
+  }
+}
+
+// Finds or creates the lost+found directory under `root`.
+fn lost_and_found<'r>(root: &File<'r>) -> File<'r> {
+  let mut content = root.get_dir_rc().borrow_mut();
+  if let Some(dir @ &Directory(_)) = content.entries.get(LOST_AND_FOUND) {
+    return dir.clone();
+  }
+
+  let name = unused_name(&content.entries, LOST_AND_FOUND.to_string());
+  let dir = File::new_dir(None);
+  content.entries.insert(Cow::Owned(name), dir.clone());
+  dir
+}
+
+// `name`, or `name` with the first numeric suffix that's not taken.
+fn unused_name<T>(entries: &HashMap<Cow<str>, T>, name: String) -> String {
+  (0..).map(|i| match i {
+    0 => name.clone(),
+    i => format!("{}.{}", name, i)
+  }).find(|name| !entries.contains_key(name.as_str())).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate rand;
+
+  use std::rc::Rc;
+  use std::cell::RefCell;
+  use self::rand::random;
+  use super::{fsck_device, FsckProblem, LOST_AND_FOUND};
+  use device::{SharedDevice, MemDevice};
+  use directory::DirectoryHandle;
+  use disk::{Superblock, DiskInode, INODE_SIZE};
+  use disk::{encode_list, decode_list, encode_entries, decode_entries};
+  use file::File;
+  use fs::FileSystem;
+  use inode::{Inode, Geometry};
+  use xattr::Xattrs;
+
+  const PAGE_SIZE: usize = 512;
+
+  fn rand_array(size: usize) -> Vec<u8> {
+    (0..size).map(|_| random::<u8>()).collect()
+  }
+
+  fn new_file<'r>(data: &[u8]) -> File<'r> {
+    let mut inode = Inode::with_geometry(Geometry::new(PAGE_SIZE, 16).unwrap());
+    inode.write(0, data).unwrap();
+    File::new_data_file(Rc::new(RefCell::new(Box::new(inode))))
+  }
+
+  fn contents(file: &File) -> Vec<u8> {
+    let mut inode = file.get_inode_rc().borrow_mut();
+    let mut buf = vec![0u8; inode.size()];
+    inode.read(0, &mut buf).unwrap();
+    buf
+  }
+
+  // Follows `path` from the root of the file system on `device`.
+  fn lookup(device: &SharedDevice, path: &[&'static str]) -> Option<File<'static>> {
+    let fs = FileSystem::mount_device(device.clone()).unwrap();
+    path.iter().try_fold(fs.root(), |dir, name| dir.get(name))
+  }
+
+  // Builds an image holding files "a", with an extended attribute, and "d/b",
+  // and an empty directory "e".
+  // Flushing numbers them breadth first: the root is 1, "a" 2, "d" 3, "e" 4
+  // and "d/b" 5.
+  fn build(a: &[u8], b: &[u8]) -> SharedDevice {
+    let device: SharedDevice = Rc::new(RefCell::new(MemDevice::new(PAGE_SIZE, 512)));
+    let geometry = Geometry::new(PAGE_SIZE, 16).unwrap();
+    FileSystem::format_device(device.clone(), geometry, 32).unwrap();
+
+    let fs = FileSystem::mount_device(device.clone()).unwrap();
+    let mut root = fs.root();
+    let mut d = File::new_dir(None);
+    d.insert("b", new_file(b));
+    root.insert("a", new_file(a));
+    root.insert("d", d);
+    root.insert("e", File::new_dir(None));
+    let tag = |xattrs: &mut Xattrs| xattrs.set("user.tag", b"1", 0, PAGE_SIZE);
+    root.get("a").unwrap().with_xattrs(tag).unwrap();
+    fs.unmount().unwrap();
+
+    assert!(fsck_device(device.clone(), false).unwrap().is_clean());
+    device
+  }
+
+  fn read(device: &SharedDevice, block: u64) -> Vec<u8> {
+    let mut buf = vec![0u8; PAGE_SIZE];
+    device.borrow_mut().read_block(block, &mut buf).unwrap();
+    buf
+  }
+
+  fn write(device: &SharedDevice, block: u64, buf: &[u8]) {
+    device.borrow_mut().write_block(block, buf).unwrap();
+  }
+
+  fn superblock(device: &SharedDevice) -> Superblock {
+    Superblock::decode(&read(device, 0)).unwrap()
+  }
+
+  // The block and offset of inode `ino` in the inode table.
+  fn inode_at(device: &SharedDevice, ino: u32) -> (u64, usize) {
+    let at = (ino as usize - 1) * INODE_SIZE;
+    (superblock(device).inode_table_start + (at / PAGE_SIZE) as u64, at % PAGE_SIZE)
+  }
+
+  fn get_inode(device: &SharedDevice, ino: u32) -> DiskInode {
+    let (block, offset) = inode_at(device, ino);
+    DiskInode::decode(&read(device, block)[offset..])
+  }
+
+  fn set_inode(device: &SharedDevice, ino: u32, inode: DiskInode) {
+    let (block, offset) = inode_at(device, ino);
+    let mut buf = read(device, block);
+    inode.encode(&mut buf[offset..]);
+    write(device, block, &buf);
+  }
+
+  // The block holding the first page of inode `ino`.
+  fn first_page(device: &SharedDevice, ino: u32) -> u64 {
+    let list = decode_list(&read(device, u64::from(get_inode(device, ino).single)), 16);
+    u64::from(list[0])
+  }
+
+  fn get_entries(device: &SharedDevice, ino: u32) -> Vec<(u32, String)> {
+    let size = get_inode(device, ino).size as usize;
+    decode_entries(&read(device, first_page(device, ino))[..size]).unwrap()
+  }
+
+  fn set_entries(device: &SharedDevice, ino: u32, entries: &[(u32, &str)]) {
+    let mut data = encode_entries(entries);
+    let size = data.len() as u64;
+    data.resize(PAGE_SIZE, 0);
+    write(device, first_page(device, ino), &data);
+    set_inode(device, ino, DiskInode { size, ..get_inode(device, ino) });
+  }
+
+  // Repairs the image on `device`, which must have `problems`, and checks
+  // that it's clean afterwards.
+  fn repair(device: &SharedDevice, problems: &[FsckProblem]) {
+    let report = fsck_device(device.clone(), true).unwrap();
+    assert_eq!(report.problems, problems);
+    assert!(report.repaired);
+
+    let report = fsck_device(device.clone(), true).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+    assert!(!report.repaired);
+  }
+
+  #[test]
+  fn test_link_count() {
+    let (a, b) = (rand_array(PAGE_SIZE * 20), rand_array(100));
+    let device = build(&a, &b);
+    set_inode(&device, 2, DiskInode { nlink: 3, ..get_inode(&device, 2) });
+    set_inode(&device, 5, DiskInode { pages: 4, ..get_inode(&device, 5) });
+
+    let report = fsck_device(device.clone(), false).unwrap();
+    assert_eq!(report.problems.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+               ["page-count ino=5 recorded=4 actual=1",
+                "link-count ino=2 recorded=3 actual=1"]);
+    assert!(!report.repaired);
+
+    repair(&device, &report.problems);
+    assert_eq!(contents(&lookup(&device, &["a"]).unwrap()), a);
+    assert_eq!(contents(&lookup(&device, &["d", "b"]).unwrap()), b);
+  }
+
+  #[test]
+  fn test_orphan_and_dangling_entry() {
+    let (a, b) = (rand_array(PAGE_SIZE * 3), rand_array(PAGE_SIZE + 1));
+    let device = build(&a, &b);
+
+    // Replace the root's entry for "d" with one for a free inode.
+    assert_eq!(get_entries(&device, 1), [(2, "a".to_string()), (3, "d".to_string()),
+                                         (4, "e".to_string())]);
+    set_entries(&device, 1, &[(2, "a"), (9, "x"), (4, "e")]);
+
+    repair(&device, &[
+      FsckProblem::DanglingEntry { dir: 1, name: "x".to_string(), ino: 9 },
+      FsckProblem::Orphan { ino: 3 },
+    ]);
+
+    assert!(lookup(&device, &["d"]).is_none());
+    assert!(lookup(&device, &["x"]).is_none());
+    assert_eq!(contents(&lookup(&device, &["a"]).unwrap()), a);
+    let b_file = lookup(&device, &[LOST_AND_FOUND, "#3", "b"]).unwrap();
+    assert_eq!(contents(&b_file), b);
+  }
+
+  #[test]
+  fn test_directory_cycle() {
+    let (a, b) = (rand_array(10), rand_array(10));
+    let device = build(&a, &b);
+
+    // Unlink "d" from the root, and link it to itself and to the root.
+    set_entries(&device, 1, &[(2, "a"), (4, "e")]);
+    set_entries(&device, 3, &[(5, "b"), (3, "self"), (1, "up")]);
+
+    repair(&device, &[
+      FsckProblem::Orphan { ino: 3 },
+      FsckProblem::DirectoryCycle { dir: 3, name: "self".to_string(), ino: 3 },
+      FsckProblem::DirectoryCycle { dir: 3, name: "up".to_string(), ino: 1 },
+    ]);
+
+    let d = lookup(&device, &[LOST_AND_FOUND, "#3"]).unwrap();
+    assert!(d.get("self").is_none() && d.get("up").is_none());
+    assert_eq!(contents(&d.get("b").unwrap()), b);
+  }
+
+  #[test]
+  fn test_duplicate_block() {
+    let (a, b) = (rand_array(PAGE_SIZE * 2), rand_array(PAGE_SIZE * 2));
+    let device = build(&a, &b);
+
+    // Point "d/b" at the page list of "a", leaking the blocks of its own.
+    let (a_inode, b_inode) = (get_inode(&device, 2), get_inode(&device, 5));
+    let a_pages = decode_list(&read(&device, u64::from(a_inode.single)), 16);
+    let b_pages = decode_list(&read(&device, u64::from(b_inode.single)), 16);
+    set_inode(&device, 5, DiskInode { single: a_inode.single, ..b_inode });
+
+    let mut problems = vec![FsckProblem::DuplicateBlock { ino: 5, block: a_inode.single }];
+    problems.extend(a_pages[..2].iter().map(|&block| {
+      FsckProblem::DuplicateBlock { ino: 5, block }
+    }));
+
+    let mut leaked = vec![u64::from(b_inode.single)];
+    leaked.extend(b_pages[..2].iter().map(|&block| u64::from(block)));
+    leaked.sort();
+    problems.extend(leaked.into_iter().map(|block| FsckProblem::LeakedBlock { block }));
+    let free_blocks = superblock(&device).free_blocks;
+    problems.push(FsckProblem::FreeBlocks { recorded: free_blocks,
+                                            actual: free_blocks + 3 });
+    repair(&device, &problems);
+
+    // "d/b" now has its own copy of "a".
+    let fs = FileSystem::mount_device(device.clone()).unwrap();
+    let b_file = fs.root().get("d").unwrap().get("b").unwrap();
+    assert_eq!(contents(&b_file), a);
+    b_file.get_inode_rc().borrow_mut().write(0, &b).unwrap();
+    fs.unmount().unwrap();
+
+    assert!(fsck_device(device.clone(), false).unwrap().is_clean());
+    assert_eq!(contents(&lookup(&device, &["a"]).unwrap()), a);
+    assert_eq!(contents(&lookup(&device, &["d", "b"]).unwrap()), b);
+  }
+
+  #[test]
+  fn test_bitmap_and_bad_pointer() {
+    let (a, b) = (rand_array(PAGE_SIZE * 2), rand_array(10));
+    let device = build(&a, &b);
+    let superblock = superblock(&device);
+
+    // Clear the bit of the first page of "a", and point "d/b" into the
+    // inode table.
+    let page = first_page(&device, 2);
+    let mut bitmap = read(&device, superblock.bitmap_start);
+    bitmap[page as usize / 8] &= !(1 << (page % 8));
+    write(&device, superblock.bitmap_start, &bitmap);
+
+    let list = get_inode(&device, 5).single;
+    let mut pages = decode_list(&read(&device, u64::from(list)), 16);
+    let old = pages[0] as u64;
+    pages[0] = superblock.inode_table_start as u32;
+    let mut buf = vec![0u8; PAGE_SIZE];
+    encode_list(&pages, &mut buf);
+    write(&device, u64::from(list), &buf);
+
+    repair(&device, &[
+      FsckProblem::BadPointer { ino: 5, block: superblock.inode_table_start as u32 },
+      FsckProblem::PageCount { ino: 5, recorded: 1, actual: 0 },
+      FsckProblem::UnmarkedBlock { block: page },
+      FsckProblem::LeakedBlock { block: old },
+      FsckProblem::FreeBlocks { recorded: superblock.free_blocks,
+                                actual: superblock.free_blocks + 1 },
+    ]);
+
+    assert_eq!(contents(&lookup(&device, &["a"]).unwrap()), a);
+    assert_eq!(contents(&lookup(&device, &["d", "b"]).unwrap()), vec![0u8; 10]);
+  }
+
+  #[test]
+  fn test_bad_xattrs() {
+    let (a, b) = (rand_array(10), rand_array(10));
+    let device = build(&a, &b);
+    let block = u64::from(get_inode(&device, 2).xattrs);
+    assert!(block != 0);
+    write(&device, block, &[0xff; PAGE_SIZE]);
+
+    repair(&device, &[FsckProblem::BadXattrs { ino: 2 }]);
+    let a_file = lookup(&device, &["a"]).unwrap();
+    assert!(a_file.with_xattrs(|xattrs| xattrs.is_empty()));
+    assert_eq!(contents(&a_file), a);
+  }
+}
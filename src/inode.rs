@@ -1,35 +1,240 @@
 use time;
 use time::Timespec;
+use std::cmp;
+use std::fmt;
 use std::mem;
-use std::ptr;
+use std::ops::{Deref, DerefMut};
 use std::ptr::copy_nonoverlapping;
+use std::rc::Rc;
+use device::SharedDevice;
+use error::{Error, Result};
+use lock::Locks;
+use quota::Owner;
+use pool::{PageBuf, PageList};
+use cache::PageCache;
+use space::SharedSpace;
+use xattr::Xattrs;
 
-const PAGE_SIZE: usize = 4096;
-const LIST_SIZE: usize = 256;
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
+pub const DEFAULT_LIST_SIZE: usize = 256;
+pub const MIN_PAGE_SIZE: usize = 512;
+pub const MAX_PAGE_SIZE: usize = 65536;
 
-type Page = Box<([u8; PAGE_SIZE])>;
-type Entry = Page;
-type EntryList = TList<Entry>; // TODO: Option<TList> for lazy loading
+type EntryList = PageList; // TODO: Option<TList> for lazy loading
 type DoubleEntryList = TList<EntryList>;
-pub type TList<T> = Box<([Option<T>; LIST_SIZE])>;
+pub type TList<T> = Box<[Option<T>]>;
+
+// What holes read as, for page slices of them.
+static ZEROS: [u8; MAX_PAGE_SIZE] = [0; MAX_PAGE_SIZE];
 
 #[inline(always)]
 fn ceil_div(x: usize, y: usize) -> usize {
-  return (x + y - 1) / y;
+  x.div_ceil(y)
 }
 
 #[inline(always)]
-pub fn create_tlist<T>() -> TList<T> {
-  let mut list: TList<T> = Box::new(unsafe { mem::uninitialized() });
-  for x in list.iter_mut() { unsafe { ptr::write(x, None); } };
-  list
+pub fn create_tlist<T>(size: usize) -> TList<T> {
+  (0..size).map(|_| None).collect::<Vec<_>>().into_boxed_slice()
+}
+
+/**
+ * The page size and indirection fan-out of a file system. Both are fixed when
+ * the file system is created and shared by every inode in it.
+ *
+ * The page size must be a power of two between `MIN_PAGE_SIZE` and
+ * `MAX_PAGE_SIZE`. The list size is the number of entries in each page list;
+ * it must be at least 2 and small enough that a list of 32-bit page numbers
+ * fits in a single page.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Geometry {
+  page_size: usize,
+  list_size: usize,
+}
+
+impl Geometry {
+  pub fn new(page_size: usize, list_size: usize) -> Result<Geometry> {
+    if !page_size.is_power_of_two()
+        || !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) {
+      return Err(Error::InvalidArgument);
+    }
+
+    if list_size < 2 || list_size > page_size / 4 {
+      return Err(Error::InvalidArgument);
+    }
+
+    Ok(Geometry { page_size, list_size })
+  }
+
+  #[inline(always)]
+  pub fn page_size(&self) -> usize {
+    self.page_size
+  }
+
+  #[inline(always)]
+  pub fn list_size(&self) -> usize {
+    self.list_size
+  }
+
+  /// The number of pages addressable through the singly and doubly indirect
+  /// lists.
+  #[inline(always)]
+  pub fn max_pages(&self) -> usize {
+    self.list_size + self.list_size * self.list_size
+  }
+
+  pub fn max_file_size(&self) -> usize {
+    self.max_pages() * self.page_size
+  }
+}
+
+impl Default for Geometry {
+  fn default() -> Geometry {
+    Geometry { page_size: DEFAULT_PAGE_SIZE, list_size: DEFAULT_LIST_SIZE }
+  }
+}
+
+/**
+ * A page of file data, along with the block holding it on the file system's
+ * device. Block 0 is never a data block, so it marks pages not yet written
+ * out. Pages of a mounted file system aren't resident until first used, and
+ * are dirty from when they're changed until they're next written out.
+ *
+ * Clones of a page, as held by snapshots, share its contents; whichever is
+ * changed first gets its own copy.
+ */
+#[derive(Clone)]
+pub struct Page {
+  data: Option<Rc<PageBuf>>,
+  block: u32,
+  dirty: bool,
+}
+
+impl Page {
+  fn new(buf: PageBuf) -> Page {
+    Page { data: Some(Rc::new(buf)), block: 0, dirty: true }
+  }
+
+  /// A page holding `data` that hasn't been written out yet.
+  pub(crate) fn from_data(data: Box<[u8]>) -> Page {
+    Page::new(PageBuf::unpooled(data))
+  }
+
+  /// A page whose contents are still in `block` of the device.
+  pub(crate) fn on_device(block: u32) -> Page {
+    Page { data: None, block, dirty: false }
+  }
+
+  pub(crate) fn is_dirty(&self) -> bool {
+    self.dirty
+  }
+
+  pub(crate) fn is_resident(&self) -> bool {
+    self.data.is_some()
+  }
+
+  pub(crate) fn block(&self) -> u32 {
+    self.block
+  }
+
+  pub(crate) fn set_block(&mut self, block: u32) {
+    self.block = block;
+  }
+}
+
+/**
+ * Part of a page of file data, as returned by `Inode::read_slices`. It shares
+ * the page's buffer rather than copying it out; if the file then changes the
+ * page, the file gets its own copy, so the slice keeps what was read. Slices
+ * of holes read as zeros.
+ */
+#[derive(Clone)]
+pub struct PageSlice {
+  data: Option<Rc<PageBuf>>,
+  start: usize,
+  end: usize,
+}
+
+impl Deref for PageSlice {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    match self.data {
+      Some(ref data) => &data[self.start..self.end],
+      None => &ZEROS[self.start..self.end]
+    }
+  }
+}
+
+impl fmt::Debug for PageSlice {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("PageSlice")
+      .field("len", &self.len())
+      .field("hole", &self.data.is_none())
+      .finish()
+  }
+}
+
+/// The blocks holding an inode's page lists as of the last flush: the singly
+/// and doubly-indirect lists, and every list block in use.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ListBlocks {
+  pub single: u32,
+  pub double: u32,
+  pub blocks: Vec<u32>,
+}
+
+impl Deref for Page {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    self.data.as_ref().expect("page is not resident")
+  }
+}
+
+impl DerefMut for Page {
+  fn deref_mut(&mut self) -> &mut [u8] {
+    let data = self.data.as_mut().expect("page is not resident");
+    if Rc::get_mut(data).is_none() { *data = Rc::new(data.duplicate()); }
+    Rc::get_mut(data).unwrap()
+  }
+}
+
+/// File metadata as reported by `Proc::stat`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stat {
+  pub uid: u32,
+  pub gid: u32,
+  pub size: usize,
+  pub blksize: usize,
+  pub blocks: usize,
+  pub create_time: Timespec,
+  pub access_time: Timespec,
+  pub mod_time: Timespec,
 }
 
 pub struct Inode {
-  single: EntryList, // Box<([Option<Page>, ..256])>
-  double: DoubleEntryList, // Box<[Option<Box<([Option<Page>>, ..256])>, ..256]
+  geometry: Geometry,
+  device: Option<SharedDevice>,
+  single: EntryList, // Box<[Option<Page>]>
+  double: DoubleEntryList, // Box<[Option<Box<[Option<Page>]>>]>
   size: usize,
+  pages: usize,
+
+  // What was last flushed: the page lists, which are stale once a page comes
+  // or goes, and the size.
+  lists: Option<ListBlocks>,
+  synced_size: usize,
+  // Whether pages were written back to the device, to evict them, since the
+  // last flush; the device may not have made them durable yet.
+  written_back: bool,
 
+  // What the inode's pages are charged to, once it's in a file system's tree,
+  // and whose quotas they count against.
+  space: Option<SharedSpace>,
+  owner: Owner,
+  xattrs: Xattrs,
+  locks: Locks,
   mod_time: Timespec,
   access_time: Timespec,
   create_time: Timespec,
@@ -37,78 +242,254 @@ pub struct Inode {
 
 impl Inode {
   pub fn new() -> Inode {
+    Inode::with_geometry(Geometry::default())
+  }
+
+  pub fn with_geometry(geometry: Geometry) -> Inode {
+    Inode::with_single(geometry, PageList::unpooled(geometry.list_size))
+  }
+
+  /// A new inode of `owner` charged to `space`, with its pages and page lists
+  /// coming from the space's pool. Fails as `set_space` does.
+  pub(crate) fn in_space(space: &SharedSpace, geometry: Geometry, owner: Owner)
+      -> Result<Inode> {
+    let mut inode = Inode::with_single(geometry, space.pool().alloc_list());
+    inode.owner = owner;
+    inode.set_space(space, false)?;
+    Ok(inode)
+  }
+
+  fn with_single(geometry: Geometry, single: EntryList) -> Inode {
     let time_now = time::get_time();
 
     Inode {
-      single: create_tlist(),
-      double: create_tlist(),
+      geometry,
+      device: None,
+      single,
+      double: create_tlist(geometry.list_size),
       size: 0,
+      pages: 0,
 
+      lists: None,
+      synced_size: 0,
+      written_back: false,
+
+      space: None,
+      owner: Owner::default(),
+      xattrs: Xattrs::default(),
+      locks: Locks::default(),
       mod_time: time_now,
       access_time: time_now,
       create_time: time_now
     }
   }
 
-  fn get_or_alloc_page<'a>(&'a mut self, num: usize) -> &'a mut Page {
-    if num >= LIST_SIZE + LIST_SIZE * LIST_SIZE {
-      panic!("Maximum file size exceeded!")
+  fn get_or_alloc_page(&mut self, num: usize) -> Result<&mut Page> {
+    if num >= self.geometry.max_pages() { return Err(Error::FileTooLarge); }
+
+    self.load_page(num)?;
+    if self.get_page(num).is_none() {
+      let page = Page::new(self.alloc_buf());
+      self.insert_page(num, page)?;
+    }
+
+    Ok(self.get_slot_mut(num).and_then(|slot| slot.as_mut()).unwrap())
+  }
+
+  // Reads page `num` from the device if it exists but isn't resident yet.
+  fn load_page(&mut self, num: usize) -> Result<()> {
+    if self.device.is_none() { return Ok(()); }
+    match self.get_page(num) {
+      Some(page) if page.is_resident() => {
+        if let Some(cache) = self.cache() { cache.hit(self, num); }
+        Ok(())
+      }
+      Some(_) => self.read_in(num, false),
+      None => Ok(())
+    }
+  }
+
+  // Reads page `num`, which isn't resident, from the device: ahead of its use
+  // if `ahead`.
+  fn read_in(&mut self, num: usize, ahead: bool) -> Result<()> {
+    let device = match self.device {
+      Some(ref device) => device.clone(),
+      None => return Ok(())
     };
 
-    // Getting a pointer to the page
-    let page = if num < LIST_SIZE {
-      // if the page num is in the singly-indirect list
-      &mut self.single[num]
+    let mut data = self.alloc_buf();
+    if let Some(&mut Some(ref mut page)) = self.get_slot_mut(num) {
+      device.borrow_mut().read_block(u64::from(page.block), &mut data)?;
+      page.data = Some(Rc::new(data));
+    }
+
+    if let Some(cache) = self.cache() { cache.miss(self, num, ahead); }
+    Ok(())
+  }
+
+  // The page cache, if the inode's pages are read from a device and so may be
+  // cached. See the `cache` module.
+  fn cache(&self) -> Option<&PageCache> {
+    match (&self.device, &self.space) {
+      (Some(_), Some(space)) => Some(space.cache()),
+      _ => None
+    }
+  }
+
+  // A zeroed buffer for a page, from the pool once the inode is in a file
+  // system.
+  fn alloc_buf(&self) -> PageBuf {
+    match self.space {
+      Some(ref space) => space.pool().alloc_page(),
+      None => PageBuf::unpooled(vec![0u8; self.geometry.page_size].into_boxed_slice())
+    }
+  }
+
+  fn get_page(&self, num: usize) -> Option<&Page> {
+    let list_size = self.geometry.list_size;
+    if num >= self.geometry.max_pages() { return None; }
+
+    if num < list_size {
+      self.single[num].as_ref()
     } else {
-      // if the page num is in the doubly-indirect list. We allocate a new
-      // entry list where necessary (*entry_list = ...)
-      let double_entry = num - LIST_SIZE;
-      let slot = double_entry / LIST_SIZE;
-      let entry_list = &mut self.double[slot];
-
-      match *entry_list {
-        None => *entry_list = Some(create_tlist()),
-        _ => { /* Do nothing */ }
+      let double_entry = num - list_size;
+      let slot = double_entry / list_size;
+      let entry_offset = double_entry % list_size;
+
+      match self.double[slot] {
+        None => None,
+        Some(ref list) => list[entry_offset].as_ref()
       }
+    }
+  }
+
+  // Returns the slot for page `num` if the list holding it exists.
+  fn get_slot_mut(&mut self, num: usize) -> Option<&mut Option<Page>> {
+    let list_size = self.geometry.list_size;
+    if num < list_size {
+      Some(&mut self.single[num])
+    } else {
+      let double_entry = num - list_size;
+      let slot = double_entry / list_size;
+      let entry_offset = double_entry % list_size;
+      self.double[slot].as_mut().map(|list| &mut list[entry_offset])
+    }
+  }
 
-      let entry_offset = double_entry % LIST_SIZE;
-      &mut entry_list.as_mut().unwrap()[entry_offset]
+  // Frees page `num`, along with its doubly-indirect entry list if the list no
+  // longer holds any pages.
+  fn release_page(&mut self, num: usize) {
+    let released = match self.get_slot_mut(num) {
+      Some(slot) => slot.take().is_some(),
+      None => false
     };
 
-    match *page {
-      None => *page = Some(Box::new([0u8; 4096])),
-      _ => { /* Do Nothing */ }
+    if !released { return; }
+    self.pages -= 1;
+    self.lists = None;
+    if let Some(cache) = self.cache() { cache.remove(self, num); }
+    if let Some(ref space) = self.space { space.release_pages(self.owner, 1); }
+
+    let list_size = self.geometry.list_size;
+    if num >= list_size {
+      let slot = (num - list_size) / list_size;
+      let empty = match self.double[slot] {
+        Some(ref list) => list.iter().all(|page| page.is_none()),
+        None => false
+      };
+
+      if empty { self.double[slot] = None; }
     }
+  }
 
-    page.as_mut().unwrap()
+  // Validates a `[offset, offset + len)` range for fallocate, returning `end`.
+  fn check_range(&self, offset: usize, len: usize) -> Result<usize> {
+    if len == 0 { return Err(Error::InvalidArgument); }
+    match offset.checked_add(len) {
+      Some(end) if end <= self.geometry.max_file_size() => Ok(end),
+      _ => Err(Error::FileTooLarge)
+    }
   }
 
-  fn get_page<'a>(&'a self, num: usize) -> &'a Option<Page> {
-    if num >= LIST_SIZE + LIST_SIZE * LIST_SIZE {
-      panic!("Page does not exist.")
-    };
+  /**
+   * Makes sure every page backing `[offset, offset + len)` is allocated. Pages
+   * that already exist keep their contents. Unless `keep_size` is set, the
+   * file is grown to cover the range.
+   */
+  pub fn allocate(&mut self, offset: usize, len: usize, keep_size: bool)
+      -> Result<()> {
+    let end = self.check_range(offset, len)?;
+    let page_size = self.geometry.page_size;
+    for num in (offset / page_size)..ceil_div(end, page_size) {
+      self.get_or_alloc_page(num)?;
+    }
 
-    if num < LIST_SIZE {
-      &self.single[num]
-    } else {
-      let double_entry = num - LIST_SIZE;
-      let slot = double_entry / LIST_SIZE;
-      let entry_offset = double_entry % LIST_SIZE;
-      let entry_list = &self.double[slot];
-
-      match *entry_list {
-        None => panic!("Page does not exist."),
-        _ => &entry_list.as_ref().unwrap()[entry_offset]
+    if !keep_size && self.size < end { self.size = end; }
+    self.mod_time = time::get_time();
+    Ok(())
+  }
+
+  /**
+   * Releases the pages lying entirely within `[offset, offset + len)` and
+   * zeroes the parts of the range that share a page with data outside of it.
+   * The range reads back as zeros. The file size is never changed.
+   */
+  pub fn punch_hole(&mut self, offset: usize, len: usize) -> Result<()> {
+    let end = self.check_range(offset, len)?;
+    let page_size = self.geometry.page_size;
+    for num in (offset / page_size)..ceil_div(end, page_size) {
+      let page_start = num * page_size;
+      let from = cmp::max(offset, page_start) - page_start;
+      let to = cmp::min(end, page_start + page_size) - page_start;
+
+      if from == 0 && to == page_size {
+        self.release_page(num);
+      } else {
+        self.load_page(num)?;
+        if let Some(&mut Some(ref mut page)) = self.get_slot_mut(num) {
+          page[from..to].fill(0);
+          page.dirty = true;
+        }
       }
     }
+
+    self.mod_time = time::get_time();
+    Ok(())
+  }
+
+  /**
+   * Zeroes `[offset, offset + len)`, allocating any pages in the range that
+   * don't exist yet. Unless `keep_size` is set, the file is grown to cover the
+   * range.
+   */
+  pub fn zero_range(&mut self, offset: usize, len: usize, keep_size: bool)
+      -> Result<()> {
+    let end = self.check_range(offset, len)?;
+    let page_size = self.geometry.page_size;
+    for num in (offset / page_size)..ceil_div(end, page_size) {
+      let page_start = num * page_size;
+      let from = cmp::max(offset, page_start) - page_start;
+      let to = cmp::min(end, page_start + page_size) - page_start;
+      let page = self.get_or_alloc_page(num)?;
+      page[from..to].fill(0);
+      page.dirty = true;
+    }
+
+    if !keep_size && self.size < end { self.size = end; }
+    self.mod_time = time::get_time();
+    Ok(())
   }
 
-  pub fn write(&mut self, offset: usize, data: &[u8]) -> usize {
+  pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<usize> {
+    // Nothing to write shouldn't allocate the page at `offset`.
+    if data.is_empty() { return Ok(0); }
+
+    let page_size = self.geometry.page_size;
     let mut written = 0;
-    let mut block_offset = offset % PAGE_SIZE; // offset from first block
+    let mut block_offset = offset % page_size; // offset from first block
 
-    let start = offset / PAGE_SIZE; // first block to act on
-    let blocks_to_act_on = ceil_div(block_offset + data.len(), PAGE_SIZE);
+    let start = offset / page_size; // first block to act on
+    let blocks_to_act_on = ceil_div(block_offset + data.len(), page_size);
 
     for i in 0..blocks_to_act_on {
       // Resetting the block offset after first pass since we want to read from
@@ -119,11 +500,19 @@ impl Inode {
       let num_bytes = if i == blocks_to_act_on - 1 {
         data.len() - written
       } else {
-        PAGE_SIZE - block_offset
+        page_size - block_offset
       };
 
-      // Finding our block, writing to it
-      let mut page = self.get_or_alloc_page(start + i);
+      // Finding our block, writing to it. Running out of space or quota, or
+      // reaching the maximum file size, after writing something makes for a
+      // short write.
+      let page = match self.get_or_alloc_page(start + i) {
+        Ok(page) => page,
+        Err(Error::NoSpace) | Err(Error::QuotaExceeded) | Err(Error::FileTooLarge)
+          if written > 0 => break,
+        Err(err) => return Err(err)
+      };
+      page.dirty = true;
       let slice = &mut page[block_offset..(block_offset + num_bytes)];
       // written += slice.copy_from(data.slice(written, written + num_bytes));
       unsafe {
@@ -142,14 +531,24 @@ impl Inode {
     self.mod_time = time_now;
     self.access_time = time_now;
 
-    written
+    Ok(written)
   }
 
-  pub fn read(&self, offset: usize, data: &mut [u8]) -> usize {
+  /**
+   * Reads into `data` starting at `offset`, stopping at the end of file. The
+   * number of bytes read may be less than `data.len()`, and is 0 if `offset`
+   * is at or past the end of file.
+   */
+  pub fn read(&mut self, offset: usize, data: &mut [u8]) -> Result<usize> {
+    if offset >= self.size { return Ok(0); }
+    let len = cmp::min(data.len(), self.size - offset);
+    let data = &mut data[..len];
+
+    let page_size = self.geometry.page_size;
     let mut read = 0;
-    let mut block_offset = offset % PAGE_SIZE; // offset from first block
-    let start = offset / PAGE_SIZE; // first block to act on
-    let blocks_to_act_on = ceil_div(block_offset + data.len(), PAGE_SIZE);
+    let mut block_offset = offset % page_size; // offset from first block
+    let start = offset / page_size; // first block to act on
+    let blocks_to_act_on = ceil_div(block_offset + data.len(), page_size);
 
     for i in 0..blocks_to_act_on {
       // Resetting the block offset after first pass since we want to read from
@@ -160,16 +559,21 @@ impl Inode {
       let num_bytes = if i == blocks_to_act_on - 1 {
         data.len() - read
       } else {
-        PAGE_SIZE - block_offset
+        page_size - block_offset
       };
 
-      // Finding our block, reading from it
+      // Finding our block, reading from it. Holes read as zeros.
+      let slice = &mut data[read..(read + num_bytes)];
+      self.load_page(start + i)?;
       let page = match self.get_page(start + i) {
-        &None => panic!("Empty data."),
-        &Some(ref pg) => pg
+        None => {
+          slice.fill(0);
+          read += num_bytes;
+          continue;
+        }
+        Some(pg) => pg
       };
 
-      let slice = &mut data[read..(read + num_bytes)];
       // read += slice.copy_from(page.slice(block_offset,
       // block_offset + num_bytes));
       unsafe {
@@ -181,15 +585,429 @@ impl Inode {
       read += num_bytes;
     }
 
-    read
+    Ok(read)
+  }
+
+  /**
+   * Reads up to `len` bytes starting at `offset` like `read`, but without
+   * copying: returns slices of the pages holding them, in order, one per page.
+   * Returns none if `offset` is at or past the end of file.
+   */
+  pub fn read_slices(&mut self, offset: usize, len: usize) -> Result<Vec<PageSlice>> {
+    if offset >= self.size { return Ok(Vec::new()); }
+    let end = offset + cmp::min(len, self.size - offset);
+
+    let page_size = self.geometry.page_size;
+    let mut slices = Vec::with_capacity(ceil_div(end, page_size) - offset / page_size);
+    let mut pos = offset;
+    while pos < end {
+      let (num, start) = (pos / page_size, pos % page_size);
+      let stop = cmp::min(end - num * page_size, page_size);
+      self.load_page(num)?;
+      let data = self.get_page(num)
+        .map(|page| page.data.clone().expect("page is not resident"));
+      slices.push(PageSlice { data, start, end: stop });
+      pos += stop - start;
+    }
+
+    Ok(slices)
+  }
+
+  /**
+   * Copies `len` bytes from `offset` of `src` to `dst_offset` of this inode,
+   * stopping at the end of `src`, and returns the number of bytes copied.
+   * Whole pages at matching page offsets are shared with `src` rather than
+   * copied, holes included; see `Page`. `src` must be a different inode with
+   * the same geometry.
+   */
+  pub fn copy_range(&mut self, src: &mut Inode, offset: usize, dst_offset: usize,
+                    len: usize) -> Result<usize> {
+    let page_size = self.geometry.page_size;
+    let len = cmp::min(len, src.size.saturating_sub(offset));
+    match dst_offset.checked_add(len) {
+      Some(end) if end <= self.geometry.max_file_size() => {},
+      _ => return Err(Error::FileTooLarge)
+    }
+
+    let mut copied = 0;
+    while copied < len {
+      let (from, to) = (offset + copied, dst_offset + copied);
+      if from % page_size == 0 && to % page_size == 0 && len - copied >= page_size {
+        let num = from / page_size;
+        src.load_page(num)?;
+        let inserted = match src.get_page(num).cloned() {
+          Some(mut page) => {
+            page.block = 0;
+            page.dirty = true;
+            self.insert_page(to / page_size, page)
+          }
+          None => {
+            self.release_page(to / page_size);
+            Ok(())
+          }
+        };
+
+        match inserted {
+          Ok(()) => copied += page_size,
+          Err(Error::NoSpace) | Err(Error::QuotaExceeded) if copied > 0 => break,
+          Err(err) => return Err(err)
+        }
+
+        continue;
+      }
+
+      // Unaligned parts go through a buffer, a page at most at a time.
+      let chunk = cmp::min(page_size - cmp::max(from % page_size, to % page_size),
+                           len - copied);
+      let mut buf = vec![0u8; chunk];
+      src.read(from, &mut buf)?;
+      match self.write(to, &buf) {
+        Ok(written) if written < chunk => {
+          copied += written;
+          break;
+        }
+        Ok(_) => copied += chunk,
+        Err(Error::NoSpace) | Err(Error::QuotaExceeded) if copied > 0 => break,
+        Err(err) => return Err(err)
+      }
+    }
+
+    if self.size < dst_offset + copied { self.size = dst_offset + copied; }
+    self.mod_time = time::get_time();
+    Ok(copied)
+  }
+
+  /// Replaces this inode's contents with those of `src`, sharing every page.
+  pub fn reflink(&mut self, src: &mut Inode) -> Result<()> {
+    let mut copy = src.snapshot()?;
+    if let Some(ref space) = self.space {
+      match copy.pages.checked_sub(self.pages) {
+        Some(more) => space.charge_pages(self.owner, more)?,
+        None => space.release_pages(self.owner, self.pages - copy.pages)
+      }
+    }
+
+    mem::swap(&mut self.single, &mut copy.single);
+    mem::swap(&mut self.double, &mut copy.double);
+    if self.cache().is_some() {
+      let old: Vec<usize> = copy.pages_mut().map(|(num, _)| num).collect();
+      let new: Vec<usize> = self.pages_mut()
+        .filter(|(_, page)| page.is_resident())
+        .map(|(num, _)| num)
+        .collect();
+
+      let cache = self.cache().unwrap();
+      for num in old { cache.remove(self, num); }
+      for num in new { cache.insert(self, num); }
+    }
+
+    self.pages = copy.pages;
+    self.size = copy.size;
+    self.lists = None;
+    self.mod_time = time::get_time();
+    Ok(())
   }
 
   pub fn size(&self) -> usize {
     self.size
   }
 
-  pub fn stat(&self) -> (Timespec, Timespec, Timespec) {
-    (self.create_time, self.access_time, self.mod_time)
+  /**
+   * The offset of the first byte at or after `offset` that lies in an
+   * allocated page, or `None` if `offset` is at or past the end of file or
+   * only holes follow it.
+   */
+  pub fn next_data(&self, offset: usize) -> Option<usize> {
+    if offset >= self.size { return None; }
+
+    let page_size = self.geometry.page_size;
+    let last = ceil_div(self.size, page_size);
+    (offset / page_size..last)
+      .find(|&num| self.get_page(num).is_some())
+      .map(|num| cmp::max(num * page_size, offset))
+  }
+
+  /**
+   * The offset of the first byte at or after `offset` that lies in a hole.
+   * The end of file counts as a hole, so this is `None` only if `offset` is at
+   * or past the end of file.
+   */
+  pub fn next_hole(&self, offset: usize) -> Option<usize> {
+    if offset >= self.size { return None; }
+
+    let page_size = self.geometry.page_size;
+    let last = ceil_div(self.size, page_size);
+    let hole = (offset / page_size..last)
+      .find(|&num| self.get_page(num).is_none())
+      .map_or(self.size, |num| cmp::max(num * page_size, offset));
+
+    Some(cmp::min(hole, self.size))
+  }
+
+  pub fn geometry(&self) -> Geometry {
+    self.geometry
+  }
+
+  /// Iterates over the allocated pages in order, along with their numbers.
+  pub(crate) fn pages_mut(&mut self) -> impl Iterator<Item = (usize, &mut Page)> {
+    let list_size = self.geometry.list_size;
+    let single = self.single.iter_mut().enumerate()
+      .filter_map(|(num, page)| page.as_mut().map(|page| (num, page)));
+
+    let double = self.double.iter_mut().enumerate()
+      .filter_map(|(slot, list)| list.as_mut().map(|list| (slot, list)))
+      .flat_map(move |(slot, list)| {
+        let base = list_size + slot * list_size;
+        list.iter_mut().enumerate()
+          .filter_map(move |(i, page)| page.as_mut().map(|page| (base + i, page)))
+      });
+
+    single.chain(double)
+  }
+
+  /// Places `page` at page number `num`, as when loading from an image.
+  /// Fails with `NoSpace` or `QuotaExceeded` if the page is new and there's no
+  /// room for it.
+  pub(crate) fn insert_page(&mut self, num: usize, page: Page) -> Result<()> {
+    let list_size = self.geometry.list_size;
+    let slot = if num < list_size {
+      &mut self.single[num]
+    } else {
+      // We allocate a new entry list where necessary
+      let double_entry = num - list_size;
+      let entry_list = &mut self.double[double_entry / list_size];
+      if entry_list.is_none() {
+        *entry_list = Some(match self.space {
+          Some(ref space) => space.pool().alloc_list(),
+          None => PageList::unpooled(list_size)
+        });
+      }
+
+      &mut entry_list.as_mut().unwrap()[double_entry % list_size]
+    };
+
+    if slot.is_none() {
+      if let Some(ref space) = self.space { space.charge_pages(self.owner, 1)?; }
+      self.pages += 1;
+    }
+
+    let resident = page.is_resident();
+    *slot = Some(page);
+    self.lists = None;
+    if let (true, Some(cache)) = (resident, self.cache()) { cache.insert(self, num); }
+    Ok(())
+  }
+
+  /**
+   * Drops the contents of page `num` so that they're read from the device
+   * when next used, as the page cache does to evict it. A dirty page is first
+   * handed to `write_back` to write it to its block, giving it one if it has
+   * none. Returns whether the page was evicted: it isn't if it isn't
+   * resident, if the inode isn't cached, or if writing it back fails.
+   */
+  pub(crate) fn evict_page<F>(&mut self, num: usize, write_back: F) -> bool
+      where F: FnOnce(&mut Page) -> Result<()> {
+    if self.cache().is_none() { return false; }
+    let (dirty, moved) = match self.get_slot_mut(num) {
+      Some(&mut Some(ref mut page)) if page.is_resident() => {
+        let (dirty, block) = (page.dirty, page.block);
+        if dirty && write_back(page).is_err() { return false; }
+        page.data = None;
+        page.dirty = false;
+        (dirty, page.block != block)
+      }
+      _ => return false
+    };
+
+    if moved { self.lists = None; }
+    self.written_back |= dirty;
+    if let Some(cache) = self.cache() { cache.evicted(self, num, dirty); }
+    true
+  }
+
+  /**
+   * Reads up to `pages` pages from the device ahead of their use, starting
+   * with the one holding `offset` and stopping at the end of file. Pages
+   * already resident are skipped, and reading stops at the first error.
+   * Returns the number of pages covered, which is at most the page cache's
+   * read-ahead limit, and 0 if the inode isn't cached.
+   */
+  pub(crate) fn read_ahead(&mut self, offset: usize, pages: usize) -> usize {
+    let pages = match self.cache() {
+      Some(cache) => cmp::min(pages, cache.max_read_ahead()),
+      None => return 0
+    };
+
+    let page_size = self.geometry.page_size;
+    let start = offset / page_size;
+    let end = cmp::min(start.saturating_add(pages), ceil_div(self.size, page_size));
+    for num in start..end {
+      if self.get_page(num).is_none_or(|page| page.is_resident()) { continue; }
+      if self.read_in(num, true).is_err() { break; }
+    }
+
+    pages
+  }
+
+  /// Where the page lists were last flushed, unless pages have since come or
+  /// gone.
+  pub(crate) fn lists(&self) -> Option<&ListBlocks> {
+    self.lists.as_ref()
+  }
+
+  /// Records that every page and the page lists, in `lists`, are on the
+  /// device.
+  pub(crate) fn mark_synced(&mut self, lists: ListBlocks) {
+    for (_, page) in self.pages_mut() { page.dirty = false; }
+    self.lists = Some(lists);
+    self.synced_size = self.size;
+    self.written_back = false;
+  }
+
+  /// Whether the inode's data, as opposed to just its timestamps, changed
+  /// since it was last flushed.
+  pub(crate) fn needs_data_sync(&mut self) -> bool {
+    self.lists.is_none() || self.size != self.synced_size || self.written_back ||
+      self.pages_mut().any(|(_, page)| page.dirty)
+  }
+
+  /**
+   * Charges the inode and its pages to `space`, and later pages too. With
+   * `force`, as for files already in the tree, the limits are ignored;
+   * otherwise this fails with `NoSpace` or `QuotaExceeded` if a limit or a
+   * quota would be exceeded. Dropping the inode releases it. Does nothing if
+   * the inode is charged already.
+   */
+  pub(crate) fn set_space(&mut self, space: &SharedSpace, force: bool) -> Result<()> {
+    if self.space.is_some() { return Ok(()); }
+    match force {
+      true => space.force_charge(self.owner, self.pages),
+      false => space.charge(self.owner, self.pages)?
+    }
+
+    self.space = Some(space.clone());
+    Ok(())
+  }
+
+  pub fn owner(&self) -> Owner {
+    self.owner
+  }
+
+  /// Gives the inode to `owner`, moving its charges over. Fails with
+  /// `QuotaExceeded` if that would put the new owner past a quota.
+  pub(crate) fn set_owner(&mut self, owner: Owner) -> Result<()> {
+    if let Some(ref space) = self.space { space.transfer(self.owner, owner, self.pages)?; }
+    self.owner = owner;
+    Ok(())
+  }
+
+  /// Has non-resident pages read from `device` when they're first used.
+  pub(crate) fn set_device(&mut self, device: SharedDevice) {
+    self.device = Some(device);
+  }
+
+  // Reads in every page not yet read from the device.
+  fn load_pages(&mut self) -> Result<()> {
+    let pending: Vec<usize> = self.pages_mut()
+      .filter(|(_, page)| !page.is_resident())
+      .map(|(num, _)| num)
+      .collect();
+    for num in pending { self.read_in(num, false)?; }
+    Ok(())
+  }
+
+  // Treats every page as never written out.
+  fn forget_blocks(&mut self) {
+    for (_, page) in self.pages_mut() {
+      page.block = 0;
+      page.dirty = true;
+    }
+  }
+
+  /**
+   * A copy of the inode that shares its pages until either inode changes
+   * them. Pages not yet read from the device are read in first, and the
+   * copy's pages are treated as never written out, so the copy doesn't depend
+   * on the device.
+   */
+  pub(crate) fn snapshot(&mut self) -> Result<Inode> {
+    self.load_pages()?;
+    let mut copy = Inode {
+      device: None,
+      single: self.single.clone(),
+      double: self.double.clone(),
+      lists: None,
+      synced_size: 0,
+      space: None,
+      xattrs: self.xattrs.clone(),
+      locks: Locks::default(),
+      ..*self
+    };
+
+    copy.forget_blocks();
+    Ok(copy)
+  }
+
+  /// Cuts the inode loose from the device, as for an unlinked file that's
+  /// still open: its pages are read in and treated as never written out, so
+  /// that their blocks can be reused.
+  pub(crate) fn detach(&mut self) -> Result<()> {
+    self.load_pages()?;
+    self.forget_blocks();
+    if let Some(cache) = self.cache() { cache.forget(self); }
+    self.device = None;
+    self.lists = None;
+    self.synced_size = 0;
+    Ok(())
+  }
+
+  /// Restores the size and timestamps of an inode loaded from an image.
+  pub(crate) fn restore(&mut self, size: usize, create_time: Timespec,
+                        access_time: Timespec, mod_time: Timespec) {
+    self.size = size;
+    self.create_time = create_time;
+    self.access_time = access_time;
+    self.mod_time = mod_time;
+  }
+
+  pub fn xattrs(&self) -> &Xattrs {
+    &self.xattrs
+  }
+
+  pub fn xattrs_mut(&mut self) -> &mut Xattrs {
+    &mut self.xattrs
+  }
+
+  pub(crate) fn locks_mut(&mut self) -> &mut Locks {
+    &mut self.locks
+  }
+
+  pub fn stat(&self) -> Stat {
+    Stat {
+      uid: self.owner.uid,
+      gid: self.owner.gid,
+      size: self.size,
+      blksize: self.geometry.page_size,
+      blocks: self.pages,
+      create_time: self.create_time,
+      access_time: self.access_time,
+      mod_time: self.mod_time,
+    }
+  }
+}
+
+impl Drop for Inode {
+  fn drop(&mut self) {
+    if let Some(cache) = self.cache() { cache.forget(self); }
+    if let Some(ref space) = self.space {
+      space.release(self.owner, self.pages);
+    }
+  }
+}
+
+impl Default for Inode {
+  fn default() -> Inode {
+    Inode::new()
   }
 }
 
@@ -197,7 +1015,8 @@ impl Inode {
 mod tests {
   extern crate rand;
 
-  use super::{Inode};
+  use super::{Inode, Geometry, PageSlice};
+  use error::Error;
   use self::rand::random;
   use time;
 
@@ -215,8 +1034,8 @@ mod tests {
     let mut buf = [0u8; SIZE];
 
     // Write the random data, read it back into buffer
-    inode.write(0, original_data.as_slice());
-    inode.read(0, &mut buf);
+    inode.write(0, original_data.as_slice()).unwrap();
+    inode.read(0, &mut buf).unwrap();
 
     // Make sure inode is right size
     assert_eq!(SIZE, inode.size());
@@ -226,7 +1045,120 @@ mod tests {
       assert_eq!(buf[i], original_data[i]);
     }
 
-    let (create, _, _) = inode.stat();
-    assert_eq!(create.sec, time_now.sec);
+    let stat = inode.stat();
+    assert_eq!(stat.create_time.sec, time_now.sec);
+    assert_eq!(stat.blksize, 4096);
+    assert_eq!(stat.blocks, 9);
+  }
+
+  #[test]
+  fn test_geometry_bounds() {
+    assert!(Geometry::new(512, 128).is_ok());
+    assert!(Geometry::new(65536, 256).is_ok());
+    assert_eq!(Geometry::new(256, 16), Err(Error::InvalidArgument));
+    assert_eq!(Geometry::new(131072, 256), Err(Error::InvalidArgument));
+    assert_eq!(Geometry::new(4000, 256), Err(Error::InvalidArgument));
+    assert_eq!(Geometry::new(512, 256), Err(Error::InvalidArgument));
+    assert_eq!(Geometry::new(4096, 1), Err(Error::InvalidArgument));
+  }
+
+  #[test]
+  fn test_small_page_write() {
+    const SIZE: usize = 512 * 40 + 77;
+
+    let geometry = Geometry::new(512, 16).unwrap();
+    let original_data = rand_array(SIZE);
+    let mut inode = Inode::with_geometry(geometry);
+    let mut buf = [0u8; SIZE];
+
+    // 40 full pages and a partial one: spills into the doubly-indirect list.
+    inode.write(0, original_data.as_slice()).unwrap();
+    inode.read(0, &mut buf).unwrap();
+
+    assert_eq!(&buf[..], &original_data[..]);
+    assert_eq!(inode.stat().blksize, 512);
+    assert_eq!(inode.stat().blocks, 41);
+    assert_eq!(geometry.max_file_size(), 512 * (16 + 16 * 16));
+  }
+
+  #[test]
+  fn test_empty_write() {
+    let geometry = Geometry::new(512, 16).unwrap();
+    let mut inode = Inode::with_geometry(geometry);
+
+    // Neither allocates a page, even at or past the maximum file size.
+    assert_eq!(inode.write(100, &[]), Ok(0));
+    assert_eq!(inode.write(geometry.max_file_size() + 100, &[]), Ok(0));
+    assert_eq!(inode.stat().blocks, 0);
+    assert_eq!(inode.size(), 0);
+  }
+
+  #[test]
+  fn test_punch_hole_releases_lists() {
+    let geometry = Geometry::new(512, 16).unwrap();
+    let mut inode = Inode::with_geometry(geometry);
+    let data = rand_array(512 * 64);
+
+    // Pages 16..64 live in three doubly-indirect lists.
+    inode.write(0, &data).unwrap();
+    assert!(inode.double[0].is_some() && inode.double[2].is_some());
+
+    inode.punch_hole(512 * 16, 512 * 48).unwrap();
+    assert_eq!(inode.stat().blocks, 16);
+    assert!(inode.double.iter().all(|list| list.is_none()));
+
+    let mut buf = vec![1u8; 512 * 64];
+    inode.read(0, &mut buf).unwrap();
+    assert_eq!(&buf[..(512 * 16)], &data[..(512 * 16)]);
+    assert!(buf[(512 * 16)..].iter().all(|&b| b == 0));
+  }
+
+  #[test]
+  fn test_read_stops_at_eof() {
+    let mut inode = Inode::new();
+    let data = rand_array(5000);
+    inode.write(0, &data).unwrap();
+
+    // Preallocated pages past the end of file are never exposed.
+    inode.allocate(0, 4096 * 4, true).unwrap();
+
+    let mut buf = vec![7u8; 4096 * 4];
+    assert_eq!(inode.read(0, &mut buf), Ok(5000));
+    assert_eq!(&buf[..5000], &data[..]);
+    assert!(buf[5000..].iter().all(|&b| b == 7));
+
+    assert_eq!(inode.read(4990, &mut buf), Ok(10));
+    assert_eq!(inode.read(5000, &mut buf), Ok(0));
+    assert_eq!(inode.read(1 << 40, &mut buf), Ok(0));
+  }
+
+  // The bytes of `slices`, one after another.
+  fn concat(slices: &[PageSlice]) -> Vec<u8> {
+    slices.iter().flat_map(|slice| slice.iter().cloned()).collect()
+  }
+
+  #[test]
+  fn test_read_slices() {
+    let mut inode = Inode::with_geometry(Geometry::new(512, 16).unwrap());
+    let data = rand_array(512 * 3 + 100);
+    inode.write(0, &data).unwrap();
+    inode.punch_hole(512, 512).unwrap();
+    let mut expected = data.clone();
+    expected[512..1024].fill(0);
+
+    // One slice per page, holes included, stopping at the end of file.
+    let slices = inode.read_slices(300, 5000).unwrap();
+    let lens: Vec<usize> = slices.iter().map(|slice| slice.len()).collect();
+    assert_eq!(lens, [212, 512, 512, 100]);
+    assert_eq!(concat(&slices), &expected[300..]);
+    assert_eq!(concat(&inode.read_slices(0, 10).unwrap()), &data[..10]);
+    assert!(inode.read_slices(512 * 3 + 100, 10).unwrap().is_empty());
+
+    // The slices share the pages, until the file writes to them.
+    let page = inode.read_slices(1024, 512).unwrap().remove(0);
+    assert_eq!(page.as_ptr(), inode.read_slices(1024, 1).unwrap()[0].as_ptr());
+    inode.write(1024, &[1u8; 10]).unwrap();
+    assert_eq!(&*page, &data[1024..1536]);
+    assert_eq!(&*inode.read_slices(1024, 10).unwrap()[0], &[1u8; 10]);
   }
 }
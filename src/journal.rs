@@ -0,0 +1,322 @@
+/*!
+ * The write-ahead journal.
+ *
+ * A flush may overwrite blocks that the image's current tree depends on: the
+ * superblock, bitmap and inode table, and any data region block being reused.
+ * Such writes are gathered into a transaction, which is first written to the
+ * journal and committed there. Only then are the blocks written in place, or
+ * "checkpointed". If the image is mounted after a crash, a committed but not
+ * necessarily checkpointed transaction is replayed, so the image always holds
+ * either the old tree or the new one, never a mix of the two.
+ *
+ * The journal occupies `journal_blocks` blocks from `journal_start`:
+ *
+ *   +0        header: [u8; 8] `HEADER_MAGIC`, u64 sequence number of the next
+ *             transaction
+ *   +1        descriptor: [u8; 8] `DESCRIPTOR_MAGIC`, u64 sequence number,
+ *             u32 number of blocks `n`, then `n` u32 target block numbers,
+ *             spilling into as many blocks as needed
+ *   ...       the `n` logged blocks, in descriptor order
+ *   ...       commit: [u8; 8] `COMMIT_MAGIC`, u64 sequence number, u32 `n`,
+ *             u64 checksum of the descriptor and logged blocks
+ *
+ * Only one transaction is ever in the journal. Once it's checkpointed, the
+ * header's sequence number moves past it, which retires it.
+ */
+
+use device::BlockDevice;
+use disk::{get_u32, get_u64, put_u32, put_u64};
+use error::{Error, Result};
+
+pub const HEADER_MAGIC: [u8; 8] = *b"RFSJhead";
+pub const DESCRIPTOR_MAGIC: [u8; 8] = *b"RFSJdesc";
+pub const COMMIT_MAGIC: [u8; 8] = *b"RFSJcmit";
+
+const DESCRIPTOR_HEADER: usize = 20;
+
+/// How a file system's flushes use the journal.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JournalMode {
+  /// Only metadata is journaled. File data is written in place before the
+  /// metadata referencing it is committed, so a crash can leave a page being
+  /// overwritten with its old contents but never with garbage.
+  #[default]
+  Ordered,
+  /// Overwrites of existing file data are journaled too, at the cost of
+  /// writing such data twice.
+  Data,
+}
+
+/// The number of journal blocks an image with `metadata_blocks` blocks of
+/// superblock, bitmap and inode table gets: enough to journal all of them
+/// twice over, with room for the header, descriptor and commit blocks.
+pub fn default_blocks(metadata_blocks: u64) -> u64 {
+  2 * metadata_blocks + 8
+}
+
+// FNV-1a, which is plenty to tell a complete commit from a torn one.
+fn checksum(hash: u64, data: &[u8]) -> u64 {
+  data.iter().fold(hash, |hash, &byte| {
+    (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+  })
+}
+
+const CHECKSUM_SEED: u64 = 0xcbf29ce484222325;
+
+/// A block to be written through the journal: its number and contents.
+pub type Logged = (u64, Box<[u8]>);
+
+pub struct Journal {
+  start: u64,
+  blocks: u64,
+  sequence: u64,
+}
+
+impl Journal {
+  /// Writes an empty journal to `blocks` blocks of `device` from `start`.
+  pub fn format(device: &mut dyn BlockDevice, start: u64, blocks: u64)
+      -> Result<Journal> {
+    let journal = Journal { start, blocks, sequence: 1 };
+    if journal.capacity(device.block_size()) == 0 {
+      return Err(Error::InvalidArgument);
+    }
+
+    // Whatever the device held before mustn't pass for a transaction.
+    device.write_block(start + 1, &vec![0u8; device.block_size()])?;
+    journal.write_header(device)?;
+    device.flush()?;
+    Ok(journal)
+  }
+
+  /**
+   * Opens the journal in `blocks` blocks of `device` from `start`, replaying
+   * the transaction in it if that was committed. Logged blocks must target
+   * blocks below `limit` outside of the journal.
+   */
+  pub fn recover(device: &mut dyn BlockDevice, start: u64, blocks: u64, limit: u64)
+      -> Result<Journal> {
+    let block_size = device.block_size();
+    let mut buf = vec![0u8; block_size];
+    device.read_block(start, &mut buf)?;
+    if buf[0..8] != HEADER_MAGIC { return Err(Error::Corrupted); }
+
+    let mut journal = Journal { start, blocks, sequence: get_u64(&buf, 8) };
+    if let Some(writes) = journal.read_transaction(device, limit)? {
+      for &(block, ref data) in writes.iter() {
+        device.write_block(block, data)?;
+      }
+
+      device.flush()?;
+      journal.retire(device)?;
+    }
+
+    Ok(journal)
+  }
+
+  /// The most blocks a single transaction can hold.
+  pub fn capacity(&self, block_size: usize) -> usize {
+    // Blocks besides the header and commit, less the descriptor's.
+    let available = self.blocks.saturating_sub(2) as usize;
+    let mut count = available.saturating_sub(1);
+    while count > 0 && self.descriptor_blocks(count, block_size) + count > available {
+      count -= 1;
+    }
+
+    count
+  }
+
+  /**
+   * Writes `writes`, pairs of target block and contents, as a transaction:
+   * logs and commits them, then checkpoints them to their targets. Every
+   * write made to the device before this call is durable before the commit.
+   */
+  pub fn commit(&mut self, device: &mut dyn BlockDevice, writes: &[Logged])
+      -> Result<()> {
+    if writes.is_empty() { return Ok(()); }
+
+    let block_size = device.block_size();
+    if writes.len() > self.capacity(block_size) { return Err(Error::NoSpace); }
+
+    let descriptor = self.encode_descriptor(writes, block_size);
+    let mut hash = checksum(CHECKSUM_SEED, &descriptor);
+    let mut at = self.start + 1;
+    for chunk in descriptor.chunks(block_size) {
+      device.write_block(at, chunk)?;
+      at += 1;
+    }
+
+    for (_, data) in writes {
+      hash = checksum(hash, data);
+      device.write_block(at, data)?;
+      at += 1;
+    }
+
+    device.flush()?;
+
+    let mut buf = vec![0u8; block_size];
+    buf[0..8].copy_from_slice(&COMMIT_MAGIC);
+    put_u64(&mut buf, 8, self.sequence);
+    put_u32(&mut buf, 16, writes.len() as u32);
+    put_u64(&mut buf, 20, hash);
+    device.write_block(at, &buf)?;
+    device.flush()?;
+
+    for (block, data) in writes {
+      device.write_block(*block, data)?;
+    }
+
+    device.flush()?;
+    self.retire(device)
+  }
+
+  fn descriptor_blocks(&self, count: usize, block_size: usize) -> usize {
+    (DESCRIPTOR_HEADER + 4 * count).div_ceil(block_size)
+  }
+
+  fn encode_descriptor(&self, writes: &[Logged], block_size: usize)
+      -> Vec<u8> {
+    let blocks = self.descriptor_blocks(writes.len(), block_size);
+    let mut descriptor = vec![0u8; blocks * block_size];
+    descriptor[0..8].copy_from_slice(&DESCRIPTOR_MAGIC);
+    put_u64(&mut descriptor, 8, self.sequence);
+    put_u32(&mut descriptor, 16, writes.len() as u32);
+    for (i, &(block, _)) in writes.iter().enumerate() {
+      put_u32(&mut descriptor, DESCRIPTOR_HEADER + 4 * i, block as u32);
+    }
+
+    descriptor
+  }
+
+  // Reads back the current transaction if it was committed in full.
+  fn read_transaction(&self, device: &mut dyn BlockDevice, limit: u64)
+      -> Result<Option<Vec<Logged>>> {
+    let block_size = device.block_size();
+    let mut buf = vec![0u8; block_size];
+    device.read_block(self.start + 1, &mut buf)?;
+    if buf[0..8] != DESCRIPTOR_MAGIC || get_u64(&buf, 8) != self.sequence {
+      return Ok(None);
+    }
+
+    let count = get_u32(&buf, 16) as usize;
+    if count == 0 || count > self.capacity(block_size) { return Ok(None); }
+
+    let mut descriptor = buf.clone();
+    let mut at = self.start + 2;
+    for _ in 1..self.descriptor_blocks(count, block_size) {
+      device.read_block(at, &mut buf)?;
+      descriptor.extend_from_slice(&buf);
+      at += 1;
+    }
+
+    let mut hash = checksum(CHECKSUM_SEED, &descriptor);
+    let mut writes = Vec::with_capacity(count);
+    for i in 0..count {
+      let block = u64::from(get_u32(&descriptor, DESCRIPTOR_HEADER + 4 * i));
+      let mut data = vec![0u8; block_size].into_boxed_slice();
+      device.read_block(at, &mut data)?;
+      hash = checksum(hash, &data);
+      writes.push((block, data));
+      at += 1;
+    }
+
+    device.read_block(at, &mut buf)?;
+    if buf[0..8] != COMMIT_MAGIC || get_u64(&buf, 8) != self.sequence
+        || get_u32(&buf, 16) as usize != count || get_u64(&buf, 20) != hash {
+      return Ok(None);
+    }
+
+    // A committed transaction that targets nonsense isn't ours to replay.
+    let journal = self.start..(self.start + self.blocks);
+    if writes.iter().any(|&(block, _)| block >= limit || journal.contains(&block)) {
+      return Err(Error::Corrupted);
+    }
+
+    Ok(Some(writes))
+  }
+
+  fn retire(&mut self, device: &mut dyn BlockDevice) -> Result<()> {
+    self.sequence += 1;
+    self.write_header(device)?;
+    device.flush()
+  }
+
+  fn write_header(&self, device: &mut dyn BlockDevice) -> Result<()> {
+    let mut buf = vec![0u8; device.block_size()];
+    buf[0..8].copy_from_slice(&HEADER_MAGIC);
+    put_u64(&mut buf, 8, self.sequence);
+    device.write_block(self.start, &buf)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Journal;
+  use device::{BlockDevice, MemDevice};
+  use error::Error;
+
+  fn block(byte: u8) -> Box<[u8]> {
+    vec![byte; 512].into_boxed_slice()
+  }
+
+  fn read(device: &mut MemDevice, num: u64) -> Box<[u8]> {
+    let mut data = block(0);
+    device.read_block(num, &mut data).unwrap();
+    data
+  }
+
+  #[test]
+  fn test_commit_and_capacity() {
+    let mut device = MemDevice::new(512, 64);
+    let mut journal = Journal::format(&mut device, 1, 16).unwrap();
+    assert_eq!(journal.capacity(512), 13);
+
+    let writes = vec![(20, block(1)), (21, block(2))];
+    journal.commit(&mut device, &writes).unwrap();
+    assert_eq!(read(&mut device, 20), block(1));
+    assert_eq!(read(&mut device, 21), block(2));
+
+    let too_many: Vec<_> = (0..14).map(|i| (30 + i, block(3))).collect();
+    assert_eq!(journal.commit(&mut device, &too_many), Err(Error::NoSpace));
+    assert_eq!(read(&mut device, 30), block(0));
+
+    // A retired transaction is never replayed over newer writes.
+    device.write_block(20, &block(9)).unwrap();
+    Journal::recover(&mut device, 1, 16, 64).unwrap();
+    assert_eq!(read(&mut device, 20), block(9));
+  }
+
+  #[test]
+  fn test_recover_committed() {
+    let mut device = MemDevice::new(512, 64);
+    let mut journal = Journal::format(&mut device, 1, 16).unwrap();
+    journal.commit(&mut device, &[(20, block(1))]).unwrap();
+
+    // Run the next transaction on a copy, keeping the journal but not the
+    // checkpoint: as if the machine died right after committing.
+    let writes = vec![(20, block(5)), (22, block(6))];
+    let mut copy = MemDevice::new(512, 64);
+    Journal { start: 1, blocks: 16, sequence: 2 }.commit(&mut copy, &writes)
+      .unwrap();
+    for num in 2..5 { device.write_block(num, &read(&mut copy, num)).unwrap(); }
+
+    // Without an intact commit block, the transaction is ignored.
+    let mut commit = read(&mut copy, 5);
+    commit[20] ^= 1;
+    device.write_block(5, &commit).unwrap();
+    assert_eq!(Journal::recover(&mut device, 1, 16, 64).unwrap().sequence, 2);
+    assert_eq!(read(&mut device, 20), block(1));
+
+    commit[20] ^= 1;
+    device.write_block(5, &commit).unwrap();
+    assert_eq!(Journal::recover(&mut device, 1, 16, 64).unwrap().sequence, 3);
+    assert_eq!(read(&mut device, 20), block(5));
+    assert_eq!(read(&mut device, 22), block(6));
+
+    // Targets outside the device mean the journal itself is damaged.
+    let mut copy = MemDevice::new(512, 64);
+    Journal { start: 1, blocks: 16, sequence: 3 }.commit(&mut copy, &[(20, block(7))])
+      .unwrap();
+    for num in 2..5 { device.write_block(num, &read(&mut copy, num)).unwrap(); }
+    assert_eq!(Journal::recover(&mut device, 1, 16, 20).err(), Some(Error::Corrupted));
+  }
+}
@@ -1,28 +1,68 @@
 extern crate time;
+extern crate slab;
 
+mod cache;
+mod device;
 mod directory;
+mod disk;
+mod error;
 mod file;
+mod fs;
+mod fsck;
 mod inode;
+mod journal;
+mod lock;
+mod mmap;
+mod quota;
+mod pool;
+mod space;
+mod xattr;
 
 use file::{File, FileHandle};
 use file::File::{EmptyFile, DataFile, Directory};
+use std::cell::RefCell;
 use std::rc::Rc;
-use std::cell::{RefCell};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
 use directory::DirectoryHandle;
+pub use cache::{CacheStats, DEFAULT_READ_AHEAD};
+pub use device::{BlockDevice, SharedDevice, MemDevice, FileDevice, MmapDevice, CrashDevice};
+pub use error::{Error, Result};
 pub use file::Whence;
-pub use inode::Inode;
+pub use fs::{FileSystem, Orphan, StatFs, LINK_MAX};
+pub use fsck::{fsck_device, fsck_image, FsckProblem, FsckReport, LOST_AND_FOUND};
+pub use inode::{Inode, Geometry, PageSlice, Stat};
+pub use journal::JournalMode;
+pub use lock::{Flock, LockKind, LOCK_SH, LOCK_EX, LOCK_NB, LOCK_UN, F_GETLK, F_SETLK, F_SETLKW};
+pub use mmap::{Mapping, MapMut, PROT_READ, PROT_WRITE, MAP_SHARED, MAP_PRIVATE, MS_ASYNC, MS_SYNC};
+pub use pool::{PoolStats, DEFAULT_POOL_LIMIT};
+pub use quota::{Owner, Quota, QuotaKind, QuotaUsage, DEFAULT_GRACE_SECS};
+pub use space::Limits;
+pub use xattr::{Xattrs, XATTR_CREATE, XATTR_REPLACE, XATTR_NAME_MAX, XATTR_SIZE_MAX};
 
 pub type FileDescriptor = isize;
 
-pub const O_RDONLY: u32 =   (1 << 0);
-pub const O_WRONLY: u32 =   (1 << 1);
-pub const O_RDWR: u32 =     (1 << 2);
-pub const O_NONBLOCK: u32 = (1 << 3);
-pub const O_APPEND: u32 =   (1 << 4);
-pub const O_CREAT: u32 =    (1 << 5);
+pub const O_RDONLY: u32 =   1 << 0;
+pub const O_WRONLY: u32 =   1 << 1;
+pub const O_RDWR: u32 =     1 << 2;
+pub const O_NONBLOCK: u32 = 1 << 3;
+pub const O_APPEND: u32 =   1 << 4;
+pub const O_CREAT: u32 =    1 << 5;
+/// Creates an unnamed file, for which `path` names the directory instead. The
+/// file is an orphan until `Proc::linkat` gives it a name.
+pub const O_TMPFILE: u32 =  1 << 6;
+
+pub const FALLOC_FL_KEEP_SIZE: u32 =  1 << 0;
+pub const FALLOC_FL_PUNCH_HOLE: u32 = 1 << 1;
+pub const FALLOC_FL_ZERO_RANGE: u32 = 1 << 2;
+
+// The next `Proc` id; ids identify the owners of locks.
+static NEXT_PID: AtomicU32 = AtomicU32::new(1);
 
 pub struct Proc<'r> {
+  pid: u32,
+  owner: Owner,
+  fs: Rc<FileSystem<'r>>,
   cwd: File<'r>,
   fd_table: HashMap<FileDescriptor, FileHandle<'r>>,
   fds: Vec<FileDescriptor>
@@ -30,8 +70,22 @@ pub struct Proc<'r> {
 
 impl<'r> Proc<'r> {
   pub fn new() -> Proc<'r> {
+    Proc::with_geometry(Geometry::default())
+  }
+
+  /// Creates a `Proc` over a fresh file system whose inodes all use
+  /// `geometry` for their page size and list fan-out.
+  pub fn with_geometry(geometry: Geometry) -> Proc<'r> {
+    Proc::with_fs(Rc::new(FileSystem::with_geometry(geometry)))
+  }
+
+  /// Creates a `Proc` over `fs`, which may be shared with other `Proc`s.
+  pub fn with_fs(fs: Rc<FileSystem<'r>>) -> Proc<'r> {
     Proc {
-      cwd: File::new_dir(None),
+      pid: NEXT_PID.fetch_add(1, Ordering::Relaxed),
+      owner: Owner::default(),
+      cwd: fs.root(),
+      fs,
       fd_table: HashMap::new(),
       fds: (0..(256 - 2)).map(|i| 256 - i).collect(),
     }
@@ -39,63 +93,490 @@ impl<'r> Proc<'r> {
 
   #[inline(always)]
   fn extract_fd(fd_opt: &Option<FileDescriptor>) -> FileDescriptor {
-    match fd_opt {
-      &Some(fd) => fd,
-      &None => panic!("Error in FD allocation.")
+    match *fd_opt {
+      Some(fd) => fd,
+      None => panic!("Error in FD allocation.")
     }
   }
 
+  /// Opens the file at `path`, creating it with `O_CREAT`, and returns its
+  /// descriptor. Failures return the negated `Error::errno()`, as with `-2`
+  /// (`NotFound`) for a missing file or `-21` (`IsDirectory`) for a directory.
   pub fn open(&mut self, path: &'r str, flags: u32) -> FileDescriptor {
-    let lookup = self.cwd.get(path);
-    let file = match lookup {
-      Some(f) => f,
-      None => {
-        if (flags & O_CREAT) != 0 {
-          // FIXME: Fetch from allocator
-          let rcinode = Rc::new(RefCell::new(Box::new(Inode::new())));
-          let file = File::new_data_file(rcinode);
-          self.cwd.insert(path, file.clone());
-          file
-        } else {
-          EmptyFile
-        }
+    let opened = match (flags & O_TMPFILE) != 0 {
+      true => self.open_tmpfile(path),
+      false => self.open_file(path, flags)
+    };
+
+    match opened {
+      Ok(fd) => fd,
+      Err(err) => -(err.errno() as isize)
+    }
+  }
+
+  fn open_file(&mut self, path: &'r str, flags: u32) -> Result<FileDescriptor> {
+    let file = match self.cwd.get(path) {
+      Some(file) => file,
+      None if (flags & O_CREAT) != 0 => {
+        if self.fs.is_read_only() { return Err(Error::ReadOnly); }
+        let file = self.fs.new_file(self.owner)?;
+        self.cwd.insert(path, file.clone());
+        file
       }
+      None => EmptyFile
     };
 
     match file {
       DataFile(_) => {
         let fd = Proc::extract_fd(&self.fds.pop());
+        self.fs.opened(&file, false);
         let handle = FileHandle::new(file);
         self.fd_table.insert(fd, handle);
-        fd
+        Ok(fd)
       }
-      Directory(_) => -1,
-      EmptyFile => -2,
+      Directory(_) => Err(Error::IsDirectory),
+      EmptyFile => Err(Error::NotFound),
     }
   }
 
-  pub fn read(&self, fd: FileDescriptor, dst: &mut [u8]) -> usize {
-    let handle = self.fd_table.get(&fd).expect("fd does not exist");
-    handle.read(dst)
+  fn open_tmpfile(&mut self, path: &'r str) -> Result<FileDescriptor> {
+    if !self.lookup(path)?.is_dir() { return Err(Error::NotDirectory); }
+    if self.fs.is_read_only() { return Err(Error::ReadOnly); }
+    let file = self.fs.new_file(self.owner)?;
+
+    let fd = Proc::extract_fd(&self.fds.pop());
+    self.fs.opened(&file, true);
+    self.fd_table.insert(fd, FileHandle::new(file));
+    Ok(fd)
+  }
+
+  pub fn read(&self, fd: FileDescriptor, dst: &mut [u8]) -> Result<usize> {
+    let handle = self.fd_table.get(&fd).ok_or(Error::BadDescriptor)?;
+    let read = handle.read(dst);
+    self.fs.trim_cache();
+    read
+  }
+
+  /// Reads up to `len` bytes like `read`, but returns slices of the file's
+  /// pages holding them instead of copying them out.
+  pub fn read_slices(&self, fd: FileDescriptor, len: usize) -> Result<Vec<PageSlice>> {
+    let handle = self.fd_table.get(&fd).ok_or(Error::BadDescriptor)?;
+    let slices = handle.read_slices(len);
+    self.fs.trim_cache();
+    slices
   }
 
-  pub fn write(&mut self, fd: FileDescriptor, src: &[u8]) -> usize {
-    let handle = self.fd_table.get_mut(&fd).expect("fd does not exist");
-    handle.write(src)
+  pub fn write(&mut self, fd: FileDescriptor, src: &[u8]) -> Result<usize> {
+    let handle = self.fd_table.get_mut(&fd).ok_or(Error::BadDescriptor)?;
+    if self.fs.is_read_only() { return Err(Error::ReadOnly); }
+    let written = handle.write(src);
+    self.fs.trim_cache();
+    written
   }
 
-  pub fn seek(&mut self, fd: FileDescriptor, o: isize, whence: Whence) -> usize {
-    let handle = self.fd_table.get_mut(&fd).expect("fd does not exist");
+  pub fn seek(&mut self, fd: FileDescriptor, o: isize, whence: Whence)
+      -> Result<usize> {
+    let handle = self.fd_table.get_mut(&fd).ok_or(Error::BadDescriptor)?;
     handle.seek(o, whence)
   }
 
+  /**
+   * Manipulates the pages backing `[offset, offset + len)` of the file open at
+   * `fd`. With a `mode` of 0 the pages are preallocated and the file grows to
+   * cover the range; `FALLOC_FL_KEEP_SIZE` preallocates without changing the
+   * size. `FALLOC_FL_PUNCH_HOLE`, which must be combined with
+   * `FALLOC_FL_KEEP_SIZE`, releases the range's pages so that it reads back as
+   * zeros. `FALLOC_FL_ZERO_RANGE` zeroes the range, allocating as needed.
+   */
+  pub fn fallocate(&mut self, fd: FileDescriptor, mode: u32, offset: usize,
+                   len: usize) -> Result<()> {
+    let handle = self.fd_table.get(&fd).ok_or(Error::BadDescriptor)?;
+    if self.fs.is_read_only() { return Err(Error::ReadOnly); }
+    let known = FALLOC_FL_KEEP_SIZE | FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE;
+    if (mode & !known) != 0 { return Err(Error::NotSupported); }
+
+    let keep_size = (mode & FALLOC_FL_KEEP_SIZE) != 0;
+    let punch_hole = (mode & FALLOC_FL_PUNCH_HOLE) != 0;
+    let zero_range = (mode & FALLOC_FL_ZERO_RANGE) != 0;
+
+    let mut inode = handle.inode_rc().borrow_mut();
+    let result = match (punch_hole, zero_range) {
+      (true, true) => Err(Error::InvalidArgument),
+      (true, false) if !keep_size => Err(Error::NotSupported),
+      (true, false) => inode.punch_hole(offset, len),
+      (false, true) => inode.zero_range(offset, len, keep_size),
+      (false, false) => inode.allocate(offset, len, keep_size)
+    };
+
+    drop(inode);
+    self.fs.trim_cache();
+    result
+  }
+
+  /**
+   * Copies `len` bytes from offset `off_in` of the file open at `fd_in` to
+   * offset `off_out` of the file open at `fd_out`, returning the number of
+   * bytes copied, which is short if the input ends first. Neither file's
+   * offset moves. Whole pages that line up are shared rather than copied, and
+   * only split when either file next writes to them. Copying between
+   * overlapping ranges of one file fails with `InvalidArgument`.
+   */
+  pub fn copy_file_range(&mut self, fd_in: FileDescriptor, off_in: usize,
+                         fd_out: FileDescriptor, off_out: usize, len: usize)
+      -> Result<usize> {
+    let src = self.fd_table.get(&fd_in).ok_or(Error::BadDescriptor)?.inode_rc();
+    let dst = self.fd_table.get(&fd_out).ok_or(Error::BadDescriptor)?.inode_rc();
+    if self.fs.is_read_only() { return Err(Error::ReadOnly); }
+    let copied = if !Rc::ptr_eq(src, dst) {
+      dst.borrow_mut().copy_range(&mut src.borrow_mut(), off_in, off_out, len)
+    } else if off_in < off_out.saturating_add(len) && off_out < off_in.saturating_add(len) {
+      return Err(Error::InvalidArgument);
+    } else {
+      // A copy of the file shares its pages, so this copies no data either.
+      let mut copy = src.borrow_mut().snapshot()?;
+      dst.borrow_mut().copy_range(&mut copy, off_in, off_out, len)
+    };
+
+    self.fs.trim_cache();
+    copied
+  }
+
+  /**
+   * Makes the file open at `fd_out` a clone of the one open at `fd_in`, like
+   * the `FICLONE` ioctl. The files share every page until either writes to
+   * it. Cloning a file onto itself fails with `InvalidArgument`.
+   */
+  pub fn reflink(&mut self, fd_in: FileDescriptor, fd_out: FileDescriptor) -> Result<()> {
+    let src = self.fd_table.get(&fd_in).ok_or(Error::BadDescriptor)?.inode_rc();
+    let dst = self.fd_table.get(&fd_out).ok_or(Error::BadDescriptor)?.inode_rc();
+    if self.fs.is_read_only() { return Err(Error::ReadOnly); }
+    if Rc::ptr_eq(src, dst) { return Err(Error::InvalidArgument); }
+    let cloned = dst.borrow_mut().reflink(&mut src.borrow_mut());
+    self.fs.trim_cache();
+    cloned
+  }
+
+  /// Makes the file open at `fd`, and the rest of the file system, durable.
+  pub fn fsync(&self, fd: FileDescriptor) -> Result<()> {
+    self.fd_table.get(&fd).ok_or(Error::BadDescriptor)?;
+    self.fs.sync()
+  }
+
+  /// Like `fsync`, but skips the flush if only the file's timestamps changed
+  /// since it was last made durable.
+  pub fn fdatasync(&self, fd: FileDescriptor) -> Result<()> {
+    let handle = self.fd_table.get(&fd).ok_or(Error::BadDescriptor)?;
+    if !handle.inode_rc().borrow_mut().needs_data_sync() { return Ok(()); }
+    self.fs.sync()
+  }
+
+  /// Makes every change to the file system durable.
+  pub fn sync(&self) -> Result<()> {
+    self.fs.sync()
+  }
+
+  /**
+   * Maps `[offset, offset + len)` of the file open at `fd`; see the `mmap`
+   * module. `prot` is `PROT_READ`, optionally with `PROT_WRITE`, and `flags`
+   * is either `MAP_SHARED` or `MAP_PRIVATE`. `offset` must be a multiple of
+   * the page size and `len` positive, or this fails with `InvalidArgument`. A
+   * shared writable mapping fails with `ReadOnly` on a read-only file system.
+   * The mapping outlives `fd`, and a shared one keeps the file open: unlinked,
+   * it lives on as an orphan until it's closed and unmapped.
+   */
+  pub fn mmap(&self, fd: FileDescriptor, offset: usize, len: usize, prot: u32,
+              flags: u32) -> Result<Mapping<'r>> {
+    let handle = self.fd_table.get(&fd).ok_or(Error::BadDescriptor)?;
+    if (prot & !(PROT_READ | PROT_WRITE)) != 0 { return Err(Error::InvalidArgument); }
+    let shared = match flags {
+      MAP_SHARED => true,
+      MAP_PRIVATE => false,
+      _ => return Err(Error::InvalidArgument)
+    };
+
+    let geometry = self.fs.geometry();
+    if len == 0 || !offset.is_multiple_of(geometry.page_size()) {
+      return Err(Error::InvalidArgument);
+    }
+
+    match offset.checked_add(len) {
+      Some(end) if end <= geometry.max_file_size() => {},
+      _ => return Err(Error::FileTooLarge)
+    }
+
+    let writable = (prot & PROT_WRITE) != 0;
+    if shared && writable && self.fs.is_read_only() { return Err(Error::ReadOnly); }
+    let map = match shared {
+      true => Mapping::shared(self.fs.clone(), handle.file().clone(), offset, len, prot),
+      false => {
+        let copy = handle.inode_rc().borrow_mut().snapshot()?;
+        Mapping::private(Rc::new(RefCell::new(Box::new(copy))), offset, len, prot)
+      }
+    };
+
+    self.fs.trim_cache();
+    Ok(map)
+  }
+
+  /**
+   * Writes back the pages of `map` that its views couldn't, failing if they
+   * still can't be, then, with `MS_SYNC`, makes a shared mapping's file
+   * durable, as `fsync` does. `flags` is either `MS_ASYNC` or `MS_SYNC`.
+   * Private mappings have nothing to sync.
+   */
+  pub fn msync(&self, map: &Mapping, flags: u32) -> Result<()> {
+    if flags != MS_ASYNC && flags != MS_SYNC { return Err(Error::InvalidArgument); }
+    let written = map.write_pending();
+    self.fs.trim_cache();
+    written?;
+    match map.is_shared() && flags == MS_SYNC {
+      true => self.fs.sync(),
+      false => Ok(())
+    }
+  }
+
+  /// Unmaps `map`. Pages its views couldn't write back are dropped with it;
+  /// `msync` first to make sure they aren't.
+  pub fn munmap(&self, map: Mapping<'r>) {
+    drop(map);
+  }
+
+  /// Closes `fd`, releasing the `flock` lock taken through it and all of this
+  /// `Proc`'s byte-range locks on the file. Closing the last descriptor for an
+  /// orphan frees it.
   pub fn close(&mut self, fd: FileDescriptor) {
-    self.fd_table.remove(&fd);
+    if let Some(handle) = self.fd_table.remove(&fd) {
+      handle.inode_rc().borrow_mut().locks_mut().release(self.pid, fd);
+      self.fs.closed(handle.file());
+    }
+
     self.fds.push(fd);
   }
 
-  pub fn unlink(&mut self, path: &'r str) {
+  /// Runs as user `uid` and group `gid` from now on, which own the files this
+  /// `Proc` creates. A `Proc` starts out as root, with both ids 0.
+  pub fn set_credentials(&mut self, uid: u32, gid: u32) {
+    self.owner = Owner { uid, gid };
+  }
+
+  pub fn credentials(&self) -> Owner {
+    self.owner
+  }
+
+  /**
+   * Gives the file at `path` to user `uid` and group `gid`, moving its usage
+   * from the old owner's quotas to the new one's. Fails with `QuotaExceeded`
+   * if that would put the new user or group past a quota. There are no
+   * permissions, so any `Proc` may do this.
+   */
+  pub fn chown(&mut self, path: &'r str, uid: u32, gid: u32) -> Result<()> {
+    let file = self.lookup(path)?;
+    if file.is_dir() { return Err(Error::IsDirectory); }
+    if self.fs.is_read_only() { return Err(Error::ReadOnly); }
+    let mut inode = file.get_inode_rc().borrow_mut();
+    inode.set_owner(Owner { uid, gid })
+  }
+
+  pub fn fchown(&mut self, fd: FileDescriptor, uid: u32, gid: u32) -> Result<()> {
+    let handle = self.fd_table.get(&fd).ok_or(Error::BadDescriptor)?;
+    if self.fs.is_read_only() { return Err(Error::ReadOnly); }
+    handle.inode_rc().borrow_mut().set_owner(Owner { uid, gid })
+  }
+
+  /// Identifies this `Proc` as the owner of a lock reported by `fcntl`.
+  pub fn pid(&self) -> u32 {
+    self.pid
+  }
+
+  /**
+   * Takes or releases a lock on the whole file open at `fd`, according to
+   * `op`: `LOCK_SH` for a shared lock, `LOCK_EX` for an exclusive one, or
+   * `LOCK_UN` to release it. Fails with `WouldBlock` if another descriptor
+   * holds a conflicting lock, with `LOCK_NB` or without, since nothing could
+   * release that lock while this waits. The lock belongs to `fd` and is
+   * released when it's closed.
+   */
+  pub fn flock(&mut self, fd: FileDescriptor, op: u32) -> Result<()> {
+    let handle = self.fd_table.get(&fd).ok_or(Error::BadDescriptor)?;
+    let mut inode = handle.inode_rc().borrow_mut();
+    inode.locks_mut().flock(self.pid, fd, op)
+  }
+
+  /**
+   * Manipulates the byte-range locks on the file open at `fd`. `F_SETLK` sets
+   * `lock`, or releases the range with a kind of `Unlock`, failing with
+   * `WouldBlock` if another `Proc` holds a conflicting lock. So does
+   * `F_SETLKW`, since nothing could release that lock while this waits.
+   * `F_GETLK` replaces `lock` with a conflicting lock, if there is one, or
+   * sets its kind to `Unlock`. The locks belong to this `Proc`, which
+   * releases them all when closing any descriptor for the file.
+   */
+  pub fn fcntl(&mut self, fd: FileDescriptor, cmd: u32, lock: &mut Flock) -> Result<()> {
+    let handle = self.fd_table.get(&fd).ok_or(Error::BadDescriptor)?;
+    let mut inode = handle.inode_rc().borrow_mut();
+    let locks = inode.locks_mut();
+    match cmd {
+      F_GETLK => {
+        *lock = locks.get(self.pid, lock)?;
+        Ok(())
+      }
+      F_SETLK | F_SETLKW => locks.set(self.pid, lock),
+      _ => Err(Error::InvalidArgument)
+    }
+  }
+
+  /**
+   * Gives the file open at `fd` the name `path` in the working directory, like
+   * `linkat` with `AT_EMPTY_PATH`; this is how a file created with `O_TMPFILE`
+   * gets a name. Fails with `Exists` if `path` is taken, with `NotFound` for
+   * a file unlinked while open, and with `TooManyLinks` for a file with
+   * `LINK_MAX` names already.
+   */
+  pub fn linkat(&mut self, fd: FileDescriptor, path: &'r str) -> Result<()> {
+    let file = self.fd_file(fd)?;
+    if self.fs.is_read_only() { return Err(Error::ReadOnly); }
+    if self.cwd.get(path).is_some() { return Err(Error::Exists); }
+    self.fs.link(&file)?;
+    self.cwd.insert(path, file);
+    Ok(())
+  }
+
+  /// Removes `path` from the working directory. A file still open lives on as
+  /// an orphan until it's closed.
+  pub fn unlink(&mut self, path: &'r str) -> Result<()> {
+    if self.fs.is_read_only() { return Err(Error::ReadOnly); }
+    if self.cwd.get(path).is_none() { return Err(Error::NotFound); }
     self.cwd.remove(path);
+    Ok(())
+  }
+
+  pub fn stat(&self, path: &'r str) -> Result<Stat> {
+    let file = match self.cwd.get(path) {
+      Some(file) => file,
+      None => return Err(Error::NotFound)
+    };
+
+    if file.is_dir() { return Err(Error::IsDirectory); }
+    let stat = file.get_inode_rc().borrow().stat();
+    Ok(stat)
+  }
+
+  // The file or directory named `path` in the working directory, which is
+  // itself named by ".".
+  fn lookup(&self, path: &'r str) -> Result<File<'r>> {
+    match path {
+      "." => Ok(self.cwd.clone()),
+      _ => self.cwd.get(path).ok_or(Error::NotFound)
+    }
+  }
+
+  fn fd_file(&self, fd: FileDescriptor) -> Result<File<'r>> {
+    let handle = self.fd_table.get(&fd).ok_or(Error::BadDescriptor)?;
+    Ok(handle.file().clone())
+  }
+
+  /**
+   * Sets extended attribute `name` of the file or directory at `path` to
+   * `value`. `flags` may be `XATTR_CREATE`, to fail with `Exists` if the
+   * attribute is already set, or `XATTR_REPLACE`, to fail with `NoAttribute`
+   * if it isn't. See the `xattr` module for the names and sizes allowed.
+   */
+  pub fn setxattr(&mut self, path: &'r str, name: &str, value: &[u8], flags: u32)
+      -> Result<()> {
+    let file = self.lookup(path)?;
+    self.set_xattr(&file, name, value, flags)
+  }
+
+  pub fn fsetxattr(&mut self, fd: FileDescriptor, name: &str, value: &[u8], flags: u32)
+      -> Result<()> {
+    let file = self.fd_file(fd)?;
+    self.set_xattr(&file, name, value, flags)
+  }
+
+  /**
+   * Reads extended attribute `name` of the file or directory at `path` into
+   * `buf`, returning its size. With an empty `buf`, only returns the size;
+   * otherwise fails with `OutOfRange` if `buf` is too small.
+   */
+  pub fn getxattr(&self, path: &'r str, name: &str, buf: &mut [u8]) -> Result<usize> {
+    let file = self.lookup(path)?;
+    file.with_xattrs(|xattrs| copy_out(xattrs.get(name)?, buf))
+  }
+
+  pub fn fgetxattr(&self, fd: FileDescriptor, name: &str, buf: &mut [u8])
+      -> Result<usize> {
+    let file = self.fd_file(fd)?;
+    file.with_xattrs(|xattrs| copy_out(xattrs.get(name)?, buf))
+  }
+
+  /// Reads the names of the extended attributes of the file or directory at
+  /// `path` into `buf`, each followed by a NUL byte. Sizes work as in
+  /// `getxattr`.
+  pub fn listxattr(&self, path: &'r str, buf: &mut [u8]) -> Result<usize> {
+    let file = self.lookup(path)?;
+    copy_out(&list_xattrs(&file), buf)
+  }
+
+  pub fn flistxattr(&self, fd: FileDescriptor, buf: &mut [u8]) -> Result<usize> {
+    let file = self.fd_file(fd)?;
+    copy_out(&list_xattrs(&file), buf)
+  }
+
+  pub fn removexattr(&mut self, path: &'r str, name: &str) -> Result<()> {
+    let file = self.lookup(path)?;
+    if self.fs.is_read_only() { return Err(Error::ReadOnly); }
+    file.with_xattrs(|xattrs| xattrs.remove(name))
+  }
+
+  pub fn fremovexattr(&mut self, fd: FileDescriptor, name: &str) -> Result<()> {
+    let file = self.fd_file(fd)?;
+    if self.fs.is_read_only() { return Err(Error::ReadOnly); }
+    file.with_xattrs(|xattrs| xattrs.remove(name))
+  }
+
+  fn set_xattr(&self, file: &File<'r>, name: &str, value: &[u8], flags: u32)
+      -> Result<()> {
+    if self.fs.is_read_only() { return Err(Error::ReadOnly); }
+    let limit = self.fs.geometry().page_size();
+    file.with_xattrs(|xattrs| xattrs.set(name, value, flags, limit))
+  }
+
+  pub fn statfs(&self) -> StatFs {
+    self.fs.statfs()
+  }
+}
+
+// Copies `data` into `buf` for the xattr calls, which report the size needed
+// when given an empty buffer.
+fn copy_out(data: &[u8], buf: &mut [u8]) -> Result<usize> {
+  if buf.is_empty() { return Ok(data.len()); }
+  if buf.len() < data.len() { return Err(Error::OutOfRange); }
+  buf[..data.len()].copy_from_slice(data);
+  Ok(data.len())
+}
+
+fn list_xattrs(file: &File) -> Vec<u8> {
+  file.with_xattrs(|xattrs| {
+    let mut names = Vec::new();
+    for (name, _) in xattrs.iter() {
+      names.extend_from_slice(name.as_bytes());
+      names.push(0);
+    }
+
+    names
+  })
+}
+
+impl<'r> Drop for Proc<'r> {
+  fn drop(&mut self) {
+    let fds: Vec<FileDescriptor> = self.fd_table.keys().cloned().collect();
+    for fd in fds { self.close(fd); }
+  }
+}
+
+impl<'r> Default for Proc<'r> {
+  fn default() -> Proc<'r> {
+    Proc::new()
   }
 }
 
@@ -104,25 +585,17 @@ mod proc_tests {
   // extern crate test;
   extern crate rand;
 
-  use super::{Proc, O_RDWR, O_CREAT};
-  use file::Whence::SeekSet;
-  use inode::Inode;
+  use super::{Proc, Error, O_RDWR, O_CREAT, O_TMPFILE, XATTR_CREATE, XATTR_REPLACE};
+  use super::{FileSystem, Limits, LINK_MAX, PoolStats, Quota, QuotaKind, Flock, LockKind, LOCK_SH, LOCK_EX, LOCK_NB, LOCK_UN};
+  use super::{F_GETLK, F_SETLK, F_SETLKW};
+  use super::{FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE};
+  use super::{PROT_READ, PROT_WRITE, MAP_SHARED, MAP_PRIVATE, MS_ASYNC, MS_SYNC, PageSlice};
+  use directory::DirectoryHandle;
+  use file::Whence::{SeekSet, SeekCur, SeekEnd, SeekData, SeekHole};
+  use inode::Geometry;
   use self::rand::random;
-
-  static mut test_inode_drop: bool = false;
-
-  impl Drop for Inode {
-    fn drop(&mut self) {
-      unsafe {
-        if test_inode_drop {
-          test_inode_drop = false;
-          panic!("Dropping.");
-        } else {
-          println!("Dropping, but no flag.");
-        }
-      }
-    }
-  }
+  use std::rc::Rc;
+  use time::{Duration, Timespec};
 
   fn rand_array(size: usize) -> Vec<u8> {
     (0..size).map(|_| random::<u8>()).collect()
@@ -145,15 +618,15 @@ mod proc_tests {
     let filename = "first_file";
 
     let fd = p.open(filename, O_RDWR | O_CREAT);
-    p.write(fd, &data);
-    p.seek(fd, 0, SeekSet);
-    p.read(fd, &mut buf);
+    p.write(fd, &data).unwrap();
+    p.seek(fd, 0, SeekSet).unwrap();
+    p.read(fd, &mut buf).unwrap();
 
     assert_eq_buf(&data, &buf);
 
     let fd2 = p.open(filename, O_RDWR);
     let mut buf2 = [0u8; SIZE];
-    p.read(fd2, &mut buf2);
+    p.read(fd2, &mut buf2).unwrap();
 
     assert_eq_buf(&data, &buf2);
 
@@ -162,131 +635,812 @@ mod proc_tests {
 
     let fd3 = p.open(filename, O_RDWR);
     let mut buf3 = [0u8; SIZE];
-    p.read(fd3, &mut buf3);
+    p.read(fd3, &mut buf3).unwrap();
 
     assert_eq_buf(&data, &buf3);
     p.close(fd3);
 
-    p.unlink(filename);
+    p.unlink(filename).unwrap();
+    assert_eq!(p.unlink(filename), Err(Error::NotFound));
 
     let fd4 = p.open(filename, O_RDWR);
-    assert_eq!(fd4, -2);
+    assert_eq!(fd4, -(Error::NotFound.errno() as isize));
   }
 
   #[test]
-  #[should_panic]
   fn test_proc_drop_inode_dealloc() {
-    // Variable is used to make sure that the Drop implemented is only valid for
-    // tests that set that test_inode_drop global variable to true.
-    unsafe { test_inode_drop = true; }
-
     const SIZE: usize = 4096 * 3 + 3498;
     let mut p = Proc::new();
-    let mut data = rand_array(SIZE);
+    let data = rand_array(SIZE);
 
     let fd = p.open("file", O_RDWR | O_CREAT);
-    p.write(fd, &mut data);
+    p.write(fd, &data).unwrap();
+
+    // The `Proc` holds the only file system, so the inode goes with it.
+    let inode = Rc::downgrade(p.fd_table[&fd].inode_rc());
+    drop(p);
+    assert!(inode.upgrade().is_none());
   }
 
   /**
-   * This function makes sure that on unlink, the inode's data structure is
-   * indeed dropped. This means that a few things have gone right:
-   *
-   * 1) The FileHandle was dropped. If it wasn't, it would hold a reference to
-   *    the file and so the file wouldn't be dropped. This should happen on
-   *    close.
-   * 2) The File, containing the Inode, was dropped. This should happen on
-   *    unlink.
+   * Makes sure that an inode is freed once it's both closed and unlinked, in
+   * either order, and that in between being unlinked and closed it's an
+   * orphan that can still be read.
    */
   #[test]
-  #[should_panic]
   fn test_inode_dealloc() {
-    // Make sure flag is set to detect drop.
-    unsafe { test_inode_drop = true; }
-
     const SIZE: usize = 4096 * 3 + 3498;
     let mut p = Proc::new();
-    let mut data = rand_array(SIZE);
+    let data = rand_array(SIZE);
     let mut buf = [0u8; SIZE];
     let filename = "first_file";
 
     let fd = p.open(filename, O_RDWR | O_CREAT);
-    p.write(fd, &mut data);
-    p.seek(fd, 0, SeekSet);
-    p.read(fd, &mut buf);
+    p.write(fd, &data).unwrap();
+    let inode = Rc::downgrade(p.fd_table[&fd].inode_rc());
+    p.close(fd);
+    assert!(inode.upgrade().is_some());
+    p.unlink(filename).unwrap();
+    assert!(inode.upgrade().is_none());
 
+    let fd = p.open(filename, O_RDWR | O_CREAT);
+    let fd2 = p.open(filename, O_RDWR);
+    p.write(fd, &data).unwrap();
+    let inode = Rc::downgrade(p.fd_table[&fd].inode_rc());
+    assert!(p.fs.orphans().is_empty());
+    p.unlink(filename).unwrap();
+
+    let orphans = p.fs.orphans();
+    assert_eq!(orphans.len(), 1);
+    assert_eq!((orphans[0].opens, orphans[0].tmpfile), (2, false));
+    assert_eq!(orphans[0].stat.size, SIZE);
+    p.read(fd2, &mut buf).unwrap();
     assert_eq_buf(&data, &buf);
 
-    // close + unlink should remove both references to inode, dropping it,
-    // causing a failure
+    // An orphan can't be given a name again.
+    assert_eq!(p.linkat(fd, filename), Err(Error::NotFound));
+
     p.close(fd);
-    p.unlink(filename);
+    assert_eq!(p.fs.orphans()[0].opens, 1);
+    p.close(fd2);
+    assert!(p.fs.orphans().is_empty());
+    assert!(inode.upgrade().is_none());
+  }
+
+  #[test]
+  fn test_tmpfile() {
+    let mut p = Proc::new();
+    let data = rand_array(5000);
+    let mut buf = [0u8; 5000];
+
+    assert_eq!(p.open("missing", O_RDWR | O_TMPFILE), -2);
+    let fd = p.open("file", O_RDWR | O_CREAT);
+    assert_eq!(p.open("file", O_RDWR | O_TMPFILE), -20);
+    let cwd = p.cwd.clone();
+    p.cwd.insert("dir", cwd);
+    assert_eq!(p.open("dir", O_RDWR), -21);
+    p.unlink("dir").unwrap();
+
+    let tmp = p.open(".", O_RDWR | O_TMPFILE);
+    assert!(tmp >= 0);
+    p.write(tmp, &data).unwrap();
+    let orphans = p.fs.orphans();
+    assert_eq!(orphans.len(), 1);
+    assert!(orphans[0].tmpfile);
+    assert_eq!(orphans[0].stat.size, 5000);
 
-    // If inode is not being dropped properly, ie, on the unlink call this will
-    // cause a double failure: once for panic! call, and once when then the Inode
-    // is dropped since the Proc structure will be dropped.
-    //
-    // To test that RC is working properly, make sure that a double failure
-    // occurs when either the close or unlink calls above are commented out.
-    panic!("Inode not dropped!");
+    assert_eq!(p.linkat(tmp, "file"), Err(Error::Exists));
+    p.linkat(tmp, "named").unwrap();
+    assert!(p.fs.orphans().is_empty());
+    p.close(tmp);
+
+    let named = p.open("named", O_RDWR);
+    p.read(named, &mut buf).unwrap();
+    assert_eq_buf(&data, &buf);
+
+    // A linked file can take another name, as a hard link.
+    p.linkat(fd, "other").unwrap();
+    p.unlink("file").unwrap();
+    assert!(p.fs.orphans().is_empty());
+    assert_eq!(p.stat("other").unwrap().size, 0);
+
+    // Closing an unlinked tmpfile frees it.
+    let tmp = p.open(".", O_RDWR | O_TMPFILE);
+    let inode = Rc::downgrade(p.fd_table[&tmp].inode_rc());
+    p.close(tmp);
+    assert!(inode.upgrade().is_none());
   }
 
   #[test]
   fn test_max_singly_file_size() {
     const SIZE: usize = 4096 * 256;
     let mut p = Proc::new();
-    let mut data = rand_array(SIZE);
+    let data = rand_array(SIZE);
     let mut buf = [0u8; SIZE];
     let filename = "first_file";
 
     let fd = p.open(filename, O_RDWR | O_CREAT);
-    p.write(fd, &mut data);
-    p.seek(fd, 0, SeekSet);
-    p.read(fd, &mut buf);
+    p.write(fd, &data).unwrap();
+    p.seek(fd, 0, SeekSet).unwrap();
+    p.read(fd, &mut buf).unwrap();
 
     assert_eq_buf(&data, &buf);
 
     p.close(fd);
-    p.unlink(filename);
+    p.unlink(filename).unwrap();
 
     let fd4 = p.open(filename, O_RDWR);
-    assert_eq!(fd4, -2);
+    assert_eq!(fd4, -(Error::NotFound.errno() as isize));
   }
 
   #[test]
   fn test_max_file_size() {
     const SIZE: usize = 2 * 4096 * 256;
     let mut p = Proc::new();
-    let mut data1 = rand_array(SIZE);
-    let mut data2 = rand_array(SIZE);
+    let data1 = rand_array(SIZE);
+    let data2 = rand_array(SIZE);
     let mut buf = vec![0; SIZE];
     let filename = "first_file";
 
     let fd = p.open(filename, O_RDWR | O_CREAT);
-    p.write(fd, &mut data1);
-    p.seek(fd, 4096 * 257 * 256 - SIZE as isize, SeekSet);
-    p.write(fd, &mut data2);
+    p.write(fd, &data1).unwrap();
+    p.seek(fd, 4096 * 257 * 256 - SIZE as isize, SeekSet).unwrap();
+    p.write(fd, &data2).unwrap();
 
-    p.seek(fd, 0, SeekSet);
-    p.read(fd, &mut buf);
+    p.seek(fd, 0, SeekSet).unwrap();
+    p.read(fd, &mut buf).unwrap();
     assert_eq_buf(&data1, &buf);
 
-    p.seek(fd, 4096 * 257 * 256 - SIZE as isize, SeekSet);
-    p.read(fd, &mut buf);
+    p.seek(fd, 4096 * 257 * 256 - SIZE as isize, SeekSet).unwrap();
+    p.read(fd, &mut buf).unwrap();
     assert_eq_buf(&data2, &buf);
   }
 
   #[test]
-  #[should_panic]
   fn test_morethan_max_file_size() {
     const SIZE: usize = 2 * 4096 * 256;
+    const MAX: usize = 4096 * 257 * 256;
     let mut p = Proc::new();
-    let mut data = rand_array(SIZE);
+    let data = rand_array(SIZE);
     let filename = "first_file";
 
+    // A write running past the maximum file size stops short at it.
     let fd = p.open(filename, O_RDWR | O_CREAT);
-    p.write(fd, &mut data);
-    p.seek(fd, 4096 * 257 * 256 + 1 - SIZE as isize, SeekSet);
-    p.write(fd, &mut data);
+    p.write(fd, &data).unwrap();
+    p.seek(fd, (MAX + 1 - SIZE) as isize, SeekSet).unwrap();
+    assert_eq!(p.write(fd, &data), Ok(SIZE - 1));
+    assert_eq!(p.stat(filename).unwrap().size, MAX);
+
+    // And one starting there fails.
+    assert_eq!(p.seek(fd, 0, SeekCur), Ok(MAX));
+    assert_eq!(p.write(fd, &data), Err(Error::FileTooLarge));
+    assert_eq!(p.stat(filename).unwrap().size, MAX);
+  }
+
+  #[test]
+  fn test_geometry_stat_statfs() {
+    const SIZE: usize = 1024 * 5 + 100;
+    let geometry = Geometry::new(1024, 64).unwrap();
+    let mut p = Proc::with_geometry(geometry);
+    let data = rand_array(SIZE);
+    let mut buf = [0u8; SIZE];
+
+    let fd = p.open("file", O_RDWR | O_CREAT);
+    p.write(fd, &data).unwrap();
+    p.seek(fd, 0, SeekSet).unwrap();
+    p.read(fd, &mut buf).unwrap();
+    assert_eq_buf(&data, &buf);
+    p.close(fd);
+
+    let stat = p.stat("file").unwrap();
+    assert_eq!(stat.size, SIZE);
+    assert_eq!(stat.blksize, 1024);
+    assert_eq!(stat.blocks, 6);
+    assert_eq!(p.stat("missing"), Err(Error::NotFound));
+
+    let statfs = p.statfs();
+    assert_eq!(statfs.bsize, 1024);
+    assert_eq!(statfs.list_size, 64);
+    assert_eq!(statfs.max_file_size, 1024 * (64 + 64 * 64));
+
+    assert_eq!(Proc::new().statfs().bsize, 4096);
+    assert_eq!((statfs.blocks, statfs.bfree, statfs.files, statfs.ffree), (0, 0, 0, 0));
+  }
+
+  #[test]
+  fn test_quotas() {
+    let fs = Rc::new(FileSystem::with_geometry(Geometry::new(1024, 64).unwrap()));
+    let (mut alice, mut bob) = (Proc::with_fs(fs.clone()), Proc::with_fs(fs.clone()));
+    alice.set_credentials(1000, 100);
+    bob.set_credentials(1001, 100);
+    fs.set_clock(Some(Timespec::new(0, 0)));
+    fs.set_grace(QuotaKind::User, Duration::seconds(100), Duration::seconds(100));
+    let quota = Quota { block_soft: Some(4), block_hard: Some(6), inode_hard: Some(2),
+                        ..Quota::default() };
+    fs.set_quota(QuotaKind::User, 1000, quota);
+
+    let a = alice.open("a", O_RDWR | O_CREAT);
+    assert_eq!(alice.stat("a").unwrap().uid, 1000);
+    assert_eq!(alice.write(a, &rand_array(1024 * 8)), Ok(1024 * 6));
+    assert_eq!(alice.write(a, &[1]), Err(Error::QuotaExceeded));
+    let usage = fs.quota(QuotaKind::User, 1000);
+    assert_eq!((usage.blocks, usage.inodes), (6, 1));
+    assert_eq!(usage.block_grace, Some(Timespec::new(100, 0)));
+
+    // Past the soft limit, only the grace period lets usage grow.
+    alice.fallocate(a, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, 0, 1024).unwrap();
+    fs.set_clock(Some(Timespec::new(101, 0)));
+    assert_eq!(alice.fallocate(a, 0, 0, 1024), Err(Error::QuotaExceeded));
+    alice.fallocate(a, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, 0, 1024 * 2).unwrap();
+    assert_eq!(fs.quota(QuotaKind::User, 1000).block_grace, None);
+    alice.fallocate(a, 0, 0, 1024).unwrap();
+
+    // Other users aren't limited, and the file system clock runs on.
+    let b = bob.open("b", O_RDWR | O_CREAT);
+    bob.write(b, &rand_array(1024 * 10)).unwrap();
+    fs.set_clock(None);
+    assert!(fs.now().sec > 101);
+
+    alice.open("c", O_RDWR | O_CREAT);
+    assert_eq!(alice.open("d", O_RDWR | O_CREAT), -122);
+    assert_eq!(alice.chown("b", 1000, 100), Err(Error::QuotaExceeded));
+    alice.chown("c", 1001, 100).unwrap();
+    alice.open("d", O_RDWR | O_CREAT);
+    bob.fchown(b, 1002, 100).unwrap();
+    assert_eq!(bob.stat("b").unwrap().uid, 1002);
+
+    let report: Vec<_> = fs.quota_report(QuotaKind::User).iter()
+      .map(|&(id, usage)| (id, usage.blocks, usage.inodes))
+      .collect();
+    assert_eq!(report, [(1000, 5, 2), (1001, 0, 1), (1002, 10, 1)]);
+    let group = fs.quota(QuotaKind::Group, 100);
+    assert_eq!((group.blocks, group.inodes), (15, 4));
+  }
+
+  #[test]
+  fn test_page_pool() {
+    let fs = Rc::new(FileSystem::with_geometry(Geometry::new(1024, 16).unwrap()));
+    let mut p = Proc::with_fs(fs.clone());
+    let data = rand_array(1024 * 20);
+    let fd = p.open("a", O_RDWR | O_CREAT);
+    p.write(fd, &data).unwrap();
+    p.close(fd);
+    // The slabs grow by doubling, so 20 pages take 32 slots.
+    assert_eq!(fs.pool_stats(), PoolStats { pages: 20, free_pages: 12, lists: 2,
+                                            free_lists: 0 });
+    p.unlink("a").unwrap();
+    assert_eq!(fs.pool_stats(), PoolStats { free_pages: 32, free_lists: 2,
+                                            ..PoolStats::default() });
+
+    // New files reuse the freed pages and lists, zeroed.
+    let fd = p.open("b", O_RDWR | O_CREAT);
+    p.fallocate(fd, 0, 0, 1024 * 18).unwrap();
+    let mut buf = vec![1u8; 1024 * 18];
+    assert_eq!(p.read(fd, &mut buf), Ok(buf.len()));
+    assert!(buf.iter().all(|&byte| byte == 0));
+    assert_eq!(fs.pool_stats(), PoolStats { pages: 18, free_pages: 14, lists: 2,
+                                            free_lists: 0 });
+
+    fs.set_pool_limit(0);
+    p.close(fd);
+    p.unlink("b").unwrap();
+    assert_eq!(fs.pool_stats().free_pages, 0);
+  }
+
+  #[test]
+  fn test_limits() {
+    let fs = Rc::new(FileSystem::with_geometry(Geometry::new(1024, 64).unwrap()));
+    let mut p = Proc::with_fs(fs.clone());
+    let fd = p.open("a", O_RDWR | O_CREAT);
+    p.write(fd, &rand_array(1024 * 3)).unwrap();
+
+    let limits = Limits { max_bytes: Some(1024 * 8), max_inodes: Some(3) };
+    fs.set_limits(limits).unwrap();
+    assert_eq!(fs.set_limits(Limits { max_inodes: Some(0), ..limits }),
+               Err(Error::InvalidArgument));
+    let statfs = p.statfs();
+    assert_eq!((statfs.blocks, statfs.bfree, statfs.bavail), (8, 5, 5));
+    assert_eq!((statfs.files, statfs.ffree, statfs.favail), (3, 2, 2));
+
+    // Running out of room part way makes for a short write, then an error.
+    let data = rand_array(1024 * 6);
+    assert_eq!(p.write(fd, &data), Ok(1024 * 5));
+    assert_eq!(p.write(fd, &data), Err(Error::NoSpace));
+    assert_eq!(p.fallocate(fd, 0, 1024 * 20, 1), Err(Error::NoSpace));
+    assert_eq!(p.statfs().bfree, 0);
+
+    // Overwriting needs no more room, and punching a hole frees some.
+    p.seek(fd, 0, SeekSet).unwrap();
+    assert_eq!(p.write(fd, &data[..1024]), Ok(1024));
+    p.fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, 0, 1024 * 2).unwrap();
+    assert_eq!(p.statfs().bfree, 2);
+
+    let b = p.open("b", O_RDWR | O_CREAT);
+    let c = p.open("c", O_RDWR | O_CREAT);
+    assert_eq!(p.open("d", O_RDWR | O_CREAT), -28);
+    assert_eq!(p.open(".", O_RDWR | O_TMPFILE), -28);
+    assert_eq!(p.statfs().ffree, 0);
+    assert_eq!(p.reflink(fd, b), Err(Error::NoSpace));
+    p.write(c, &[1u8; 10]).unwrap();
+    assert_eq!(p.copy_file_range(fd, 1024 * 2, b, 0, 1024 * 3), Ok(1024));
+
+    // Files are only released once they're both unlinked and closed.
+    p.unlink("a").unwrap();
+    assert_eq!(p.statfs().ffree, 0);
+    p.close(fd);
+    assert_eq!((p.statfs().bfree, p.statfs().ffree), (6, 1));
+    p.reflink(c, b).unwrap();
+    assert_eq!(p.statfs().bfree, 6);
+  }
+
+  #[test]
+  fn test_sync_in_memory() {
+    let mut p = Proc::new();
+    let fd = p.open("file", O_RDWR | O_CREAT);
+    p.write(fd, &rand_array(5000)).unwrap();
+
+    assert_eq!(p.fsync(fd), Ok(()));
+    assert_eq!(p.fdatasync(fd), Ok(()));
+    assert_eq!(p.sync(), Ok(()));
+    assert_eq!(p.fsync(fd + 1), Err(Error::BadDescriptor));
+    assert_eq!(p.fdatasync(fd + 1), Err(Error::BadDescriptor));
+  }
+
+  #[test]
+  fn test_fallocate() {
+    const SIZE: usize = 4096 * 4;
+    let mut p = Proc::new();
+    let data = rand_array(SIZE);
+    let mut buf = vec![0; SIZE];
+
+    // Preallocating with KEEP_SIZE reserves pages but leaves the size alone.
+    let fd = p.open("wal", O_RDWR | O_CREAT);
+    p.fallocate(fd, FALLOC_FL_KEEP_SIZE, 0, SIZE).unwrap();
+    assert_eq!(p.stat("wal").unwrap().size, 0);
+    assert_eq!(p.stat("wal").unwrap().blocks, 4);
+
+    // Without it, the file grows to cover the range and reads as zeros.
+    p.fallocate(fd, 0, 0, SIZE + 10).unwrap();
+    assert_eq!(p.stat("wal").unwrap().size, SIZE + 10);
+    assert_eq!(p.stat("wal").unwrap().blocks, 5);
+    p.read(fd, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0));
+
+    // Punching a hole releases whole pages and zeroes partial ones.
+    p.seek(fd, 0, SeekSet).unwrap();
+    p.write(fd, &data).unwrap();
+    p.fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, 100, 4096 * 2)
+      .unwrap();
+    assert_eq!(p.stat("wal").unwrap().size, SIZE + 10);
+    assert_eq!(p.stat("wal").unwrap().blocks, 4);
+
+    p.seek(fd, 0, SeekSet).unwrap();
+    p.read(fd, &mut buf).unwrap();
+    assert_eq_buf(&data[..100], &buf[..100]);
+    assert!(buf[100..(4096 * 2 + 100)].iter().all(|&b| b == 0));
+    assert_eq_buf(&data[(4096 * 2 + 100)..], &buf[(4096 * 2 + 100)..]);
+
+    // Zeroing a range keeps the pages allocated.
+    p.fallocate(fd, FALLOC_FL_ZERO_RANGE, 0, 4096 * 4).unwrap();
+    assert_eq!(p.stat("wal").unwrap().blocks, 5);
+    p.seek(fd, 0, SeekSet).unwrap();
+    p.read(fd, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0));
+    p.close(fd);
+  }
+
+  #[test]
+  fn test_fallocate_errors() {
+    let mut p = Proc::new();
+    let fd = p.open("file", O_RDWR | O_CREAT);
+    let max = p.statfs().max_file_size;
+
+    assert_eq!(p.fallocate(fd, 0, 0, 0), Err(Error::InvalidArgument));
+    assert_eq!(p.fallocate(fd, 0, max, 1), Err(Error::FileTooLarge));
+    assert_eq!(p.fallocate(fd, 0, usize::MAX, 2), Err(Error::FileTooLarge));
+    assert_eq!(p.fallocate(fd, FALLOC_FL_PUNCH_HOLE, 0, 1),
+               Err(Error::NotSupported));
+    assert_eq!(p.fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_ZERO_RANGE, 0, 1),
+               Err(Error::InvalidArgument));
+    assert_eq!(p.fallocate(fd, 1 << 7, 0, 1), Err(Error::NotSupported));
+    assert_eq!(p.fallocate(fd + 1, 0, 0, 1), Err(Error::BadDescriptor));
+    p.close(fd);
+  }
+
+  #[test]
+  fn test_seek_data_hole() {
+    let mut p = Proc::new();
+    let data = rand_array(4096 * 2);
+    let fd = p.open("sparse", O_RDWR | O_CREAT);
+
+    // Layout: [hole: 0, 1] [data: 2, 3] [hole: 4..9] [data: 9, 10] -> EOF
+    p.seek(fd, 4096 * 2, SeekSet).unwrap();
+    p.write(fd, &data).unwrap();
+    p.seek(fd, 4096 * 9, SeekSet).unwrap();
+    p.write(fd, &data[..5000]).unwrap();
+    let size = 4096 * 9 + 5000;
+
+    assert_eq!(p.seek(fd, 0, SeekData), Ok(4096 * 2));
+    assert_eq!(p.seek(fd, 4096 * 2 + 7, SeekData), Ok(4096 * 2 + 7));
+    assert_eq!(p.seek(fd, 4096 * 4, SeekData), Ok(4096 * 9));
+    assert_eq!(p.seek(fd, 0, SeekHole), Ok(0));
+    assert_eq!(p.seek(fd, 4096 * 2, SeekHole), Ok(4096 * 4));
+    assert_eq!(p.seek(fd, 4096 * 9, SeekHole), Ok(size));
+    assert_eq!(p.seek(fd, size as isize, SeekData), Err(Error::NoSuchAddress));
+    assert_eq!(p.seek(fd, size as isize, SeekHole), Err(Error::NoSuchAddress));
+    assert_eq!(p.seek(fd, -1, SeekData), Err(Error::InvalidArgument));
+    assert_eq!(p.seek(fd, -1, SeekHole), Err(Error::InvalidArgument));
+
+    // A sparse-aware copy only visits the two data regions.
+    let mut copied = vec![0u8; size];
+    let mut regions = 0;
+    let mut offset = 0;
+    while let Ok(start) = p.seek(fd, offset as isize, SeekData) {
+      let end = p.seek(fd, start as isize, SeekHole).unwrap();
+      p.seek(fd, start as isize, SeekSet).unwrap();
+      p.read(fd, &mut copied[start..end]).unwrap();
+      regions += 1;
+      offset = end;
+    }
+
+    assert_eq!(regions, 2);
+    assert!(copied[..(4096 * 2)].iter().all(|&b| b == 0));
+    assert_eq_buf(&data, &copied[(4096 * 2)..(4096 * 4)]);
+    assert_eq_buf(&data[..5000], &copied[(4096 * 9)..]);
+    p.close(fd);
+  }
+
+  #[test]
+  fn test_seek_before_start() {
+    let mut p = Proc::new();
+    let data = rand_array(100);
+    let fd = p.open("file", O_RDWR | O_CREAT);
+    p.write(fd, &data).unwrap();
+
+    assert_eq!(p.seek(fd, -1, SeekSet), Err(Error::InvalidArgument));
+    assert_eq!(p.seek(fd, -101, SeekCur), Err(Error::InvalidArgument));
+    assert_eq!(p.seek(fd, -101, SeekEnd), Err(Error::InvalidArgument));
+    assert_eq!(p.seek(fd, isize::MIN, SeekCur), Err(Error::InvalidArgument));
+
+    // Failed seeks leave the offset where it was.
+    assert_eq!(p.seek(fd, 0, SeekCur), Ok(100));
+    assert_eq!(p.seek(fd, -100, SeekCur), Ok(0));
+    assert_eq!(p.seek(fd, -100, SeekEnd), Ok(0));
+
+    // So the next write lands at the last valid offset.
+    assert_eq!(p.seek(fd, -50, SeekEnd), Ok(50));
+    assert_eq!(p.seek(fd, -51, SeekCur), Err(Error::InvalidArgument));
+    p.write(fd, &data).unwrap();
+    assert_eq!(p.stat("file").unwrap().size, 150);
+    p.close(fd);
+  }
+
+  #[test]
+  fn test_seek_bounds() {
+    let mut p = Proc::new();
+    let max = p.statfs().max_file_size;
+    let fd = p.open("file", O_RDWR | O_CREAT);
+
+    assert_eq!(p.seek(fd, max as isize, SeekSet), Ok(max));
+    assert_eq!(p.seek(fd, 1, SeekCur), Err(Error::InvalidArgument));
+    assert_eq!(p.seek(fd, max as isize + 1, SeekSet), Err(Error::InvalidArgument));
+    assert_eq!(p.seek(fd, max as isize + 1, SeekEnd), Err(Error::InvalidArgument));
+    assert_eq!(p.seek(fd, isize::MAX, SeekSet), Err(Error::InvalidArgument));
+    assert_eq!(p.seek(fd, 0, SeekCur), Ok(max));
+    p.close(fd);
+  }
+
+  #[test]
+  fn test_seek_large_relative() {
+    let geometry = Geometry::new(512, 16).unwrap();
+    let mut p = Proc::with_geometry(geometry);
+    let max = p.statfs().max_file_size;
+    let fd = p.open("file", O_RDWR | O_CREAT);
+
+    assert_eq!(p.seek(fd, max as isize, SeekSet), Ok(max));
+    assert_eq!(p.seek(fd, isize::MAX, SeekCur), Err(Error::InvalidArgument));
+    assert_eq!(p.seek(fd, 0, SeekCur), Ok(max));
+    p.close(fd);
+  }
+
+  #[test]
+  fn test_read_loop_terminates() {
+    const SIZE: usize = 4096 * 3 + 1234;
+    let mut p = Proc::new();
+    let data = rand_array(SIZE);
+    let fd = p.open("file", O_RDWR | O_CREAT);
+    p.write(fd, &data).unwrap();
+    p.seek(fd, 0, SeekSet).unwrap();
+
+    // The equivalent of `read_to_end`: read until a zero-length read.
+    let mut contents = Vec::new();
+    let mut chunk = [0u8; 1000];
+    loop {
+      let read = p.read(fd, &mut chunk).unwrap();
+      if read == 0 { break; }
+      contents.extend_from_slice(&chunk[..read]);
+    }
+
+    assert_eq_buf(&data, &contents);
+    assert_eq!(p.seek(fd, 0, SeekCur), Ok(SIZE));
+    assert_eq!(p.read(fd, &mut chunk), Ok(0));
+
+    // Reading from past the end of file also signals EOF.
+    p.seek(fd, SIZE as isize + 10, SeekSet).unwrap();
+    assert_eq!(p.read(fd, &mut chunk), Ok(0));
+    assert_eq!(p.seek(fd, 0, SeekCur), Ok(SIZE + 10));
+    p.close(fd);
+  }
+
+  fn read_all(p: &mut Proc, fd: isize, size: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; size + 1];
+    p.seek(fd, 0, SeekSet).unwrap();
+    assert_eq!(p.read(fd, &mut buf), Ok(size));
+    buf.truncate(size);
+    buf
+  }
+
+  // The addresses of the pages of the file open at `fd`.
+  fn page_ptrs(p: &Proc, fd: isize) -> Vec<*const u8> {
+    let mut inode = p.fd_table[&fd].inode_rc().borrow_mut();
+    inode.pages_mut().map(|(_, page)| page.as_ptr()).collect()
+  }
+
+  #[test]
+  fn test_copy_file_range() {
+    const PAGE: usize = 512;
+    let mut p = Proc::with_geometry(Geometry::new(PAGE, 16).unwrap());
+    let data = rand_array(PAGE * 6 + 100);
+    let (src, dst) = (p.open("src", O_RDWR | O_CREAT), p.open("dst", O_RDWR | O_CREAT));
+    p.write(src, &data).unwrap();
+    p.fallocate(src, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, PAGE, PAGE).unwrap();
+    let mut expected = data.clone();
+    for byte in expected[PAGE..(PAGE * 2)].iter_mut() { *byte = 0; }
+
+    // Aligned ranges share pages, holes included, and split on write.
+    p.write(dst, &[9u8; PAGE * 4]).unwrap();
+    assert_eq!(p.copy_file_range(src, 0, dst, PAGE * 2, PAGE * 10), Ok(data.len()));
+    let mut copied = vec![9u8; PAGE * 2];
+    copied.extend_from_slice(&expected);
+    assert_eq!(read_all(&mut p, dst, copied.len()), copied);
+
+    let (src_pages, dst_pages) = (page_ptrs(&p, src), page_ptrs(&p, dst));
+    assert_eq!(dst_pages.len(), 2 + 6);
+    assert_eq!(dst_pages[2], src_pages[0]);
+    assert_eq!(&dst_pages[3..6], &src_pages[1..4]);
+
+    p.seek(dst, PAGE as isize * 2, SeekSet).unwrap();
+    p.write(dst, &[1, 2, 3]).unwrap();
+    assert_eq!(read_all(&mut p, src, expected.len()), expected);
+    assert!(page_ptrs(&p, dst)[2] != src_pages[0]);
+
+    // Unaligned ranges are copied, and copies stop at the end of the input.
+    assert_eq!(p.copy_file_range(src, 700, dst, 13, PAGE * 3), Ok(PAGE * 3));
+    assert_eq!(&read_all(&mut p, dst, copied.len())[13..(13 + PAGE * 3)],
+               &expected[700..(700 + PAGE * 3)]);
+    assert_eq!(p.copy_file_range(src, data.len() - 10, dst, 0, 100), Ok(10));
+    assert_eq!(p.copy_file_range(src, data.len() + 10, dst, 0, 100), Ok(0));
+
+    // Within a file, only ranges that don't overlap can be copied.
+    assert_eq!(p.copy_file_range(src, 0, src, 100, PAGE), Err(Error::InvalidArgument));
+    assert_eq!(p.copy_file_range(src, 0, src, PAGE * 7, PAGE * 2), Ok(PAGE * 2));
+    let mut grown = expected.clone();
+    grown.resize(PAGE * 7, 0);
+    grown.extend_from_slice(&expected[..(PAGE * 2)]);
+    assert_eq!(read_all(&mut p, src, grown.len()), grown);
+
+    assert_eq!(p.copy_file_range(src, 0, 99, 0, 1), Err(Error::BadDescriptor));
+    let max = p.statfs().max_file_size;
+    assert_eq!(p.copy_file_range(src, 0, dst, max, 1), Err(Error::FileTooLarge));
+  }
+
+  #[test]
+  fn test_reflink() {
+    let mut p = Proc::new();
+    let data = rand_array(4096 * 3 + 5);
+    let (src, dst) = (p.open("src", O_RDWR | O_CREAT), p.open("dst", O_RDWR | O_CREAT));
+    p.write(src, &data).unwrap();
+    p.write(dst, &rand_array(4096 * 5)).unwrap();
+
+    p.reflink(src, dst).unwrap();
+    assert_eq!(p.stat("dst").unwrap().size, data.len());
+    assert_eq!(p.stat("dst").unwrap().blocks, 4);
+    assert_eq!(page_ptrs(&p, src), page_ptrs(&p, dst));
+    assert_eq!(read_all(&mut p, dst, data.len()), data);
+
+    // Writing to the source splits off its page, leaving the clone as it was.
+    p.seek(src, 0, SeekSet).unwrap();
+    p.write(src, &[0u8; 10]).unwrap();
+    assert_eq!(read_all(&mut p, dst, data.len()), data);
+    assert!(page_ptrs(&p, src)[0] != page_ptrs(&p, dst)[0]);
+    assert_eq!(page_ptrs(&p, src)[1..], page_ptrs(&p, dst)[1..]);
+
+    assert_eq!(p.reflink(src, src), Err(Error::InvalidArgument));
+    assert_eq!(p.reflink(src, 99), Err(Error::BadDescriptor));
+  }
+
+  #[test]
+  fn test_xattrs() {
+    let mut p = Proc::new();
+    let fd = p.open("file", O_RDWR | O_CREAT);
+    let mut buf = [0u8; 64];
+
+    p.setxattr("file", "user.origin", b"ci", XATTR_CREATE).unwrap();
+    p.fsetxattr(fd, "security.label", b"x", 0).unwrap();
+    p.setxattr(".", "trusted.dir", b"root", 0).unwrap();
+    assert_eq!(p.setxattr("file", "user.origin", b"", XATTR_CREATE), Err(Error::Exists));
+    assert_eq!(p.setxattr("file", "user.other", b"", XATTR_REPLACE),
+               Err(Error::NoAttribute));
+    assert_eq!(p.setxattr("file", "system.x", b"", 0), Err(Error::NotSupported));
+    assert_eq!(p.setxattr("missing", "user.a", b"", 0), Err(Error::NotFound));
+    assert_eq!(p.fsetxattr(99, "user.a", b"", 0), Err(Error::BadDescriptor));
+
+    // Sizes can be queried with an empty buffer.
+    assert_eq!(p.getxattr("file", "user.origin", &mut []), Ok(2));
+    assert_eq!(p.getxattr("file", "user.origin", &mut buf[..1]), Err(Error::OutOfRange));
+    assert_eq!(p.fgetxattr(fd, "user.origin", &mut buf), Ok(2));
+    assert_eq!(&buf[..2], b"ci");
+    assert_eq!(p.getxattr(".", "trusted.dir", &mut buf), Ok(4));
+    assert_eq!(p.getxattr("file", "user.none", &mut buf), Err(Error::NoAttribute));
+
+    let names = b"security.label\0user.origin\0";
+    assert_eq!(p.listxattr("file", &mut []), Ok(names.len()));
+    assert_eq!(p.flistxattr(fd, &mut buf), Ok(names.len()));
+    assert_eq!(&buf[..names.len()], &names[..]);
+
+    // Attributes must all fit in a page.
+    let big = vec![0u8; 4096];
+    assert_eq!(p.setxattr("file", "user.big", &big, 0), Err(Error::NoSpace));
+    assert_eq!(p.setxattr("file", "user.big", &vec![0u8; 65537], 0), Err(Error::TooBig));
+
+    p.removexattr("file", "user.origin").unwrap();
+    p.fremovexattr(fd, "security.label").unwrap();
+    assert_eq!(p.removexattr("file", "user.origin"), Err(Error::NoAttribute));
+    assert_eq!(p.listxattr("file", &mut buf), Ok(0));
+    p.close(fd);
+  }
+
+  #[test]
+  fn test_locks() {
+    let fs = Rc::new(FileSystem::new());
+    let mut p = Proc::with_fs(fs.clone());
+    let mut q = Proc::with_fs(fs);
+    let (fd, fd2) = (p.open("file", O_RDWR | O_CREAT), p.open("file", O_RDWR));
+    let qfd = q.open("file", O_RDWR);
+
+    // Whole-file locks belong to descriptors, even within one `Proc`.
+    p.flock(fd, LOCK_EX).unwrap();
+    assert_eq!(p.flock(fd2, LOCK_SH | LOCK_NB), Err(Error::WouldBlock));
+    assert_eq!(q.flock(qfd, LOCK_SH), Err(Error::WouldBlock));
+    p.flock(fd, LOCK_SH).unwrap();
+    q.flock(qfd, LOCK_SH).unwrap();
+    q.flock(qfd, LOCK_UN).unwrap();
+    assert_eq!(p.flock(fd, 0), Err(Error::InvalidArgument));
+
+    // Byte-range locks belong to the `Proc`.
+    p.fcntl(fd, F_SETLK, &mut Flock::new(LockKind::Write, 100, 50)).unwrap();
+    p.fcntl(fd2, F_SETLK, &mut Flock::new(LockKind::Read, 120, 10)).unwrap();
+    let mut lock = Flock::new(LockKind::Read, 0, 0);
+    q.fcntl(qfd, F_GETLK, &mut lock).unwrap();
+    assert_eq!(lock, Flock { kind: LockKind::Write, start: 100, len: 20, pid: p.pid() });
+    assert_eq!(q.fcntl(qfd, F_SETLK, &mut Flock::new(LockKind::Read, 0, 101)),
+               Err(Error::WouldBlock));
+    assert_eq!(q.fcntl(qfd, F_SETLKW, &mut Flock::new(LockKind::Write, 125, 1)),
+               Err(Error::WouldBlock));
+    q.fcntl(qfd, F_SETLKW, &mut Flock::new(LockKind::Read, 0, 100)).unwrap();
+    assert_eq!(q.fcntl(qfd, 99, &mut lock), Err(Error::InvalidArgument));
+
+    // Closing either of `p`'s descriptors drops all its byte-range locks, but
+    // only the other's whole-file lock.
+    p.close(fd2);
+    q.fcntl(qfd, F_SETLK, &mut Flock::new(LockKind::Read, 0, 0)).unwrap();
+    assert_eq!(q.flock(qfd, LOCK_EX | LOCK_NB), Err(Error::WouldBlock));
+
+    // As does dropping the `Proc`.
+    drop(p);
+    q.flock(qfd, LOCK_EX | LOCK_NB).unwrap();
+    let mut lock = Flock::new(LockKind::Write, 0, 0);
+    q.fcntl(qfd, F_GETLK, &mut lock).unwrap();
+    assert_eq!(lock.kind, LockKind::Unlock);
+  }
+
+  #[test]
+  fn test_mmap() {
+    let mut p = Proc::new();
+    let data = rand_array(4096 * 2 + 100);
+    let (fd, fd2) = (p.open("file", O_RDWR | O_CREAT), p.open("file", O_RDWR));
+    p.write(fd, &data).unwrap();
+
+    // Views span pages, and past the end of file read as zeros.
+    let shared = p.mmap(fd, 4096, 8192, PROT_READ | PROT_WRITE, MAP_SHARED).unwrap();
+    assert_eq!(&shared.view().unwrap()[..4196], &data[4096..]);
+    assert!(shared.view().unwrap()[4196..].iter().all(|&b| b == 0));
+
+    // Shared writes reach the file, but never past its end.
+    shared.view_mut().unwrap()[4000..4300].copy_from_slice(&[7u8; 300]);
+    let mut expected = data.clone();
+    expected[8096..].copy_from_slice(&[7u8; 196]);
+    assert_eq!(read_all(&mut p, fd2, data.len()), expected);
+    assert_eq!(p.stat("file").unwrap().size, data.len());
+
+    // And the file's writes reach the mapping.
+    p.seek(fd2, 4096, SeekSet).unwrap();
+    p.write(fd2, &[1u8; 10]).unwrap();
+    assert_eq!(&shared.view().unwrap()[..10], &[1u8; 10]);
+    expected[4096..4106].copy_from_slice(&[1u8; 10]);
+
+    // Private writes stay in the mapping, which doesn't see the file's.
+    let private = p.mmap(fd, 0, 4096 * 3, PROT_READ | PROT_WRITE, MAP_PRIVATE).unwrap();
+    private.view_mut().unwrap()[..4096].copy_from_slice(&[9u8; 4096]);
+    p.seek(fd2, 4096, SeekSet).unwrap();
+    p.write(fd2, &[2u8; 10]).unwrap();
+    assert_eq!(&private.view().unwrap()[..4096], &[9u8; 4096][..]);
+    assert_eq!(&private.view().unwrap()[4096..4106], &[1u8; 10]);
+    expected[4096..4106].copy_from_slice(&[2u8; 10]);
+    assert_eq!(read_all(&mut p, fd2, data.len()), expected);
+
+    // The mapping outlives the descriptor.
+    let readonly = p.mmap(fd, 0, 10, PROT_READ, MAP_SHARED).unwrap();
+    p.close(fd);
+    assert_eq!(&*readonly.view().unwrap(), &expected[..10]);
+    assert_eq!(readonly.view_mut().err(), Some(Error::PermissionDenied));
+    assert_eq!(p.msync(&shared, MS_SYNC), Ok(()));
+    assert_eq!(p.msync(&private, MS_ASYNC), Ok(()));
+    assert_eq!(p.msync(&shared, MS_ASYNC | MS_SYNC), Err(Error::InvalidArgument));
+    p.munmap(shared);
+    p.munmap(private);
+
+    assert_eq!(p.mmap(fd, 0, 10, PROT_READ, MAP_SHARED).err(), Some(Error::BadDescriptor));
+    p.fs.snapshot("snap").unwrap();
+    let mut q = Proc::with_fs(Rc::new(p.fs.open_snapshot("snap").unwrap()));
+    let qfd = q.open("file", O_RDWR);
+    assert_eq!(q.mmap(qfd, 0, 10, PROT_READ | PROT_WRITE, MAP_SHARED).err(),
+               Some(Error::ReadOnly));
+    q.mmap(qfd, 0, 10, PROT_READ | PROT_WRITE, MAP_PRIVATE).unwrap();
+    assert_eq!(p.mmap(fd2, 100, 10, PROT_READ, MAP_SHARED).err(),
+               Some(Error::InvalidArgument));
+    assert_eq!(p.mmap(fd2, 0, 0, PROT_READ, MAP_SHARED).err(), Some(Error::InvalidArgument));
+    assert_eq!(p.mmap(fd2, 0, 10, PROT_READ, MAP_SHARED | MAP_PRIVATE).err(),
+               Some(Error::InvalidArgument));
+    assert_eq!(p.mmap(fd2, 0, usize::MAX, PROT_READ, MAP_PRIVATE).err(),
+               Some(Error::FileTooLarge));
+  }
+
+  // The bytes of `slices`, one after another.
+  fn concat(slices: &[PageSlice]) -> Vec<u8> {
+    slices.iter().flat_map(|slice| slice.iter().cloned()).collect()
+  }
+
+  #[test]
+  fn test_read_slices() {
+    let mut p = Proc::new();
+    let data = rand_array(4096 * 2 + 100);
+    let fd = p.open("file", O_RDWR | O_CREAT);
+    p.write(fd, &data).unwrap();
+
+    // Slices move the offset like reads do.
+    p.seek(fd, 100, SeekSet).unwrap();
+    let slices = p.read_slices(fd, 4096).unwrap();
+    assert_eq!(slices.len(), 2);
+    assert_eq!(concat(&slices), &data[100..4196]);
+    assert_eq!(concat(&p.read_slices(fd, 1 << 20).unwrap()), &data[4196..]);
+    assert!(p.read_slices(fd, 10).unwrap().is_empty());
+    assert_eq!(p.seek(fd, 0, SeekCur), Ok(data.len()));
+    assert_eq!(p.read_slices(99, 10).err(), Some(Error::BadDescriptor));
+  }
+
+  #[test]
+  fn test_link_max() {
+    let names: Vec<String> = (0..LINK_MAX).map(|i| format!("link{}", i)).collect();
+    let mut p = Proc::new();
+    let fd = p.open("file", O_RDWR | O_CREAT);
+    let file = p.fd_file(fd).unwrap();
+    for name in names[1..(LINK_MAX - 1)].iter() { p.cwd.insert(name, file.clone()); }
+
+    // The file has one name short of the most it may have.
+    p.linkat(fd, &names[LINK_MAX - 1]).unwrap();
+    assert_eq!(p.linkat(fd, "one too many"), Err(Error::TooManyLinks));
+    p.unlink("file").unwrap();
+    p.linkat(fd, "file").unwrap();
   }
 }
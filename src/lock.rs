@@ -0,0 +1,254 @@
+/*!
+ * Advisory locks: whole-file `flock` locks and POSIX byte-range locks, as set
+ * with `Proc::flock` and `Proc::fcntl`.
+ *
+ * Both kinds are kept per inode and only get in the way of other locks; reads
+ * and writes ignore them. The two kinds don't interact. A `flock` lock belongs
+ * to the descriptor it was taken through and goes away when that's closed. A
+ * byte-range lock belongs to the `Proc`, and closing any of its descriptors
+ * for the file releases all of its byte-range locks on the file, as POSIX
+ * has it. Dropping a `Proc` closes its descriptors.
+ *
+ * Nothing else can run while a `Proc` waits, so a blocking request that
+ * conflicts with a lock held elsewhere could never be granted. Rather than
+ * hang, such requests fail with `WouldBlock`, as non-blocking ones do.
+ */
+
+use std::cmp;
+use error::{Error, Result};
+use FileDescriptor;
+
+/// `flock`: take a shared lock.
+pub const LOCK_SH: u32 = 1;
+/// `flock`: take an exclusive lock.
+pub const LOCK_EX: u32 = 2;
+/// `flock`: don't wait. Conflicts fail with `WouldBlock` either way.
+pub const LOCK_NB: u32 = 4;
+/// `flock`: release the lock.
+pub const LOCK_UN: u32 = 8;
+
+/// `fcntl`: describe a lock conflicting with the one given, if any.
+pub const F_GETLK: u32 = 5;
+/// `fcntl`: set or release a lock, failing with `WouldBlock` on a conflict.
+pub const F_SETLK: u32 = 6;
+/// `fcntl`: set or release a lock. Conflicts fail with `WouldBlock` as with
+/// `F_SETLK`, since nothing could release the lock while this waits.
+pub const F_SETLKW: u32 = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockKind {
+  Read,
+  Write,
+  Unlock,
+}
+
+/**
+ * A byte-range lock over `len` bytes from `start`, or from `start` on if `len`
+ * is 0. `pid` is only meaningful as returned by `F_GETLK`: the owner of the
+ * conflicting lock, as given by `Proc::pid`.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Flock {
+  pub kind: LockKind,
+  pub start: usize,
+  pub len: usize,
+  pub pid: u32,
+}
+
+impl Flock {
+  pub fn new(kind: LockKind, start: usize, len: usize) -> Flock {
+    Flock { kind, start, len, pid: 0 }
+  }
+
+  // The end of the range, exclusive; `usize::MAX` for ranges to end of file.
+  fn end(&self) -> Result<usize> {
+    match self.len {
+      0 => Ok(usize::MAX),
+      len => self.start.checked_add(len).ok_or(Error::Overflow)
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Range {
+  owner: u32,
+  kind: LockKind,
+  start: usize,
+  end: usize,
+}
+
+// A `flock` lock: the owning `Proc` and descriptor, and whether it's exclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Whole {
+  owner: u32,
+  fd: FileDescriptor,
+  exclusive: bool,
+}
+
+/// The locks held on an inode.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Locks {
+  ranges: Vec<Range>,
+  wholes: Vec<Whole>,
+}
+
+impl Locks {
+  // The first range held by someone other than `owner` that overlaps
+  // `[start, end)` and conflicts with a lock of `kind`.
+  fn conflict(&self, owner: u32, kind: LockKind, start: usize, end: usize)
+      -> Option<&Range> {
+    self.ranges.iter().find(|range| {
+      range.owner != owner && range.start < end && start < range.end &&
+        (kind == LockKind::Write || range.kind == LockKind::Write)
+    })
+  }
+
+  /// The lock that would keep `owner` from taking `lock`, or `lock` with kind
+  /// `Unlock` if there's none.
+  pub fn get(&self, owner: u32, lock: &Flock) -> Result<Flock> {
+    if lock.kind == LockKind::Unlock { return Err(Error::InvalidArgument); }
+    let conflict = self.conflict(owner, lock.kind, lock.start, lock.end()?);
+    Ok(match conflict {
+      Some(range) => Flock {
+        kind: range.kind,
+        start: range.start,
+        len: if range.end == usize::MAX { 0 } else { range.end - range.start },
+        pid: range.owner,
+      },
+      None => Flock { kind: LockKind::Unlock, ..*lock }
+    })
+  }
+
+  /// Sets `lock` for `owner`, replacing whatever `owner` held over its range.
+  /// Fails with `WouldBlock` if another owner's lock conflicts.
+  pub fn set(&mut self, owner: u32, lock: &Flock) -> Result<()> {
+    let (start, end) = (lock.start, lock.end()?);
+    if lock.kind != LockKind::Unlock && self.conflict(owner, lock.kind, start, end).is_some() {
+      return Err(Error::WouldBlock);
+    }
+
+    let mut ranges = Vec::with_capacity(self.ranges.len() + 2);
+    for range in self.ranges.drain(..) {
+      if range.owner != owner || range.end <= start || end <= range.start {
+        ranges.push(range);
+        continue;
+      }
+
+      // Keep the parts of the owner's range outside the new one.
+      if range.start < start { ranges.push(Range { end: start, ..range }); }
+      if end < range.end { ranges.push(Range { start: end, ..range }); }
+    }
+
+    if lock.kind != LockKind::Unlock {
+      let mut new = Range { owner, kind: lock.kind, start, end };
+      ranges.retain(|range| {
+        let adjacent = range.end == new.start || new.end == range.start;
+        if range.owner != owner || range.kind != new.kind || !adjacent { return true; }
+        new.start = cmp::min(new.start, range.start);
+        new.end = cmp::max(new.end, range.end);
+        false
+      });
+
+      ranges.push(new);
+    }
+
+    self.ranges = ranges;
+    Ok(())
+  }
+
+  /// Applies `flock` operation `op` for descriptor `fd` of `owner`. Fails with
+  /// `WouldBlock` if another descriptor's lock conflicts.
+  pub fn flock(&mut self, owner: u32, fd: FileDescriptor, op: u32) -> Result<()> {
+    let exclusive = match op & !LOCK_NB {
+      LOCK_SH => false,
+      LOCK_EX => true,
+      LOCK_UN => {
+        self.wholes.retain(|whole| whole.owner != owner || whole.fd != fd);
+        return Ok(());
+      }
+      _ => return Err(Error::InvalidArgument)
+    };
+
+    let conflict = self.wholes.iter().any(|whole| {
+      (whole.owner != owner || whole.fd != fd) && (whole.exclusive || exclusive)
+    });
+
+    if conflict { return Err(Error::WouldBlock); }
+    self.wholes.retain(|whole| whole.owner != owner || whole.fd != fd);
+    self.wholes.push(Whole { owner, fd, exclusive });
+    Ok(())
+  }
+
+  /// Releases what closing descriptor `fd` of `owner` releases: the `flock`
+  /// lock taken through it and all of the owner's byte-range locks.
+  pub fn release(&mut self, owner: u32, fd: FileDescriptor) {
+    self.wholes.retain(|whole| whole.owner != owner || whole.fd != fd);
+    self.ranges.retain(|range| range.owner != owner);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Locks, Flock, LockKind, LOCK_SH, LOCK_EX, LOCK_NB, LOCK_UN};
+  use error::Error;
+
+  fn ranges(locks: &Locks) -> Vec<(u32, LockKind, usize, usize)> {
+    let mut ranges: Vec<_> = locks.ranges.iter()
+      .map(|range| (range.owner, range.kind, range.start, range.end))
+      .collect();
+    ranges.sort_by_key(|&(owner, _, start, _)| (owner, start));
+    ranges
+  }
+
+  #[test]
+  fn test_split_and_merge() {
+    let mut locks = Locks::default();
+    locks.set(1, &Flock::new(LockKind::Write, 0, 100)).unwrap();
+    locks.set(1, &Flock::new(LockKind::Read, 40, 20)).unwrap();
+    assert_eq!(ranges(&locks), [(1, LockKind::Write, 0, 40), (1, LockKind::Read, 40, 60),
+                                (1, LockKind::Write, 60, 100)]);
+
+    // Writing over the read lock joins the write locks back up.
+    locks.set(1, &Flock::new(LockKind::Write, 40, 20)).unwrap();
+    assert_eq!(ranges(&locks), [(1, LockKind::Write, 0, 100)]);
+
+    locks.set(1, &Flock::new(LockKind::Unlock, 10, 0)).unwrap();
+    assert_eq!(ranges(&locks), [(1, LockKind::Write, 0, 10)]);
+    assert_eq!(locks.set(1, &Flock::new(LockKind::Read, usize::MAX, 2)),
+               Err(Error::Overflow));
+  }
+
+  #[test]
+  fn test_conflicts() {
+    let mut locks = Locks::default();
+    locks.set(1, &Flock::new(LockKind::Read, 0, 10)).unwrap();
+    locks.set(2, &Flock::new(LockKind::Read, 5, 10)).unwrap();
+    assert_eq!(locks.set(2, &Flock::new(LockKind::Write, 5, 10)), Err(Error::WouldBlock));
+    locks.set(2, &Flock::new(LockKind::Write, 10, 0)).unwrap();
+
+    let conflict = locks.get(1, &Flock::new(LockKind::Read, 50, 1)).unwrap();
+    assert_eq!(conflict, Flock { kind: LockKind::Write, start: 10, len: 0, pid: 2 });
+    let none = locks.get(2, &Flock::new(LockKind::Read, 0, 0)).unwrap();
+    assert_eq!(none.kind, LockKind::Unlock);
+
+    locks.release(2, 7);
+    assert_eq!(ranges(&locks), [(1, LockKind::Read, 0, 10)]);
+  }
+
+  #[test]
+  fn test_flock() {
+    let mut locks = Locks::default();
+    locks.flock(1, 3, LOCK_SH).unwrap();
+    locks.flock(1, 4, LOCK_SH | LOCK_NB).unwrap();
+    assert_eq!(locks.flock(2, 3, LOCK_EX | LOCK_NB), Err(Error::WouldBlock));
+    assert_eq!(locks.flock(1, 3, LOCK_EX), Err(Error::WouldBlock));
+
+    locks.flock(1, 4, LOCK_UN).unwrap();
+    locks.flock(1, 3, LOCK_EX).unwrap();
+    assert_eq!(locks.flock(1, 4, LOCK_SH), Err(Error::WouldBlock));
+    assert_eq!(locks.flock(1, 3, LOCK_SH | LOCK_EX), Err(Error::InvalidArgument));
+
+    locks.release(1, 3);
+    locks.flock(2, 3, LOCK_EX).unwrap();
+  }
+}
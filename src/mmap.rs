@@ -0,0 +1,238 @@
+/*!
+ * Memory mappings of file ranges, as made with `Proc::mmap`.
+ *
+ * There's no virtual memory to map pages into, so a mapping keeps the range
+ * in a buffer of its own and hands out views of that. Every view starts from
+ * the file's current contents, so the range reads as one slice across page
+ * boundaries, and a mutable view writes the pages it changed back to the file
+ * when it's dropped. Writes through a `MAP_SHARED` mapping are thus seen by
+ * reads on any descriptor, and by other mappings of the file, as soon as the
+ * view goes away. A shared mapping also keeps its file open, like a
+ * descriptor, so a file unlinked while mapped lives on as an orphan.
+ *
+ * A `MAP_PRIVATE` mapping maps a copy-on-write copy of the file taken when it
+ * was made instead: its writes never reach the file, and the file's later
+ * writes never reach it.
+ *
+ * Like the real thing, a mapping never changes the file's size. The part of
+ * the range past the end of file reads as zeros, and writes to it are
+ * dropped. Pages that can't be written back, as when there's no room to
+ * fill a hole, stay pending in the mapping, which keeps showing them as
+ * written; `Proc::msync` tries them again, and fails until they're written.
+ */
+
+use std::cell::{Ref, RefCell, RefMut};
+use std::cmp;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use error::{Error, Result};
+use file::{File, RcInode};
+use fs::FileSystem;
+
+/// `mmap`: the mapping may be read.
+pub const PROT_READ: u32 = 1;
+/// `mmap`: the mapping may be written.
+pub const PROT_WRITE: u32 = 2;
+
+/// `mmap`: share writes with the file and everyone else mapping it.
+pub const MAP_SHARED: u32 = 1;
+/// `mmap`: keep writes to a private copy of the file.
+pub const MAP_PRIVATE: u32 = 2;
+
+/// `msync`: return at once; writes already reached the file.
+pub const MS_ASYNC: u32 = 1;
+/// `msync`: make the mapped file durable before returning.
+pub const MS_SYNC: u32 = 4;
+
+/// A mapping of `[offset, offset + len)` of a file, as made by `Proc::mmap`.
+pub struct Mapping<'r> {
+  inode: RcInode,
+  offset: usize,
+  buf: RefCell<Box<[u8]>>,
+  prot: u32,
+  pending: RefCell<BTreeSet<usize>>, // pages not yet written back
+  open: Option<Open<'r>>, // set for shared mappings
+}
+
+impl<'r> Mapping<'r> {
+  /// Maps the range of `file` itself, which counts as open with `fs` for as
+  /// long as the mapping is around.
+  pub(crate) fn shared(fs: Rc<FileSystem<'r>>, file: File<'r>, offset: usize, len: usize,
+                       prot: u32) -> Mapping<'r> {
+    let inode = file.get_inode_rc().clone();
+    fs.opened(&file, false);
+    Mapping::new(inode, offset, len, prot, Some(Open { fs, file }))
+  }
+
+  /// Maps the range of `copy`, a copy of a file no one else sees.
+  pub(crate) fn private(copy: RcInode, offset: usize, len: usize, prot: u32)
+      -> Mapping<'r> {
+    Mapping::new(copy, offset, len, prot, None)
+  }
+
+  fn new(inode: RcInode, offset: usize, len: usize, prot: u32, open: Option<Open<'r>>)
+      -> Mapping<'r> {
+    Mapping {
+      inode,
+      offset,
+      buf: RefCell::new(vec![0; len].into_boxed_slice()),
+      prot,
+      pending: RefCell::new(BTreeSet::new()),
+      open,
+    }
+  }
+
+  /// The offset in the file of the first mapped byte.
+  pub fn offset(&self) -> usize {
+    self.offset
+  }
+
+  pub fn len(&self) -> usize {
+    self.buf.borrow().len()
+  }
+
+  /// Never true; `Proc::mmap` doesn't map empty ranges.
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Whether writes reach the file, as with `MAP_SHARED`.
+  pub fn is_shared(&self) -> bool {
+    self.open.is_some()
+  }
+
+  /**
+   * The mapped bytes. Unless other views are still around, which see the same
+   * bytes, they're read afresh from the file. Panics if a mutable view is
+   * around.
+   */
+  pub fn view(&self) -> Result<Ref<'_, [u8]>> {
+    if let Ok(mut buf) = self.buf.try_borrow_mut() { self.load(&mut buf)?; }
+    Ok(Ref::map(self.buf.borrow(), |buf| &**buf))
+  }
+
+  /**
+   * The mapped bytes, for writing. They're read afresh from the file, and
+   * whatever changed is written back when the view is dropped. Fails with
+   * `PermissionDenied` unless the mapping was made with `PROT_WRITE`. Panics
+   * if any other view is around.
+   */
+  pub fn view_mut(&self) -> Result<MapMut<'_, 'r>> {
+    if (self.prot & PROT_WRITE) == 0 { return Err(Error::PermissionDenied); }
+    let mut buf = self.buf.borrow_mut();
+    self.load(&mut buf)?;
+    Ok(MapMut { map: self, buf })
+  }
+
+  /// Writes back the pages still pending from views that couldn't write them,
+  /// failing if any still can't be. Panics if a mutable view is around.
+  pub(crate) fn write_pending(&self) -> Result<()> {
+    if self.pending.borrow().is_empty() { return Ok(()); }
+    self.store(&self.buf.borrow(), true)
+  }
+
+  // Fills `buf` from the file, with zeros past its end, except for the pages
+  // still pending.
+  fn load(&self, buf: &mut [u8]) -> Result<()> {
+    let mut inode = self.inode.borrow_mut();
+    let page_size = inode.geometry().page_size();
+    let pending = self.pending.borrow();
+    for (num, chunk) in buf.chunks_mut(page_size).enumerate() {
+      if pending.contains(&num) { continue; }
+      let read = inode.read(self.offset + num * page_size, chunk)?;
+      chunk[read..].fill(0);
+    }
+
+    Ok(())
+  }
+
+  // Writes the pages of `buf` that differ from the file back to it, up to the
+  // end of file, or with `only_pending` just the pending ones. Pages that
+  // can't be written are left pending, and the first error is returned.
+  fn store(&self, buf: &[u8], only_pending: bool) -> Result<()> {
+    let mut inode = self.inode.borrow_mut();
+    let end = cmp::min(inode.size().saturating_sub(self.offset), buf.len());
+    let page_size = inode.geometry().page_size();
+    let mut pending = self.pending.borrow_mut();
+    let mut current = vec![0; page_size];
+    let mut result = Ok(());
+    for (num, start) in (0..end).step_by(page_size).enumerate() {
+      if only_pending && !pending.contains(&num) { continue; }
+      let chunk = &buf[start..cmp::min(start + page_size, end)];
+      let current = &mut current[..chunk.len()];
+      let stored = inode.read(self.offset + start, current).and_then(|_| {
+        if chunk == &*current { return Ok(()); }
+        match inode.write(self.offset + start, chunk)? {
+          written if written < chunk.len() => Err(Error::NoSpace),
+          _ => Ok(())
+        }
+      });
+
+      match stored {
+        Ok(()) => { pending.remove(&num); }
+        Err(err) => {
+          pending.insert(num);
+          if result.is_ok() { result = Err(err); }
+        }
+      }
+    }
+
+    // Pages the file no longer reaches have nowhere to go.
+    pending.retain(|&num| num * page_size < end);
+    result
+  }
+}
+
+impl<'r> fmt::Debug for Mapping<'r> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Mapping")
+      .field("offset", &self.offset)
+      .field("len", &self.len())
+      .field("prot", &self.prot)
+      .field("shared", &self.is_shared())
+      .finish()
+  }
+}
+
+// A shared mapping's hold on its file, which keeps an unlinked file from
+// being freed, and its blocks reused, while it's still mapped.
+struct Open<'r> {
+  fs: Rc<FileSystem<'r>>,
+  file: File<'r>,
+}
+
+impl<'r> Drop for Open<'r> {
+  fn drop(&mut self) {
+    self.fs.closed(&self.file);
+  }
+}
+
+/// A mutable view of a mapping, from `Mapping::view_mut`. Dropping it writes
+/// what changed back.
+pub struct MapMut<'m, 'r: 'm> {
+  map: &'m Mapping<'r>,
+  buf: RefMut<'m, Box<[u8]>>,
+}
+
+impl<'m, 'r> Deref for MapMut<'m, 'r> {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    &self.buf
+  }
+}
+
+impl<'m, 'r> DerefMut for MapMut<'m, 'r> {
+  fn deref_mut(&mut self) -> &mut [u8] {
+    &mut self.buf
+  }
+}
+
+impl<'m, 'r> Drop for MapMut<'m, 'r> {
+  fn drop(&mut self) {
+    // Whatever can't be written stays pending for `msync`.
+    let _ = self.map.store(&self.buf, false);
+  }
+}
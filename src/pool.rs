@@ -0,0 +1,235 @@
+/*!
+ * Pooled allocation of page buffers and page lists, from libslab's slabs:
+ * memory given back by freed pages and deleted files stays in the slabs and
+ * is handed out again, so workloads that create and delete files over and
+ * over stop going to the system allocator for every page.
+ *
+ * Each file system has a pool, reached through its `Space`, with a slab of
+ * page buffers and one of page lists. They grow as needed and, once more than
+ * a set number of buffers or lists are free, give back chunks that are free
+ * in their entirety. Buffers and lists remember the slab they came from, so
+ * pages shared with snapshots or other file systems find their way home.
+ */
+
+use std::cell::Cell;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use slab::{ArrayAllocator, SizeClassAllocator, SlabArray, SlabBytes};
+use inode::{create_tlist, Geometry, Page, TList};
+
+/// The most free page buffers, and separately page lists, a pool keeps by
+/// default.
+pub const DEFAULT_POOL_LIMIT: usize = 1024;
+
+/// How a file system's page pool is used, as reported by
+/// `FileSystem::pool_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+  /// Page buffers handed out and not yet given back.
+  pub pages: usize,
+  /// Free page buffers kept for reuse.
+  pub free_pages: usize,
+  /// Page lists handed out and not yet given back.
+  pub lists: usize,
+  /// Free page lists kept for reuse.
+  pub free_lists: usize,
+}
+
+pub(crate) struct PagePool {
+  geometry: Geometry,
+  pages: SizeClassAllocator, // with a single class, of the page size
+  lists: ArrayAllocator<Option<Page>>,
+  limit: Cell<usize>,
+}
+
+pub(crate) type SharedPool = Rc<PagePool>;
+
+impl PagePool {
+  pub fn new(geometry: Geometry) -> PagePool {
+    let pool = PagePool {
+      geometry,
+      pages: SizeClassAllocator::with_classes(&[geometry.page_size()], 0),
+      lists: ArrayAllocator::new(geometry.list_size(), 0),
+      limit: Cell::new(DEFAULT_POOL_LIMIT),
+    };
+
+    pool.set_limit(DEFAULT_POOL_LIMIT);
+    pool
+  }
+
+  pub fn stats(&self) -> PoolStats {
+    let pages = self.pages.stats().remove(0).1;
+    let lists = self.lists.stats();
+    PoolStats {
+      pages: pages.alloc,
+      free_pages: pages.free(),
+      lists: lists.alloc,
+      free_lists: lists.free(),
+    }
+  }
+
+  /// Has the slabs give back chunks with nothing in use while more than
+  /// `limit` buffers, or lists, are free. A limit of 0 turns pooling off.
+  pub fn set_limit(&self, limit: usize) {
+    self.limit.set(limit);
+    self.pages.set_high_water(Some(limit));
+    self.lists.set_high_water(Some(limit));
+  }
+
+  /// A zeroed page buffer.
+  pub fn alloc_page(self: &Rc<Self>) -> PageBuf {
+    let data = self.pages.alloc(self.geometry.page_size());
+    PageBuf::Pooled(data, self.clone())
+  }
+
+  /// An empty page list.
+  pub fn alloc_list(&self) -> PageList {
+    PageList::Pooled(self.lists.alloc_with(|| None))
+  }
+}
+
+impl fmt::Debug for PagePool {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("PagePool")
+      .field("geometry", &self.geometry)
+      .field("limit", &self.limit.get())
+      .field("stats", &self.stats())
+      .finish()
+  }
+}
+
+/// The contents of a page, given back to the pool it came from, if any, when
+/// dropped.
+#[derive(Debug)]
+pub(crate) enum PageBuf {
+  // Along with the pool, for copies to come from.
+  Pooled(SlabBytes, SharedPool),
+  Unpooled(Box<[u8]>),
+}
+
+impl PageBuf {
+  /// Wraps `data`, which came from the system allocator rather than a pool.
+  pub fn unpooled(data: Box<[u8]>) -> PageBuf {
+    PageBuf::Unpooled(data)
+  }
+
+  /// A copy of the buffer, from the same pool.
+  pub fn duplicate(&self) -> PageBuf {
+    match *self {
+      PageBuf::Pooled(ref data, ref pool) => {
+        let mut copy = pool.alloc_page();
+        copy.copy_from_slice(data);
+        copy
+      }
+      PageBuf::Unpooled(ref data) => PageBuf::Unpooled(data.clone())
+    }
+  }
+}
+
+impl Deref for PageBuf {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    match *self {
+      PageBuf::Pooled(ref data, _) => data,
+      PageBuf::Unpooled(ref data) => data
+    }
+  }
+}
+
+impl DerefMut for PageBuf {
+  fn deref_mut(&mut self) -> &mut [u8] {
+    match *self {
+      PageBuf::Pooled(ref mut data, _) => data,
+      PageBuf::Unpooled(ref mut data) => data
+    }
+  }
+}
+
+/// A list of page entries, given back to the pool it came from, if any, when
+/// dropped. Copies come from the same pool.
+#[derive(Clone)]
+pub(crate) enum PageList {
+  Pooled(SlabArray<Option<Page>>),
+  Unpooled(TList<Page>),
+}
+
+impl PageList {
+  /// An empty list of `len` entries from the system allocator.
+  pub fn unpooled(len: usize) -> PageList {
+    PageList::Unpooled(create_tlist(len))
+  }
+}
+
+impl Deref for PageList {
+  type Target = [Option<Page>];
+
+  fn deref(&self) -> &[Option<Page>] {
+    match *self {
+      PageList::Pooled(ref list) => list,
+      PageList::Unpooled(ref list) => list
+    }
+  }
+}
+
+impl DerefMut for PageList {
+  fn deref_mut(&mut self) -> &mut [Option<Page>] {
+    match *self {
+      PageList::Pooled(ref mut list) => list,
+      PageList::Unpooled(ref mut list) => list
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{PagePool, PoolStats};
+  use inode::{Geometry, Page};
+  use std::rc::Rc;
+
+  #[test]
+  fn test_page_recycling() {
+    let pool = Rc::new(PagePool::new(Geometry::new(512, 16).unwrap()));
+    let mut first = pool.alloc_page();
+    first[..3].copy_from_slice(b"abc");
+    let copy = first.duplicate();
+    assert_eq!(&copy[..4], b"abc\0");
+    drop(first);
+    drop(copy);
+    assert_eq!(pool.stats(), PoolStats { free_pages: 2, ..PoolStats::default() });
+
+    // Freed buffers come back zeroed, and only once they run out does the
+    // slab grow.
+    let reused: Vec<_> = (0..2).map(|_| pool.alloc_page()).collect();
+    assert!(reused.iter().all(|page| page.iter().all(|&byte| byte == 0)));
+    assert_eq!(pool.stats(), PoolStats { pages: 2, ..PoolStats::default() });
+    let fresh = pool.alloc_page();
+    assert_eq!(fresh.len(), 512);
+    assert_eq!(pool.stats(), PoolStats { pages: 3, free_pages: 1, ..PoolStats::default() });
+
+    // Past the limit, chunks that empty out go back to the system.
+    pool.set_limit(1);
+    drop(fresh);
+    assert_eq!(pool.stats().free_pages, 0);
+    drop(reused);
+    assert_eq!(pool.stats().free_pages, 1);
+    pool.set_limit(0);
+    assert_eq!(pool.stats().free_pages, 0);
+  }
+
+  #[test]
+  fn test_list_recycling() {
+    let pool = Rc::new(PagePool::new(Geometry::new(512, 16).unwrap()));
+    let mut list = pool.alloc_list();
+    assert_eq!(list.len(), 16);
+    list[3] = Some(Page::on_device(7));
+    let copy = list.clone();
+    assert_eq!(pool.stats().lists, 2);
+    drop((list, copy));
+
+    let list = pool.alloc_list();
+    assert!(list.iter().all(|page| page.is_none()));
+    assert_eq!(pool.stats(), PoolStats { lists: 1, free_lists: 1, ..PoolStats::default() });
+  }
+}
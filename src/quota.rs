@@ -0,0 +1,275 @@
+/*!
+ * Per-user and per-group quotas on blocks (pages of file data) and inodes
+ * (files).
+ *
+ * Each limit comes as a soft and a hard limit. Usage may never go past the
+ * hard limit. It may go past the soft limit for a grace period, which starts
+ * when it first does and ends once usage is back at or below the soft limit;
+ * after the grace period runs out, the soft limit is enforced like the hard
+ * one. Grace periods run on the file system clock; see `FileSystem::set_clock`.
+ * Going past a limit fails with `QuotaExceeded`.
+ */
+
+use std::collections::HashMap;
+use time::{Duration, Timespec};
+use error::{Error, Result};
+
+/// The grace period quotas start out with: a week, as on Linux.
+pub const DEFAULT_GRACE_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Whose usage a quota limits: a user's or a group's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuotaKind {
+  User,
+  Group,
+}
+
+/// The owner of a file, whose quotas the file is charged to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Owner {
+  pub uid: u32,
+  pub gid: u32,
+}
+
+/// Soft and hard limits on blocks and inodes. `None` means no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quota {
+  pub block_soft: Option<usize>,
+  pub block_hard: Option<usize>,
+  pub inode_soft: Option<usize>,
+  pub inode_hard: Option<usize>,
+}
+
+/**
+ * A user's or group's usage and quota. The grace fields are when the grace
+ * period for going past the soft limit ends, if usage is past it.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QuotaUsage {
+  pub blocks: usize,
+  pub inodes: usize,
+  pub quota: Quota,
+  pub block_grace: Option<Timespec>,
+  pub inode_grace: Option<Timespec>,
+}
+
+// Whether usage of `used` may grow by `more` under `soft` and `hard`, with the
+// grace period ending at `grace`.
+fn allowed(used: usize, more: usize, soft: Option<usize>, hard: Option<usize>,
+           grace: Option<Timespec>, now: Timespec) -> bool {
+  let new = used + more;
+  if more == 0 { return true; }
+  if hard.is_some_and(|hard| new > hard) { return false; }
+  !(soft.is_some_and(|soft| new > soft) && grace.is_some_and(|grace| now > grace))
+}
+
+// Starts the grace period once `used` goes past `soft`, and ends it once it's
+// back under.
+fn update_grace(used: usize, soft: Option<usize>, grace: &mut Option<Timespec>,
+                period: Duration, now: Timespec) {
+  match soft {
+    Some(soft) if used > soft => if grace.is_none() { *grace = Some(now + period); },
+    _ => *grace = None
+  }
+}
+
+impl QuotaUsage {
+  fn allows(&self, blocks: usize, inodes: usize, now: Timespec) -> bool {
+    let quota = self.quota;
+    allowed(self.blocks, blocks, quota.block_soft, quota.block_hard, self.block_grace, now)
+      && allowed(self.inodes, inodes, quota.inode_soft, quota.inode_hard,
+                 self.inode_grace, now)
+  }
+
+  fn update_grace(&mut self, periods: (Duration, Duration), now: Timespec) {
+    update_grace(self.blocks, self.quota.block_soft, &mut self.block_grace, periods.0, now);
+    update_grace(self.inodes, self.quota.inode_soft, &mut self.inode_grace, periods.1, now);
+  }
+}
+
+/// The quotas of every user and group, and the grace periods for each kind.
+#[derive(Debug)]
+pub(crate) struct Quotas {
+  users: HashMap<u32, QuotaUsage>,
+  groups: HashMap<u32, QuotaUsage>,
+  // Block and inode grace periods for users, then for groups.
+  grace: [(Duration, Duration); 2],
+}
+
+impl Quotas {
+  pub fn new() -> Quotas {
+    let week = Duration::seconds(DEFAULT_GRACE_SECS);
+    Quotas { users: HashMap::new(), groups: HashMap::new(), grace: [(week, week); 2] }
+  }
+
+  fn table(&self, kind: QuotaKind) -> &HashMap<u32, QuotaUsage> {
+    match kind {
+      QuotaKind::User => &self.users,
+      QuotaKind::Group => &self.groups
+    }
+  }
+
+  // The usage of `id`, and its grace periods.
+  fn entry(&mut self, kind: QuotaKind, id: u32)
+      -> (&mut QuotaUsage, (Duration, Duration)) {
+    match kind {
+      QuotaKind::User => (self.users.entry(id).or_default(), self.grace[0]),
+      QuotaKind::Group => (self.groups.entry(id).or_default(), self.grace[1])
+    }
+  }
+
+  // The ids of `owner` of each kind, paired with the kind.
+  fn ids(owner: Owner) -> [(QuotaKind, u32); 2] {
+    [(QuotaKind::User, owner.uid), (QuotaKind::Group, owner.gid)]
+  }
+
+  pub fn get(&self, kind: QuotaKind, id: u32) -> QuotaUsage {
+    self.table(kind).get(&id).cloned().unwrap_or_default()
+  }
+
+  /// Every id of `kind` with a quota or some usage, sorted.
+  pub fn report(&self, kind: QuotaKind) -> Vec<(u32, QuotaUsage)> {
+    let mut report: Vec<_> = self.table(kind).iter()
+      .filter(|&(_, usage)| *usage != QuotaUsage::default())
+      .map(|(&id, &usage)| (id, usage))
+      .collect();
+    report.sort_by_key(|&(id, _)| id);
+    report
+  }
+
+  /// Sets the quota of `id`, starting its grace periods if it's already past
+  /// the new soft limits.
+  pub fn set(&mut self, kind: QuotaKind, id: u32, quota: Quota, now: Timespec) {
+    let (usage, periods) = self.entry(kind, id);
+    usage.quota = quota;
+    usage.update_grace(periods, now);
+  }
+
+  /// Sets the grace periods of quotas of `kind`, for those periods that start
+  /// from now on.
+  pub fn set_grace(&mut self, kind: QuotaKind, blocks: Duration, inodes: Duration) {
+    let index = match kind { QuotaKind::User => 0, QuotaKind::Group => 1 };
+    self.grace[index] = (blocks, inodes);
+  }
+
+  /// Fails with `QuotaExceeded` unless `owner` can use `blocks` more blocks
+  /// and `inodes` more inodes.
+  pub fn check(&self, owner: Owner, blocks: usize, inodes: usize, now: Timespec)
+      -> Result<()> {
+    for &(kind, id) in Quotas::ids(owner).iter() {
+      if !self.get(kind, id).allows(blocks, inodes, now) {
+        return Err(Error::QuotaExceeded);
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Adds to `owner`'s usage regardless of its quotas.
+  pub fn add(&mut self, owner: Owner, blocks: usize, inodes: usize, now: Timespec) {
+    for &(kind, id) in Quotas::ids(owner).iter() {
+      let (usage, periods) = self.entry(kind, id);
+      usage.blocks += blocks;
+      usage.inodes += inodes;
+      usage.update_grace(periods, now);
+    }
+  }
+
+  pub fn remove(&mut self, owner: Owner, blocks: usize, inodes: usize, now: Timespec) {
+    for &(kind, id) in Quotas::ids(owner).iter() {
+      let (usage, periods) = self.entry(kind, id);
+      usage.blocks -= blocks;
+      usage.inodes -= inodes;
+      usage.update_grace(periods, now);
+    }
+  }
+
+  /**
+   * Moves a file with `blocks` blocks from `from` to `to`, failing with
+   * `QuotaExceeded` if that would put the new user or group past a quota.
+   * Ids that stay the same are left alone.
+   */
+  pub fn transfer(&mut self, from: Owner, to: Owner, blocks: usize, now: Timespec)
+      -> Result<()> {
+    let (old, new) = (Quotas::ids(from), Quotas::ids(to));
+    for (&(kind, old_id), &(_, new_id)) in old.iter().zip(new.iter()) {
+      if old_id != new_id && !self.get(kind, new_id).allows(blocks, 1, now) {
+        return Err(Error::QuotaExceeded);
+      }
+    }
+
+    for (&(kind, old_id), &(_, new_id)) in old.iter().zip(new.iter()) {
+      if old_id == new_id { continue; }
+      let (usage, periods) = self.entry(kind, old_id);
+      usage.blocks -= blocks;
+      usage.inodes -= 1;
+      usage.update_grace(periods, now);
+
+      let (usage, periods) = self.entry(kind, new_id);
+      usage.blocks += blocks;
+      usage.inodes += 1;
+      usage.update_grace(periods, now);
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Quotas, Quota, QuotaKind, Owner};
+  use error::Error;
+  use time::{Duration, Timespec};
+
+  #[test]
+  fn test_soft_and_hard_limits() {
+    let mut quotas = Quotas::new();
+    let now = Timespec::new(1000, 0);
+    let owner = Owner { uid: 5, gid: 7 };
+    let quota = Quota { block_soft: Some(10), block_hard: Some(20), ..Quota::default() };
+    quotas.set(QuotaKind::User, 5, quota, now);
+    quotas.set_grace(QuotaKind::User, Duration::seconds(60), Duration::seconds(60));
+
+    quotas.add(owner, 10, 1, now);
+    assert_eq!(quotas.get(QuotaKind::User, 5).block_grace, None);
+    quotas.check(owner, 10, 0, now).unwrap();
+    assert_eq!(quotas.check(owner, 11, 0, now), Err(Error::QuotaExceeded));
+
+    // Going past the soft limit starts the grace period.
+    quotas.add(owner, 5, 0, now);
+    let grace = Timespec::new(1060, 0);
+    assert_eq!(quotas.get(QuotaKind::User, 5).block_grace, Some(grace));
+    quotas.check(owner, 1, 0, grace).unwrap();
+    assert_eq!(quotas.check(owner, 1, 0, grace + Duration::seconds(1)),
+               Err(Error::QuotaExceeded));
+    quotas.check(owner, 0, 1, grace + Duration::seconds(1)).unwrap();
+
+    // Dropping back to the soft limit ends it.
+    quotas.remove(owner, 5, 0, now);
+    assert_eq!(quotas.get(QuotaKind::User, 5).block_grace, None);
+    assert_eq!(quotas.get(QuotaKind::Group, 7).blocks, 10);
+  }
+
+  #[test]
+  fn test_transfer_and_report() {
+    let mut quotas = Quotas::new();
+    let now = Timespec::new(0, 0);
+    let (a, b) = (Owner { uid: 1, gid: 100 }, Owner { uid: 2, gid: 100 });
+    let quota = Quota { inode_hard: Some(1), ..Quota::default() };
+    quotas.set(QuotaKind::User, 2, quota, now);
+    quotas.add(a, 3, 1, now);
+    quotas.add(a, 4, 1, now);
+
+    quotas.transfer(a, b, 3, now).unwrap();
+    assert_eq!(quotas.transfer(a, b, 4, now), Err(Error::QuotaExceeded));
+    assert_eq!(quotas.get(QuotaKind::User, 1).blocks, 4);
+    assert_eq!(quotas.get(QuotaKind::User, 2).inodes, 1);
+    assert_eq!(quotas.get(QuotaKind::Group, 100).blocks, 7);
+
+    let report = quotas.report(QuotaKind::User);
+    assert_eq!(report.iter().map(|&(id, usage)| (id, usage.blocks)).collect::<Vec<_>>(),
+               [(1, 4), (2, 3)]);
+    quotas.remove(a, 4, 1, now);
+    assert_eq!(quotas.report(QuotaKind::User).len(), 1);
+  }
+}
@@ -0,0 +1,217 @@
+/*!
+ * Capacity limits, like tmpfs's `size=` and `nr_inodes=`: caps on the bytes of
+ * file data and the number of files a file system holds in memory.
+ *
+ * Every file in a file system's tree is charged to the file system's `Space`
+ * for itself and for each of its pages. Pages shared between files, as after
+ * `Proc::reflink`, are charged to each; snapshots aren't charged at all until
+ * they're rolled back to. Allocations past a limit fail with `NoSpace`.
+ *
+ * Charges also count against the quotas of the file's owner; see the `quota`
+ * module. The memory itself comes from the space's page pool; see the `pool`
+ * module. Which pages of a mounted file system are resident is up to the
+ * space's page cache; see the `cache` module.
+ */
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use time::{self, Timespec};
+use error::{Error, Result};
+use inode::Geometry;
+use quota::{Owner, Quotas};
+use pool::{PagePool, SharedPool};
+use cache::PageCache;
+
+/// Limits on a file system's contents. `None` means no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+  /// The most bytes of file data, counted in whole pages.
+  pub max_bytes: Option<usize>,
+  /// The most files.
+  pub max_inodes: Option<usize>,
+}
+
+/// What a file system's files use, along with its limits, quotas, page pool
+/// and page cache.
+#[derive(Debug)]
+pub(crate) struct Space {
+  page_size: usize,
+  limits: Cell<Limits>,
+  pages: Cell<usize>,
+  inodes: Cell<usize>,
+  pub quotas: RefCell<Quotas>,
+  clock: Cell<Option<Timespec>>,
+  pool: SharedPool,
+  cache: PageCache,
+}
+
+pub(crate) type SharedSpace = Rc<Space>;
+
+impl Space {
+  pub fn new(geometry: Geometry) -> Space {
+    Space {
+      page_size: geometry.page_size(),
+      limits: Cell::new(Limits::default()),
+      pages: Cell::new(0),
+      inodes: Cell::new(0),
+      quotas: RefCell::new(Quotas::new()),
+      clock: Cell::new(None),
+      pool: Rc::new(PagePool::new(geometry)),
+      cache: PageCache::new(),
+    }
+  }
+
+  pub fn pool(&self) -> &SharedPool {
+    &self.pool
+  }
+
+  pub fn cache(&self) -> &PageCache {
+    &self.cache
+  }
+
+  /// The time on the file system clock: the system time unless the clock was
+  /// set.
+  pub fn now(&self) -> Timespec {
+    self.clock.get().unwrap_or_else(time::get_time)
+  }
+
+  pub fn set_clock(&self, time: Option<Timespec>) {
+    self.clock.set(time);
+  }
+
+  pub fn limits(&self) -> Limits {
+    self.limits.get()
+  }
+
+  /// Fails with `InvalidArgument` if more is already in use than `limits`
+  /// allow.
+  pub fn set_limits(&self, limits: Limits) -> Result<()> {
+    let old = self.limits.replace(limits);
+    if self.pages.get() > self.max_pages() || self.inodes.get() > self.max_inodes() {
+      self.limits.set(old);
+      return Err(Error::InvalidArgument);
+    }
+
+    Ok(())
+  }
+
+  /// The most pages allowed, or `usize::MAX`.
+  pub fn max_pages(&self) -> usize {
+    self.limits.get().max_bytes.map_or(usize::MAX, |bytes| bytes / self.page_size)
+  }
+
+  /// The most files allowed, or `usize::MAX`.
+  pub fn max_inodes(&self) -> usize {
+    self.limits.get().max_inodes.unwrap_or(usize::MAX)
+  }
+
+  pub fn pages(&self) -> usize {
+    self.pages.get()
+  }
+
+  pub fn inodes(&self) -> usize {
+    self.inodes.get()
+  }
+
+  /// Charges `count` more pages to `owner`, failing with `NoSpace` past the
+  /// limit or `QuotaExceeded` past a quota.
+  pub fn charge_pages(&self, owner: Owner, count: usize) -> Result<()> {
+    let pages = self.pages.get() + count;
+    if pages > self.max_pages() { return Err(Error::NoSpace); }
+    let now = self.now();
+    let mut quotas = self.quotas.borrow_mut();
+    quotas.check(owner, count, 0, now)?;
+    quotas.add(owner, count, 0, now);
+    self.pages.set(pages);
+    Ok(())
+  }
+
+  pub fn release_pages(&self, owner: Owner, count: usize) {
+    self.quotas.borrow_mut().remove(owner, count, 0, self.now());
+    self.pages.set(self.pages.get() - count);
+  }
+
+  /// Charges another file of `owner` along with its `pages`, failing with
+  /// `NoSpace` past either limit or `QuotaExceeded` past a quota.
+  pub fn charge(&self, owner: Owner, pages: usize) -> Result<()> {
+    if self.inodes.get() >= self.max_inodes() { return Err(Error::NoSpace); }
+    if self.pages.get() + pages > self.max_pages() { return Err(Error::NoSpace); }
+    self.quotas.borrow().check(owner, pages, 1, self.now())?;
+    self.force_charge(owner, pages);
+    Ok(())
+  }
+
+  /// Charges a file and its `pages` regardless of the limits and quotas, for
+  /// files that already exist, as when mounting or rolling back.
+  pub fn force_charge(&self, owner: Owner, pages: usize) {
+    self.quotas.borrow_mut().add(owner, pages, 1, self.now());
+    self.inodes.set(self.inodes.get() + 1);
+    self.pages.set(self.pages.get() + pages);
+  }
+
+  /// Releases a file of `owner` along with its `pages`.
+  pub fn release(&self, owner: Owner, pages: usize) {
+    self.quotas.borrow_mut().remove(owner, pages, 1, self.now());
+    self.inodes.set(self.inodes.get() - 1);
+    self.pages.set(self.pages.get() - pages);
+  }
+
+  /// Moves a file and its `pages` from `from` to `to`, failing with
+  /// `QuotaExceeded` past one of the new owner's quotas.
+  pub fn transfer(&self, from: Owner, to: Owner, pages: usize) -> Result<()> {
+    self.quotas.borrow_mut().transfer(from, to, pages, self.now())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Space, Limits};
+  use error::Error;
+  use inode::Geometry;
+  use quota::{Owner, Quota, QuotaKind};
+
+  #[test]
+  fn test_limits() {
+    let space = Space::new(Geometry::new(512, 16).unwrap());
+    let owner = Owner::default();
+    space.charge(owner, 10).unwrap();
+
+    let limits = Limits { max_bytes: Some(512 * 12 + 100), max_inodes: Some(2) };
+    space.set_limits(limits).unwrap();
+    assert_eq!(space.max_pages(), 12);
+    assert_eq!(space.charge_pages(owner, 3), Err(Error::NoSpace));
+    assert_eq!(space.charge(owner, 3), Err(Error::NoSpace));
+    space.charge(owner, 2).unwrap();
+    assert_eq!(space.charge(owner, 0), Err(Error::NoSpace));
+
+    // Limits can't drop below what's in use, except by force.
+    let lower = Limits { max_bytes: Some(512 * 11), ..limits };
+    assert_eq!(space.set_limits(lower), Err(Error::InvalidArgument));
+    space.force_charge(owner, 5);
+    assert_eq!((space.inodes(), space.pages()), (3, 17));
+    space.release(owner, 5);
+    space.release_pages(owner, 12);
+    assert_eq!((space.inodes(), space.pages()), (2, 0));
+    space.set_limits(Limits::default()).unwrap();
+    assert_eq!(space.max_inodes(), usize::MAX);
+  }
+
+  #[test]
+  fn test_quotas() {
+    let space = Space::new(Geometry::new(512, 16).unwrap());
+    let (owner, other) = (Owner { uid: 1, gid: 1 }, Owner { uid: 2, gid: 1 });
+    let quota = Quota { block_hard: Some(4), ..Quota::default() };
+    space.quotas.borrow_mut().set(QuotaKind::User, 1, quota, space.now());
+
+    space.charge(owner, 3).unwrap();
+    assert_eq!(space.charge(owner, 2), Err(Error::QuotaExceeded));
+    assert_eq!(space.charge_pages(owner, 2), Err(Error::QuotaExceeded));
+    assert_eq!(space.pages(), 3);
+    space.charge(other, 2).unwrap();
+    assert_eq!(space.transfer(other, owner, 2), Err(Error::QuotaExceeded));
+    space.release_pages(owner, 1);
+    space.transfer(other, owner, 2).unwrap();
+    assert_eq!(space.quotas.borrow().get(QuotaKind::User, 1).blocks, 4);
+    assert_eq!(space.quotas.borrow().get(QuotaKind::Group, 1).inodes, 2);
+  }
+}
@@ -0,0 +1,163 @@
+/*!
+ * Extended attributes: small name/value pairs attached to files and
+ * directories.
+ *
+ * Names must start with one of the supported namespaces, `user.`, `trusted.`
+ * or `security.`, followed by at least one more byte. Names are at most
+ * `XATTR_NAME_MAX` bytes and values at most `XATTR_SIZE_MAX` bytes. All of a
+ * file's attributes, encoded as in the `disk` module, must fit in a single
+ * page, since that's how they're stored in an image.
+ */
+
+use std::collections::BTreeMap;
+use disk::{XATTR_HEADER, XATTR_ENTRY_HEADER};
+use error::{Error, Result};
+
+/// Fail if the attribute already exists.
+pub const XATTR_CREATE: u32 = 1 << 0;
+/// Fail if the attribute doesn't exist yet.
+pub const XATTR_REPLACE: u32 = 1 << 1;
+
+pub const XATTR_NAME_MAX: usize = 255;
+pub const XATTR_SIZE_MAX: usize = 65536;
+
+const NAMESPACES: [&str; 3] = ["user.", "trusted.", "security."];
+
+/// The extended attributes of a file or directory, ordered by name.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Xattrs {
+  attrs: BTreeMap<String, Vec<u8>>,
+}
+
+// Checks that `name` is in a supported namespace and not too long.
+fn check_name(name: &str) -> Result<()> {
+  if name.len() > XATTR_NAME_MAX { return Err(Error::OutOfRange); }
+  let known = NAMESPACES.iter()
+    .any(|namespace| name.len() > namespace.len() && name.starts_with(namespace));
+  if !known { return Err(Error::NotSupported); }
+  Ok(())
+}
+
+impl Xattrs {
+  pub fn is_empty(&self) -> bool {
+    self.attrs.is_empty()
+  }
+
+  /// Fails with `NoAttribute` if there's no attribute `name`.
+  pub fn get(&self, name: &str) -> Result<&[u8]> {
+    check_name(name)?;
+    self.attrs.get(name).map(|value| &value[..]).ok_or(Error::NoAttribute)
+  }
+
+  /**
+   * Sets attribute `name` to `value`. With `XATTR_CREATE`, fails with `Exists`
+   * if the attribute is already set; with `XATTR_REPLACE`, fails with
+   * `NoAttribute` if it isn't. Fails with `NoSpace` if the attributes would no
+   * longer fit in `limit` bytes once encoded.
+   */
+  pub fn set(&mut self, name: &str, value: &[u8], flags: u32, limit: usize)
+      -> Result<()> {
+    check_name(name)?;
+    if value.len() > XATTR_SIZE_MAX { return Err(Error::TooBig); }
+
+    let old = match flags {
+      0 => self.attrs.get(name),
+      XATTR_CREATE if self.attrs.contains_key(name) => return Err(Error::Exists),
+      XATTR_CREATE => None,
+      XATTR_REPLACE => Some(self.attrs.get(name).ok_or(Error::NoAttribute)?),
+      _ => return Err(Error::InvalidArgument)
+    };
+
+    let size = match old {
+      Some(old) => self.encoded_len() - old.len() + value.len(),
+      None => self.encoded_len() + XATTR_ENTRY_HEADER + name.len() + value.len()
+    };
+
+    if size > limit { return Err(Error::NoSpace); }
+    self.attrs.insert(name.to_string(), value.to_vec());
+    Ok(())
+  }
+
+  /// Fails with `NoAttribute` if there's no attribute `name`.
+  pub fn remove(&mut self, name: &str) -> Result<()> {
+    check_name(name)?;
+    self.attrs.remove(name).map(|_| ()).ok_or(Error::NoAttribute)
+  }
+
+  /// The attributes, in order of name.
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+    self.attrs.iter().map(|(name, value)| (name.as_str(), &value[..]))
+  }
+
+  /// The size of the attributes once encoded with `disk::encode_xattrs`.
+  pub fn encoded_len(&self) -> usize {
+    self.iter().fold(XATTR_HEADER, |size, (name, value)| {
+      size + XATTR_ENTRY_HEADER + name.len() + value.len()
+    })
+  }
+}
+
+impl From<Vec<(String, Vec<u8>)>> for Xattrs {
+  fn from(attrs: Vec<(String, Vec<u8>)>) -> Xattrs {
+    Xattrs { attrs: attrs.into_iter().collect() }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Xattrs, XATTR_CREATE, XATTR_REPLACE, XATTR_NAME_MAX, XATTR_SIZE_MAX};
+  use disk::{encode_xattrs, decode_xattrs};
+  use error::Error;
+
+  #[test]
+  fn test_set_get_remove() {
+    let mut xattrs = Xattrs::default();
+    assert_eq!(xattrs.get("user.a"), Err(Error::NoAttribute));
+    assert_eq!(xattrs.set("user.a", b"1", XATTR_REPLACE, 512), Err(Error::NoAttribute));
+    xattrs.set("user.a", b"1", XATTR_CREATE, 512).unwrap();
+    assert_eq!(xattrs.set("user.a", b"2", XATTR_CREATE, 512), Err(Error::Exists));
+    xattrs.set("user.a", b"22", XATTR_REPLACE, 512).unwrap();
+    xattrs.set("security.b", b"", 0, 512).unwrap();
+    assert_eq!(xattrs.set("user.a", b"", XATTR_CREATE | XATTR_REPLACE, 512),
+               Err(Error::InvalidArgument));
+
+    assert_eq!(xattrs.get("user.a"), Ok(&b"22"[..]));
+    assert_eq!(xattrs.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+               ["security.b", "user.a"]);
+
+    let encoded = encode_xattrs(&xattrs.iter().collect::<Vec<_>>());
+    assert_eq!(encoded.len(), xattrs.encoded_len());
+    assert_eq!(decode_xattrs(&encoded).map(Xattrs::from), Ok(xattrs.clone()));
+    assert_eq!(decode_xattrs(&encoded[..(encoded.len() - 1)]), Err(Error::Corrupted));
+
+    xattrs.remove("user.a").unwrap();
+    assert_eq!(xattrs.remove("user.a"), Err(Error::NoAttribute));
+    xattrs.remove("security.b").unwrap();
+    assert!(xattrs.is_empty());
+  }
+
+  #[test]
+  fn test_limits() {
+    let mut xattrs = Xattrs::default();
+    for &name in ["a", "user", "user.", "system.posix_acl_access", "os2.x"].iter() {
+      assert_eq!(xattrs.set(name, b"", 0, 512), Err(Error::NotSupported));
+      assert_eq!(xattrs.get(name), Err(Error::NotSupported));
+    }
+
+    let long = format!("user.{}", "x".repeat(XATTR_NAME_MAX));
+    assert_eq!(xattrs.set(&long, b"", 0, 512), Err(Error::OutOfRange));
+    let big = vec![0u8; XATTR_SIZE_MAX + 1];
+    assert_eq!(xattrs.set("user.a", &big, 0, 1 << 20), Err(Error::TooBig));
+
+    // Replacing a value only counts the difference in size.
+    let fits = 512 - xattrs.encoded_len() - 2 * (super::XATTR_ENTRY_HEADER + 6);
+    xattrs.set("user.a", &vec![1u8; fits / 2], 0, 512).unwrap();
+    xattrs.set("user.b", &vec![2u8; fits - fits / 2], 0, 512).unwrap();
+    assert_eq!(xattrs.encoded_len(), 512);
+    assert_eq!(xattrs.set("user.c", b"", 0, 512), Err(Error::NoSpace));
+    assert_eq!(xattrs.set("user.a", &vec![1u8; fits / 2 + 1], 0, 512),
+               Err(Error::NoSpace));
+    xattrs.set("user.a", b"", 0, 512).unwrap();
+    xattrs.set("user.b", &vec![2u8; fits], 0, 512).unwrap();
+  }
+}